@@ -0,0 +1,240 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimal bindings to the Linux native asynchronous I/O interface
+//! (io_setup/io_submit/io_getevents/io_destroy).
+
+use std::io;
+use std::os::unix::io::RawFd;
+
+pub const IOCB_CMD_PREAD: u16 = 0;
+pub const IOCB_CMD_PWRITE: u16 = 1;
+
+/// Signal completions on the eventfd stored in `aio_resfd`.
+pub const IOCB_FLAG_RESFD: u32 = 1 << 0;
+
+/// Control block describing a single asynchronous operation, matching the
+/// kernel's `struct iocb`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct IoControlBlock {
+    pub aio_data: u64,
+    pub aio_key: u32,
+    pub aio_rw_flags: u32,
+    pub aio_lio_opcode: u16,
+    pub aio_reqprio: i16,
+    pub aio_fildes: u32,
+    pub aio_buf: u64,
+    pub aio_nbytes: u64,
+    pub aio_offset: i64,
+    pub aio_reserved2: u64,
+    pub aio_flags: u32,
+    pub aio_resfd: u32,
+}
+
+impl IoControlBlock {
+    /// Build a read or write control block, signaling completion through
+    /// `eventfd`.
+    pub fn new_rw(
+        opcode: u16,
+        fd: RawFd,
+        buf: u64,
+        len: u64,
+        offset: i64,
+        eventfd: RawFd,
+        user_data: u64,
+    ) -> Self {
+        IoControlBlock {
+            aio_data: user_data,
+            aio_lio_opcode: opcode,
+            aio_fildes: fd as u32,
+            aio_buf: buf,
+            aio_nbytes: len,
+            aio_offset: offset,
+            aio_flags: IOCB_FLAG_RESFD,
+            aio_resfd: eventfd as u32,
+            ..Default::default()
+        }
+    }
+}
+
+/// Completion of an asynchronous operation, matching the kernel's
+/// `struct io_event`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct IoEvent {
+    pub data: u64,
+    pub obj: u64,
+    pub res: i64,
+    pub res2: i64,
+}
+
+/// An AIO context, destroyed when dropped.
+pub struct IoContext(libc::c_ulong);
+
+impl IoContext {
+    /// Create a new context able to hold `nr_events` operations in flight.
+    pub fn new(nr_events: u32) -> io::Result<Self> {
+        let mut ctx: libc::c_ulong = 0;
+        // Safe because we give a valid pointer to a context we own.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_io_setup,
+                nr_events as libc::c_long,
+                &mut ctx as *mut libc::c_ulong,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(IoContext(ctx))
+    }
+
+    /// Submit the given control blocks, returning how many of them were
+    /// queued by the kernel.
+    pub fn submit(&self, iocbs: &[&IoControlBlock]) -> io::Result<usize> {
+        // Safe because the kernel only reads the control blocks, and copies
+        // them before returning.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_io_submit,
+                self.0,
+                iocbs.len() as libc::c_long,
+                iocbs.as_ptr(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(ret as usize)
+    }
+
+    /// Retrieve completed operations without blocking.
+    pub fn get_events(&self, events: &mut [IoEvent]) -> io::Result<usize> {
+        let min_nr: libc::c_long = 0;
+        let timeout = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // Safe because the kernel writes at most `events.len()` entries.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_io_getevents,
+                self.0,
+                min_nr,
+                events.len() as libc::c_long,
+                events.as_mut_ptr(),
+                &timeout as *const libc::timespec,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(ret as usize)
+    }
+}
+
+impl Drop for IoContext {
+    fn drop(&mut self) {
+        // Safe because the context is valid and not used after this.
+        unsafe { libc::syscall(libc::SYS_io_destroy, self.0) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, OpenOptions};
+    use std::os::unix::io::AsRawFd;
+    use std::process;
+    use vmm_sys_util::eventfd::EventFd;
+
+    fn wait_event(ctx: &IoContext, eventfd: &EventFd) -> IoEvent {
+        let mut events = [IoEvent::default(); 1];
+        loop {
+            // The eventfd is blocking, wait for the kernel to signal the
+            // completion before collecting it.
+            eventfd.read().unwrap();
+            if ctx.get_events(&mut events).unwrap() == 1 {
+                return events[0];
+            }
+        }
+    }
+
+    #[test]
+    fn aio_write_read() {
+        let ctx = match IoContext::new(4) {
+            Ok(ctx) => ctx,
+            // Linux native AIO can be disabled on the host.
+            Err(_) => return,
+        };
+        let path = std::env::temp_dir().join(format!("aio-{}.img", process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        file.set_len(0x10000).unwrap();
+        let eventfd = EventFd::new(0).unwrap();
+
+        let data = [0xa5u8; 512];
+        let iocb = IoControlBlock::new_rw(
+            IOCB_CMD_PWRITE,
+            file.as_raw_fd(),
+            data.as_ptr() as u64,
+            data.len() as u64,
+            0x1000,
+            eventfd.as_raw_fd(),
+            1,
+        );
+        assert_eq!(ctx.submit(&[&iocb]).unwrap(), 1);
+        let event = wait_event(&ctx, &eventfd);
+        assert_eq!(event.data, 1);
+        assert_eq!(event.res, 512);
+
+        let mut buf = [0u8; 1024];
+        let iocb = IoControlBlock::new_rw(
+            IOCB_CMD_PREAD,
+            file.as_raw_fd(),
+            buf.as_mut_ptr() as u64,
+            buf.len() as u64,
+            0x1000 - 512,
+            eventfd.as_raw_fd(),
+            2,
+        );
+        assert_eq!(ctx.submit(&[&iocb]).unwrap(), 1);
+        let event = wait_event(&ctx, &eventfd);
+        assert_eq!(event.data, 2);
+        assert_eq!(event.res, 1024);
+        assert!(buf[..512].iter().all(|&b| b == 0));
+        assert!(buf[512..].iter().all(|&b| b == 0xa5));
+    }
+
+    #[test]
+    fn aio_get_events_does_not_block() {
+        let ctx = match IoContext::new(1) {
+            Ok(ctx) => ctx,
+            Err(_) => return,
+        };
+        let mut events = [IoEvent::default(); 1];
+        assert_eq!(ctx.get_events(&mut events).unwrap(), 0);
+    }
+
+    #[test]
+    fn aio_submit_invalid_fd() {
+        let ctx = match IoContext::new(1) {
+            Ok(ctx) => ctx,
+            Err(_) => return,
+        };
+        let buf = [0u8; 512];
+        let iocb = IoControlBlock::new_rw(IOCB_CMD_PWRITE, -1, buf.as_ptr() as u64, 512, 0, -1, 0);
+        assert!(ctx.submit(&[&iocb]).is_err());
+    }
+}
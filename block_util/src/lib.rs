@@ -13,21 +13,32 @@ extern crate log;
 #[macro_use]
 extern crate serde_derive;
 
+pub mod aio;
+pub mod nbd;
+pub mod stats;
+pub mod zoned;
+
 #[cfg(feature = "io_uring")]
 use io_uring::{opcode, IoUring, Probe};
+#[cfg(feature = "io_uring")]
+use qcow::QcowFile;
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 use std::cmp;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::os::linux::fs::MetadataExt;
 #[cfg(feature = "io_uring")]
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
 use std::path::PathBuf;
 use std::result;
 use virtio_bindings::bindings::virtio_blk::*;
+#[cfg(feature = "io_uring")]
+use vm_memory::Address;
 use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap};
 use vm_virtio::DescriptorChain;
 #[cfg(feature = "io_uring")]
 use vmm_sys_util::eventfd::EventFd;
+use zoned::{ZoneError, ZoneOperation, ZONE_APPEND_SECTOR_SIZE};
 
 const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
@@ -93,7 +104,14 @@ pub enum ExecuteError {
     Write(GuestMemoryError),
     Unsupported(u32),
     SubmitIoUring(io::Error),
+    /// Submitting failed after the given number of operations were handed
+    /// over, the request can only fail once they complete.
+    PartialSubmitIoUring(u32, io::Error),
+    SubmitAio(io::Error),
     GetHostAddress(GuestMemoryError),
+    Zone(ZoneError),
+    /// The write pointers of the zones couldn't be saved.
+    ZoneState(io::Error),
 }
 
 impl ExecuteError {
@@ -106,7 +124,11 @@ impl ExecuteError {
             ExecuteError::Write(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
             ExecuteError::SubmitIoUring(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::PartialSubmitIoUring(_, _) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::SubmitAio(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::GetHostAddress(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Zone(ref e) => e.status(),
+            ExecuteError::ZoneState(_) => VIRTIO_BLK_S_IOERR,
         }
     }
 }
@@ -117,6 +139,7 @@ pub enum RequestType {
     Out,
     Flush,
     GetDeviceID,
+    Zone(ZoneOperation),
     Unsupported(u32),
 }
 
@@ -130,7 +153,9 @@ pub fn request_type(
         VIRTIO_BLK_T_OUT => Ok(RequestType::Out),
         VIRTIO_BLK_T_FLUSH => Ok(RequestType::Flush),
         VIRTIO_BLK_T_GET_ID => Ok(RequestType::GetDeviceID),
-        t => Ok(RequestType::Unsupported(t)),
+        t => Ok(ZoneOperation::from_request_type(t)
+            .map(RequestType::Zone)
+            .unwrap_or(RequestType::Unsupported(t))),
     }
 }
 
@@ -180,9 +205,12 @@ impl Request {
 
         if !desc.has_next() {
             status_desc = desc;
-            // Only flush requests are allowed to skip the data descriptor.
-            if req.request_type != RequestType::Flush {
-                return Err(Error::DescriptorChainTooShort);
+            // Only flush and zone management requests are allowed to skip
+            // the data descriptor.
+            match req.request_type {
+                RequestType::Flush => {}
+                RequestType::Zone(operation) if !operation.has_data() => {}
+                _ => return Err(Error::DescriptorChainTooShort),
             }
         } else {
             data_desc = desc;
@@ -190,10 +218,16 @@ impl Request {
                 .next_descriptor()
                 .ok_or(Error::DescriptorChainTooShort)?;
 
-            if data_desc.is_write_only() && req.request_type == RequestType::Out {
+            if data_desc.is_write_only()
+                && (req.request_type == RequestType::Out
+                    || req.request_type == RequestType::Zone(ZoneOperation::Append))
+            {
                 return Err(Error::UnexpectedWriteOnlyDescriptor);
             }
-            if !data_desc.is_write_only() && req.request_type == RequestType::In {
+            if !data_desc.is_write_only()
+                && (req.request_type == RequestType::In
+                    || req.request_type == RequestType::Zone(ZoneOperation::Report))
+            {
                 return Err(Error::UnexpectedReadOnlyDescriptor);
             }
             if !data_desc.is_write_only() && req.request_type == RequestType::GetDeviceID {
@@ -215,6 +249,20 @@ impl Request {
 
         req.status_addr = status_desc.addr;
 
+        // The status of a zone append comes after the sector the data was
+        // appended to.
+        if req.request_type == RequestType::Zone(ZoneOperation::Append) {
+            if u64::from(status_desc.len) < ZONE_APPEND_SECTOR_SIZE + 1 {
+                return Err(Error::DescriptorLengthTooSmall);
+            }
+            req.status_addr = mem
+                .checked_offset(status_desc.addr, ZONE_APPEND_SECTOR_SIZE as usize)
+                .ok_or(Error::CheckedOffset(
+                    status_desc.addr,
+                    ZONE_APPEND_SECTOR_SIZE as usize,
+                ))?;
+        }
+
         Ok(req)
     }
 
@@ -261,6 +309,9 @@ impl Request {
                 mem.write_slice(&disk_id.as_slice(), self.data_addr)
                     .map_err(ExecuteError::Write)?;
             }
+            RequestType::Zone(operation) => {
+                return Err(ExecuteError::Unsupported(operation as u32))
+            }
             RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
         };
         Ok(0)
@@ -345,6 +396,9 @@ impl Request {
                     .map_err(ExecuteError::Write)?;
                 return Ok(false);
             }
+            RequestType::Zone(operation) => {
+                return Err(ExecuteError::Unsupported(operation as u32))
+            }
             RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
         }
 
@@ -356,6 +410,230 @@ impl Request {
         Ok(true)
     }
 
+    #[allow(clippy::too_many_arguments)]
+    pub fn execute_aio(
+        &self,
+        mem: &GuestMemoryMmap,
+        io_ctx: &aio::IoContext,
+        disk_nsectors: u64,
+        disk_image_fd: RawFd,
+        disk_id: &[u8],
+        eventfd: RawFd,
+        user_data: u64,
+    ) -> result::Result<bool, ExecuteError> {
+        let data_len = self.data_len;
+        let sector = self.sector;
+        let data_addr = self.data_addr;
+        let request_type = self.request_type;
+
+        let mut top: u64 = u64::from(data_len) / SECTOR_SIZE;
+        if u64::from(data_len) % SECTOR_SIZE != 0 {
+            top += 1;
+        }
+        top = top
+            .checked_add(sector)
+            .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
+        if top > disk_nsectors {
+            return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+        }
+
+        let buf = mem
+            .get_slice(data_addr, data_len as usize)
+            .map_err(ExecuteError::GetHostAddress)?
+            .as_ptr();
+        let offset = (sector as i64) << SECTOR_SHIFT;
+
+        let opcode = match request_type {
+            RequestType::In => aio::IOCB_CMD_PREAD,
+            RequestType::Out => aio::IOCB_CMD_PWRITE,
+            RequestType::Flush => {
+                // Asynchronous fsync is missing from the older kernels this
+                // engine targets, and the guest only flushes once the writes
+                // it cares about have completed anyway.
+                // Safe because we know the file descriptor is valid.
+                if unsafe { libc::fsync(disk_image_fd) } < 0 {
+                    return Err(ExecuteError::Flush(io::Error::last_os_error()));
+                }
+                return Ok(false);
+            }
+            RequestType::GetDeviceID => {
+                if (data_len as usize) < disk_id.len() {
+                    return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+                }
+                mem.write_slice(disk_id, data_addr)
+                    .map_err(ExecuteError::Write)?;
+                return Ok(false);
+            }
+            RequestType::Zone(operation) => {
+                return Err(ExecuteError::Unsupported(operation as u32))
+            }
+            RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
+        };
+
+        let iocb = aio::IoControlBlock::new_rw(
+            opcode,
+            disk_image_fd,
+            buf as u64,
+            u64::from(data_len),
+            offset,
+            eventfd,
+            user_data,
+        );
+        match io_ctx.submit(&[&iocb]) {
+            Ok(1) => Ok(true),
+            Ok(_) => Err(ExecuteError::SubmitAio(io::Error::from_raw_os_error(
+                libc::EAGAIN,
+            ))),
+            Err(e) => Err(ExecuteError::SubmitAio(e)),
+        }
+    }
+
+    #[cfg(feature = "io_uring")]
+    pub fn execute_io_uring_qcow(
+        &self,
+        mem: &GuestMemoryMmap,
+        io_uring: &mut IoUring,
+        disk_nsectors: u64,
+        disk_image: &mut QcowFile,
+        disk_id: &[u8],
+        user_data: u64,
+    ) -> result::Result<u32, ExecuteError> {
+        let data_len = self.data_len;
+        let sector = self.sector;
+        let data_addr = self.data_addr;
+        let request_type = self.request_type;
+
+        let mut top: u64 = u64::from(data_len) / SECTOR_SIZE;
+        if u64::from(data_len) % SECTOR_SIZE != 0 {
+            top += 1;
+        }
+        top = top
+            .checked_add(sector)
+            .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
+        if top > disk_nsectors {
+            return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+        }
+
+        let buf = mem
+            .get_slice(data_addr, data_len as usize)
+            .map_err(ExecuteError::GetHostAddress)?
+            .as_ptr();
+        let offset = sector << SECTOR_SHIFT;
+        let disk_image_fd = disk_image.as_raw_fd();
+        let cluster_size = disk_image.cluster_size();
+
+        // The guest range is split at cluster boundaries since consecutive
+        // guest clusters are not contiguous in the host file. Translating
+        // guest offsets, and allocating clusters for writes, is done
+        // synchronously so that the L2 tables are up to date before any
+        // data is submitted.
+        let mut entries = Vec::new();
+        let mut done: u64 = 0;
+        while done < u64::from(data_len) {
+            let address = offset + done;
+            let count = cmp::min(
+                cluster_size - address % cluster_size,
+                u64::from(data_len) - done,
+            );
+            // Safe because the whole range has been validated by vm-memory.
+            let chunk = unsafe { buf.add(done as usize) };
+
+            match request_type {
+                RequestType::In => match disk_image
+                    .file_offset_read(address)
+                    .map_err(ExecuteError::Seek)?
+                {
+                    Some(host_offset) => entries.push(
+                        opcode::Read::new(opcode::types::Fd(disk_image_fd), chunk, count as u32)
+                            .offset(host_offset as i64)
+                            .build()
+                            .user_data(user_data),
+                    ),
+                    // Unallocated clusters read as zeros.
+                    None => mem
+                        .write_slice(&vec![0u8; count as usize], data_addr.unchecked_add(done))
+                        .map_err(ExecuteError::Read)?,
+                },
+                RequestType::Out => {
+                    let host_offset = disk_image
+                        .file_offset_write(address)
+                        .map_err(ExecuteError::Seek)?;
+                    entries.push(
+                        opcode::Write::new(opcode::types::Fd(disk_image_fd), chunk, count as u32)
+                            .offset(host_offset as i64)
+                            .build()
+                            .user_data(user_data),
+                    );
+                }
+                _ => break,
+            }
+
+            done += count;
+        }
+
+        match request_type {
+            RequestType::In | RequestType::Out => {}
+            RequestType::Flush => {
+                // Commit the metadata and sync the host file. The guest only
+                // flushes once the writes it cares about have completed.
+                disk_image.flush().map_err(ExecuteError::Flush)?;
+                return Ok(0);
+            }
+            RequestType::GetDeviceID => {
+                if (data_len as usize) < disk_id.len() {
+                    return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+                }
+                mem.write_slice(disk_id, data_addr)
+                    .map_err(ExecuteError::Write)?;
+                return Ok(0);
+            }
+            RequestType::Zone(operation) => {
+                return Err(ExecuteError::Unsupported(operation as u32))
+            }
+            RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
+        }
+
+        if entries.is_empty() {
+            return Ok(0);
+        }
+
+        // The entries pushed to the submission queue are processed by the
+        // kernel whatever happens next, the request can't be completed before
+        // their completion.
+        let submit_error = |pushed: u32, e: io::Error| {
+            if pushed == 0 {
+                ExecuteError::SubmitIoUring(e)
+            } else {
+                ExecuteError::PartialSubmitIoUring(pushed, e)
+            }
+        };
+        let mut pushed = 0;
+        let (submitter, sq, _) = io_uring.split();
+        let mut avail_sq = sq.available();
+
+        for entry in entries {
+            // Safe because we know the file descriptor is valid and we
+            // relied on vm-memory to provide the buffer addresses.
+            if let Err(entry) = unsafe { avail_sq.push(entry) } {
+                // The submission queue is full, hand the pending entries over
+                // to the kernel to make some room.
+                avail_sq.sync();
+                submitter.submit().map_err(|e| submit_error(pushed, e))?;
+                avail_sq.sync();
+                unsafe { avail_sq.push(entry) }
+                    .map_err(|_| submit_error(pushed, io::Error::from_raw_os_error(libc::EBUSY)))?;
+            }
+            pushed += 1;
+        }
+
+        // Update the submission queue and submit new operations to the
+        // io_uring instance.
+        avail_sq.sync();
+        submitter.submit().map_err(|e| submit_error(pushed, e))?;
+
+        Ok(pushed)
+    }
+
     pub fn set_writeback(&mut self, writeback: bool) {
         self.writeback = writeback
     }
@@ -543,3 +821,15 @@ pub fn block_io_uring_is_supported() -> bool {
 
     true
 }
+
+pub fn block_aio_is_supported() -> bool {
+    // Check we can create an AIO context, which effectively verifies that
+    // io_setup() syscall is supported.
+    match aio::IoContext::new(1) {
+        Ok(_) => true,
+        Err(e) => {
+            info!("Linux AIO not supported: failed to create context: {}", e);
+            false
+        }
+    }
+}
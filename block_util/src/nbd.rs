@@ -0,0 +1,1202 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Client side of the Network Block Device protocol, allowing a disk to be
+//! backed by an export served over a Unix or TCP socket.
+//!
+//! Only the fixed newstyle handshake is supported. Requests are issued one
+//! at a time, and the connection is transparently re-established when the
+//! server goes away. Since the server may lose the writes it didn't make
+//! persistent along with the connection, the next flush fails in that case.
+
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::result;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use vmm_sys_util::write_zeroes::{PunchHole, WriteZeroesAt};
+
+const NBD_DEFAULT_PORT: u16 = 10809;
+
+// Handshake magic numbers.
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+const NBD_OPTS_MAGIC: u64 = 0x4948_4156_454f_5054;
+const NBD_CLISERV_MAGIC: u64 = 0x0000_4202_8186_1253;
+const NBD_REP_MAGIC: u64 = 0x0003_e889_0455_65a9;
+
+// Handshake flags sent by the server, and client flags sent back.
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+// Options and option replies.
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+const NBD_OPT_GO: u32 = 7;
+const NBD_OPT_STRUCTURED_REPLY: u32 = 8;
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_FLAG_ERROR: u32 = 1 << 31;
+const NBD_REP_ERR_UNSUP: u32 = NBD_REP_FLAG_ERROR | 1;
+const NBD_INFO_EXPORT: u16 = 0;
+// Option replies are small, anything bigger is a misbehaving server.
+const NBD_MAX_OPTION_REPLY: u32 = 64 << 10;
+
+// Transmission flags describing the export.
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+// Transmission requests and replies.
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+const NBD_STRUCTURED_REPLY_MAGIC: u32 = 0x668e_33ef;
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+const NBD_CMD_WRITE_ZEROES: u16 = 6;
+const NBD_REPLY_FLAG_DONE: u16 = 1 << 0;
+const NBD_REPLY_TYPE_NONE: u16 = 0;
+const NBD_REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const NBD_REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+const NBD_REPLY_TYPE_ERROR_BIT: u16 = 1 << 15;
+
+// Servers aren't required to accept payloads bigger than 32MiB.
+const NBD_MAX_PAYLOAD: usize = 32 << 20;
+// Trim and write zeroes lengths are limited by the 32 bits length field,
+// keep them aligned on a sector.
+const NBD_MAX_EFFECT_LENGTH: u64 = 0xffff_fe00;
+
+// Number of times a request is replayed on a new connection, and delay
+// before the first reconnection attempt, doubled after each failure.
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_DELAY_MS: u64 = 100;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The URI isn't a valid NBD URI.
+    InvalidUri(String),
+    /// The server is designated by a name, which isn't resolved.
+    HostnameNotSupported(String),
+    /// Connecting to the server failed.
+    Connect(io::Error),
+    /// Exchanging handshake messages with the server failed.
+    Handshake(io::Error),
+    /// The server doesn't support the fixed newstyle handshake.
+    UnsupportedServer,
+    /// The server sent an unexpected magic number.
+    InvalidMagic(u64),
+    /// The server rejected an option.
+    OptionRejected(u32, u32),
+    /// The server sent a reply violating the protocol.
+    InvalidReply,
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidUri(uri) => write!(f, "invalid NBD URI {}", uri),
+            HostnameNotSupported(host) => {
+                write!(f, "NBD server must be an IP address, not {}", host)
+            }
+            Connect(e) => write!(f, "failed to connect to NBD server: {}", e),
+            Handshake(e) => write!(f, "NBD handshake failed: {}", e),
+            UnsupportedServer => write!(f, "NBD server doesn't support fixed newstyle handshake"),
+            InvalidMagic(m) => write!(f, "invalid NBD magic number {:#x}", m),
+            OptionRejected(o, r) => write!(f, "NBD server rejected option {} with {:#x}", o, r),
+            InvalidReply => write!(f, "invalid reply from NBD server"),
+        }
+    }
+}
+
+/// Returns whether `path` refers to an NBD export rather than a local file.
+pub fn is_nbd_uri(path: &str) -> bool {
+    path.starts_with("nbd://") || path.starts_with("nbd+unix://")
+}
+
+/// Location of an NBD server.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NbdAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// An NBD export, described by a URI following the NBD URI format:
+/// `nbd://host[:port]/export` or `nbd+unix:///export?socket=path`. The host
+/// must be an IP address, as resolving names requires more than the VMM
+/// seccomp filter allows.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NbdUri {
+    pub address: NbdAddress,
+    pub export: String,
+}
+
+impl NbdUri {
+    pub fn parse(uri: &str) -> Result<Self> {
+        let invalid = || Error::InvalidUri(uri.to_owned());
+
+        let (unix, rest) = if let Some(rest) = uri.strip_prefix("nbd+unix://") {
+            (true, rest)
+        } else if let Some(rest) = uri.strip_prefix("nbd://") {
+            (false, rest)
+        } else {
+            return Err(invalid());
+        };
+        let (rest, query) = match rest.find('?') {
+            Some(i) => (&rest[..i], Some(&rest[i + 1..])),
+            None => (rest, None),
+        };
+        let (authority, export) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => (rest, ""),
+        };
+
+        let address = if unix {
+            if !authority.is_empty() {
+                return Err(invalid());
+            }
+            let socket = query
+                .and_then(|q| q.split('&').find_map(|p| p.strip_prefix("socket=")))
+                .filter(|s| !s.is_empty())
+                .ok_or_else(invalid)?;
+            NbdAddress::Unix(PathBuf::from(socket))
+        } else {
+            let (host, port) = if authority.starts_with('[') {
+                // IPv6 address literal
+                let end = authority.find(']').ok_or_else(invalid)?;
+                let port = &authority[end + 1..];
+                if !port.is_empty() && !port.starts_with(':') {
+                    return Err(invalid());
+                }
+                let port = if port.is_empty() {
+                    None
+                } else {
+                    Some(&port[1..])
+                };
+                (&authority[1..end], port)
+            } else {
+                match authority.rfind(':') {
+                    Some(i) => (&authority[..i], Some(&authority[i + 1..])),
+                    None => (authority, None),
+                }
+            };
+            if host.is_empty() {
+                return Err(invalid());
+            }
+            let port = match port {
+                Some(p) => p.parse().map_err(|_| invalid())?,
+                None => NBD_DEFAULT_PORT,
+            };
+            let ip: IpAddr = host
+                .parse()
+                .map_err(|_| Error::HostnameNotSupported(host.to_owned()))?;
+            NbdAddress::Tcp(SocketAddr::new(ip, port))
+        };
+
+        Ok(NbdUri {
+            address,
+            export: export.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for NbdUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.address {
+            NbdAddress::Tcp(addr) => write!(f, "nbd://{}/{}", addr, self.export),
+            NbdAddress::Unix(path) => {
+                write!(f, "nbd+unix:///{}?socket={}", self.export, path.display())
+            }
+        }
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    fn shutdown(&self) {
+        let _ = match self {
+            Stream::Tcp(s) => s.shutdown(Shutdown::Both),
+            Stream::Unix(s) => s.shutdown(Shutdown::Both),
+        };
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            Stream::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            Stream::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            Stream::Unix(s) => s.flush(),
+        }
+    }
+}
+
+fn read_u16<R: Read>(r: &mut R) -> io::Result<u16> {
+    let mut b = [0u8; 2];
+    r.read_exact(&mut b)?;
+    Ok(u16::from_be_bytes(b))
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_be_bytes(b))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut b = [0u8; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_be_bytes(b))
+}
+
+fn skip<R: Read>(r: &mut R, len: u64) -> io::Result<()> {
+    if io::copy(&mut r.take(len), &mut io::sink())? != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    Ok(())
+}
+
+fn send_option<W: Write>(w: &mut W, option: u32, data: &[u8]) -> io::Result<()> {
+    let mut msg = Vec::with_capacity(16 + data.len());
+    msg.extend_from_slice(&NBD_OPTS_MAGIC.to_be_bytes());
+    msg.extend_from_slice(&option.to_be_bytes());
+    msg.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    msg.extend_from_slice(data);
+    w.write_all(&msg)
+}
+
+// Read the reply to `option`, returning the reply type and its payload.
+fn read_option_reply<R: Read>(r: &mut R, option: u32) -> Result<(u32, Vec<u8>)> {
+    let magic = read_u64(r).map_err(Error::Handshake)?;
+    if magic != NBD_REP_MAGIC {
+        return Err(Error::InvalidMagic(magic));
+    }
+    if read_u32(r).map_err(Error::Handshake)? != option {
+        return Err(Error::InvalidReply);
+    }
+    let reply = read_u32(r).map_err(Error::Handshake)?;
+    let len = read_u32(r).map_err(Error::Handshake)?;
+    if len > NBD_MAX_OPTION_REPLY {
+        return Err(Error::InvalidReply);
+    }
+    let mut data = vec![0u8; len as usize];
+    r.read_exact(&mut data).map_err(Error::Handshake)?;
+
+    Ok((reply, data))
+}
+
+// Export properties agreed on during the handshake.
+struct ExportInfo {
+    size: u64,
+    flags: u16,
+    structured_replies: bool,
+}
+
+fn negotiate<S: Read + Write>(s: &mut S, export: &str) -> Result<ExportInfo> {
+    let magic = read_u64(s).map_err(Error::Handshake)?;
+    if magic != NBD_MAGIC {
+        return Err(Error::InvalidMagic(magic));
+    }
+    let magic = read_u64(s).map_err(Error::Handshake)?;
+    if magic == NBD_CLISERV_MAGIC {
+        return Err(Error::UnsupportedServer);
+    } else if magic != NBD_OPTS_MAGIC {
+        return Err(Error::InvalidMagic(magic));
+    }
+    let handshake_flags = read_u16(s).map_err(Error::Handshake)?;
+    if handshake_flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+        return Err(Error::UnsupportedServer);
+    }
+    let no_zeroes = handshake_flags & NBD_FLAG_NO_ZEROES != 0;
+    let mut client_flags = NBD_FLAG_C_FIXED_NEWSTYLE;
+    if no_zeroes {
+        client_flags |= NBD_FLAG_C_NO_ZEROES;
+    }
+    s.write_all(&client_flags.to_be_bytes())
+        .map_err(Error::Handshake)?;
+
+    send_option(s, NBD_OPT_STRUCTURED_REPLY, &[]).map_err(Error::Handshake)?;
+    let structured_replies = match read_option_reply(s, NBD_OPT_STRUCTURED_REPLY)?.0 {
+        NBD_REP_ACK => true,
+        r if r & NBD_REP_FLAG_ERROR != 0 => false,
+        _ => return Err(Error::InvalidReply),
+    };
+
+    let mut data = Vec::with_capacity(6 + export.len());
+    data.extend_from_slice(&(export.len() as u32).to_be_bytes());
+    data.extend_from_slice(export.as_bytes());
+    data.extend_from_slice(&0u16.to_be_bytes());
+    send_option(s, NBD_OPT_GO, &data).map_err(Error::Handshake)?;
+    let mut export_info = None;
+    loop {
+        let (reply, data) = read_option_reply(s, NBD_OPT_GO)?;
+        match reply {
+            NBD_REP_INFO => {
+                if data.len() < 2 {
+                    return Err(Error::InvalidReply);
+                }
+                if u16::from_be_bytes([data[0], data[1]]) != NBD_INFO_EXPORT {
+                    continue;
+                }
+                if data.len() != 12 {
+                    return Err(Error::InvalidReply);
+                }
+                let mut size = [0u8; 8];
+                size.copy_from_slice(&data[2..10]);
+                export_info = Some(ExportInfo {
+                    size: u64::from_be_bytes(size),
+                    flags: u16::from_be_bytes([data[10], data[11]]),
+                    structured_replies,
+                });
+            }
+            NBD_REP_ACK => return export_info.ok_or(Error::InvalidReply),
+            NBD_REP_ERR_UNSUP => break,
+            r => return Err(Error::OptionRejected(NBD_OPT_GO, r)),
+        }
+    }
+
+    // Servers predating NBD_OPT_GO only know about NBD_OPT_EXPORT_NAME,
+    // which gets a bare reply and makes the server close the connection if
+    // the export doesn't exist.
+    send_option(s, NBD_OPT_EXPORT_NAME, export.as_bytes()).map_err(Error::Handshake)?;
+    let size = read_u64(s).map_err(Error::Handshake)?;
+    let flags = read_u16(s).map_err(Error::Handshake)?;
+    if !no_zeroes {
+        skip(s, 124).map_err(Error::Handshake)?;
+    }
+
+    Ok(ExportInfo {
+        size,
+        flags,
+        structured_replies,
+    })
+}
+
+enum CommandError {
+    /// The server failed the request with the given errno value.
+    Server(u32),
+    /// The connection can't be relied upon anymore.
+    Transport(io::Error),
+}
+
+impl From<io::Error> for CommandError {
+    fn from(e: io::Error) -> Self {
+        CommandError::Transport(e)
+    }
+}
+
+fn invalid_reply() -> CommandError {
+    CommandError::Transport(io::Error::new(
+        io::ErrorKind::InvalidData,
+        Error::InvalidReply.to_string(),
+    ))
+}
+
+// Returns where the chunk covering `len` bytes from `chunk_offset` lands in
+// the buffer read from `offset`.
+fn chunk_start(
+    chunk_offset: u64,
+    len: usize,
+    offset: u64,
+    buf_len: usize,
+) -> result::Result<usize, CommandError> {
+    let start = chunk_offset
+        .checked_sub(offset)
+        .filter(|s| *s <= buf_len as u64 && len <= buf_len - *s as usize)
+        .ok_or_else(invalid_reply)?;
+    Ok(start as usize)
+}
+
+// Read the reply to the request identified by `handle`, storing the data
+// read from `offset` into `buf`.
+fn read_reply<R: Read>(
+    r: &mut R,
+    handle: u64,
+    offset: u64,
+    buf: &mut [u8],
+) -> result::Result<(), CommandError> {
+    let mut magic = read_u32(r)?;
+    if magic == NBD_SIMPLE_REPLY_MAGIC {
+        let error = read_u32(r)?;
+        if read_u64(r)? != handle {
+            return Err(invalid_reply());
+        }
+        if error != 0 {
+            return Err(CommandError::Server(error));
+        }
+        r.read_exact(buf)?;
+        return Ok(());
+    }
+
+    let mut error = None;
+    loop {
+        if magic != NBD_STRUCTURED_REPLY_MAGIC {
+            return Err(invalid_reply());
+        }
+        let flags = read_u16(r)?;
+        let reply_type = read_u16(r)?;
+        if read_u64(r)? != handle {
+            return Err(invalid_reply());
+        }
+        let len = read_u32(r)?;
+
+        match reply_type {
+            NBD_REPLY_TYPE_NONE => {
+                if len != 0 {
+                    return Err(invalid_reply());
+                }
+            }
+            NBD_REPLY_TYPE_OFFSET_DATA => {
+                if len < 8 {
+                    return Err(invalid_reply());
+                }
+                let chunk_offset = read_u64(r)?;
+                let chunk_len = (len - 8) as usize;
+                let start = chunk_start(chunk_offset, chunk_len, offset, buf.len())?;
+                r.read_exact(&mut buf[start..start + chunk_len])?;
+            }
+            NBD_REPLY_TYPE_OFFSET_HOLE => {
+                if len != 12 {
+                    return Err(invalid_reply());
+                }
+                let chunk_offset = read_u64(r)?;
+                let hole_len = read_u32(r)? as usize;
+                let start = chunk_start(chunk_offset, hole_len, offset, buf.len())?;
+                for b in &mut buf[start..start + hole_len] {
+                    *b = 0;
+                }
+            }
+            t if t & NBD_REPLY_TYPE_ERROR_BIT != 0 => {
+                if len < 6 {
+                    return Err(invalid_reply());
+                }
+                let e = read_u32(r)?;
+                // Skip the error message, and the offset which may follow.
+                skip(r, u64::from(len - 4))?;
+                if e == 0 {
+                    return Err(invalid_reply());
+                }
+                error.get_or_insert(e);
+            }
+            // Unknown non error chunks can safely be ignored.
+            _ => skip(r, u64::from(len))?,
+        }
+
+        if flags & NBD_REPLY_FLAG_DONE != 0 {
+            break;
+        }
+        magic = read_u32(r)?;
+    }
+
+    match error {
+        Some(e) => Err(CommandError::Server(e)),
+        None => Ok(()),
+    }
+}
+
+/// Connection to an NBD export.
+pub struct NbdClient {
+    uri: NbdUri,
+    stream: Option<Stream>,
+    size: u64,
+    flags: u16,
+    structured_replies: bool,
+    next_handle: u64,
+    // Writes completed since the last flush, which the server may not have
+    // made persistent yet.
+    unflushed_writes: bool,
+    // Unflushed writes may have been lost along with a connection.
+    writes_lost: bool,
+}
+
+impl NbdClient {
+    /// Connect to the export described by `uri`.
+    pub fn connect(uri: NbdUri) -> Result<Self> {
+        let mut client = NbdClient {
+            uri,
+            stream: None,
+            size: 0,
+            flags: 0,
+            structured_replies: false,
+            next_handle: 0,
+            unflushed_writes: false,
+            writes_lost: false,
+        };
+        client.establish()?;
+
+        Ok(client)
+    }
+
+    /// Size of the export in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Whether the server only allows reading from the export.
+    pub fn is_read_only(&self) -> bool {
+        self.flags & NBD_FLAG_READ_ONLY != 0
+    }
+
+    fn establish(&mut self) -> Result<()> {
+        let mut stream = match &self.uri.address {
+            NbdAddress::Tcp(addr) => {
+                let s = TcpStream::connect(addr).map_err(Error::Connect)?;
+                s.set_nodelay(true).map_err(Error::Connect)?;
+                Stream::Tcp(s)
+            }
+            NbdAddress::Unix(path) => {
+                Stream::Unix(UnixStream::connect(path).map_err(Error::Connect)?)
+            }
+        };
+
+        let info = negotiate(&mut stream, &self.uri.export)?;
+        if self.size != 0 && info.size != self.size {
+            warn!(
+                "NBD export {} size changed from {} to {}",
+                self.uri, self.size, info.size
+            );
+        }
+        self.size = info.size;
+        self.flags = info.flags;
+        self.structured_replies = info.structured_replies;
+        self.stream = Some(stream);
+
+        Ok(())
+    }
+
+    fn reconnect(&mut self) -> io::Result<()> {
+        let mut delay = Duration::from_millis(RECONNECT_DELAY_MS);
+        let mut attempt = 1;
+        loop {
+            match self.establish() {
+                Ok(()) => {
+                    info!("Reconnected to NBD export {}", self.uri);
+                    return Ok(());
+                }
+                Err(e) if attempt == RECONNECT_ATTEMPTS => {
+                    return Err(io::Error::new(io::ErrorKind::NotConnected, e.to_string()));
+                }
+                Err(e) => {
+                    warn!("Failed reconnecting to NBD export {}: {}", self.uri, e);
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    // Send a request, returning the handle identifying its reply.
+    fn send_request(
+        &mut self,
+        command: u16,
+        offset: u64,
+        len: u32,
+        data: &[u8],
+    ) -> io::Result<u64> {
+        let handle = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+
+        let stream = self
+            .stream
+            .as_mut()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        let mut request = [0u8; 28];
+        request[0..4].copy_from_slice(&NBD_REQUEST_MAGIC.to_be_bytes());
+        request[6..8].copy_from_slice(&command.to_be_bytes());
+        request[8..16].copy_from_slice(&handle.to_be_bytes());
+        request[16..24].copy_from_slice(&offset.to_be_bytes());
+        request[24..28].copy_from_slice(&len.to_be_bytes());
+        stream.write_all(&request)?;
+        stream.write_all(data)?;
+
+        Ok(handle)
+    }
+
+    fn transmit(
+        &mut self,
+        command: u16,
+        offset: u64,
+        len: u32,
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> result::Result<(), CommandError> {
+        let handle = self.send_request(command, offset, len, data)?;
+        let stream = self
+            .stream
+            .as_mut()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+
+        read_reply(stream, handle, offset, buf)
+    }
+
+    // Issue a request, replaying it on a new connection if the current one
+    // fails. This is fine as all requests are idempotent, except for flushes
+    // which can't vouch for the writes sent over the lost connection.
+    fn command(
+        &mut self,
+        command: u16,
+        offset: u64,
+        len: u32,
+        data: &[u8],
+        buf: &mut [u8],
+    ) -> io::Result<()> {
+        let mut retries = 0;
+        loop {
+            if self.stream.is_none() {
+                self.reconnect()?;
+            }
+            match self.transmit(command, offset, len, data, buf) {
+                Ok(()) => {
+                    if command == NBD_CMD_WRITE || command == NBD_CMD_WRITE_ZEROES {
+                        self.unflushed_writes = true;
+                    }
+                    return Ok(());
+                }
+                Err(CommandError::Server(e)) => return Err(io::Error::from_raw_os_error(e as i32)),
+                Err(CommandError::Transport(e)) => {
+                    if let Some(stream) = self.stream.take() {
+                        stream.shutdown();
+                    }
+                    if self.unflushed_writes {
+                        self.unflushed_writes = false;
+                        self.writes_lost = true;
+                    }
+                    if command == NBD_CMD_FLUSH {
+                        warn!(
+                            "Lost connection to NBD export {} while flushing: {}",
+                            self.uri, e
+                        );
+                        self.writes_lost = false;
+                        return Err(io::Error::from_raw_os_error(libc::EIO));
+                    }
+                    if retries == RECONNECT_ATTEMPTS {
+                        return Err(e);
+                    }
+                    warn!("Lost connection to NBD export {}: {}", self.uri, e);
+                    retries += 1;
+                }
+            }
+        }
+    }
+
+    /// Fill `buf` with the data at `offset`.
+    pub fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let mut done = 0;
+        for chunk in buf.chunks_mut(NBD_MAX_PAYLOAD) {
+            let len = chunk.len();
+            self.command(NBD_CMD_READ, offset + done, len as u32, &[], chunk)?;
+            done += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Write `data` at `offset`.
+    pub fn write_at(&mut self, data: &[u8], offset: u64) -> io::Result<()> {
+        let mut done = 0;
+        for chunk in data.chunks(NBD_MAX_PAYLOAD) {
+            self.command(
+                NBD_CMD_WRITE,
+                offset + done,
+                chunk.len() as u32,
+                chunk,
+                &mut [],
+            )?;
+            done += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Make previously completed writes persistent. Fails with EIO, once,
+    /// when some of them may have been lost by the server since the last
+    /// flush.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.flags & NBD_FLAG_SEND_FLUSH == 0 {
+            return Ok(());
+        }
+        self.command(NBD_CMD_FLUSH, 0, 0, &[], &mut [])?;
+        self.unflushed_writes = false;
+        if self.writes_lost {
+            self.writes_lost = false;
+            return Err(io::Error::from_raw_os_error(libc::EIO));
+        }
+
+        Ok(())
+    }
+
+    /// Let the server know `len` bytes from `offset` aren't needed anymore.
+    /// This is only advisory, the data may or may not be discarded.
+    pub fn trim(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        if self.flags & NBD_FLAG_SEND_TRIM == 0 {
+            return Ok(());
+        }
+        self.effect(NBD_CMD_TRIM, offset, len)
+    }
+
+    /// Write `len` zero bytes at `offset`.
+    pub fn write_zeroes(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        if self.flags & NBD_FLAG_SEND_WRITE_ZEROES != 0 {
+            return self.effect(NBD_CMD_WRITE_ZEROES, offset, len);
+        }
+
+        let zeroes = vec![0u8; cmp::min(len, NBD_MAX_PAYLOAD as u64) as usize];
+        let mut done = 0;
+        while done < len {
+            let chunk = cmp::min(len - done, zeroes.len() as u64) as usize;
+            self.write_at(&zeroes[..chunk], offset + done)?;
+            done += chunk as u64;
+        }
+        Ok(())
+    }
+
+    // Issue a request carrying no payload, affecting `len` bytes.
+    fn effect(&mut self, command: u16, offset: u64, len: u64) -> io::Result<()> {
+        let mut done = 0;
+        while done < len {
+            let chunk = cmp::min(len - done, NBD_MAX_EFFECT_LENGTH);
+            self.command(command, offset + done, chunk as u32, &[], &mut [])?;
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for NbdClient {
+    fn drop(&mut self) {
+        if self.stream.is_some() {
+            // The server doesn't reply to a disconnection request.
+            let _ = self.send_request(NBD_CMD_DISC, 0, 0, &[]);
+        }
+    }
+}
+
+/// Disk image backed by an NBD export. Clones share the same connection,
+/// each keeping its own position.
+#[derive(Clone)]
+pub struct NbdDisk {
+    client: Arc<Mutex<NbdClient>>,
+    size: u64,
+    position: u64,
+}
+
+impl NbdDisk {
+    /// Connect to the export described by `uri`.
+    pub fn new(uri: &str) -> Result<Self> {
+        let client = NbdClient::connect(NbdUri::parse(uri)?)?;
+
+        Ok(NbdDisk {
+            size: client.size(),
+            client: Arc::new(Mutex::new(client)),
+            position: 0,
+        })
+    }
+
+    /// Whether the server only allows reading from the export.
+    pub fn is_read_only(&self) -> bool {
+        self.client.lock().unwrap().is_read_only()
+    }
+
+    fn remaining(&self, len: usize) -> usize {
+        cmp::min(len as u64, self.size.saturating_sub(self.position)) as usize
+    }
+}
+
+impl Read for NbdDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.remaining(buf.len());
+        if len > 0 {
+            self.client
+                .lock()
+                .unwrap()
+                .read_at(&mut buf[..len], self.position)?;
+            self.position += len as u64;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for NbdDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.remaining(buf.len());
+        if len > 0 {
+            self.client
+                .lock()
+                .unwrap()
+                .write_at(&buf[..len], self.position)?;
+            self.position += len as u64;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.client.lock().unwrap().flush()
+    }
+}
+
+impl Seek for NbdDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(off) => {
+                if off < 0 {
+                    self.size.checked_sub(off.wrapping_neg() as u64)
+                } else {
+                    self.size.checked_add(off as u64)
+                }
+            }
+            SeekFrom::Current(off) => {
+                if off < 0 {
+                    self.position.checked_sub(off.wrapping_neg() as u64)
+                } else {
+                    self.position.checked_add(off as u64)
+                }
+            }
+        };
+
+        match new_pos {
+            Some(p) => {
+                self.position = p;
+                Ok(p)
+            }
+            None => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+}
+
+impl PunchHole for NbdDisk {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.client.lock().unwrap().trim(offset, length)
+    }
+}
+
+impl WriteZeroesAt for NbdDisk {
+    fn write_zeroes_at(&mut self, offset: u64, length: usize) -> io::Result<usize> {
+        let length = cmp::min(length as u64, self.size.saturating_sub(offset));
+        self.client.lock().unwrap().write_zeroes(offset, length)?;
+        Ok(length as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const EXPORT_SIZE: usize = 1 << 20;
+    const HOLE_OFFSET: usize = 4096;
+    const HOLE_SIZE: usize = 4096;
+
+    // Where the server drops a connection.
+    #[derive(Clone, Copy, PartialEq)]
+    enum DropPoint {
+        Handshake,
+        Command(u16),
+    }
+
+    // Minimal NBD server backed by memory, answering reads with a data
+    // chunk and a hole chunk when the read spans the hole.
+    fn serve(
+        mut s: UnixStream,
+        disk: &Mutex<Vec<u8>>,
+        drop_point: Option<DropPoint>,
+    ) -> io::Result<()> {
+        s.write_all(&NBD_MAGIC.to_be_bytes())?;
+        s.write_all(&NBD_OPTS_MAGIC.to_be_bytes())?;
+        s.write_all(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes())?;
+        read_u32(&mut s)?;
+
+        loop {
+            assert_eq!(read_u64(&mut s)?, NBD_OPTS_MAGIC);
+            let option = read_u32(&mut s)?;
+            let len = read_u32(&mut s)?;
+            skip(&mut s, u64::from(len))?;
+            let reply = |s: &mut UnixStream, reply: u32, data: &[u8]| {
+                s.write_all(&NBD_REP_MAGIC.to_be_bytes())?;
+                s.write_all(&option.to_be_bytes())?;
+                s.write_all(&reply.to_be_bytes())?;
+                s.write_all(&(data.len() as u32).to_be_bytes())?;
+                s.write_all(data)
+            };
+            match option {
+                NBD_OPT_STRUCTURED_REPLY => reply(&mut s, NBD_REP_ACK, &[])?,
+                NBD_OPT_GO => {
+                    let mut info = Vec::new();
+                    info.extend_from_slice(&NBD_INFO_EXPORT.to_be_bytes());
+                    info.extend_from_slice(&(EXPORT_SIZE as u64).to_be_bytes());
+                    let flags = NBD_FLAG_SEND_FLUSH | NBD_FLAG_SEND_TRIM;
+                    info.extend_from_slice(&flags.to_be_bytes());
+                    reply(&mut s, NBD_REP_INFO, &info)?;
+                    reply(&mut s, NBD_REP_ACK, &[])?;
+                    break;
+                }
+                _ => reply(&mut s, NBD_REP_ERR_UNSUP, &[])?,
+            }
+        }
+
+        if drop_point == Some(DropPoint::Handshake) {
+            return Ok(());
+        }
+
+        loop {
+            assert_eq!(read_u32(&mut s)?, NBD_REQUEST_MAGIC);
+            read_u16(&mut s)?;
+            let command = read_u16(&mut s)?;
+            let handle = read_u64(&mut s)?;
+            let offset = read_u64(&mut s)? as usize;
+            let len = read_u32(&mut s)? as usize;
+            if drop_point == Some(DropPoint::Command(command)) {
+                return Ok(());
+            }
+            let simple_reply = |s: &mut UnixStream| {
+                s.write_all(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes())?;
+                s.write_all(&0u32.to_be_bytes())?;
+                s.write_all(&handle.to_be_bytes())
+            };
+            let chunk = |s: &mut UnixStream, flags: u16, reply_type: u16, data: &[u8]| {
+                s.write_all(&NBD_STRUCTURED_REPLY_MAGIC.to_be_bytes())?;
+                s.write_all(&flags.to_be_bytes())?;
+                s.write_all(&reply_type.to_be_bytes())?;
+                s.write_all(&handle.to_be_bytes())?;
+                s.write_all(&(data.len() as u32).to_be_bytes())?;
+                s.write_all(data)
+            };
+
+            match command {
+                NBD_CMD_READ => {
+                    let disk = disk.lock().unwrap();
+                    let end = offset + len;
+                    if offset < HOLE_OFFSET && end > HOLE_OFFSET + HOLE_SIZE {
+                        let mut data = (offset as u64).to_be_bytes().to_vec();
+                        data.extend_from_slice(&disk[offset..HOLE_OFFSET]);
+                        chunk(&mut s, 0, NBD_REPLY_TYPE_OFFSET_DATA, &data)?;
+                        let mut hole = (HOLE_OFFSET as u64).to_be_bytes().to_vec();
+                        hole.extend_from_slice(&(HOLE_SIZE as u32).to_be_bytes());
+                        chunk(&mut s, 0, NBD_REPLY_TYPE_OFFSET_HOLE, &hole)?;
+                        let tail = HOLE_OFFSET + HOLE_SIZE;
+                        let mut data = (tail as u64).to_be_bytes().to_vec();
+                        data.extend_from_slice(&disk[tail..end]);
+                        chunk(
+                            &mut s,
+                            NBD_REPLY_FLAG_DONE,
+                            NBD_REPLY_TYPE_OFFSET_DATA,
+                            &data,
+                        )?;
+                    } else {
+                        let mut data = (offset as u64).to_be_bytes().to_vec();
+                        data.extend_from_slice(&disk[offset..end]);
+                        chunk(
+                            &mut s,
+                            NBD_REPLY_FLAG_DONE,
+                            NBD_REPLY_TYPE_OFFSET_DATA,
+                            &data,
+                        )?;
+                    }
+                }
+                NBD_CMD_WRITE => {
+                    let mut disk = disk.lock().unwrap();
+                    s.read_exact(&mut disk[offset..offset + len])?;
+                    simple_reply(&mut s)?;
+                }
+                NBD_CMD_FLUSH => chunk(&mut s, NBD_REPLY_FLAG_DONE, NBD_REPLY_TYPE_NONE, &[])?,
+                NBD_CMD_TRIM => {
+                    let mut msg = (libc::EPERM as u32).to_be_bytes().to_vec();
+                    msg.extend_from_slice(&0u16.to_be_bytes());
+                    chunk(
+                        &mut s,
+                        NBD_REPLY_FLAG_DONE,
+                        NBD_REPLY_TYPE_ERROR_BIT | 1,
+                        &msg,
+                    )?;
+                }
+                NBD_CMD_DISC => return Ok(()),
+                _ => panic!("unexpected command {}", command),
+            }
+        }
+    }
+
+    fn start_server(name: &str, drop_first: Option<DropPoint>) -> (PathBuf, Arc<AtomicUsize>) {
+        let path = std::env::temp_dir().join(format!("nbd-{}-{}.sock", name, process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let count = connections.clone();
+        thread::spawn(move || {
+            let disk = Mutex::new(vec![0u8; EXPORT_SIZE]);
+            for s in listener.incoming() {
+                let n = count.fetch_add(1, Ordering::SeqCst);
+                let _ = serve(s.unwrap(), &disk, drop_first.filter(|_| n == 0));
+            }
+        });
+        (path, connections)
+    }
+
+    #[test]
+    fn parse_uri() {
+        assert_eq!(
+            NbdUri::parse("nbd://127.0.0.1/disk0").unwrap(),
+            NbdUri {
+                address: NbdAddress::Tcp(SocketAddr::from(([127, 0, 0, 1], NBD_DEFAULT_PORT))),
+                export: "disk0".to_owned(),
+            }
+        );
+        assert_eq!(
+            NbdUri::parse("nbd://[::1]:1234").unwrap(),
+            NbdUri {
+                address: NbdAddress::Tcp("[::1]:1234".parse().unwrap()),
+                export: "".to_owned(),
+            }
+        );
+        assert_eq!(
+            NbdUri::parse("nbd://[::1]:1234/disk0").unwrap().to_string(),
+            "nbd://[::1]:1234/disk0"
+        );
+        match NbdUri::parse("nbd://localhost/disk0") {
+            Err(Error::HostnameNotSupported(host)) => assert_eq!(host, "localhost"),
+            _ => panic!("the hostname should be rejected"),
+        }
+        assert_eq!(
+            NbdUri::parse("nbd+unix:///disk0?socket=/run/nbd.sock").unwrap(),
+            NbdUri {
+                address: NbdAddress::Unix(PathBuf::from("/run/nbd.sock")),
+                export: "disk0".to_owned(),
+            }
+        );
+        assert!(NbdUri::parse("nbd+unix:///disk0").is_err());
+        assert!(NbdUri::parse("nbd+unix://host/disk0?socket=/run/nbd.sock").is_err());
+        assert!(NbdUri::parse("nbd://host:port/disk0").is_err());
+        assert!(NbdUri::parse("nbd:///disk0").is_err());
+        assert!(NbdUri::parse("/path/to/disk").is_err());
+        assert!(is_nbd_uri("nbd+unix:///disk0?socket=/run/nbd.sock"));
+        assert!(!is_nbd_uri("/path/to/disk"));
+    }
+
+    #[test]
+    fn read_write() {
+        let (path, _) = start_server("rw", None);
+        let uri = format!("nbd+unix:///disk0?socket={}", path.display());
+        let mut disk = NbdDisk::new(&uri).unwrap();
+        assert_eq!(disk.seek(SeekFrom::End(0)).unwrap(), EXPORT_SIZE as u64);
+        assert!(!disk.is_read_only());
+
+        let data: Vec<u8> = (0..4 * HOLE_SIZE).map(|i| (i % 251) as u8 + 1).collect();
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        disk.write_all(&data).unwrap();
+        disk.flush().unwrap();
+
+        // The server reports a hole in the middle of the data.
+        let mut buf = vec![0xffu8; data.len()];
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..HOLE_OFFSET], &data[..HOLE_OFFSET]);
+        assert!(buf[HOLE_OFFSET..HOLE_OFFSET + HOLE_SIZE]
+            .iter()
+            .all(|b| *b == 0));
+        assert_eq!(
+            &buf[HOLE_OFFSET + HOLE_SIZE..],
+            &data[HOLE_OFFSET + HOLE_SIZE..]
+        );
+
+        // Errors sent by the server are reported as such.
+        let err = disk.punch_hole(0, 4096).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+
+        // Nothing can be written past the end of the export.
+        disk.seek(SeekFrom::Start(EXPORT_SIZE as u64 - 1)).unwrap();
+        assert_eq!(disk.write(&[1, 2]).unwrap(), 1);
+        assert_eq!(disk.write(&[1]).unwrap(), 0);
+        assert_eq!(disk.read(&mut buf).unwrap(), 0);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn reconnect() {
+        let (path, connections) = start_server("reconnect", Some(DropPoint::Handshake));
+        let uri = format!("nbd+unix:///disk0?socket={}", path.display());
+        let mut disk = NbdDisk::new(&uri).unwrap();
+
+        // The server drops the first connection, the write is replayed on
+        // a new one.
+        disk.write_all(&[0xaa; 512]).unwrap();
+        let mut buf = [0u8; 512];
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 0xaa));
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn flush_interrupted() {
+        // The server drops the first connection when asked to flush.
+        let (path, _) = start_server("flush", Some(DropPoint::Command(NBD_CMD_FLUSH)));
+        let uri = format!("nbd+unix:///disk0?socket={}", path.display());
+        let mut disk = NbdDisk::new(&uri).unwrap();
+
+        disk.write_all(&[0xaa; 512]).unwrap();
+        let err = disk.flush().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        // The failure is only reported once.
+        disk.flush().unwrap();
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn flush_after_reconnect() {
+        // The server drops the first connection on the first read, which is
+        // replayed, while the write before may have been lost.
+        let (path, connections) = start_server("lost", Some(DropPoint::Command(NBD_CMD_READ)));
+        let uri = format!("nbd+unix:///disk0?socket={}", path.display());
+        let mut disk = NbdDisk::new(&uri).unwrap();
+
+        disk.write_all(&[0xaa; 512]).unwrap();
+        let mut buf = [0u8; 512];
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+        let err = disk.flush().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+
+        disk.write_all(&[0x55; 512]).unwrap();
+        disk.flush().unwrap();
+
+        let _ = fs::remove_file(&path);
+    }
+}
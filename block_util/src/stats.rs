@@ -0,0 +1,209 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Latency histograms and queue depth of a block device.
+//!
+//! The statistics only rely on atomic counters, so that they can be shared
+//! between the threads of a device, or between a vhost-user-block backend and
+//! the VMM through a file mapped by both processes.
+
+use super::zoned::ZoneOperation;
+use super::RequestType;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::num::Wrapping;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use vm_memory::{FileOffset, MmapRegion};
+
+/// Upper bounds, in microseconds, of the latency histogram buckets. An extra
+/// bucket counts the requests slower than the last bound.
+pub const LATENCY_BUCKETS_US: [u64; 16] = [
+    10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000,
+    500_000, 1_000_000,
+];
+const LATENCY_BUCKETS: usize = LATENCY_BUCKETS_US.len() + 1;
+
+macro_rules! latency_counter_names {
+    ($op:expr) => {
+        [
+            concat!($op, "_latency_us_10"),
+            concat!($op, "_latency_us_25"),
+            concat!($op, "_latency_us_50"),
+            concat!($op, "_latency_us_100"),
+            concat!($op, "_latency_us_250"),
+            concat!($op, "_latency_us_500"),
+            concat!($op, "_latency_us_1000"),
+            concat!($op, "_latency_us_2500"),
+            concat!($op, "_latency_us_5000"),
+            concat!($op, "_latency_us_10000"),
+            concat!($op, "_latency_us_25000"),
+            concat!($op, "_latency_us_50000"),
+            concat!($op, "_latency_us_100000"),
+            concat!($op, "_latency_us_250000"),
+            concat!($op, "_latency_us_500000"),
+            concat!($op, "_latency_us_1000000"),
+            concat!($op, "_latency_us_inf"),
+        ]
+    };
+}
+
+const READ_LATENCY_COUNTERS: [&str; LATENCY_BUCKETS] = latency_counter_names!("read");
+const WRITE_LATENCY_COUNTERS: [&str; LATENCY_BUCKETS] = latency_counter_names!("write");
+const FLUSH_LATENCY_COUNTERS: [&str; LATENCY_BUCKETS] = latency_counter_names!("flush");
+
+/// Number of requests completed within each latency bucket.
+#[derive(Default)]
+#[repr(C)]
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl LatencyHistogram {
+    pub fn record(&self, latency: Duration) {
+        let latency_us = latency.as_micros();
+        let bucket = LATENCY_BUCKETS_US
+            .iter()
+            .position(|&bound| latency_us <= u128::from(bound))
+            .unwrap_or(LATENCY_BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bucket(&self, index: usize) -> u64 {
+        self.buckets[index].load(Ordering::Relaxed)
+    }
+
+    fn add_counters(
+        &self,
+        names: &[&'static str; LATENCY_BUCKETS],
+        counters: &mut HashMap<&'static str, Wrapping<u64>>,
+    ) {
+        for (index, name) in names.iter().enumerate() {
+            counters.insert(name, Wrapping(self.bucket(index)));
+        }
+    }
+}
+
+/// Latency histograms of the requests handled by a block device, and number
+/// of requests in flight.
+#[derive(Default)]
+#[repr(C)]
+pub struct BlockStats {
+    read_latency: LatencyHistogram,
+    write_latency: LatencyHistogram,
+    flush_latency: LatencyHistogram,
+    inflight: AtomicU64,
+    max_inflight: AtomicU64,
+}
+
+impl BlockStats {
+    /// Accounts for a request the device started processing.
+    pub fn request_started(&self) {
+        let inflight = self.inflight.fetch_add(1, Ordering::AcqRel) + 1;
+        self.max_inflight.fetch_max(inflight, Ordering::AcqRel);
+    }
+
+    /// Accounts for a request completed `latency` after it was started.
+    pub fn request_completed(&self, request_type: RequestType, latency: Duration) {
+        self.inflight.fetch_sub(1, Ordering::AcqRel);
+        match request_type {
+            RequestType::In => self.read_latency.record(latency),
+            RequestType::Out | RequestType::Zone(ZoneOperation::Append) => {
+                self.write_latency.record(latency)
+            }
+            RequestType::Flush => self.flush_latency.record(latency),
+            _ => {}
+        }
+    }
+
+    /// Adds the statistics to the counters reported for the device.
+    pub fn add_counters(&self, counters: &mut HashMap<&'static str, Wrapping<u64>>) {
+        self.read_latency
+            .add_counters(&READ_LATENCY_COUNTERS, counters);
+        self.write_latency
+            .add_counters(&WRITE_LATENCY_COUNTERS, counters);
+        self.flush_latency
+            .add_counters(&FLUSH_LATENCY_COUNTERS, counters);
+        counters.insert(
+            "inflight_requests",
+            Wrapping(self.inflight.load(Ordering::Acquire)),
+        );
+        counters.insert(
+            "max_inflight_requests",
+            Wrapping(self.max_inflight.load(Ordering::Acquire)),
+        );
+    }
+}
+
+/// Block statistics stored in a file, which every process mapping the same
+/// file gets to see.
+pub struct SharedBlockStats {
+    mapping: MmapRegion,
+}
+
+impl SharedBlockStats {
+    pub fn new(file: File) -> io::Result<SharedBlockStats> {
+        let size = std::mem::size_of::<BlockStats>();
+        // Growing the file fills it with zeros, which is a valid and empty
+        // BlockStats. The first process to map the file does it.
+        if file.metadata()?.len() < size as u64 {
+            file.set_len(size as u64)?;
+        }
+        let mapping = MmapRegion::from_file(FileOffset::new(file, 0), size)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+
+        Ok(SharedBlockStats { mapping })
+    }
+}
+
+impl Deref for SharedBlockStats {
+    type Target = BlockStats;
+
+    fn deref(&self) -> &BlockStats {
+        // Safe because the mapping is page aligned and large enough to hold
+        // the structure, which is only made of atomic integers that can be
+        // concurrently accessed through a shared reference.
+        unsafe { &*(self.mapping.as_ptr() as *const BlockStats) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_histogram_buckets() {
+        let histogram = LatencyHistogram::default();
+        histogram.record(Duration::from_micros(3));
+        histogram.record(Duration::from_micros(10));
+        histogram.record(Duration::from_micros(11));
+        histogram.record(Duration::from_millis(2));
+        histogram.record(Duration::from_secs(3));
+
+        assert_eq!(histogram.bucket(0), 2);
+        assert_eq!(histogram.bucket(1), 1);
+        assert_eq!(histogram.bucket(7), 1);
+        assert_eq!(histogram.bucket(LATENCY_BUCKETS - 1), 1);
+    }
+
+    #[test]
+    fn block_stats_counters() {
+        let stats = BlockStats::default();
+        stats.request_started();
+        stats.request_started();
+        stats.request_completed(RequestType::In, Duration::from_micros(40));
+        stats.request_started();
+        stats.request_completed(RequestType::Flush, Duration::from_millis(20));
+
+        let mut counters = HashMap::new();
+        stats.add_counters(&mut counters);
+        assert_eq!(counters["read_latency_us_50"], Wrapping(1));
+        assert_eq!(counters["flush_latency_us_25000"], Wrapping(1));
+        assert_eq!(counters["write_latency_us_inf"], Wrapping(0));
+        assert_eq!(counters["inflight_requests"], Wrapping(1));
+        assert_eq!(counters["max_inflight_requests"], Wrapping(2));
+    }
+}
@@ -0,0 +1,866 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Emulation of a host-managed zoned block device on top of a regular disk
+//! image, as exposed through the virtio-blk zoned extension.
+//!
+//! The disk is split into sequential write required zones of the same size,
+//! the last one being smaller when the disk size isn't a multiple of the zone
+//! size. The zone conditions are tracked in memory, while the write pointers
+//! can be saved to a state file next to the disk image, the conditions being
+//! rebuilt from them when the disk is opened again.
+
+use super::{Error, ExecuteError, Request, RequestType, SECTOR_SHIFT, SECTOR_SIZE};
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::Arc;
+use vm_memory::{Address, ByteValued, Bytes, GuestMemoryMmap};
+
+pub const VIRTIO_BLK_F_ZONED: u32 = 17;
+
+pub const VIRTIO_BLK_T_ZONE_APPEND: u32 = 15;
+pub const VIRTIO_BLK_T_ZONE_REPORT: u32 = 16;
+pub const VIRTIO_BLK_T_ZONE_OPEN: u32 = 18;
+pub const VIRTIO_BLK_T_ZONE_CLOSE: u32 = 20;
+pub const VIRTIO_BLK_T_ZONE_FINISH: u32 = 22;
+pub const VIRTIO_BLK_T_ZONE_RESET: u32 = 24;
+pub const VIRTIO_BLK_T_ZONE_RESET_ALL: u32 = 26;
+
+pub const VIRTIO_BLK_S_ZONE_INVALID_CMD: u32 = 3;
+pub const VIRTIO_BLK_S_ZONE_UNALIGNED_WP: u32 = 4;
+pub const VIRTIO_BLK_S_ZONE_OPEN_RESOURCE: u32 = 5;
+pub const VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE: u32 = 6;
+
+// Host-managed zoned model.
+const VIRTIO_BLK_Z_HM: u8 = 1;
+
+// Sequential write required zone type.
+const VIRTIO_BLK_ZT_SWR: u8 = 2;
+
+// Zone states reported to the guest.
+const VIRTIO_BLK_ZS_EMPTY: u8 = 1;
+const VIRTIO_BLK_ZS_IOPEN: u8 = 2;
+const VIRTIO_BLK_ZS_EOPEN: u8 = 3;
+const VIRTIO_BLK_ZS_CLOSED: u8 = 4;
+const VIRTIO_BLK_ZS_FULL: u8 = 14;
+
+/// Offset of the zoned characteristics in the configuration space, right
+/// after the secure erase fields following `VirtioBlockConfig`.
+pub const VIRTIO_BLK_ZONED_CONFIG_OFFSET: usize = 72;
+
+// Identifies a zone state file, "CHZONES" in little endian.
+const ZONES_STATE_MAGIC: u64 = 0x0053_454e_4f5a_4843;
+
+// The sector found in the input header of a zone append request precedes the
+// status byte.
+pub(crate) const ZONE_APPEND_SECTOR_SIZE: u64 = 8;
+
+/// Zoned requests, using the virtio request type as discriminant.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u32)]
+pub enum ZoneOperation {
+    Append = VIRTIO_BLK_T_ZONE_APPEND,
+    Report = VIRTIO_BLK_T_ZONE_REPORT,
+    Open = VIRTIO_BLK_T_ZONE_OPEN,
+    Close = VIRTIO_BLK_T_ZONE_CLOSE,
+    Finish = VIRTIO_BLK_T_ZONE_FINISH,
+    Reset = VIRTIO_BLK_T_ZONE_RESET,
+    ResetAll = VIRTIO_BLK_T_ZONE_RESET_ALL,
+}
+
+impl ZoneOperation {
+    pub fn from_request_type(type_: u32) -> Option<ZoneOperation> {
+        match type_ {
+            VIRTIO_BLK_T_ZONE_APPEND => Some(ZoneOperation::Append),
+            VIRTIO_BLK_T_ZONE_REPORT => Some(ZoneOperation::Report),
+            VIRTIO_BLK_T_ZONE_OPEN => Some(ZoneOperation::Open),
+            VIRTIO_BLK_T_ZONE_CLOSE => Some(ZoneOperation::Close),
+            VIRTIO_BLK_T_ZONE_FINISH => Some(ZoneOperation::Finish),
+            VIRTIO_BLK_T_ZONE_RESET => Some(ZoneOperation::Reset),
+            VIRTIO_BLK_T_ZONE_RESET_ALL => Some(ZoneOperation::ResetAll),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the request carries data, management requests only
+    /// refer to a zone through the sector of the request header.
+    pub fn has_data(self) -> bool {
+        matches!(self, ZoneOperation::Append | ZoneOperation::Report)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ZoneError {
+    /// The request doesn't apply to the zone, or to its current condition.
+    InvalidCommand,
+    /// A write doesn't start at the write pointer of the zone.
+    UnalignedWritePointer,
+    /// Opening the zone would exceed the maximum number of open zones.
+    OpenResource,
+    /// Opening the zone would exceed the maximum number of active zones.
+    ActiveResource,
+}
+
+impl ZoneError {
+    pub fn status(&self) -> u32 {
+        match *self {
+            ZoneError::InvalidCommand => VIRTIO_BLK_S_ZONE_INVALID_CMD,
+            ZoneError::UnalignedWritePointer => VIRTIO_BLK_S_ZONE_UNALIGNED_WP,
+            ZoneError::OpenResource => VIRTIO_BLK_S_ZONE_OPEN_RESOURCE,
+            ZoneError::ActiveResource => VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ZoneCondition {
+    Empty,
+    ImplicitOpen,
+    ExplicitOpen,
+    Closed,
+    Full,
+}
+
+impl ZoneCondition {
+    fn is_open(self) -> bool {
+        matches!(
+            self,
+            ZoneCondition::ImplicitOpen | ZoneCondition::ExplicitOpen
+        )
+    }
+
+    fn is_active(self) -> bool {
+        self.is_open() || self == ZoneCondition::Closed
+    }
+}
+
+/// A zone, with all its addresses expressed in sectors.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Zone {
+    pub start: u64,
+    pub len: u64,
+    pub write_pointer: u64,
+    pub condition: ZoneCondition,
+}
+
+impl Zone {
+    fn end(&self) -> u64 {
+        self.start + self.len
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+pub struct VirtioBlockZonedConfig {
+    pub zone_sectors: u32,
+    pub max_open_zones: u32,
+    pub max_active_zones: u32,
+    pub max_append_sectors: u32,
+    pub write_granularity: u32,
+    pub model: u8,
+    pub unused: [u8; 3],
+}
+
+unsafe impl ByteValued for VirtioBlockZonedConfig {}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C, packed)]
+struct VirtioBlockZoneReportHeader {
+    nr_zones: u64,
+    reserved: [u8; 56],
+}
+
+impl Default for VirtioBlockZoneReportHeader {
+    fn default() -> Self {
+        VirtioBlockZoneReportHeader {
+            nr_zones: 0,
+            reserved: [0; 56],
+        }
+    }
+}
+
+unsafe impl ByteValued for VirtioBlockZoneReportHeader {}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C, packed)]
+pub struct VirtioBlockZoneDescriptor {
+    pub z_cap: u64,
+    pub z_start: u64,
+    pub z_wp: u64,
+    pub z_type: u8,
+    pub z_state: u8,
+    pub reserved: [u8; 38],
+}
+
+unsafe impl ByteValued for VirtioBlockZoneDescriptor {}
+
+// Header of a zone state file, followed by the write pointer of each zone as
+// a little endian 64-bit sector.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+struct ZonesStateHeader {
+    magic: u64,
+    zone_sectors: u64,
+    nr_zones: u64,
+}
+
+unsafe impl ByteValued for ZonesStateHeader {}
+
+const ZONES_STATE_HEADER_SIZE: u64 = std::mem::size_of::<ZonesStateHeader>() as u64;
+
+/// Returns the path of the file holding the write pointers of the zones of the disk image
+/// found at `disk_path`.
+pub fn zones_state_path(disk_path: &Path) -> PathBuf {
+    let mut path = OsString::from(disk_path);
+    path.push(".zones");
+    PathBuf::from(path)
+}
+
+impl From<&Zone> for VirtioBlockZoneDescriptor {
+    fn from(zone: &Zone) -> Self {
+        let z_state = match zone.condition {
+            ZoneCondition::Empty => VIRTIO_BLK_ZS_EMPTY,
+            ZoneCondition::ImplicitOpen => VIRTIO_BLK_ZS_IOPEN,
+            ZoneCondition::ExplicitOpen => VIRTIO_BLK_ZS_EOPEN,
+            ZoneCondition::Closed => VIRTIO_BLK_ZS_CLOSED,
+            ZoneCondition::Full => VIRTIO_BLK_ZS_FULL,
+        };
+        VirtioBlockZoneDescriptor {
+            z_cap: zone.len,
+            z_start: zone.start,
+            z_wp: zone.write_pointer,
+            z_type: VIRTIO_BLK_ZT_SWR,
+            z_state,
+            reserved: [0; 38],
+        }
+    }
+}
+
+/// State of all the zones of a disk.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Zones {
+    zone_sectors: u64,
+    max_open_zones: u32,
+    max_active_zones: u32,
+    zones: Vec<Zone>,
+    // Where the write pointers are saved, the zones only living in memory without it.
+    #[serde(skip)]
+    state_file: Option<Arc<File>>,
+}
+
+impl Zones {
+    /// Splits a disk of `disk_nsectors` sectors into empty zones of `zone_sectors` sectors. A
+    /// maximum number of open or active zones set to 0 means there is no limit.
+    pub fn new(
+        disk_nsectors: u64,
+        zone_sectors: u64,
+        max_open_zones: u32,
+        max_active_zones: u32,
+    ) -> Zones {
+        let mut zones = Vec::new();
+        let mut start = 0;
+        while start < disk_nsectors {
+            let len = std::cmp::min(zone_sectors, disk_nsectors - start);
+            zones.push(Zone {
+                start,
+                len,
+                write_pointer: start,
+                condition: ZoneCondition::Empty,
+            });
+            start += len;
+        }
+
+        Zones {
+            zone_sectors,
+            max_open_zones,
+            max_active_zones,
+            zones,
+            state_file: None,
+        }
+    }
+
+    /// Splits the disk like `new()`, loading the write pointers saved to `path` if it exists.
+    /// The zones holding data come up closed or full, as they would when a drive is powered on.
+    /// With `persist`, `path` is created if needed and the write pointers get saved to it.
+    pub fn with_state_file(
+        path: &Path,
+        persist: bool,
+        disk_nsectors: u64,
+        zone_sectors: u64,
+        max_open_zones: u32,
+        max_active_zones: u32,
+    ) -> io::Result<Zones> {
+        let mut zones = Zones::new(
+            disk_nsectors,
+            zone_sectors,
+            max_open_zones,
+            max_active_zones,
+        );
+        let file = match OpenOptions::new()
+            .read(true)
+            .write(persist)
+            .create(persist)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(e) if !persist && e.kind() == io::ErrorKind::NotFound => return Ok(zones),
+            Err(e) => return Err(e),
+        };
+
+        let new = file.metadata()?.len() == 0;
+        if !new {
+            zones.load(&file)?;
+        }
+        if persist {
+            zones.state_file = Some(Arc::new(file));
+            if new {
+                zones.save_all(true)?;
+            }
+        }
+
+        Ok(zones)
+    }
+
+    fn load(&mut self, file: &File) -> io::Result<()> {
+        let mut header = ZonesStateHeader::default();
+        file.read_exact_at(header.as_mut_slice(), 0)?;
+        let (magic, zone_sectors, nr_zones) = (header.magic, header.zone_sectors, header.nr_zones);
+        if magic != ZONES_STATE_MAGIC
+            || zone_sectors != self.zone_sectors
+            || nr_zones != self.zones.len() as u64
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "zone state doesn't match the zone layout",
+            ));
+        }
+
+        let mut write_pointers = vec![0u8; self.zones.len() * 8];
+        file.read_exact_at(&mut write_pointers, ZONES_STATE_HEADER_SIZE)?;
+        for (zone, write_pointer) in self.zones.iter_mut().zip(write_pointers.chunks_exact(8)) {
+            let write_pointer = u64::from_le_bytes(write_pointer.try_into().unwrap());
+            if write_pointer < zone.start || write_pointer > zone.end() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "zone write pointer out of the zone",
+                ));
+            }
+            zone.write_pointer = write_pointer;
+            zone.condition = if write_pointer == zone.start {
+                ZoneCondition::Empty
+            } else if write_pointer == zone.end() {
+                ZoneCondition::Full
+            } else {
+                ZoneCondition::Closed
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Saves the write pointer of the zone holding `sector` to the state file, if any, making
+    /// sure it reached the disk when `sync` is set.
+    pub fn save(&self, sector: u64, sync: bool) -> io::Result<()> {
+        let file = match &self.state_file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let index = sector / self.zone_sectors;
+        if let Some(zone) = self.zones.get(index as usize) {
+            file.write_all_at(
+                &zone.write_pointer.to_le_bytes(),
+                ZONES_STATE_HEADER_SIZE + index * 8,
+            )?;
+        }
+        if sync {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Saves the write pointers of all the zones to the state file, if any.
+    pub fn save_all(&self, sync: bool) -> io::Result<()> {
+        let file = match &self.state_file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let header = ZonesStateHeader {
+            magic: ZONES_STATE_MAGIC,
+            zone_sectors: self.zone_sectors,
+            nr_zones: self.zones.len() as u64,
+        };
+        let mut state = header.as_slice().to_vec();
+        for zone in self.zones.iter() {
+            state.extend_from_slice(&zone.write_pointer.to_le_bytes());
+        }
+        file.write_all_at(&state, 0)?;
+        if sync {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Makes sure the saved write pointers reached the disk.
+    pub fn sync(&self) -> io::Result<()> {
+        match &self.state_file {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes over the zones restored from a snapshot, saving them to the state file, if any.
+    pub fn restore(&mut self, zones: Zones) -> io::Result<()> {
+        let state_file = self.state_file.take();
+        *self = zones;
+        self.state_file = state_file;
+        self.save_all(true)
+    }
+
+    pub fn zones(&self) -> &[Zone] {
+        &self.zones
+    }
+
+    /// Returns the zoned characteristics exposed through the configuration space.
+    pub fn config(&self) -> VirtioBlockZonedConfig {
+        VirtioBlockZonedConfig {
+            zone_sectors: self.zone_sectors as u32,
+            max_open_zones: self.max_open_zones,
+            max_active_zones: self.max_active_zones,
+            max_append_sectors: self.zone_sectors as u32,
+            write_granularity: SECTOR_SIZE as u32,
+            model: VIRTIO_BLK_Z_HM,
+            unused: [0; 3],
+        }
+    }
+
+    fn zone_index(&self, sector: u64) -> result::Result<usize, ZoneError> {
+        let index = (sector / self.zone_sectors) as usize;
+        if index < self.zones.len() {
+            Ok(index)
+        } else {
+            Err(ZoneError::InvalidCommand)
+        }
+    }
+
+    // Zone management requests must refer to the first sector of a zone.
+    fn zone_start_index(&self, sector: u64) -> result::Result<usize, ZoneError> {
+        let index = self.zone_index(sector)?;
+        if self.zones[index].start != sector {
+            return Err(ZoneError::InvalidCommand);
+        }
+        Ok(index)
+    }
+
+    fn count(&self, filter: fn(ZoneCondition) -> bool) -> u32 {
+        self.zones.iter().filter(|z| filter(z.condition)).count() as u32
+    }
+
+    fn close_zone(&mut self, index: usize) {
+        let zone = &mut self.zones[index];
+        zone.condition = if zone.write_pointer == zone.start {
+            ZoneCondition::Empty
+        } else {
+            ZoneCondition::Closed
+        };
+    }
+
+    // Opens a zone which is either empty or closed, checking the open and active zone limits.
+    fn open_zone(
+        &mut self,
+        index: usize,
+        condition: ZoneCondition,
+    ) -> result::Result<(), ZoneError> {
+        if self.zones[index].condition == ZoneCondition::Empty
+            && self.max_active_zones != 0
+            && self.count(ZoneCondition::is_active) >= self.max_active_zones
+        {
+            return Err(ZoneError::ActiveResource);
+        }
+        if self.max_open_zones != 0 && self.count(ZoneCondition::is_open) >= self.max_open_zones {
+            // Make room by closing an implicitly opened zone, as a real drive would.
+            let implicit = self
+                .zones
+                .iter()
+                .position(|z| z.condition == ZoneCondition::ImplicitOpen)
+                .ok_or(ZoneError::OpenResource)?;
+            self.close_zone(implicit);
+        }
+        self.zones[index].condition = condition;
+
+        Ok(())
+    }
+
+    /// Checks that `nsectors` can be written at `sector`, implicitly opening the zone if needed.
+    /// A zone append only provides the start of the zone, the data being written at its write
+    /// pointer. Returns the sector the data must be written to.
+    pub fn start_write(
+        &mut self,
+        sector: u64,
+        nsectors: u64,
+        append: bool,
+    ) -> result::Result<u64, ZoneError> {
+        let index = self.zone_index(sector)?;
+        let zone = &self.zones[index];
+        if append {
+            if sector != zone.start {
+                return Err(ZoneError::InvalidCommand);
+            }
+        } else if zone.condition != ZoneCondition::Full && sector != zone.write_pointer {
+            return Err(ZoneError::UnalignedWritePointer);
+        }
+        if zone.condition == ZoneCondition::Full || zone.write_pointer + nsectors > zone.end() {
+            return Err(ZoneError::InvalidCommand);
+        }
+
+        let write_pointer = zone.write_pointer;
+        if !zone.condition.is_open() {
+            self.open_zone(index, ZoneCondition::ImplicitOpen)?;
+        }
+
+        Ok(write_pointer)
+    }
+
+    /// Moves the write pointer past the `nsectors` written at `sector`, once a write started
+    /// with `start_write()` has succeeded.
+    pub fn complete_write(&mut self, sector: u64, nsectors: u64) {
+        if let Ok(index) = self.zone_index(sector) {
+            let zone = &mut self.zones[index];
+            zone.write_pointer = sector + nsectors;
+            if zone.write_pointer == zone.end() {
+                zone.condition = ZoneCondition::Full;
+            }
+        }
+    }
+
+    /// Explicitly opens the zone starting at `sector`.
+    pub fn open(&mut self, sector: u64) -> result::Result<(), ZoneError> {
+        let index = self.zone_start_index(sector)?;
+        match self.zones[index].condition {
+            ZoneCondition::Empty | ZoneCondition::Closed => {
+                self.open_zone(index, ZoneCondition::ExplicitOpen)
+            }
+            ZoneCondition::ImplicitOpen => {
+                self.zones[index].condition = ZoneCondition::ExplicitOpen;
+                Ok(())
+            }
+            ZoneCondition::ExplicitOpen | ZoneCondition::Full => Ok(()),
+        }
+    }
+
+    /// Closes the zone starting at `sector`.
+    pub fn close(&mut self, sector: u64) -> result::Result<(), ZoneError> {
+        let index = self.zone_start_index(sector)?;
+        if self.zones[index].condition.is_open() {
+            self.close_zone(index);
+        }
+        Ok(())
+    }
+
+    /// Moves the write pointer of the zone starting at `sector` to the end of the zone.
+    pub fn finish(&mut self, sector: u64) -> result::Result<(), ZoneError> {
+        let index = self.zone_start_index(sector)?;
+        let zone = &mut self.zones[index];
+        zone.write_pointer = zone.end();
+        zone.condition = ZoneCondition::Full;
+        Ok(())
+    }
+
+    /// Moves the write pointer of the zone starting at `sector` back to the start of the zone.
+    pub fn reset(&mut self, sector: u64) -> result::Result<(), ZoneError> {
+        let index = self.zone_start_index(sector)?;
+        let zone = &mut self.zones[index];
+        zone.write_pointer = zone.start;
+        zone.condition = ZoneCondition::Empty;
+        Ok(())
+    }
+
+    /// Resets all the zones.
+    pub fn reset_all(&mut self) {
+        for zone in self.zones.iter_mut() {
+            zone.write_pointer = zone.start;
+            zone.condition = ZoneCondition::Empty;
+        }
+    }
+
+    /// Describes at most `max_zones` zones, starting with the zone holding `sector`.
+    pub fn report(&self, sector: u64, max_zones: usize) -> Vec<VirtioBlockZoneDescriptor> {
+        let first = (sector / self.zone_sectors) as usize;
+        self.zones
+            .iter()
+            .skip(first)
+            .take(max_zones)
+            .map(VirtioBlockZoneDescriptor::from)
+            .collect()
+    }
+}
+
+impl Request {
+    /// Executes a request on a zoned disk. Writes must follow the write pointer of the zone
+    /// they target, other requests not related to zones are executed as usual, a flush also
+    /// syncing the saved write pointers.
+    #[allow(clippy::ptr_arg)]
+    pub fn execute_zoned<T: Seek + Read + Write>(
+        &self,
+        disk: &mut T,
+        disk_nsectors: u64,
+        mem: &GuestMemoryMmap,
+        disk_id: &Vec<u8>,
+        zones: &mut Zones,
+    ) -> result::Result<u32, ExecuteError> {
+        let mut nsectors = u64::from(self.data_len) / SECTOR_SIZE;
+        if u64::from(self.data_len) % SECTOR_SIZE != 0 {
+            nsectors += 1;
+        }
+
+        let operation = match self.request_type {
+            RequestType::Out => {
+                zones
+                    .start_write(self.sector, nsectors, false)
+                    .map_err(ExecuteError::Zone)?;
+                let len = self.execute(disk, disk_nsectors, mem, disk_id)?;
+                zones.complete_write(self.sector, nsectors);
+                zones
+                    .save(self.sector, !self.writeback)
+                    .map_err(ExecuteError::ZoneState)?;
+                return Ok(len);
+            }
+            RequestType::Flush => {
+                let len = self.execute(disk, disk_nsectors, mem, disk_id)?;
+                zones.sync().map_err(ExecuteError::Flush)?;
+                return Ok(len);
+            }
+            RequestType::Zone(operation) => operation,
+            _ => return self.execute(disk, disk_nsectors, mem, disk_id),
+        };
+
+        match operation {
+            ZoneOperation::Append => {
+                let sector = zones
+                    .start_write(self.sector, nsectors, true)
+                    .map_err(ExecuteError::Zone)?;
+                disk.seek(SeekFrom::Start(sector << SECTOR_SHIFT))
+                    .map_err(ExecuteError::Seek)?;
+                mem.write_all_to(self.data_addr, disk, self.data_len as usize)
+                    .map_err(ExecuteError::Write)?;
+                if !self.writeback {
+                    disk.flush().map_err(ExecuteError::Flush)?;
+                }
+                zones.complete_write(sector, nsectors);
+                zones
+                    .save(sector, !self.writeback)
+                    .map_err(ExecuteError::ZoneState)?;
+                // The sector the data landed on is returned in front of the status.
+                mem.write_obj(
+                    sector,
+                    self.status_addr.unchecked_sub(ZONE_APPEND_SECTOR_SIZE),
+                )
+                .map_err(ExecuteError::Write)?;
+            }
+            ZoneOperation::Report => {
+                let header_size = std::mem::size_of::<VirtioBlockZoneReportHeader>();
+                let descriptor_size = std::mem::size_of::<VirtioBlockZoneDescriptor>();
+                if (self.data_len as usize) < header_size {
+                    return Err(ExecuteError::BadRequest(Error::DescriptorLengthTooSmall));
+                }
+                let descriptors = zones.report(
+                    self.sector,
+                    (self.data_len as usize - header_size) / descriptor_size,
+                );
+                let header = VirtioBlockZoneReportHeader {
+                    nr_zones: descriptors.len() as u64,
+                    ..Default::default()
+                };
+
+                let mut report = header.as_slice().to_vec();
+                for descriptor in descriptors.iter() {
+                    report.extend_from_slice(descriptor.as_slice());
+                }
+                mem.write_slice(&report, self.data_addr)
+                    .map_err(ExecuteError::Write)?;
+                return Ok(report.len() as u32);
+            }
+            ZoneOperation::Open => zones.open(self.sector).map_err(ExecuteError::Zone)?,
+            ZoneOperation::Close => zones.close(self.sector).map_err(ExecuteError::Zone)?,
+            ZoneOperation::Finish => {
+                zones.finish(self.sector).map_err(ExecuteError::Zone)?;
+                zones
+                    .save(self.sector, !self.writeback)
+                    .map_err(ExecuteError::ZoneState)?;
+            }
+            ZoneOperation::Reset => {
+                zones.reset(self.sector).map_err(ExecuteError::Zone)?;
+                zones
+                    .save(self.sector, !self.writeback)
+                    .map_err(ExecuteError::ZoneState)?;
+            }
+            ZoneOperation::ResetAll => {
+                zones.reset_all();
+                zones
+                    .save_all(!self.writeback)
+                    .map_err(ExecuteError::ZoneState)?;
+            }
+        }
+
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn zones_layout() {
+        let zones = Zones::new(2500, 1024, 0, 0);
+        assert_eq!(zones.zones().len(), 3);
+        assert_eq!(zones.zones()[2].start, 2048);
+        assert_eq!(zones.zones()[2].len, 452);
+        assert!(zones
+            .zones()
+            .iter()
+            .all(|z| z.condition == ZoneCondition::Empty && z.write_pointer == z.start));
+    }
+
+    #[test]
+    fn zones_sequential_write() {
+        let mut zones = Zones::new(4096, 1024, 0, 0);
+        assert_eq!(zones.start_write(1024, 8, false), Ok(1024));
+        zones.complete_write(1024, 8);
+        assert_eq!(zones.zones()[1].condition, ZoneCondition::ImplicitOpen);
+        assert_eq!(zones.zones()[1].write_pointer, 1032);
+
+        assert_eq!(
+            zones.start_write(1024, 8, false),
+            Err(ZoneError::UnalignedWritePointer)
+        );
+        assert_eq!(
+            zones.start_write(1032, 1017, false),
+            Err(ZoneError::InvalidCommand)
+        );
+
+        assert_eq!(zones.start_write(1032, 1016, false), Ok(1032));
+        zones.complete_write(1032, 1016);
+        assert_eq!(zones.zones()[1].condition, ZoneCondition::Full);
+        assert_eq!(
+            zones.start_write(2040, 8, false),
+            Err(ZoneError::InvalidCommand)
+        );
+        assert_eq!(
+            zones.start_write(1024, 8, true),
+            Err(ZoneError::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn zones_append() {
+        let mut zones = Zones::new(4096, 1024, 0, 0);
+        assert_eq!(zones.start_write(0, 16, true), Ok(0));
+        zones.complete_write(0, 16);
+        assert_eq!(zones.start_write(0, 16, true), Ok(16));
+        zones.complete_write(16, 16);
+        assert_eq!(zones.zones()[0].write_pointer, 32);
+        assert_eq!(
+            zones.start_write(8, 16, true),
+            Err(ZoneError::InvalidCommand)
+        );
+    }
+
+    #[test]
+    fn zones_open_limits() {
+        let mut zones = Zones::new(4096, 1024, 2, 3);
+        zones.start_write(0, 8, false).unwrap();
+        zones.complete_write(0, 8);
+        zones.open(1024).unwrap();
+
+        // The implicitly opened zone gets closed to make room.
+        zones.open(2048).unwrap();
+        assert_eq!(zones.zones()[0].condition, ZoneCondition::Closed);
+        assert_eq!(zones.zones()[1].condition, ZoneCondition::ExplicitOpen);
+        assert_eq!(zones.zones()[2].condition, ZoneCondition::ExplicitOpen);
+
+        // Both open zones were explicitly opened.
+        assert_eq!(zones.start_write(8, 8, false), Err(ZoneError::OpenResource));
+
+        zones.close(2048).unwrap();
+        assert_eq!(zones.zones()[2].condition, ZoneCondition::Empty);
+        zones.start_write(8, 8, false).unwrap();
+        zones.complete_write(8, 8);
+
+        // Opening a third zone closes the implicitly opened one again,
+        // leaving three active zones.
+        zones.open(2048).unwrap();
+        assert_eq!(zones.zones()[0].condition, ZoneCondition::Closed);
+        assert_eq!(zones.open(3072), Err(ZoneError::ActiveResource));
+
+        zones.finish(1024).unwrap();
+        assert_eq!(zones.open(3072), Ok(()));
+        assert_eq!(zones.zones()[3].condition, ZoneCondition::ExplicitOpen);
+    }
+
+    #[test]
+    fn zones_management() {
+        let mut zones = Zones::new(4096, 1024, 0, 0);
+        assert_eq!(zones.open(8), Err(ZoneError::InvalidCommand));
+        assert_eq!(zones.reset(4096), Err(ZoneError::InvalidCommand));
+
+        zones.finish(0).unwrap();
+        assert_eq!(zones.zones()[0].condition, ZoneCondition::Full);
+        assert_eq!(zones.zones()[0].write_pointer, 1024);
+
+        zones.start_write(1024, 8, false).unwrap();
+        zones.complete_write(1024, 8);
+        zones.reset(0).unwrap();
+        assert_eq!(zones.zones()[0].condition, ZoneCondition::Empty);
+        assert_eq!(zones.zones()[0].write_pointer, 0);
+
+        zones.reset_all();
+        assert_eq!(zones.zones()[1].condition, ZoneCondition::Empty);
+        assert_eq!(zones.zones()[1].write_pointer, 1024);
+    }
+
+    #[test]
+    fn zones_report() {
+        let mut zones = Zones::new(4096, 1024, 0, 0);
+        zones.start_write(2048, 8, false).unwrap();
+        zones.complete_write(2048, 8);
+
+        let report = zones.report(1500, 2);
+        assert_eq!(report.len(), 2);
+        let (z_start, z_wp, z_state) = (report[1].z_start, report[1].z_wp, report[1].z_state);
+        assert_eq!(z_start, 2048);
+        assert_eq!(z_wp, 2056);
+        assert_eq!(z_state, VIRTIO_BLK_ZS_IOPEN);
+        assert_eq!(zones.report(3072, 8).len(), 1);
+        assert!(zones.report(4096, 8).is_empty());
+    }
+
+    #[test]
+    fn zones_state_file() {
+        let state = TempFile::new().unwrap();
+        let mut zones = Zones::with_state_file(state.as_path(), true, 4096, 1024, 0, 0).unwrap();
+        zones.start_write(1024, 8, false).unwrap();
+        zones.complete_write(1024, 8);
+        zones.save(1024, false).unwrap();
+        zones.finish(2048).unwrap();
+        zones.save(2048, false).unwrap();
+
+        // The write pointers survive the disk being opened again, the
+        // zone holding data coming up closed.
+        let zones = Zones::with_state_file(state.as_path(), false, 4096, 1024, 0, 0).unwrap();
+        assert_eq!(zones.zones()[0].condition, ZoneCondition::Empty);
+        assert_eq!(zones.zones()[1].condition, ZoneCondition::Closed);
+        assert_eq!(zones.zones()[1].write_pointer, 1032);
+        assert_eq!(zones.zones()[2].condition, ZoneCondition::Full);
+        assert_eq!(zones.zones()[3].write_pointer, 3072);
+
+        // The state doesn't apply to another zone layout.
+        assert!(Zones::with_state_file(state.as_path(), false, 4096, 512, 0, 0).is_err());
+    }
+}
@@ -25,7 +25,7 @@ fuzz_target!(|bytes| {
     let mut disk_file: File = unsafe { File::from_raw_fd(shm) };
     disk_file.write_all(&bytes[16..]).unwrap();
     disk_file.seek(SeekFrom::Start(0)).unwrap();
-    if let Ok(mut qcow) = QcowFile::from(RawFile::new(disk_file, false)) {
+    if let Ok(mut qcow) = QcowFile::from(RawFile::new(disk_file, false), false) {
         if qcow.seek(SeekFrom::Start(addr)).is_ok() {
             let _ = qcow.write_all(&value.to_le_bytes());
         }
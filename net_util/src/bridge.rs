@@ -0,0 +1,324 @@
+// Copyright (c) 2020 Intel Corporation. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use super::{create_socket, Error as NetUtilError};
+use net_gen;
+use serde_json::{self, Value};
+use std::env;
+use std::ffi::CString;
+use std::io::{BufReader, Error as IoError, Write};
+use std::os::raw::{c_char, c_ulong};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use vmm_sys_util::ioctl::ioctl_with_ref;
+
+const OVS_DEFAULT_RUNDIR: &str = "/var/run/openvswitch";
+const OVSDB_SOCKET: &str = "db.sock";
+const OVSDB_DATABASE: &str = "Open_vSwitch";
+// How long to wait for the database server to answer.
+const OVSDB_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum Error {
+    /// The bridge doesn't exist.
+    UnknownBridge(String),
+    /// Invalid interface name.
+    InvalidIfname(String),
+    /// Failed to create a socket.
+    NetUtil(NetUtilError),
+    /// Failed to retrieve the index of the interface.
+    GetIfindex(IoError),
+    /// Adding or removing the interface of the Linux bridge failed.
+    BridgeIoctl(IoError),
+    /// Failed to communicate with the Open vSwitch database server.
+    OvsdbIo(IoError),
+    /// Invalid answer from the Open vSwitch database server.
+    OvsdbResponse(String),
+    /// The Open vSwitch database transaction failed, with the given error.
+    Ovsdb(String),
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// Kind of the bridge an interface is attached to.
+enum BridgeKind {
+    Linux,
+    OpenVSwitch,
+}
+
+fn bridge_kind(bridge: &str) -> Result<BridgeKind> {
+    let path = Path::new("/sys/class/net").join(bridge);
+    if bridge.is_empty() || bridge.contains('/') || !path.exists() {
+        return Err(Error::UnknownBridge(bridge.to_owned()));
+    }
+
+    // Linux bridges expose their attributes in sysfs, while Open vSwitch
+    // bridges are plain internal interfaces of the datapath.
+    if path.join("bridge").exists() {
+        Ok(BridgeKind::Linux)
+    } else {
+        Ok(BridgeKind::OpenVSwitch)
+    }
+}
+
+fn linux_bridge_ioctl(bridge: &str, if_name: &str, req: u32) -> Result<()> {
+    let c_if_name = CString::new(if_name).map_err(|_| Error::InvalidIfname(if_name.to_owned()))?;
+    // This is safe since the name is a valid null-terminated string.
+    let ifindex = unsafe { libc::if_nametoindex(c_if_name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(Error::GetIfindex(IoError::last_os_error()));
+    }
+
+    let sock = create_socket().map_err(Error::NetUtil)?;
+
+    // The bridge name has been checked against sysfs, hence fits in the
+    // name field. Since we don't call as_mut on the same union field more
+    // than once, this block is safe.
+    let mut ifreq: net_gen::ifreq = Default::default();
+    unsafe {
+        let ifrn_name = ifreq.ifr_ifrn.ifrn_name.as_mut();
+        for (i, b) in bridge.bytes().enumerate() {
+            ifrn_name[i] = b as c_char;
+        }
+        *ifreq.ifr_ifru.ifru_ivalue.as_mut() = ifindex as i32;
+    }
+
+    // ioctl is safe. Called with a valid sock fd, and we check the return.
+    #[allow(clippy::cast_lossless)]
+    let ret = unsafe { ioctl_with_ref(&sock, req as c_ulong, &ifreq) };
+    if ret < 0 {
+        return Err(Error::BridgeIoctl(IoError::last_os_error()));
+    }
+
+    Ok(())
+}
+
+// Path of the socket of the Open vSwitch database server, found the same way
+// as ovs-vsctl does.
+fn ovsdb_socket() -> PathBuf {
+    env::var_os("OVS_RUNDIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(OVS_DEFAULT_RUNDIR))
+        .join(OVSDB_SOCKET)
+}
+
+// Runs the operations as a single transaction of the Open vSwitch database,
+// through its JSON-RPC interface (RFC 7047), and returns their results.
+fn ovsdb_transact(socket: &Path, ops: Vec<Value>) -> Result<Vec<Value>> {
+    let mut stream = UnixStream::connect(socket).map_err(Error::OvsdbIo)?;
+    stream
+        .set_read_timeout(Some(OVSDB_TIMEOUT))
+        .map_err(Error::OvsdbIo)?;
+
+    let mut params = vec![Value::from(OVSDB_DATABASE)];
+    params.extend(ops);
+    let request = json!({ "method": "transact", "params": params, "id": 0 });
+    stream
+        .write_all(request.to_string().as_bytes())
+        .map_err(Error::OvsdbIo)?;
+
+    // The messages aren't delimited, they are parsed as they come.
+    let mut messages =
+        serde_json::Deserializer::from_reader(BufReader::new(&stream)).into_iter::<Value>();
+    let response = loop {
+        let message = match messages.next() {
+            Some(message) => message.map_err(|e| Error::OvsdbResponse(e.to_string()))?,
+            None => return Err(Error::OvsdbResponse("connection closed".to_owned())),
+        };
+        // Skip the requests from the server, such as echo.
+        if message["id"] == json!(0) && message.get("method").is_none() {
+            break message;
+        }
+    };
+
+    if !response["error"].is_null() {
+        return Err(Error::Ovsdb(response["error"].to_string()));
+    }
+    let results = match response["result"].as_array() {
+        Some(results) => results.clone(),
+        None => return Err(Error::OvsdbResponse(response.to_string())),
+    };
+    // A failed operation aborts the transaction, and is reported along with
+    // the results of the previous operations.
+    if let Some(error) = results.iter().find(|result| !result["error"].is_null()) {
+        return Err(Error::Ovsdb(error.to_string()));
+    }
+
+    Ok(results)
+}
+
+// Returns the UUID of the Open vSwitch port named after the interface, if
+// any.
+fn ovs_port_uuid(socket: &Path, if_name: &str) -> Result<Option<Value>> {
+    let results = ovsdb_transact(
+        socket,
+        vec![json!({
+            "op": "select",
+            "table": "Port",
+            "where": [["name", "==", if_name]],
+            "columns": ["_uuid"],
+        })],
+    )?;
+
+    Ok(results[0]["rows"]
+        .as_array()
+        .and_then(|rows| rows.first())
+        .map(|row| row["_uuid"].clone()))
+}
+
+// Same as `ovs-vsctl --may-exist add-port`.
+fn ovs_add_port(socket: &Path, bridge: &str, if_name: &str) -> Result<()> {
+    if ovs_port_uuid(socket, if_name)?.is_some() {
+        return Ok(());
+    }
+
+    let results = ovsdb_transact(
+        socket,
+        vec![
+            json!({
+                "op": "insert",
+                "table": "Interface",
+                "row": { "name": if_name },
+                "uuid-name": "iface",
+            }),
+            json!({
+                "op": "insert",
+                "table": "Port",
+                "row": { "name": if_name, "interfaces": ["named-uuid", "iface"] },
+                "uuid-name": "port",
+            }),
+            json!({
+                "op": "mutate",
+                "table": "Bridge",
+                "where": [["name", "==", bridge]],
+                "mutations": [["ports", "insert", ["set", [["named-uuid", "port"]]]]],
+            }),
+        ],
+    )?;
+    // Without a bridge referencing them, the port and the interface are
+    // garbage collected by the server.
+    if results[2]["count"] != json!(1) {
+        return Err(Error::UnknownBridge(bridge.to_owned()));
+    }
+
+    Ok(())
+}
+
+// Same as `ovs-vsctl --if-exists del-port`, the port and its interface
+// being garbage collected once the bridge doesn't reference them anymore.
+fn ovs_del_port(socket: &Path, bridge: &str, if_name: &str) -> Result<()> {
+    let uuid = match ovs_port_uuid(socket, if_name)? {
+        Some(uuid) => uuid,
+        None => return Ok(()),
+    };
+
+    ovsdb_transact(
+        socket,
+        vec![json!({
+            "op": "mutate",
+            "table": "Bridge",
+            "where": [["name", "==", bridge]],
+            "mutations": [["ports", "delete", ["set", [uuid]]]],
+        })],
+    )?;
+
+    Ok(())
+}
+
+/// Attach the interface to a Linux bridge or an Open vSwitch bridge, the
+/// latter being managed through the socket of the Open vSwitch database
+/// server.
+pub fn attach_to_bridge(bridge: &str, if_name: &str) -> Result<()> {
+    if if_name.is_empty() {
+        return Err(Error::InvalidIfname(if_name.to_owned()));
+    }
+
+    match bridge_kind(bridge)? {
+        BridgeKind::Linux => linux_bridge_ioctl(bridge, if_name, net_gen::sockios::SIOCBRADDIF),
+        BridgeKind::OpenVSwitch => ovs_add_port(&ovsdb_socket(), bridge, if_name),
+    }
+}
+
+/// Detach the interface from the bridge it was attached to by
+/// `attach_to_bridge`.
+pub fn detach_from_bridge(bridge: &str, if_name: &str) -> Result<()> {
+    if if_name.is_empty() {
+        return Err(Error::InvalidIfname(if_name.to_owned()));
+    }
+
+    match bridge_kind(bridge)? {
+        BridgeKind::Linux => linux_bridge_ioctl(bridge, if_name, net_gen::sockios::SIOCBRDELIF),
+        BridgeKind::OpenVSwitch => ovs_del_port(&ovsdb_socket(), bridge, if_name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_unknown_bridge() {
+        match attach_to_bridge("chbr_missing0", "tap0") {
+            Err(Error::UnknownBridge(name)) => assert_eq!(name, "chbr_missing0"),
+            _ => panic!("the bridge should be unknown"),
+        }
+        assert!(attach_to_bridge("../lo", "tap0").is_err());
+        assert!(detach_from_bridge("", "tap0").is_err());
+        assert!(attach_to_bridge("lo", "").is_err());
+    }
+
+    // Answers the next request with the given result.
+    fn ovsdb_server(listener: UnixListener, result: Value) -> thread::JoinHandle<Value> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let request = serde_json::Deserializer::from_reader(&stream)
+                .into_iter::<Value>()
+                .next()
+                .unwrap()
+                .unwrap();
+            let echo = json!({ "method": "echo", "params": [], "id": "echo" });
+            let response = json!({ "id": request["id"], "result": result, "error": null });
+            (&stream)
+                .write_all(format!("{}{}", echo, response).as_bytes())
+                .unwrap();
+            request
+        })
+    }
+
+    #[test]
+    fn test_ovsdb_transact() {
+        let dir = TempDir::new().unwrap();
+        let socket = dir.as_path().join(OVSDB_SOCKET);
+        let listener = UnixListener::bind(&socket).unwrap();
+
+        let server = ovsdb_server(listener.try_clone().unwrap(), json!([{ "rows": [] }]));
+        assert_eq!(ovs_port_uuid(&socket, "tap0").unwrap(), None);
+        let request = server.join().unwrap();
+        assert_eq!(request["method"], "transact");
+        assert_eq!(request["params"][0], OVSDB_DATABASE);
+        assert_eq!(request["params"][1]["table"], "Port");
+
+        let uuid = json!(["uuid", "36bb4f3c-b0c2-4fd2-9e26-c6a1f9a58ec4"]);
+        let server = ovsdb_server(
+            listener.try_clone().unwrap(),
+            json!([{ "rows": [{ "_uuid": uuid }] }]),
+        );
+        assert_eq!(ovs_port_uuid(&socket, "tap0").unwrap(), Some(uuid));
+        server.join().unwrap();
+
+        let server = ovsdb_server(
+            listener,
+            json!([{ "uuid": ["uuid", "ab"] }, { "error": "constraint violation" }]),
+        );
+        match ovsdb_transact(&socket, Vec::new()) {
+            Err(Error::Ovsdb(error)) => assert!(error.contains("constraint violation")),
+            _ => panic!("the transaction should fail"),
+        }
+        server.join().unwrap();
+    }
+}
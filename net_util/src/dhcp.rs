@@ -0,0 +1,275 @@
+// Copyright (c) 2020 Intel Corporation. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use mac::{MacAddr, MAC_ADDR_LEN};
+use packet::{build_udp_frame, parse_ipv4, parse_udp, IPPROTO_UDP};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+const BOOTP_REQUEST: u8 = 1;
+const BOOTP_REPLY: u8 = 2;
+const BOOTP_MIN_LEN: usize = 300;
+const BOOTP_FLAG_BROADCAST: u16 = 0x8000;
+const DHCP_MAGIC: [u8; 4] = [99, 130, 83, 99];
+const DHCP_OPTIONS_OFFSET: usize = 240;
+
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+const DHCP_NAK: u8 = 6;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_HOSTNAME: u8 = 12;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_END: u8 = 255;
+
+/// Default lease time, in seconds.
+pub const DEFAULT_LEASE_TIME: u32 = 86400;
+/// Longest hostname which fits in its option.
+pub const MAX_HOSTNAME_LEN: usize = 255;
+/// Largest number of DNS servers which fit in their option.
+pub const MAX_DNS_SERVERS: usize = 63;
+
+/// DHCP server handing out a single lease, to the client of a virtual NIC.
+#[derive(Clone, Debug)]
+pub struct DhcpServer {
+    /// Address and MAC address the replies are sent from.
+    pub server_ip: Ipv4Addr,
+    pub server_mac: MacAddr,
+    /// Lease offered to the client.
+    pub client_ip: Ipv4Addr,
+    pub mask: Ipv4Addr,
+    pub router: Option<Ipv4Addr>,
+    pub dns: Vec<Ipv4Addr>,
+    pub hostname: Option<String>,
+    pub lease_time: u32,
+}
+
+struct DhcpMessage<'a> {
+    message_type: u8,
+    xid: &'a [u8],
+    flags: u16,
+    ciaddr: Ipv4Addr,
+    chaddr: MacAddr,
+    requested_ip: Option<Ipv4Addr>,
+}
+
+fn parse_dhcp(payload: &[u8]) -> Option<DhcpMessage> {
+    if payload.len() < DHCP_OPTIONS_OFFSET
+        || payload[0] != BOOTP_REQUEST
+        || payload[1] != 1
+        || usize::from(payload[2]) != MAC_ADDR_LEN
+        || payload[236..240] != DHCP_MAGIC
+    {
+        return None;
+    }
+
+    let mut message_type = None;
+    let mut requested_ip = None;
+    let mut options = &payload[DHCP_OPTIONS_OFFSET..];
+    while let Some(&code) = options.first() {
+        match code {
+            OPT_END => break,
+            OPT_PAD => options = &options[1..],
+            _ => {
+                let len = usize::from(*options.get(1)?);
+                let data = options.get(2..2 + len)?;
+                match code {
+                    OPT_MESSAGE_TYPE if len == 1 => message_type = Some(data[0]),
+                    OPT_REQUESTED_IP if len == 4 => {
+                        requested_ip = Some(Ipv4Addr::new(data[0], data[1], data[2], data[3]))
+                    }
+                    _ => {}
+                }
+                options = &options[2 + len..];
+            }
+        }
+    }
+
+    Some(DhcpMessage {
+        message_type: message_type?,
+        xid: &payload[4..8],
+        flags: u16::from_be_bytes([payload[10], payload[11]]),
+        ciaddr: Ipv4Addr::new(payload[12], payload[13], payload[14], payload[15]),
+        chaddr: MacAddr::from_bytes(&payload[28..28 + MAC_ADDR_LEN]).ok()?,
+        requested_ip,
+    })
+}
+
+fn push_option(options: &mut Vec<u8>, code: u8, data: &[u8]) {
+    options.push(code);
+    options.push(data.len() as u8);
+    options.extend_from_slice(data);
+}
+
+impl DhcpServer {
+    /// Check if the Ethernet frame is a datagram sent to a DHCP server.
+    pub fn is_dhcp_request(frame: &[u8]) -> bool {
+        parse_ipv4(frame)
+            .filter(|ip| ip.protocol == IPPROTO_UDP)
+            .and_then(|ip| parse_udp(ip.payload))
+            .map_or(false, |udp| udp.dst_port == DHCP_SERVER_PORT)
+    }
+
+    /// Answer the DHCP DISCOVER or REQUEST held by the Ethernet frame. None
+    /// is returned if the frame doesn't need a reply.
+    pub fn process(&self, frame: &[u8]) -> Option<Vec<u8>> {
+        let ip = parse_ipv4(frame).filter(|ip| ip.protocol == IPPROTO_UDP)?;
+        let udp = parse_udp(ip.payload)
+            .filter(|udp| udp.src_port == DHCP_CLIENT_PORT && udp.dst_port == DHCP_SERVER_PORT)?;
+        let request = parse_dhcp(udp.payload)?;
+
+        let reply_type = match request.message_type {
+            DHCP_DISCOVER => DHCP_OFFER,
+            DHCP_REQUEST => {
+                let requested_ip = request.requested_ip.unwrap_or(request.ciaddr);
+                if requested_ip == self.client_ip {
+                    DHCP_ACK
+                } else {
+                    DHCP_NAK
+                }
+            }
+            _ => return None,
+        };
+
+        let mut reply = vec![0u8; DHCP_OPTIONS_OFFSET];
+        reply[0] = BOOTP_REPLY;
+        reply[1] = 1;
+        reply[2] = MAC_ADDR_LEN as u8;
+        reply[4..8].copy_from_slice(request.xid);
+        reply[10..12].copy_from_slice(&request.flags.to_be_bytes());
+        if reply_type != DHCP_NAK {
+            reply[12..16].copy_from_slice(&request.ciaddr.octets());
+            reply[16..20].copy_from_slice(&self.client_ip.octets());
+            reply[20..24].copy_from_slice(&self.server_ip.octets());
+        }
+        reply[28..28 + MAC_ADDR_LEN].copy_from_slice(request.chaddr.get_bytes());
+        reply[236..240].copy_from_slice(&DHCP_MAGIC);
+
+        push_option(&mut reply, OPT_MESSAGE_TYPE, &[reply_type]);
+        push_option(&mut reply, OPT_SERVER_ID, &self.server_ip.octets());
+        if reply_type != DHCP_NAK {
+            push_option(&mut reply, OPT_LEASE_TIME, &self.lease_time.to_be_bytes());
+            push_option(&mut reply, OPT_SUBNET_MASK, &self.mask.octets());
+            if let Some(router) = self.router {
+                push_option(&mut reply, OPT_ROUTER, &router.octets());
+            }
+            if !self.dns.is_empty() {
+                let dns: Vec<u8> = self.dns.iter().flat_map(|d| d.octets().to_vec()).collect();
+                push_option(&mut reply, OPT_DNS, &dns);
+            }
+            if let Some(hostname) = &self.hostname {
+                push_option(&mut reply, OPT_HOSTNAME, hostname.as_bytes());
+            }
+        }
+        reply.push(OPT_END);
+        if reply.len() < BOOTP_MIN_LEN {
+            reply.resize(BOOTP_MIN_LEN, 0);
+        }
+
+        // The client can only receive unicast datagrams once configured,
+        // unless it didn't ask for broadcast replies.
+        let dst_ip = if reply_type == DHCP_NAK {
+            Ipv4Addr::BROADCAST
+        } else if !request.ciaddr.is_unspecified() {
+            request.ciaddr
+        } else if request.flags & BOOTP_FLAG_BROADCAST == 0 {
+            self.client_ip
+        } else {
+            Ipv4Addr::BROADCAST
+        };
+
+        Some(build_udp_frame(
+            request.chaddr,
+            self.server_mac,
+            SocketAddrV4::new(self.server_ip, DHCP_SERVER_PORT),
+            SocketAddrV4::new(dst_ip, DHCP_CLIENT_PORT),
+            &reply,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(message_type: u8, requested_ip: Option<Ipv4Addr>) -> Vec<u8> {
+        let mut payload = vec![0u8; DHCP_OPTIONS_OFFSET];
+        payload[0] = BOOTP_REQUEST;
+        payload[1] = 1;
+        payload[2] = MAC_ADDR_LEN as u8;
+        payload[4..8].copy_from_slice(&[1, 2, 3, 4]);
+        payload[10] = 0x80;
+        payload[28..34].copy_from_slice(&[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+        payload[236..240].copy_from_slice(&DHCP_MAGIC);
+        push_option(&mut payload, OPT_MESSAGE_TYPE, &[message_type]);
+        if let Some(ip) = requested_ip {
+            push_option(&mut payload, OPT_REQUESTED_IP, &ip.octets());
+        }
+        payload.push(OPT_END);
+
+        build_udp_frame(
+            MacAddr::parse_str("ff:ff:ff:ff:ff:ff").unwrap(),
+            MacAddr::parse_str("12:34:56:78:9a:bc").unwrap(),
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DHCP_CLIENT_PORT),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_SERVER_PORT),
+            &payload,
+        )
+    }
+
+    fn reply_type(frame: &[u8]) -> (u8, Ipv4Addr) {
+        let ip = parse_ipv4(frame).unwrap();
+        let udp = parse_udp(ip.payload).unwrap();
+        assert_eq!(udp.dst_port, DHCP_CLIENT_PORT);
+        let payload = udp.payload;
+        assert_eq!(payload[0], BOOTP_REPLY);
+        assert_eq!(&payload[4..8], &[1, 2, 3, 4]);
+        assert_eq!(
+            &payload[DHCP_OPTIONS_OFFSET..DHCP_OPTIONS_OFFSET + 2],
+            &[53, 1]
+        );
+        (
+            payload[DHCP_OPTIONS_OFFSET + 2],
+            Ipv4Addr::new(payload[16], payload[17], payload[18], payload[19]),
+        )
+    }
+
+    #[test]
+    fn test_dhcp_server() {
+        let server = DhcpServer {
+            server_ip: Ipv4Addr::new(192, 168, 249, 1),
+            server_mac: MacAddr::parse_str("52:55:0a:00:02:02").unwrap(),
+            client_ip: Ipv4Addr::new(192, 168, 249, 2),
+            mask: Ipv4Addr::new(255, 255, 255, 0),
+            router: Some(Ipv4Addr::new(192, 168, 249, 1)),
+            dns: vec![Ipv4Addr::new(192, 168, 249, 1)],
+            hostname: Some("guest".to_owned()),
+            lease_time: DEFAULT_LEASE_TIME,
+        };
+
+        let discover = request(DHCP_DISCOVER, None);
+        assert!(DhcpServer::is_dhcp_request(&discover));
+        let offer = server.process(&discover).unwrap();
+        assert_eq!(reply_type(&offer), (DHCP_OFFER, server.client_ip));
+
+        let ack = server
+            .process(&request(DHCP_REQUEST, Some(server.client_ip)))
+            .unwrap();
+        assert_eq!(reply_type(&ack), (DHCP_ACK, server.client_ip));
+
+        let nak = server
+            .process(&request(DHCP_REQUEST, Some(Ipv4Addr::new(10, 0, 0, 1))))
+            .unwrap();
+        assert_eq!(reply_type(&nak), (DHCP_NAK, Ipv4Addr::UNSPECIFIED));
+    }
+}
@@ -16,24 +16,51 @@ extern crate log;
 extern crate net_gen;
 extern crate rand;
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
 extern crate virtio_bindings;
 extern crate vm_memory;
 extern crate vm_virtio;
 extern crate vmm_sys_util;
 
+mod bridge;
+mod dhcp;
 mod mac;
+mod offloads;
 mod open_tap;
+mod packet;
+mod pcap;
 mod queue_pair;
+mod rss;
+mod rx_filter;
 mod tap;
+mod user_net;
 
 use std::io::Error as IoError;
 use std::os::unix::io::{FromRawFd, RawFd};
 use std::{io, mem, net};
 
+pub use bridge::{attach_to_bridge, detach_from_bridge, Error as BridgeError};
+pub use dhcp::{
+    DhcpServer, DEFAULT_LEASE_TIME as DHCP_DEFAULT_LEASE_TIME,
+    MAX_DNS_SERVERS as DHCP_MAX_DNS_SERVERS, MAX_HOSTNAME_LEN as DHCP_MAX_HOSTNAME_LEN,
+};
 pub use mac::{MacAddr, MAC_ADDR_LEN};
-pub use open_tap::{open_tap, Error as OpenTapError};
+pub use offloads::{NetOffloads, MIN_MTU};
+pub use open_tap::{open_macvtap, open_tap, open_tap_fds, Error as OpenTapError};
+pub use pcap::{PcapCapture, PCAP_DEFAULT_SNAPLEN};
 pub use queue_pair::{NetCounters, NetQueuePair, NetQueuePairError, RxVirtio, TxVirtio};
+pub use rss::{
+    Rss, RssHash, RssSteering, SteeredFrames, RSS_MAX_INDIRECTION_TABLE_LEN, RSS_MAX_KEY_SIZE,
+    RSS_SUPPORTED_HASH_TYPES,
+};
+pub use rx_filter::{RxFilter, MAC_TABLE_ENTRIES};
 pub use tap::{Error as TapError, Tap};
+pub use user_net::{
+    Error as UserNetError, HostFwd, HostFwdParseError, HostFwdProtocol, UserNet, UserNetConfig,
+};
 
 #[derive(Debug)]
 pub enum Error {
@@ -59,8 +86,17 @@ fn create_sockaddr(ip_addr: net::Ipv4Addr) -> net_gen::sockaddr {
 }
 
 fn create_socket() -> Result<net::UdpSocket> {
+    create_socket_with_domain(libc::AF_INET)
+}
+
+/// Create an IPv6 socket, required by the ioctls managing IPv6 addresses.
+fn create_inet6_socket() -> Result<net::UdpSocket> {
+    create_socket_with_domain(libc::AF_INET6)
+}
+
+fn create_socket_with_domain(domain: libc::c_int) -> Result<net::UdpSocket> {
     // This is safe since we check the return value.
-    let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    let sock = unsafe { libc::socket(domain, libc::SOCK_DGRAM, 0) };
     if sock < 0 {
         return Err(Error::CreateSocket(IoError::last_os_error()));
     }
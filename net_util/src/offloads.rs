@@ -0,0 +1,167 @@
+// Copyright (c) 2020 Intel Corporation. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use net_gen;
+use std::os::raw::c_uint;
+use virtio_bindings::bindings::virtio_net::*;
+
+/// Lowest MTU a virtio-net device can advertise.
+pub const MIN_MTU: u16 = 68;
+
+/// Offloads negotiated by a virtio-net device and enabled on its tap.
+///
+/// Segmentation offloads rely on the checksum offload, and ECN on a TCP
+/// segmentation offload, which is why they are left disabled when the
+/// offload they depend on is. The TCP segmentation offload applies to IPv4
+/// with `tso`, and to IPv6 with `tso6`.
+///
+/// The default offloads are the ones a virtio-net device always offered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NetOffloads {
+    pub csum: bool,
+    pub tso: bool,
+    pub tso6: bool,
+    pub ufo: bool,
+    pub ecn: bool,
+}
+
+impl Default for NetOffloads {
+    fn default() -> Self {
+        NetOffloads {
+            csum: true,
+            tso: true,
+            tso6: false,
+            ufo: true,
+            ecn: false,
+        }
+    }
+}
+
+impl NetOffloads {
+    fn tso(&self) -> bool {
+        self.csum && self.tso
+    }
+
+    fn tso6(&self) -> bool {
+        self.csum && self.tso6
+    }
+
+    fn ufo(&self) -> bool {
+        self.csum && self.ufo
+    }
+
+    fn ecn(&self) -> bool {
+        (self.tso() || self.tso6()) && self.ecn
+    }
+
+    /// virtio-net features to advertise for the enabled offloads.
+    pub fn virtio_features(&self) -> u64 {
+        let mut features = 0;
+        if self.csum {
+            features |= 1 << VIRTIO_NET_F_CSUM | 1 << VIRTIO_NET_F_GUEST_CSUM;
+        }
+        if self.tso() {
+            features |= 1 << VIRTIO_NET_F_HOST_TSO4 | 1 << VIRTIO_NET_F_GUEST_TSO4;
+        }
+        if self.tso6() {
+            features |= 1 << VIRTIO_NET_F_HOST_TSO6 | 1 << VIRTIO_NET_F_GUEST_TSO6;
+        }
+        if self.ufo() {
+            features |= 1 << VIRTIO_NET_F_HOST_UFO | 1 << VIRTIO_NET_F_GUEST_UFO;
+        }
+        if self.ecn() {
+            features |= 1 << VIRTIO_NET_F_HOST_ECN | 1 << VIRTIO_NET_F_GUEST_ECN;
+        }
+        features
+    }
+
+    /// Flags to enable the offloads on the tap through TUNSETOFFLOAD.
+    pub fn tap_flags(&self) -> c_uint {
+        let mut flags = 0;
+        if self.csum {
+            flags |= net_gen::TUN_F_CSUM;
+        }
+        if self.tso() {
+            flags |= net_gen::TUN_F_TSO4;
+        }
+        if self.tso6() {
+            flags |= net_gen::TUN_F_TSO6;
+        }
+        if self.ufo() {
+            flags |= net_gen::TUN_F_UFO;
+        }
+        if self.ecn() {
+            flags |= net_gen::TUN_F_TSO_ECN;
+        }
+        flags
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_net_offloads() {
+        // Features offered by a virtio-net device before the offloads could
+        // be configured.
+        assert_eq!(
+            NetOffloads::default().virtio_features(),
+            1 << VIRTIO_NET_F_GUEST_CSUM
+                | 1 << VIRTIO_NET_F_CSUM
+                | 1 << VIRTIO_NET_F_GUEST_TSO4
+                | 1 << VIRTIO_NET_F_GUEST_UFO
+                | 1 << VIRTIO_NET_F_HOST_TSO4
+                | 1 << VIRTIO_NET_F_HOST_UFO
+        );
+    }
+
+    #[test]
+    fn test_net_offloads() {
+        let offloads = NetOffloads::default();
+        assert_eq!(
+            offloads.tap_flags(),
+            net_gen::TUN_F_CSUM | net_gen::TUN_F_TSO4 | net_gen::TUN_F_UFO
+        );
+
+        let offloads = NetOffloads {
+            tso6: true,
+            ecn: true,
+            ..Default::default()
+        };
+        assert_ne!(offloads.virtio_features() & 1 << VIRTIO_NET_F_HOST_ECN, 0);
+        assert_ne!(offloads.virtio_features() & 1 << VIRTIO_NET_F_GUEST_TSO6, 0);
+        assert_eq!(
+            offloads.tap_flags(),
+            net_gen::TUN_F_CSUM
+                | net_gen::TUN_F_TSO4
+                | net_gen::TUN_F_TSO6
+                | net_gen::TUN_F_UFO
+                | net_gen::TUN_F_TSO_ECN
+        );
+
+        let offloads = NetOffloads {
+            tso: false,
+            ecn: true,
+            ..Default::default()
+        };
+        assert_eq!(offloads.virtio_features() & 1 << VIRTIO_NET_F_HOST_ECN, 0);
+
+        let offloads = NetOffloads {
+            csum: false,
+            ..Default::default()
+        };
+        assert_eq!(offloads.virtio_features(), 0);
+        assert_eq!(offloads.tap_flags(), 0);
+
+        let offloads = NetOffloads {
+            tso: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            offloads.tap_flags(),
+            net_gen::TUN_F_CSUM | net_gen::TUN_F_UFO
+        );
+    }
+}
@@ -2,9 +2,10 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
-use super::{vnet_hdr_len, MacAddr, Tap, TapError};
-use std::net::Ipv4Addr;
-use std::path::Path;
+use super::{vnet_hdr_len, MacAddr, NetOffloads, Tap, TapError};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
 use std::{fs, io};
 
 #[derive(Debug)]
@@ -23,16 +24,32 @@ pub enum Error {
     TapSetIp(TapError),
     /// Setting tap netmask failed.
     TapSetNetmask(TapError),
+    /// Setting tap IPv6 address failed.
+    TapSetIpv6(TapError),
     /// Setting MAC address failed
     TapSetMac(TapError),
     /// Getting MAC address failed
     TapGetMac(TapError),
+    /// Setting tap MTU failed.
+    TapSetMtu(TapError),
     /// Setting tap interface offload flags failed.
     TapSetOffload(TapError),
     /// Setting vnet header size failed.
     TapSetVnetHdrSize(TapError),
     /// Enabling tap interface failed.
     TapEnable(TapError),
+    /// Invalid macvtap interface name or character device path.
+    InvalidMacvtap(String),
+    /// Failed to read the macvtap interface index from sysfs.
+    ReadSysfsIfindex(io::Error),
+    /// Opening macvtap character device failed.
+    MacvtapOpen(TapError),
+    /// Duplicating a tap file descriptor failed.
+    DupTapFd(io::Error),
+    /// Wrapping a tap file descriptor failed.
+    TapFromFd(TapError),
+    /// No tap file descriptor was provided.
+    NoTapFd,
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -58,18 +75,22 @@ fn check_mq_support(if_name: &Option<&str>, queue_pairs: usize) -> Result<()> {
 }
 
 /// Create a new virtio network device with the given IP address and
-/// netmask.
+/// netmask, and the given IPv6 address and prefix length.
+#[allow(clippy::too_many_arguments)]
 pub fn open_tap(
     if_name: Option<&str>,
     ip_addr: Option<Ipv4Addr>,
     netmask: Option<Ipv4Addr>,
+    ipv6_addr: Option<(Ipv6Addr, u8)>,
     host_mac: &mut Option<MacAddr>,
     num_rx_q: usize,
+    mtu: Option<u16>,
+    offloads: NetOffloads,
 ) -> Result<Vec<Tap>> {
     let mut taps: Vec<Tap> = Vec::new();
     let mut ifname: String = String::new();
     let vnet_hdr_size = vnet_hdr_len() as i32;
-    let flag = net_gen::TUN_F_CSUM | net_gen::TUN_F_UFO | net_gen::TUN_F_TSO4 | net_gen::TUN_F_TSO6;
+    let flag = offloads.tap_flags();
 
     // In case the tap interface already exists, check if the number of
     // queues is appropriate. The tap might not support multiqueue while
@@ -96,6 +117,15 @@ pub fn open_tap(
             } else {
                 *host_mac = Some(tap.get_mac_addr().map_err(Error::TapGetMac)?)
             }
+            if let Some(mtu) = mtu {
+                tap.set_mtu(i32::from(mtu)).map_err(Error::TapSetMtu)?;
+            }
+            // IPv6 is disabled on interfaces with an MTU below 1280, which
+            // must be set first.
+            if let Some((ip, prefix_len)) = ipv6_addr {
+                tap.set_ipv6_addr(ip, prefix_len)
+                    .map_err(Error::TapSetIpv6)?;
+            }
             tap.enable().map_err(Error::TapEnable)?;
             tap.set_offload(flag).map_err(Error::TapSetOffload)?;
 
@@ -114,3 +144,140 @@ pub fn open_tap(
     }
     Ok(taps)
 }
+
+const MACVTAP_DEV_PREFIX: &str = "/dev/tap";
+
+// Look for the interface with the given index among the entries of the
+// sysfs network class. Entries without a readable index, such as
+// bonding_masters, aren't interfaces and are skipped.
+fn if_name_from_index(class_dir: &Path, ifindex: u32) -> Result<Option<String>> {
+    for entry in fs::read_dir(class_dir).map_err(Error::ReadSysfsIfindex)? {
+        let entry = entry.map_err(Error::ReadSysfsIfindex)?;
+        let index = match fs::read_to_string(entry.path().join("ifindex")) {
+            Ok(index) => index,
+            Err(_) => continue,
+        };
+        if index.trim().parse::<u32>().ok() == Some(ifindex) {
+            return Ok(Some(entry.file_name().to_string_lossy().into_owned()));
+        }
+    }
+    Ok(None)
+}
+
+// Find the name and the character device of the macvtap interface, which
+// can be identified by either of them, the device being named after the
+// index of the interface.
+fn macvtap_paths(macvtap: &str) -> Result<(String, PathBuf)> {
+    if let Some(ifindex) = macvtap.strip_prefix(MACVTAP_DEV_PREFIX) {
+        let ifindex = ifindex
+            .parse::<u32>()
+            .map_err(|_| Error::InvalidMacvtap(macvtap.to_owned()))?;
+        match if_name_from_index(Path::new("/sys/class/net"), ifindex)? {
+            Some(if_name) => Ok((if_name, PathBuf::from(macvtap))),
+            None => Err(Error::InvalidMacvtap(macvtap.to_owned())),
+        }
+    } else {
+        let path = format!("/sys/class/net/{}/ifindex", macvtap);
+        if !Path::new(&path).exists() {
+            return Err(Error::InvalidMacvtap(macvtap.to_owned()));
+        }
+        let ifindex = fs::read_to_string(path).map_err(Error::ReadSysfsIfindex)?;
+        let dev_path = format!("{}{}", MACVTAP_DEV_PREFIX, ifindex.trim());
+        Ok((macvtap.to_owned(), PathBuf::from(dev_path)))
+    }
+}
+
+/// Open one queue per queue pair on an existing macvtap interface, given
+/// either its name or its character device path. The MAC address of the
+/// macvtap link is returned, as it is the one the guest must use for the
+/// frames to be delivered to it.
+pub fn open_macvtap(
+    macvtap: &str,
+    num_rx_q: usize,
+    mtu: Option<u16>,
+    offloads: NetOffloads,
+) -> Result<(Vec<Tap>, MacAddr)> {
+    let (if_name, dev_path) = macvtap_paths(macvtap)?;
+    let vnet_hdr_size = vnet_hdr_len() as i32;
+    let flag = offloads.tap_flags();
+
+    let mut taps: Vec<Tap> = Vec::new();
+    for i in 0..num_rx_q {
+        let tap = Tap::open_macvtap(&dev_path, &if_name, num_rx_q).map_err(Error::MacvtapOpen)?;
+        if i == 0 {
+            if let Some(mtu) = mtu {
+                tap.set_mtu(i32::from(mtu)).map_err(Error::TapSetMtu)?;
+            }
+            tap.enable().map_err(Error::TapEnable)?;
+        }
+        tap.set_offload(flag).map_err(Error::TapSetOffload)?;
+
+        tap.set_vnet_hdr_size(vnet_hdr_size)
+            .map_err(Error::TapSetVnetHdrSize)?;
+        taps.push(tap);
+    }
+
+    let mac = taps[0].get_mac_addr().map_err(Error::TapGetMac)?;
+    Ok((taps, mac))
+}
+
+/// Use tap file descriptors opened by another process, one per queue pair.
+/// The descriptors are duplicated, so that they remain usable once the taps
+/// are closed, e.g. to create the device again on reboot. The MAC address of
+/// the tap is returned.
+pub fn open_tap_fds(fds: &[RawFd], offloads: NetOffloads) -> Result<(Vec<Tap>, MacAddr)> {
+    if fds.is_empty() {
+        return Err(Error::NoTapFd);
+    }
+
+    let vnet_hdr_size = vnet_hdr_len() as i32;
+    let flag = offloads.tap_flags();
+
+    let mut taps: Vec<Tap> = Vec::new();
+    for fd in fds {
+        // This is safe since we check the return value.
+        let fd = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(Error::DupTapFd(io::Error::last_os_error()));
+        }
+        let tap = Tap::from_tap_fd(fd, fds.len()).map_err(Error::TapFromFd)?;
+        tap.set_offload(flag).map_err(Error::TapSetOffload)?;
+
+        tap.set_vnet_hdr_size(vnet_hdr_size)
+            .map_err(Error::TapSetVnetHdrSize)?;
+        taps.push(tap);
+    }
+
+    let mac = taps[0].get_mac_addr().map_err(Error::TapGetMac)?;
+    Ok((taps, mac))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_if_name_from_index() {
+        let class_dir = TempDir::new().unwrap();
+        let class_path = class_dir.as_path();
+        fs::write(class_path.join("bonding_masters"), "bond0\n").unwrap();
+        fs::create_dir(class_path.join("broken")).unwrap();
+        fs::create_dir(class_path.join("macvtap0")).unwrap();
+        fs::write(class_path.join("macvtap0").join("ifindex"), "12\n").unwrap();
+
+        assert_eq!(
+            if_name_from_index(class_path, 12).unwrap(),
+            Some("macvtap0".to_owned())
+        );
+        assert_eq!(if_name_from_index(class_path, 13).unwrap(), None);
+    }
+
+    #[test]
+    fn test_open_tap_fds_empty() {
+        assert!(matches!(
+            open_tap_fds(&[], NetOffloads::default()),
+            Err(Error::NoTapFd)
+        ));
+    }
+}
@@ -0,0 +1,383 @@
+// Copyright (c) 2020 Intel Corporation. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Minimal parsing and building of the Ethernet, ARP, IPv4, UDP and TCP
+//! headers, for the frames the VMM answers on behalf of the network.
+
+use mac::{MacAddr, MAC_ADDR_LEN};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+pub const ETH_HLEN: usize = 14;
+pub const ETH_P_IP: u16 = 0x0800;
+pub const ETH_P_ARP: u16 = 0x0806;
+
+pub const IPV4_HLEN: usize = 20;
+pub const IP_FLAG_MF: u16 = 0x2000;
+pub const IP_FRAG_OFFSET_MASK: u16 = 0x1fff;
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+
+pub const UDP_HLEN: usize = 8;
+pub const TCP_HLEN: usize = 20;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+
+const ARP_LEN: usize = 28;
+const ARP_OP_REQUEST: u16 = 1;
+const ARP_OP_REPLY: u16 = 2;
+const IP_DEFAULT_TTL: u8 = 64;
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_MSS: u8 = 2;
+
+/// Internet checksum of the data, on top of an initial sum.
+pub fn checksum(data: &[u8], initial: u32) -> u16 {
+    let mut sum = initial;
+    for chunk in data.chunks(2) {
+        let word = if chunk.len() == 2 {
+            u16::from_be_bytes([chunk[0], chunk[1]])
+        } else {
+            u16::from_be_bytes([chunk[0], 0])
+        };
+        sum += u32::from(word);
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    let mut sum = 0;
+    for addr in [src.octets(), dst.octets()].iter() {
+        sum += u32::from(u16::from_be_bytes([addr[0], addr[1]]));
+        sum += u32::from(u16::from_be_bytes([addr[2], addr[3]]));
+    }
+    sum + u32::from(protocol) + len as u32
+}
+
+fn ethertype(frame: &[u8]) -> Option<u16> {
+    if frame.len() < ETH_HLEN {
+        return None;
+    }
+    Some(u16::from_be_bytes([frame[12], frame[13]]))
+}
+
+/// Source MAC address of the Ethernet frame.
+pub fn source_mac(frame: &[u8]) -> Option<MacAddr> {
+    if frame.len() < ETH_HLEN {
+        return None;
+    }
+    MacAddr::from_bytes(&frame[MAC_ADDR_LEN..2 * MAC_ADDR_LEN]).ok()
+}
+
+fn build_eth_frame(dst_mac: MacAddr, src_mac: MacAddr, ethertype: u16, len: usize) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(ETH_HLEN + len);
+    frame.extend_from_slice(dst_mac.get_bytes());
+    frame.extend_from_slice(src_mac.get_bytes());
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame
+}
+
+/// ARP request for an IPv4 address.
+pub struct ArpRequest {
+    pub sender_mac: MacAddr,
+    pub sender_ip: Ipv4Addr,
+    pub target_ip: Ipv4Addr,
+}
+
+pub fn parse_arp_request(frame: &[u8]) -> Option<ArpRequest> {
+    if ethertype(frame)? != ETH_P_ARP || frame.len() < ETH_HLEN + ARP_LEN {
+        return None;
+    }
+    let arp = &frame[ETH_HLEN..ETH_HLEN + ARP_LEN];
+    // Ethernet hardware type, IPv4 protocol type and address lengths.
+    if arp[0..6] != [0, 1, 8, 0, 6, 4] || u16::from_be_bytes([arp[6], arp[7]]) != ARP_OP_REQUEST {
+        return None;
+    }
+    Some(ArpRequest {
+        sender_mac: MacAddr::from_bytes(&arp[8..14]).ok()?,
+        sender_ip: Ipv4Addr::new(arp[14], arp[15], arp[16], arp[17]),
+        target_ip: Ipv4Addr::new(arp[24], arp[25], arp[26], arp[27]),
+    })
+}
+
+/// Build the reply to the ARP request, for the target IP address being
+/// owned by the given MAC address.
+pub fn build_arp_reply(request: &ArpRequest, mac: MacAddr) -> Vec<u8> {
+    let mut frame = build_eth_frame(request.sender_mac, mac, ETH_P_ARP, ARP_LEN);
+    frame.extend_from_slice(&[0, 1, 8, 0, 6, 4]);
+    frame.extend_from_slice(&ARP_OP_REPLY.to_be_bytes());
+    frame.extend_from_slice(mac.get_bytes());
+    frame.extend_from_slice(&request.target_ip.octets());
+    frame.extend_from_slice(request.sender_mac.get_bytes());
+    frame.extend_from_slice(&request.sender_ip.octets());
+    frame
+}
+
+/// Unfragmented IPv4 packet.
+pub struct Ipv4Packet<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub payload: &'a [u8],
+}
+
+pub fn parse_ipv4(frame: &[u8]) -> Option<Ipv4Packet> {
+    if ethertype(frame)? != ETH_P_IP || frame.len() < ETH_HLEN + IPV4_HLEN {
+        return None;
+    }
+    let ip = &frame[ETH_HLEN..];
+    let hlen = usize::from(ip[0] & 0xf) * 4;
+    let total_len = usize::from(u16::from_be_bytes([ip[2], ip[3]]));
+    if ip[0] >> 4 != 4 || hlen < IPV4_HLEN || total_len < hlen || total_len > ip.len() {
+        return None;
+    }
+    let frag = u16::from_be_bytes([ip[6], ip[7]]);
+    if frag & (IP_FLAG_MF | IP_FRAG_OFFSET_MASK) != 0 {
+        return None;
+    }
+    Some(Ipv4Packet {
+        src: Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]),
+        dst: Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]),
+        protocol: ip[9],
+        payload: &ip[hlen..total_len],
+    })
+}
+
+/// Build an Ethernet frame holding an IPv4 packet, the payload already
+/// containing a complete transport header.
+pub fn build_ipv4_frame(
+    dst_mac: MacAddr,
+    src_mac: MacAddr,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    protocol: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let total_len = IPV4_HLEN + payload.len();
+    let mut frame = build_eth_frame(dst_mac, src_mac, ETH_P_IP, total_len);
+    let mut header = [0u8; IPV4_HLEN];
+    header[0] = 0x45;
+    header[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+    // Don't fragment.
+    header[6] = 0x40;
+    header[8] = IP_DEFAULT_TTL;
+    header[9] = protocol;
+    header[12..16].copy_from_slice(&src.octets());
+    header[16..20].copy_from_slice(&dst.octets());
+    let csum = checksum(&header, 0);
+    header[10..12].copy_from_slice(&csum.to_be_bytes());
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Build an ICMP echo reply from the payload of the echo request.
+pub fn build_icmp_echo_reply(request: &[u8]) -> Option<Vec<u8>> {
+    const ICMP_ECHO_REPLY: u8 = 0;
+    const ICMP_ECHO_REQUEST: u8 = 8;
+
+    if request.len() < 8 || request[0] != ICMP_ECHO_REQUEST {
+        return None;
+    }
+    let mut reply = request.to_vec();
+    reply[0] = ICMP_ECHO_REPLY;
+    reply[2] = 0;
+    reply[3] = 0;
+    let csum = checksum(&reply, 0);
+    reply[2..4].copy_from_slice(&csum.to_be_bytes());
+    Some(reply)
+}
+
+pub struct UdpDatagram<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub payload: &'a [u8],
+}
+
+pub fn parse_udp(payload: &[u8]) -> Option<UdpDatagram> {
+    if payload.len() < UDP_HLEN {
+        return None;
+    }
+    let len = usize::from(u16::from_be_bytes([payload[4], payload[5]]));
+    if len < UDP_HLEN || len > payload.len() {
+        return None;
+    }
+    Some(UdpDatagram {
+        src_port: u16::from_be_bytes([payload[0], payload[1]]),
+        dst_port: u16::from_be_bytes([payload[2], payload[3]]),
+        payload: &payload[UDP_HLEN..len],
+    })
+}
+
+pub fn build_udp_frame(
+    dst_mac: MacAddr,
+    src_mac: MacAddr,
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    payload: &[u8],
+) -> Vec<u8> {
+    let len = UDP_HLEN + payload.len();
+    let mut udp = Vec::with_capacity(len);
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&(len as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+    let mut csum = checksum(
+        &udp,
+        pseudo_header_sum(*src.ip(), *dst.ip(), IPPROTO_UDP, len),
+    );
+    if csum == 0 {
+        csum = 0xffff;
+    }
+    udp[6..8].copy_from_slice(&csum.to_be_bytes());
+    build_ipv4_frame(dst_mac, src_mac, *src.ip(), *dst.ip(), IPPROTO_UDP, &udp)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TcpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    /// Maximum segment size option, only carried by SYN segments.
+    pub mss: Option<u16>,
+}
+
+pub fn parse_tcp(payload: &[u8]) -> Option<(TcpHeader, &[u8])> {
+    if payload.len() < TCP_HLEN {
+        return None;
+    }
+    let hlen = usize::from(payload[12] >> 4) * 4;
+    if hlen < TCP_HLEN || hlen > payload.len() {
+        return None;
+    }
+
+    let mut mss = None;
+    let mut options = &payload[TCP_HLEN..hlen];
+    while let Some(&kind) = options.first() {
+        match kind {
+            TCP_OPT_END => break,
+            TCP_OPT_NOP => options = &options[1..],
+            _ => {
+                if options.len() < 2 || usize::from(options[1]) < 2 {
+                    break;
+                }
+                let len = usize::from(options[1]);
+                if len > options.len() {
+                    break;
+                }
+                if kind == TCP_OPT_MSS && len == 4 {
+                    mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                }
+                options = &options[len..];
+            }
+        }
+    }
+
+    let header = TcpHeader {
+        src_port: u16::from_be_bytes([payload[0], payload[1]]),
+        dst_port: u16::from_be_bytes([payload[2], payload[3]]),
+        seq: u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]),
+        ack: u32::from_be_bytes([payload[8], payload[9], payload[10], payload[11]]),
+        flags: payload[13],
+        window: u16::from_be_bytes([payload[14], payload[15]]),
+        mss,
+    };
+    Some((header, &payload[hlen..]))
+}
+
+pub fn build_tcp_frame(
+    dst_mac: MacAddr,
+    src_mac: MacAddr,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    header: &TcpHeader,
+    payload: &[u8],
+) -> Vec<u8> {
+    let hlen = if header.mss.is_some() {
+        TCP_HLEN + 4
+    } else {
+        TCP_HLEN
+    };
+    let len = hlen + payload.len();
+    let mut tcp = Vec::with_capacity(len);
+    tcp.extend_from_slice(&header.src_port.to_be_bytes());
+    tcp.extend_from_slice(&header.dst_port.to_be_bytes());
+    tcp.extend_from_slice(&header.seq.to_be_bytes());
+    tcp.extend_from_slice(&header.ack.to_be_bytes());
+    tcp.push((hlen as u8 / 4) << 4);
+    tcp.push(header.flags);
+    tcp.extend_from_slice(&header.window.to_be_bytes());
+    tcp.extend_from_slice(&[0, 0, 0, 0]);
+    if let Some(mss) = header.mss {
+        tcp.extend_from_slice(&[TCP_OPT_MSS, 4]);
+        tcp.extend_from_slice(&mss.to_be_bytes());
+    }
+    tcp.extend_from_slice(payload);
+    let csum = checksum(&tcp, pseudo_header_sum(src, dst, IPPROTO_TCP, len));
+    tcp[16..18].copy_from_slice(&csum.to_be_bytes());
+    build_ipv4_frame(dst_mac, src_mac, src, dst, IPPROTO_TCP, &tcp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_udp_frame() {
+        let guest = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let gateway = MacAddr::parse_str("52:55:0a:00:02:02").unwrap();
+        let src = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 2), 53);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 15), 34567);
+        let frame = build_udp_frame(guest, gateway, src, dst, b"payload");
+
+        let ip = parse_ipv4(&frame).unwrap();
+        assert_eq!(ip.src, *src.ip());
+        assert_eq!(ip.dst, *dst.ip());
+        assert_eq!(ip.protocol, IPPROTO_UDP);
+        assert_eq!(checksum(&frame[ETH_HLEN..ETH_HLEN + IPV4_HLEN], 0), 0);
+        let pseudo = pseudo_header_sum(ip.src, ip.dst, IPPROTO_UDP, ip.payload.len());
+        assert_eq!(checksum(ip.payload, pseudo), 0);
+
+        let udp = parse_udp(ip.payload).unwrap();
+        assert_eq!(udp.src_port, 53);
+        assert_eq!(udp.dst_port, 34567);
+        assert_eq!(udp.payload, b"payload");
+    }
+
+    #[test]
+    fn test_tcp_frame() {
+        let guest = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let gateway = MacAddr::parse_str("52:55:0a:00:02:02").unwrap();
+        let header = TcpHeader {
+            src_port: 80,
+            dst_port: 45678,
+            seq: 0x1234_5678,
+            ack: 0x9abc_def0,
+            flags: TCP_SYN | TCP_ACK,
+            window: 65535,
+            mss: Some(1460),
+        };
+        let src = Ipv4Addr::new(10, 0, 2, 2);
+        let dst = Ipv4Addr::new(10, 0, 2, 15);
+        let frame = build_tcp_frame(guest, gateway, src, dst, &header, &[]);
+
+        let ip = parse_ipv4(&frame).unwrap();
+        let pseudo = pseudo_header_sum(ip.src, ip.dst, IPPROTO_TCP, ip.payload.len());
+        assert_eq!(checksum(ip.payload, pseudo), 0);
+        let (parsed, payload) = parse_tcp(ip.payload).unwrap();
+        assert_eq!(parsed, header);
+        assert!(payload.is_empty());
+    }
+}
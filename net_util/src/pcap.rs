@@ -0,0 +1,169 @@
+// Copyright (c) 2020 Intel Corporation. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Snapshot length used when none is given, large enough for any frame.
+pub const PCAP_DEFAULT_SNAPLEN: u32 = 65535;
+
+// Records are gathered before being written to the file, which only blocks
+// the queue pair capturing a frame once per buffer.
+const PCAP_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Writer of Ethernet frames to a file in the pcap format. The records are
+/// buffered, and only reach the file once the buffer is full or when the
+/// writer is flushed.
+pub struct PcapWriter {
+    file: BufWriter<File>,
+    snaplen: u32,
+}
+
+impl PcapWriter {
+    /// Create the file, or truncate it if it exists, and write the pcap
+    /// header. Frames are truncated to `snaplen` bytes.
+    pub fn new(path: &Path, snaplen: u32) -> io::Result<Self> {
+        let mut file = BufWriter::with_capacity(
+            PCAP_BUFFER_SIZE,
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?,
+        );
+
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&PCAP_VERSION_MAJOR.to_le_bytes());
+        header.extend_from_slice(&PCAP_VERSION_MINOR.to_le_bytes());
+        // Timestamps are in UTC, and their accuracy isn't reported.
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&snaplen.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        file.write_all(&header)?;
+
+        Ok(PcapWriter { file, snaplen })
+    }
+
+    /// Record a frame, timestamped with the current time.
+    pub fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let caplen = std::cmp::min(frame.len(), self.snaplen as usize);
+
+        let mut record = Vec::with_capacity(16 + caplen);
+        record.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&time.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(caplen as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&frame[..caplen]);
+        self.file.write_all(&record)
+    }
+
+    /// Write the buffered records to the file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Capture of the frames of a network device, shared by its queue pairs.
+/// It can be started and stopped at any time.
+#[derive(Clone, Default)]
+pub struct PcapCapture {
+    // Avoids taking the lock for every frame while no capture is running.
+    active: Arc<AtomicBool>,
+    writer: Arc<Mutex<Option<PcapWriter>>>,
+}
+
+impl PcapCapture {
+    /// Start capturing to the given file, replacing the current capture if
+    /// any.
+    pub fn start(&self, path: &Path, snaplen: Option<u32>) -> io::Result<()> {
+        let writer = PcapWriter::new(path, snaplen.unwrap_or(PCAP_DEFAULT_SNAPLEN))?;
+        *self.writer.lock().unwrap() = Some(writer);
+        self.active.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Stop capturing, returning whether a capture was running. The frames
+    /// recorded so far are written to the file.
+    pub fn stop(&self) -> bool {
+        self.active.store(false, Ordering::Release);
+        match self.writer.lock().unwrap().take() {
+            Some(mut writer) => {
+                if let Err(e) = writer.flush() {
+                    error!("Failed to write captured frames: {}", e);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Record the frame if a capture is running. The capture is stopped if
+    /// the frame can't be written. The lock shared by the queue pairs is held
+    /// while the frame is copied to the buffer of the writer, and while the
+    /// buffer is written to the file once full, delaying the queue pairs
+    /// capturing frames in the meantime.
+    pub fn capture(&self, frame: &[u8]) {
+        if !self.active.load(Ordering::Acquire) {
+            return;
+        }
+
+        let mut writer = self.writer.lock().unwrap();
+        if let Some(w) = writer.as_mut() {
+            if let Err(e) = w.write_frame(frame) {
+                error!("Failed to write captured frame, stopping capture: {}", e);
+                self.active.store(false, Ordering::Release);
+                *writer = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_pcap_capture() {
+        let file = TempFile::new().unwrap();
+        let capture = PcapCapture::default();
+        capture.capture(&[0u8; 64]);
+
+        capture.start(file.as_path(), Some(16)).unwrap();
+        capture.capture(&[0xaau8; 64]);
+        capture.capture(&[0x55u8; 8]);
+        assert!(capture.stop());
+        assert!(!capture.stop());
+        capture.capture(&[0u8; 64]);
+
+        let data = fs::read(file.as_path()).unwrap();
+        assert_eq!(data.len(), 24 + 16 + 16 + 16 + 8);
+        assert_eq!(&data[0..4], &PCAP_MAGIC.to_le_bytes());
+        assert_eq!(&data[16..20], &16u32.to_le_bytes());
+        assert_eq!(&data[20..24], &LINKTYPE_ETHERNET.to_le_bytes());
+
+        // The first frame is truncated to the snapshot length.
+        assert_eq!(&data[32..36], &16u32.to_le_bytes());
+        assert_eq!(&data[36..40], &64u32.to_le_bytes());
+        assert_eq!(&data[40..56], &[0xaau8; 16]);
+        assert_eq!(&data[64..68], &8u32.to_le_bytes());
+        assert_eq!(&data[68..72], &8u32.to_le_bytes());
+        assert_eq!(&data[72..80], &[0x55u8; 8]);
+    }
+}
@@ -2,16 +2,22 @@
 //
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
-use super::{register_listener, unregister_listener, vnet_hdr_len, Tap};
+use super::{
+    register_listener, unregister_listener, vnet_hdr_len, DhcpServer, PcapCapture, RxFilter, Tap,
+};
 use libc::EAGAIN;
+use rss::{RssHash, RssSteering, VIRTIO_NET_HASH_REPORT_NONE};
 use std::cmp;
 use std::io;
 use std::io::{Read, Write};
 use std::num::Wrapping;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
-use vm_memory::{Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use virtio_bindings::bindings::virtio_net::VIRTIO_NET_S_LINK_UP;
+use vm_memory::{
+    Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic, GuestMemoryMmap,
+};
 use vm_virtio::{DescriptorChain, Queue};
 
 /// The maximum buffer size when segmentation offload is enabled. This
@@ -19,12 +25,32 @@ use vm_virtio::{DescriptorChain, Queue};
 /// http://docs.oasis-open.org/virtio/virtio/v1.0/virtio-v1.0.html#x1-1740003
 const MAX_BUFFER_SIZE: usize = 65562;
 
+/// Offset of the `num_buffers` field in the virtio net header.
+const VIRTIO_NET_HDR_NUM_BUFFERS_OFFSET: usize = 10;
+
+/// Size of the hash fields following the virtio net header when
+/// VIRTIO_NET_F_HASH_REPORT has been negotiated. The tap doesn't know
+/// about them, hence they are added and removed by the device.
+const VIRTIO_NET_HDR_HASH_LEN: usize = 8;
+
 #[derive(Clone)]
 pub struct TxVirtio {
     pub iovec: Vec<(GuestAddress, usize)>,
     pub frame_buf: [u8; MAX_BUFFER_SIZE],
     pub counter_bytes: Wrapping<u64>,
     pub counter_frames: Wrapping<u64>,
+    // DHCP requests sent by the guest are answered by this server instead
+    // of being written to the tap.
+    pub dhcp_server: Option<DhcpServer>,
+    // Reply waiting to be received by the guest, vnet header included. Only
+    // the reply to the latest request is kept, so that a guest not posting
+    // receive buffers can't make it grow.
+    pub dhcp_reply: Option<Vec<u8>>,
+    // Frames sent by the guest are recorded here while a capture runs.
+    pub capture: PcapCapture,
+    // VIRTIO_NET_F_HASH_REPORT has been negotiated, the virtio net header
+    // sent by the driver includes the hash fields.
+    pub hash_report: bool,
 }
 
 impl Default for TxVirtio {
@@ -40,7 +66,31 @@ impl TxVirtio {
             frame_buf: [0u8; MAX_BUFFER_SIZE],
             counter_bytes: Wrapping(0),
             counter_frames: Wrapping(0),
+            dhcp_server: None,
+            dhcp_reply: None,
+            capture: PcapCapture::default(),
+            hash_report: false,
+        }
+    }
+
+    // Answer the frame if it is a DHCP request and the responder is enabled.
+    // Returns whether the frame was consumed.
+    fn intercept_dhcp(&mut self, len: usize) -> bool {
+        let dhcp_server = match &self.dhcp_server {
+            Some(dhcp_server) if len > vnet_hdr_len() => dhcp_server,
+            _ => return false,
+        };
+        let frame = &self.frame_buf[vnet_hdr_len()..len];
+        if !DhcpServer::is_dhcp_request(frame) {
+            return false;
+        }
+
+        if let Some(reply) = dhcp_server.process(frame) {
+            let mut buf = vec![0u8; vnet_hdr_len()];
+            buf.extend_from_slice(&reply);
+            self.dhcp_reply = Some(buf);
         }
+        true
     }
 
     pub fn process_desc_chain(&mut self, mem: &GuestMemoryMmap, tap: &mut Tap, queue: &mut Queue) {
@@ -80,13 +130,28 @@ impl TxVirtio {
                 }
             }
 
-            let write_result = tap.write(&self.frame_buf[..read_count]);
-            match write_result {
-                Ok(_) => {}
-                Err(e) => {
-                    println!("net: tx: error failed to write to tap: {}", e);
-                }
-            };
+            if self.hash_report && read_count >= vnet_hdr_len() + VIRTIO_NET_HDR_HASH_LEN {
+                self.frame_buf.copy_within(
+                    vnet_hdr_len() + VIRTIO_NET_HDR_HASH_LEN..read_count,
+                    vnet_hdr_len(),
+                );
+                read_count -= VIRTIO_NET_HDR_HASH_LEN;
+            }
+
+            if read_count > vnet_hdr_len() {
+                self.capture
+                    .capture(&self.frame_buf[vnet_hdr_len()..read_count]);
+            }
+
+            if !self.intercept_dhcp(read_count) {
+                let write_result = tap.write(&self.frame_buf[..read_count]);
+                match write_result {
+                    Ok(_) => {}
+                    Err(e) => {
+                        println!("net: tx: error failed to write to tap: {}", e);
+                    }
+                };
+            }
 
             self.counter_bytes += Wrapping((read_count - vnet_hdr_len()) as u64);
             self.counter_frames += Wrapping(1);
@@ -95,16 +160,37 @@ impl TxVirtio {
             queue.update_avail_event(&mem);
         }
     }
+
+    // Complete the frames sent by the guest without writing them anywhere.
+    pub fn drop_desc_chains(&mut self, mem: &GuestMemoryMmap, queue: &mut Queue) {
+        while let Some(avail_desc) = queue.iter(&mem).next() {
+            let head_index = avail_desc.index;
+            queue.add_used(&mem, head_index, 0);
+            queue.update_avail_event(&mem);
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct RxVirtio {
     pub deferred_frame: bool,
     pub deferred_irqs: bool,
+    // VIRTIO_NET_F_MRG_RXBUF has been negotiated, a frame can be spread
+    // across several descriptor chains.
+    pub mergeable: bool,
     pub bytes_read: usize,
-    pub frame_buf: [u8; MAX_BUFFER_SIZE],
+    // Large enough for the hash fields to be added to the header of the
+    // biggest frame.
+    pub frame_buf: [u8; MAX_BUFFER_SIZE + VIRTIO_NET_HDR_HASH_LEN],
     pub counter_bytes: Wrapping<u64>,
     pub counter_frames: Wrapping<u64>,
+    // Frames dropped as the buffers of the guest couldn't hold them.
+    pub counter_dropped: Wrapping<u64>,
+    // Frames received by the guest are recorded here while a capture runs.
+    pub capture: PcapCapture,
+    // VIRTIO_NET_F_HASH_REPORT has been negotiated, the hash of the frames
+    // is reported to the driver through the virtio net header.
+    pub hash_report: bool,
 }
 
 impl Default for RxVirtio {
@@ -118,13 +204,45 @@ impl RxVirtio {
         RxVirtio {
             deferred_frame: false,
             deferred_irqs: false,
+            mergeable: false,
             bytes_read: 0,
-            frame_buf: [0u8; MAX_BUFFER_SIZE],
+            frame_buf: [0u8; MAX_BUFFER_SIZE + VIRTIO_NET_HDR_HASH_LEN],
             counter_bytes: Wrapping(0),
             counter_frames: Wrapping(0),
+            counter_dropped: Wrapping(0),
+            capture: PcapCapture::default(),
+            hash_report: false,
         }
     }
 
+    fn hdr_len(&self) -> usize {
+        if self.hash_report {
+            vnet_hdr_len() + VIRTIO_NET_HDR_HASH_LEN
+        } else {
+            vnet_hdr_len()
+        }
+    }
+
+    // Insert the hash fields between the virtio net header read from the tap
+    // and the frame. Returns the new length of the frame.
+    fn add_hash_report(&mut self, count: usize, hash: Option<RssHash>) -> usize {
+        let hdr_len = vnet_hdr_len();
+        if count < hdr_len {
+            return count;
+        }
+        let (value, report) = hash.map_or((0, VIRTIO_NET_HASH_REPORT_NONE), |hash| {
+            (hash.value, hash.report)
+        });
+
+        self.frame_buf
+            .copy_within(hdr_len..count, hdr_len + VIRTIO_NET_HDR_HASH_LEN);
+        self.frame_buf[hdr_len..hdr_len + 4].copy_from_slice(&value.to_le_bytes());
+        self.frame_buf[hdr_len + 4..hdr_len + 6].copy_from_slice(&report.to_le_bytes());
+        self.frame_buf[hdr_len + 6..hdr_len + VIRTIO_NET_HDR_HASH_LEN].copy_from_slice(&[0, 0]);
+
+        count + VIRTIO_NET_HDR_HASH_LEN
+    }
+
     pub fn process_desc_chain(
         &mut self,
         mem: &GuestMemoryMmap,
@@ -167,7 +285,7 @@ impl RxVirtio {
             }
         }
 
-        self.counter_bytes += Wrapping((write_count - vnet_hdr_len()) as u64);
+        self.counter_bytes += Wrapping((write_count - self.hdr_len()) as u64);
         self.counter_frames += Wrapping(1);
 
         queue.add_used(&mem, head_index, write_count as u32);
@@ -186,6 +304,112 @@ impl RxVirtio {
             true
         }
     }
+
+    /// Copies the frame into as many descriptor chains as needed, starting
+    /// with `head`, and reports their number through the `num_buffers` field
+    /// of the virtio net header. Returns false without using any chain if the
+    /// driver hasn't made enough buffers available to hold the whole frame.
+    /// The frame is dropped if a chain can't hold any of it, the chains being
+    /// left to the driver.
+    pub fn process_mergeable_desc_chains(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        head: DescriptorChain,
+        queue: &mut Queue,
+    ) -> bool {
+        // Buffers of each chain the frame is copied to, so that the number of
+        // chains is known before writing the header.
+        let mut chains: Vec<(u16, Vec<(GuestAddress, usize)>)> = Vec::new();
+        let mut capacity = 0;
+        let mut next_head = Some(head);
+
+        while capacity < self.bytes_read {
+            let head = match next_head.take().or_else(|| queue.iter(&mem).next()) {
+                Some(head) => head,
+                None => {
+                    // Give the chains back to the driver, the frame will be
+                    // received once more buffers are available.
+                    for _ in 0..chains.len() {
+                        queue.go_to_previous_position();
+                    }
+                    return false;
+                }
+            };
+            let head_index = head.index;
+            let mut buffers = Vec::new();
+            let mut chain_len = 0;
+
+            let mut next_desc = Some(head);
+            while let Some(desc) = next_desc {
+                if !desc.is_write_only() || capacity + chain_len >= self.bytes_read {
+                    break;
+                }
+                let len = cmp::min(desc.len as usize, self.bytes_read - capacity - chain_len);
+                if len > 0 {
+                    buffers.push((desc.addr, len));
+                    chain_len += len;
+                }
+                next_desc = desc.next_descriptor();
+            }
+
+            chains.push((head_index, buffers));
+            if chain_len == 0 {
+                warn!("Receiving buffer can't hold any data, dropping frame");
+                return self.drop_mergeable_frame(queue, chains.len());
+            }
+            capacity += chain_len;
+        }
+
+        // The header is written along the frame, whatever the size of the
+        // buffers holding it.
+        let offset = VIRTIO_NET_HDR_NUM_BUFFERS_OFFSET;
+        if self.bytes_read >= offset + 2 {
+            let num_buffers = chains.len() as u16;
+            self.frame_buf[offset..offset + 2].copy_from_slice(&num_buffers.to_le_bytes());
+        }
+
+        let mut write_count = 0;
+        let mut used_lens = Vec::new();
+        for (_, buffers) in chains.iter() {
+            let chain_start = write_count;
+            for (addr, len) in buffers {
+                let limit = write_count + len;
+                if let Err(e) = mem.write_slice(&self.frame_buf[write_count..limit], *addr) {
+                    error!("Failed to write slice: {:?}", e);
+                    return self.drop_mergeable_frame(queue, chains.len());
+                }
+                write_count = limit;
+            }
+            used_lens.push((write_count - chain_start) as u32);
+        }
+
+        if write_count >= self.hdr_len() {
+            self.counter_bytes += Wrapping((write_count - self.hdr_len()) as u64);
+        }
+        self.counter_frames += Wrapping(1);
+
+        for ((head_index, _), len) in chains.iter().zip(used_lens) {
+            queue.add_used(&mem, *head_index, len);
+        }
+        queue.update_avail_event(&mem);
+
+        // Mark that we have at least one pending packet and we need to interrupt the guest.
+        self.deferred_irqs = true;
+        self.bytes_read = 0;
+
+        true
+    }
+
+    // Drop the frame without using the chains taken for it, which are given
+    // back to the driver.
+    fn drop_mergeable_frame(&mut self, queue: &mut Queue, num_chains: usize) -> bool {
+        for _ in 0..num_chains {
+            queue.go_to_previous_position();
+        }
+        self.counter_dropped += Wrapping(1);
+        self.bytes_read = 0;
+        true
+    }
 }
 
 #[derive(Default, Clone)]
@@ -194,6 +418,9 @@ pub struct NetCounters {
     pub tx_frames: Arc<AtomicU64>,
     pub rx_bytes: Arc<AtomicU64>,
     pub rx_frames: Arc<AtomicU64>,
+    /// Frames dropped as the queue pair they were steered to is lagging, or
+    /// as the buffers of the guest can't hold them.
+    pub rx_dropped: Arc<AtomicU64>,
 }
 
 #[derive(Debug)]
@@ -217,6 +444,14 @@ pub struct NetQueuePair {
     pub rx_tap_listening: bool,
     pub counters: NetCounters,
     pub tap_event_id: u16,
+    // Filter programmed by the driver, frames it rejects are dropped.
+    pub rx_filter: Option<Arc<RwLock<RxFilter>>>,
+    // Hash computation and steering of the received frames, programmed by
+    // the driver.
+    pub rss: Option<RssSteering>,
+    // Status reported to the driver, frames are dropped in both directions
+    // while VIRTIO_NET_S_LINK_UP is cleared. The link is always up if None.
+    pub link_status: Option<Arc<AtomicU16>>,
 }
 
 impl NetQueuePair {
@@ -229,10 +464,16 @@ impl NetQueuePair {
             .as_ref()
             .ok_or(NetQueuePairError::NoMemoryConfigured)
             .map(|m| m.memory())?;
-        let next_desc = queue.iter(&mem).next();
-
-        if next_desc.is_none() {
-            // Queue has no available descriptors
+        let used = match queue.iter(&mem).next() {
+            Some(head) if self.rx.mergeable => self
+                .rx
+                .process_mergeable_desc_chains(&mem, head, &mut queue),
+            Some(head) => return Ok(self.rx.process_desc_chain(&mem, Some(head), &mut queue)),
+            None => false,
+        };
+
+        if !used {
+            // Queue has not enough available descriptors
             if self.rx_tap_listening {
                 unregister_listener(
                     self.epoll_fd.unwrap(),
@@ -244,17 +485,31 @@ impl NetQueuePair {
                 self.rx_tap_listening = false;
                 info!("Listener unregistered");
             }
-            return Ok(false);
         }
 
-        Ok(self.rx.process_desc_chain(&mem, next_desc, &mut queue))
+        Ok(used)
     }
 
     fn process_rx(&mut self, queue: &mut Queue) -> Result<bool, NetQueuePairError> {
         // Read as many frames as possible.
         loop {
-            match self.read_tap() {
-                Ok(count) => {
+            match self.read_frame() {
+                Ok((mut count, from_tap)) => {
+                    if !self.link_up() || !self.rx_filter_accepts(count) {
+                        continue;
+                    }
+                    let hash = self.rss_hash(count);
+                    if from_tap && self.steer_frame(count, hash) {
+                        continue;
+                    }
+                    if count > vnet_hdr_len() {
+                        self.rx
+                            .capture
+                            .capture(&self.rx.frame_buf[vnet_hdr_len()..count]);
+                    }
+                    if self.rx.hash_report {
+                        count = self.rx.add_hash_report(count, hash);
+                    }
                     self.rx.bytes_read = count;
                     if !self.rx_single_frame(queue)? {
                         self.rx.deferred_frame = true;
@@ -285,8 +540,12 @@ impl NetQueuePair {
         self.counters
             .rx_frames
             .fetch_add(self.rx.counter_frames.0, Ordering::AcqRel);
+        self.counters
+            .rx_dropped
+            .fetch_add(self.rx.counter_dropped.0, Ordering::AcqRel);
         self.rx.counter_bytes = Wrapping(0);
         self.rx.counter_frames = Wrapping(0);
+        self.rx.counter_dropped = Wrapping(0);
 
         if self.rx.deferred_irqs {
             self.rx.deferred_irqs = false;
@@ -313,6 +572,7 @@ impl NetQueuePair {
             self.rx_tap_listening = true;
             info!("Listener registered");
         }
+        self.drop_deferred_frame();
         if self.rx.deferred_frame {
             if self.rx_single_frame(queue)? {
                 self.rx.deferred_frame = false;
@@ -341,7 +601,11 @@ impl NetQueuePair {
             .as_ref()
             .ok_or(NetQueuePairError::NoMemoryConfigured)
             .map(|m| m.memory())?;
-        self.tx.process_desc_chain(&mem, &mut self.tap, &mut queue);
+        if self.link_up() {
+            self.tx.process_desc_chain(&mem, &mut self.tap, &mut queue);
+        } else {
+            self.tx.drop_desc_chains(&mem, &mut queue);
+        }
 
         self.counters
             .tx_bytes
@@ -356,6 +620,7 @@ impl NetQueuePair {
     }
 
     pub fn process_rx_tap(&mut self, mut queue: &mut Queue) -> Result<bool, NetQueuePairError> {
+        self.drop_deferred_frame();
         if self.rx.deferred_frame
         // Process a deferred frame first if available. Don't read from tap again
         // until we manage to receive this deferred frame.
@@ -374,7 +639,227 @@ impl NetQueuePair {
         }
     }
 
-    fn read_tap(&mut self) -> io::Result<usize> {
-        self.tap.read(&mut self.rx.frame_buf)
+    fn link_up(&self) -> bool {
+        match &self.link_status {
+            Some(status) => status.load(Ordering::Acquire) & VIRTIO_NET_S_LINK_UP as u16 != 0,
+            None => true,
+        }
+    }
+
+    // The frame waiting for a buffer is dropped if the link went down in
+    // the meantime.
+    fn drop_deferred_frame(&mut self) {
+        if self.rx.deferred_frame && !self.link_up() {
+            self.rx.deferred_frame = false;
+        }
+    }
+
+    fn rx_filter_accepts(&self, count: usize) -> bool {
+        match &self.rx_filter {
+            Some(rx_filter) if count > vnet_hdr_len() => rx_filter
+                .read()
+                .unwrap()
+                .accepts(&self.rx.frame_buf[vnet_hdr_len()..count]),
+            _ => true,
+        }
+    }
+
+    fn rss_hash(&self, count: usize) -> Option<RssHash> {
+        match &self.rss {
+            Some(steering) if count > vnet_hdr_len() => steering
+                .rss
+                .read()
+                .unwrap()
+                .hash(&self.rx.frame_buf[vnet_hdr_len()..count]),
+            _ => None,
+        }
+    }
+
+    // Hand the frame over to the queue pair picked by RSS, if not this one.
+    // Returns whether the frame was steered.
+    fn steer_frame(&self, count: usize, hash: Option<RssHash>) -> bool {
+        let steering = match &self.rss {
+            Some(steering) => steering,
+            None => return false,
+        };
+        let index = match steering.rss.read().unwrap().queue_pair(hash) {
+            Some(index) if usize::from(index) != steering.index => usize::from(index),
+            _ => return false,
+        };
+
+        match steering.steered_frames.get(index) {
+            Some(steered_frames) => {
+                if !steered_frames.push(&self.rx.frame_buf[..count]) {
+                    self.counters.rx_dropped.fetch_add(1, Ordering::AcqRel);
+                }
+                true
+            }
+            // The driver picked a queue pair the device doesn't have.
+            None => false,
+        }
+    }
+
+    // Read the next frame for the guest, the replies generated on the TX path
+    // and the frames steered from the other queue pairs being received before
+    // the frames from the tap. Returns the frame length, and whether it was
+    // read from the tap.
+    fn read_frame(&mut self) -> io::Result<(usize, bool)> {
+        if let Some(reply) = self.tx.dhcp_reply.take() {
+            self.rx.frame_buf[..reply.len()].copy_from_slice(&reply);
+            return Ok((reply.len(), false));
+        }
+        let steered_frame = self
+            .rss
+            .as_ref()
+            .and_then(|steering| steering.steered_frames.get(steering.index))
+            .and_then(|steered_frames| steered_frames.pop());
+        if let Some(frame) = steered_frame {
+            self.rx.frame_buf[..frame.len()].copy_from_slice(&frame);
+            return Ok((frame.len(), false));
+        }
+        self.tap
+            .read(&mut self.rx.frame_buf[..MAX_BUFFER_SIZE])
+            .map(|count| (count, true))
+    }
+
+    /// Whether frames generated by the device are waiting to be received by
+    /// the guest, in which case the RX queue must be processed.
+    pub fn has_pending_rx(&self) -> bool {
+        self.tx.dhcp_reply.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixDatagram;
+    use vm_virtio::queue::testing::VirtQueue;
+    use vm_virtio::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    const FRAME_LEN: usize = 100;
+
+    #[test]
+    fn test_link_down_drops_frames() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let rx_vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let tx_vq = VirtQueue::new(GuestAddress(0x1000), &mem, 16);
+        let mut rx_queue = rx_vq.create_queue();
+        let mut tx_queue = tx_vq.create_queue();
+
+        // A datagram socket stands for the tap, keeping the frame boundaries.
+        let (tap_end, peer) = UnixDatagram::pair().unwrap();
+        tap_end.set_nonblocking(true).unwrap();
+        peer.set_nonblocking(true).unwrap();
+        let tap = Tap::from_socket(unsafe { File::from_raw_fd(tap_end.into_raw_fd()) });
+
+        let status = Arc::new(AtomicU16::new(0));
+        let mut net = NetQueuePair {
+            mem: Some(GuestMemoryAtomic::new(mem.clone())),
+            tap,
+            rx: RxVirtio::new(),
+            tx: TxVirtio::new(),
+            epoll_fd: None,
+            rx_tap_listening: true,
+            counters: NetCounters::default(),
+            tap_event_id: 0,
+            rx_filter: None,
+            rss: None,
+            link_status: Some(status.clone()),
+        };
+        let mut buf = [0u8; FRAME_LEN];
+
+        // Frames sent by the guest are completed without reaching the tap.
+        mem.write_slice(&[0xa5; FRAME_LEN], GuestAddress(0x4000))
+            .unwrap();
+        tx_vq.dtable[0].set(0x4000, FRAME_LEN as u32, 0, 0);
+        tx_vq.avail.ring[0].set(0);
+        tx_vq.avail.idx.set(1);
+        net.process_tx(&mut tx_queue).unwrap();
+        assert_eq!(tx_vq.used.idx.get(), 1);
+        assert!(peer.recv(&mut buf).is_err());
+
+        // Frames read from the tap don't use the buffers of the guest.
+        rx_vq.dtable[0].set(0x8000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        rx_vq.avail.ring[0].set(0);
+        rx_vq.avail.idx.set(1);
+        peer.send(&[0x5a; FRAME_LEN]).unwrap();
+        net.process_rx_tap(&mut rx_queue).unwrap();
+        assert_eq!(rx_vq.used.idx.get(), 0);
+
+        // Both directions work again once the link is up.
+        status.store(VIRTIO_NET_S_LINK_UP as u16, Ordering::Release);
+        peer.send(&[0x5a; FRAME_LEN]).unwrap();
+        net.process_rx_tap(&mut rx_queue).unwrap();
+        assert_eq!(rx_vq.used.idx.get(), 1);
+        assert_eq!(rx_vq.used.ring[0].get().len, FRAME_LEN as u32);
+
+        tx_vq.dtable[1].set(0x4000, FRAME_LEN as u32, 0, 0);
+        tx_vq.avail.ring[1].set(1);
+        tx_vq.avail.idx.set(2);
+        net.process_tx(&mut tx_queue).unwrap();
+        assert_eq!(tx_vq.used.idx.get(), 2);
+        assert_eq!(peer.recv(&mut buf).unwrap(), FRAME_LEN);
+    }
+
+    #[test]
+    fn test_mergeable_small_buffers() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let rx_vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let mut rx_queue = rx_vq.create_queue();
+
+        let (tap_end, peer) = UnixDatagram::pair().unwrap();
+        tap_end.set_nonblocking(true).unwrap();
+        let tap = Tap::from_socket(unsafe { File::from_raw_fd(tap_end.into_raw_fd()) });
+
+        let mut net = NetQueuePair {
+            mem: Some(GuestMemoryAtomic::new(mem.clone())),
+            tap,
+            rx: RxVirtio::new(),
+            tx: TxVirtio::new(),
+            epoll_fd: None,
+            rx_tap_listening: true,
+            counters: NetCounters::default(),
+            tap_event_id: 0,
+            rx_filter: None,
+            rss: None,
+            link_status: None,
+        };
+        net.rx.mergeable = true;
+
+        // The virtio net header is split across the first buffers, the
+        // first one being shorter than num_buffers' offset.
+        mem.write_slice(&[0xff; 8], GuestAddress(0x8000)).unwrap();
+        rx_vq.dtable[0].set(0x8000, 4, VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT, 1);
+        rx_vq.dtable[1].set(0x9000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        rx_vq.avail.ring[0].set(0);
+        rx_vq.avail.idx.set(1);
+        peer.send(&[0u8; FRAME_LEN]).unwrap();
+        net.process_rx_tap(&mut rx_queue).unwrap();
+        assert_eq!(rx_vq.used.idx.get(), 1);
+        assert_eq!(rx_vq.used.ring[0].get().len, FRAME_LEN as u32);
+        let mut num_buffers = [0u8; 2];
+        mem.read_slice(
+            &mut num_buffers,
+            GuestAddress(0x9000 + VIRTIO_NET_HDR_NUM_BUFFERS_OFFSET as u64 - 4),
+        )
+        .unwrap();
+        assert_eq!(u16::from_le_bytes(num_buffers), 1);
+        let mut past_buffer = [0u8; 4];
+        mem.read_slice(&mut past_buffer, GuestAddress(0x8004))
+            .unwrap();
+        assert_eq!(past_buffer, [0xff; 4]);
+
+        // A chain the device can't write to doesn't receive the frame, which
+        // is dropped.
+        rx_vq.dtable[2].set(0xa000, 0x1000, 0, 0);
+        rx_vq.avail.ring[1].set(2);
+        rx_vq.avail.idx.set(2);
+        peer.send(&[0u8; FRAME_LEN]).unwrap();
+        net.process_rx_tap(&mut rx_queue).unwrap();
+        assert_eq!(rx_vq.used.idx.get(), 1);
+        assert_eq!(net.counters.rx_dropped.load(Ordering::Acquire), 1);
+        assert_eq!(net.counters.rx_frames.load(Ordering::Acquire), 1);
     }
 }
@@ -0,0 +1,448 @@
+// Copyright (c) 2020 Intel Corporation. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! Receive-side scaling and hash reporting, as configured by the driver
+//! through the control queue when VIRTIO_NET_F_RSS or
+//! VIRTIO_NET_F_HASH_REPORT has been negotiated.
+
+use libc::EFD_NONBLOCK;
+use packet::{
+    ETH_HLEN, ETH_P_IP, IPPROTO_TCP, IPPROTO_UDP, IPV4_HLEN, IP_FLAG_MF, IP_FRAG_OFFSET_MASK,
+};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use vmm_sys_util::eventfd::EventFd;
+
+pub const VIRTIO_NET_RSS_HASH_TYPE_IPV4: u32 = 1 << 0;
+pub const VIRTIO_NET_RSS_HASH_TYPE_TCPV4: u32 = 1 << 1;
+pub const VIRTIO_NET_RSS_HASH_TYPE_UDPV4: u32 = 1 << 2;
+pub const VIRTIO_NET_RSS_HASH_TYPE_IPV6: u32 = 1 << 3;
+pub const VIRTIO_NET_RSS_HASH_TYPE_TCPV6: u32 = 1 << 4;
+pub const VIRTIO_NET_RSS_HASH_TYPE_UDPV6: u32 = 1 << 5;
+
+pub const VIRTIO_NET_HASH_REPORT_NONE: u16 = 0;
+pub const VIRTIO_NET_HASH_REPORT_IPV4: u16 = 1;
+pub const VIRTIO_NET_HASH_REPORT_TCPV4: u16 = 2;
+pub const VIRTIO_NET_HASH_REPORT_UDPV4: u16 = 3;
+pub const VIRTIO_NET_HASH_REPORT_IPV6: u16 = 4;
+pub const VIRTIO_NET_HASH_REPORT_TCPV6: u16 = 5;
+pub const VIRTIO_NET_HASH_REPORT_UDPV6: u16 = 6;
+
+/// Hash types the device can compute. The IPv6 extension headers aren't
+/// parsed, hence the types relying on them aren't supported.
+pub const RSS_SUPPORTED_HASH_TYPES: u32 = VIRTIO_NET_RSS_HASH_TYPE_IPV4
+    | VIRTIO_NET_RSS_HASH_TYPE_TCPV4
+    | VIRTIO_NET_RSS_HASH_TYPE_UDPV4
+    | VIRTIO_NET_RSS_HASH_TYPE_IPV6
+    | VIRTIO_NET_RSS_HASH_TYPE_TCPV6
+    | VIRTIO_NET_RSS_HASH_TYPE_UDPV6;
+/// Largest hash key accepted from the driver.
+pub const RSS_MAX_KEY_SIZE: u8 = 40;
+/// Largest indirection table accepted from the driver.
+pub const RSS_MAX_INDIRECTION_TABLE_LEN: u16 = 128;
+
+/// Maximum number of frames waiting to be received on a queue pair they have
+/// been steered to. Beyond that, the frames are dropped.
+const MAX_STEERED_FRAMES: usize = 256;
+
+const ETH_P_8021Q: u16 = 0x8100;
+const ETH_P_IPV6: u16 = 0x86dd;
+const IPV6_HLEN: usize = 40;
+
+/// Toeplitz hash of the input, as defined by the Microsoft RSS
+/// specification. The key is padded with zeros if it is too short.
+pub fn toeplitz_hash(key: &[u8], input: &[u8]) -> u32 {
+    let key_byte = |i: usize| key.get(i).cloned().unwrap_or(0);
+
+    // 32 bits of the key, starting at the one matching the current bit of
+    // the input.
+    let mut window = (0..4).fold(0u32, |window, i| window << 8 | u32::from(key_byte(i)));
+    let mut hash = 0;
+    for (i, byte) in input.iter().enumerate() {
+        let next_key_byte = key_byte(i + 4);
+        for bit in (0..8).rev() {
+            if byte & (1 << bit) != 0 {
+                hash ^= window;
+            }
+            window = window << 1 | u32::from(next_key_byte >> bit & 1);
+        }
+    }
+
+    hash
+}
+
+/// Hash of a frame, along with its type as reported to the driver.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RssHash {
+    pub value: u32,
+    pub report: u16,
+}
+
+// Hash types and reports applying to an IP version.
+struct IpHashTypes {
+    ip: (u32, u16),
+    tcp: (u32, u16),
+    udp: (u32, u16),
+}
+
+const IPV4_HASH_TYPES: IpHashTypes = IpHashTypes {
+    ip: (VIRTIO_NET_RSS_HASH_TYPE_IPV4, VIRTIO_NET_HASH_REPORT_IPV4),
+    tcp: (VIRTIO_NET_RSS_HASH_TYPE_TCPV4, VIRTIO_NET_HASH_REPORT_TCPV4),
+    udp: (VIRTIO_NET_RSS_HASH_TYPE_UDPV4, VIRTIO_NET_HASH_REPORT_UDPV4),
+};
+
+const IPV6_HASH_TYPES: IpHashTypes = IpHashTypes {
+    ip: (VIRTIO_NET_RSS_HASH_TYPE_IPV6, VIRTIO_NET_HASH_REPORT_IPV6),
+    tcp: (VIRTIO_NET_RSS_HASH_TYPE_TCPV6, VIRTIO_NET_HASH_REPORT_TCPV6),
+    udp: (VIRTIO_NET_RSS_HASH_TYPE_UDPV6, VIRTIO_NET_HASH_REPORT_UDPV6),
+};
+
+/// Hash computation and steering of the received frames, programmed by the
+/// driver. Until then, no hash is computed and the frames are received on
+/// the queue pair of the tap queue they come from.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Rss {
+    steering: bool,
+    hash_types: u32,
+    key: Vec<u8>,
+    indirection_table: Vec<u16>,
+    unclassified_queue: u16,
+}
+
+impl Rss {
+    /// Steer the frames to the queue pairs picked from the indirection table
+    /// by their hash, the frames without hash going to `unclassified_queue`.
+    /// The table length must be a power of two.
+    pub fn set_rss(
+        &mut self,
+        hash_types: u32,
+        key: Vec<u8>,
+        indirection_table: Vec<u16>,
+        unclassified_queue: u16,
+    ) -> bool {
+        if !indirection_table.len().is_power_of_two()
+            || indirection_table.len() > usize::from(RSS_MAX_INDIRECTION_TABLE_LEN)
+            || key.len() > usize::from(RSS_MAX_KEY_SIZE)
+        {
+            return false;
+        }
+        self.steering = true;
+        self.hash_types = hash_types & RSS_SUPPORTED_HASH_TYPES;
+        self.key = key;
+        self.indirection_table = indirection_table;
+        self.unclassified_queue = unclassified_queue;
+        true
+    }
+
+    /// Only compute the hash of the frames, for it to be reported to the
+    /// driver, without steering them.
+    pub fn set_hash_config(&mut self, hash_types: u32, key: Vec<u8>) -> bool {
+        if key.len() > usize::from(RSS_MAX_KEY_SIZE) {
+            return false;
+        }
+        self.steering = false;
+        self.hash_types = hash_types & RSS_SUPPORTED_HASH_TYPES;
+        self.key = key;
+        self.indirection_table.clear();
+        self.unclassified_queue = 0;
+        true
+    }
+
+    /// Hash of the Ethernet frame according to the enabled hash types, if
+    /// any applies.
+    pub fn hash(&self, frame: &[u8]) -> Option<RssHash> {
+        if self.hash_types == 0 || frame.len() < ETH_HLEN {
+            return None;
+        }
+
+        // Look past a VLAN tag, if any.
+        let mut ethertype = u16::from_be_bytes([frame[12], frame[13]]);
+        let mut packet = &frame[ETH_HLEN..];
+        if ethertype == ETH_P_8021Q {
+            if packet.len() < 4 {
+                return None;
+            }
+            ethertype = u16::from_be_bytes([packet[2], packet[3]]);
+            packet = &packet[4..];
+        }
+
+        match ethertype {
+            ETH_P_IP => self.hash_ipv4(packet),
+            ETH_P_IPV6 => self.hash_ipv6(packet),
+            _ => None,
+        }
+    }
+
+    fn hash_ipv4(&self, packet: &[u8]) -> Option<RssHash> {
+        if packet.len() < IPV4_HLEN || packet[0] >> 4 != 4 {
+            return None;
+        }
+        let hlen = usize::from(packet[0] & 0xf) * 4;
+        // Only the first fragment holds the ports, which can't be relied on.
+        let frag = u16::from_be_bytes([packet[6], packet[7]]);
+        let ports = if frag & (IP_FLAG_MF | IP_FRAG_OFFSET_MASK) == 0 && hlen >= IPV4_HLEN {
+            packet.get(hlen..hlen + 4)
+        } else {
+            None
+        };
+
+        self.hash_ip(&packet[12..20], packet[9], ports, &IPV4_HASH_TYPES)
+    }
+
+    fn hash_ipv6(&self, packet: &[u8]) -> Option<RssHash> {
+        if packet.len() < IPV6_HLEN || packet[0] >> 4 != 6 {
+            return None;
+        }
+        let ports = packet.get(IPV6_HLEN..IPV6_HLEN + 4);
+
+        self.hash_ip(&packet[8..40], packet[6], ports, &IPV6_HASH_TYPES)
+    }
+
+    // Hash the addresses, followed by the ports if the hash type of the
+    // transport protocol is enabled.
+    fn hash_ip(
+        &self,
+        addrs: &[u8],
+        protocol: u8,
+        ports: Option<&[u8]>,
+        types: &IpHashTypes,
+    ) -> Option<RssHash> {
+        let transport = match protocol {
+            IPPROTO_TCP => Some(types.tcp),
+            IPPROTO_UDP => Some(types.udp),
+            _ => None,
+        };
+        if let (Some((hash_type, report)), Some(ports)) = (transport, ports) {
+            if self.hash_types & hash_type != 0 {
+                let mut input = addrs.to_vec();
+                input.extend_from_slice(ports);
+                return Some(RssHash {
+                    value: toeplitz_hash(&self.key, &input),
+                    report,
+                });
+            }
+        }
+
+        let (hash_type, report) = types.ip;
+        if self.hash_types & hash_type != 0 {
+            return Some(RssHash {
+                value: toeplitz_hash(&self.key, addrs),
+                report,
+            });
+        }
+
+        None
+    }
+
+    /// Queue pair the frame with the given hash must be received on, if
+    /// the frames are steered.
+    pub fn queue_pair(&self, hash: Option<RssHash>) -> Option<u16> {
+        if !self.steering {
+            return None;
+        }
+
+        match hash {
+            Some(hash) if !self.indirection_table.is_empty() => {
+                let mask = self.indirection_table.len() - 1;
+                Some(self.indirection_table[hash.value as usize & mask])
+            }
+            _ => Some(self.unclassified_queue),
+        }
+    }
+}
+
+/// Frames steered to a queue pair by the other ones, waiting to be received
+/// by the guest. The queue pair is notified through the eventfd.
+pub struct SteeredFrames {
+    frames: Mutex<VecDeque<Vec<u8>>>,
+    evt: EventFd,
+}
+
+impl SteeredFrames {
+    pub fn new() -> io::Result<Self> {
+        Ok(SteeredFrames {
+            frames: Mutex::new(VecDeque::new()),
+            evt: EventFd::new(EFD_NONBLOCK)?,
+        })
+    }
+
+    /// Queue the frame, vnet header included, and notify the queue pair.
+    /// Returns false if the frame was dropped, too many frames waiting to
+    /// be received by the queue pair.
+    pub fn push(&self, frame: &[u8]) -> bool {
+        let mut frames = self.frames.lock().unwrap();
+        if frames.len() >= MAX_STEERED_FRAMES {
+            return false;
+        }
+        frames.push_back(frame.to_vec());
+        if let Err(e) = self.evt.write(1) {
+            error!("Failed to notify the queue pair of a steered frame: {}", e);
+        }
+        true
+    }
+
+    pub fn pop(&self) -> Option<Vec<u8>> {
+        self.frames.lock().unwrap().pop_front()
+    }
+
+    pub fn evt(&self) -> &EventFd {
+        &self.evt
+    }
+}
+
+/// What a queue pair needs to steer the frames it reads from its tap queue
+/// to the other queue pairs of the device.
+#[derive(Clone)]
+pub struct RssSteering {
+    pub rss: Arc<RwLock<Rss>>,
+    /// Index of the queue pair in the device.
+    pub index: usize,
+    /// Frames steered to each queue pair of the device.
+    pub steered_frames: Vec<Arc<SteeredFrames>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Key and results from the verification suite of the Microsoft RSS
+    // specification.
+    const KEY: [u8; 40] = [
+        0x6d, 0x5a, 0x56, 0xda, 0x25, 0x5b, 0x0e, 0xc2, 0x41, 0x67, 0x25, 0x3d, 0x43, 0xa3, 0x8f,
+        0xb0, 0xd0, 0xca, 0x2b, 0xcb, 0xae, 0x7b, 0x30, 0xb4, 0x77, 0xcb, 0x2d, 0xa3, 0x80, 0x30,
+        0xf2, 0x0c, 0x6a, 0x42, 0xb7, 0x3b, 0xbe, 0xac, 0x01, 0xfa,
+    ];
+
+    fn ipv4_frame(src: [u8; 4], dst: [u8; 4], protocol: u8, sport: u16, dport: u16) -> Vec<u8> {
+        let mut frame = vec![0u8; 12];
+        frame.extend_from_slice(&ETH_P_IP.to_be_bytes());
+        frame.extend_from_slice(&[0x45, 0, 0, 40, 0, 0, 0, 0, 64, protocol, 0, 0]);
+        frame.extend_from_slice(&src);
+        frame.extend_from_slice(&dst);
+        frame.extend_from_slice(&sport.to_be_bytes());
+        frame.extend_from_slice(&dport.to_be_bytes());
+        frame.extend_from_slice(&[0u8; 16]);
+        frame
+    }
+
+    #[test]
+    fn test_toeplitz_hash() {
+        let input = [66, 9, 149, 187, 161, 142, 100, 80];
+        assert_eq!(toeplitz_hash(&KEY, &input), 0x323e_8fc2);
+        let input = [66, 9, 149, 187, 161, 142, 100, 80, 0x0a, 0xea, 0x06, 0xe6];
+        assert_eq!(toeplitz_hash(&KEY, &input), 0x51cc_c178);
+        let input = [199, 92, 111, 2, 65, 69, 140, 83];
+        assert_eq!(toeplitz_hash(&KEY, &input), 0xd718_262a);
+        let input = [199, 92, 111, 2, 65, 69, 140, 83, 0x37, 0x96, 0x12, 0x83];
+        assert_eq!(toeplitz_hash(&KEY, &input), 0xc626_b0ea);
+    }
+
+    #[test]
+    fn test_rss_hash() {
+        let tcp = ipv4_frame(
+            [66, 9, 149, 187],
+            [161, 142, 100, 80],
+            IPPROTO_TCP,
+            2794,
+            1766,
+        );
+        let mut rss = Rss::default();
+        assert_eq!(rss.hash(&tcp), None);
+        assert_eq!(rss.queue_pair(None), None);
+
+        assert!(rss.set_hash_config(
+            VIRTIO_NET_RSS_HASH_TYPE_IPV4 | VIRTIO_NET_RSS_HASH_TYPE_TCPV4,
+            KEY.to_vec()
+        ));
+        let hash = rss.hash(&tcp);
+        assert_eq!(
+            hash,
+            Some(RssHash {
+                value: 0x51cc_c178,
+                report: VIRTIO_NET_HASH_REPORT_TCPV4
+            })
+        );
+        assert_eq!(rss.queue_pair(hash), None);
+
+        // UDP falls back to the hash of the addresses.
+        let udp = ipv4_frame(
+            [66, 9, 149, 187],
+            [161, 142, 100, 80],
+            IPPROTO_UDP,
+            2794,
+            1766,
+        );
+        assert_eq!(
+            rss.hash(&udp),
+            Some(RssHash {
+                value: 0x323e_8fc2,
+                report: VIRTIO_NET_HASH_REPORT_IPV4
+            })
+        );
+        assert_eq!(rss.hash(&[0u8; 64]), None);
+
+        // IPv6 addresses and TCP ports.
+        let mut tcp6 = vec![0u8; 12];
+        tcp6.extend_from_slice(&ETH_P_IPV6.to_be_bytes());
+        tcp6.extend_from_slice(&[0x60, 0, 0, 0, 0, 20, IPPROTO_TCP, 64]);
+        tcp6.extend_from_slice(&[
+            0x3f, 0xfe, 0x25, 0x01, 0x02, 0x00, 0x1f, 0xff, 0, 0, 0, 0, 0, 0, 0, 0x07,
+        ]);
+        tcp6.extend_from_slice(&[
+            0x3f, 0xfe, 0x25, 0x01, 0x02, 0x00, 0x00, 0x03, 0, 0, 0, 0, 0, 0, 0, 0x01,
+        ]);
+        tcp6.extend_from_slice(&2794u16.to_be_bytes());
+        tcp6.extend_from_slice(&1766u16.to_be_bytes());
+        tcp6.extend_from_slice(&[0u8; 16]);
+        assert_eq!(rss.hash(&tcp6), None);
+        assert!(rss.set_hash_config(
+            VIRTIO_NET_RSS_HASH_TYPE_IPV6 | VIRTIO_NET_RSS_HASH_TYPE_TCPV6,
+            KEY.to_vec()
+        ));
+        assert_eq!(
+            rss.hash(&tcp6),
+            Some(RssHash {
+                value: 0x4020_7d3d,
+                report: VIRTIO_NET_HASH_REPORT_TCPV6
+            })
+        );
+        assert!(rss.set_hash_config(VIRTIO_NET_RSS_HASH_TYPE_IPV6, KEY.to_vec()));
+        assert_eq!(
+            rss.hash(&tcp6),
+            Some(RssHash {
+                value: 0x2cc1_8cd5,
+                report: VIRTIO_NET_HASH_REPORT_IPV6
+            })
+        );
+
+        assert!(rss.set_rss(
+            VIRTIO_NET_RSS_HASH_TYPE_TCPV4,
+            KEY.to_vec(),
+            vec![0, 1, 2, 3],
+            3
+        ));
+        // 0x51ccc178 & 3 is 0.
+        assert_eq!(rss.queue_pair(rss.hash(&tcp)), Some(0));
+        assert_eq!(rss.queue_pair(rss.hash(&udp)), Some(3));
+        assert!(!rss.set_rss(0, KEY.to_vec(), vec![0, 1, 2], 0));
+    }
+
+    #[test]
+    fn test_steered_frames() {
+        let steered_frames = SteeredFrames::new().unwrap();
+        for i in 0..MAX_STEERED_FRAMES {
+            assert!(steered_frames.push(&[i as u8]));
+        }
+        assert!(!steered_frames.push(&[0xff]));
+        assert_eq!(
+            steered_frames.evt().read().unwrap(),
+            MAX_STEERED_FRAMES as u64
+        );
+
+        assert_eq!(steered_frames.pop(), Some(vec![0]));
+        assert!(steered_frames.push(&[0xff]));
+        assert_eq!(steered_frames.pop(), Some(vec![1]));
+    }
+}
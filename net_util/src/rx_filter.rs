@@ -0,0 +1,230 @@
+// Copyright (c) 2020 Intel Corporation. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use mac::{MacAddr, MAC_ADDR_LEN};
+use std::collections::BTreeSet;
+
+/// Maximum number of entries of each MAC filter table. Beyond that, the
+/// table overflows and all the addresses of its kind are accepted.
+pub const MAC_TABLE_ENTRIES: usize = 64;
+/// Highest VLAN ID which can be filtered.
+pub const MAX_VLAN_ID: u16 = 4095;
+
+const ETH_HLEN: usize = 14;
+const ETH_P_8021Q: u16 = 0x8100;
+const VLAN_VID_MASK: u16 = 0x0fff;
+const BROADCAST_MAC: [u8; MAC_ADDR_LEN] = [0xff; MAC_ADDR_LEN];
+
+/// Receive filter programmed by the driver through the control queue,
+/// deciding which frames from the tap are handed over to the guest.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RxFilter {
+    pub promisc: bool,
+    pub allmulti: bool,
+    pub alluni: bool,
+    pub nomulti: bool,
+    pub nouni: bool,
+    pub nobcast: bool,
+    mac: Option<MacAddr>,
+    unicast: Vec<MacAddr>,
+    multicast: Vec<MacAddr>,
+    unicast_overflow: bool,
+    multicast_overflow: bool,
+    vlan_filtering: bool,
+    vlans: BTreeSet<u16>,
+}
+
+impl Default for RxFilter {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl RxFilter {
+    /// Create a filter accepting all frames, as the device must until the
+    /// driver configures it.
+    pub fn new(mac: Option<MacAddr>) -> Self {
+        RxFilter {
+            promisc: true,
+            allmulti: false,
+            alluni: false,
+            nomulti: false,
+            nouni: false,
+            nobcast: false,
+            mac,
+            unicast: Vec::new(),
+            multicast: Vec::new(),
+            unicast_overflow: false,
+            multicast_overflow: false,
+            vlan_filtering: false,
+            vlans: BTreeSet::new(),
+        }
+    }
+
+    pub fn mac(&self) -> Option<MacAddr> {
+        self.mac
+    }
+
+    pub fn set_mac(&mut self, mac: MacAddr) {
+        self.mac = Some(mac);
+    }
+
+    /// Program the MAC filter tables, `None` standing for a table the driver
+    /// sent with more than `MAC_TABLE_ENTRIES` entries.
+    pub fn set_mac_tables(
+        &mut self,
+        unicast: Option<Vec<MacAddr>>,
+        multicast: Option<Vec<MacAddr>>,
+    ) {
+        let (unicast, unicast_overflow) = Self::mac_table(unicast);
+        self.unicast = unicast;
+        self.unicast_overflow = unicast_overflow;
+        let (multicast, multicast_overflow) = Self::mac_table(multicast);
+        self.multicast = multicast;
+        self.multicast_overflow = multicast_overflow;
+    }
+
+    fn mac_table(table: Option<Vec<MacAddr>>) -> (Vec<MacAddr>, bool) {
+        match table {
+            Some(table) if table.len() <= MAC_TABLE_ENTRIES => (table, false),
+            _ => (Vec::new(), true),
+        }
+    }
+
+    /// Only let the tagged frames from the VLANs added by the driver through,
+    /// which the device does when VIRTIO_NET_F_CTRL_VLAN has been negotiated.
+    pub fn set_vlan_filtering(&mut self, vlan_filtering: bool) {
+        self.vlan_filtering = vlan_filtering;
+    }
+
+    pub fn add_vlan(&mut self, vid: u16) -> bool {
+        if vid > MAX_VLAN_ID {
+            return false;
+        }
+        self.vlans.insert(vid);
+        true
+    }
+
+    pub fn del_vlan(&mut self, vid: u16) -> bool {
+        if vid > MAX_VLAN_ID {
+            return false;
+        }
+        self.vlans.remove(&vid);
+        true
+    }
+
+    /// Check if the Ethernet frame can be received by the guest.
+    pub fn accepts(&self, frame: &[u8]) -> bool {
+        if self.promisc || frame.len() < ETH_HLEN {
+            return true;
+        }
+
+        if self.vlan_filtering && u16::from_be_bytes([frame[12], frame[13]]) == ETH_P_8021Q {
+            if frame.len() < ETH_HLEN + 2 {
+                return false;
+            }
+            let vid = u16::from_be_bytes([frame[14], frame[15]]) & VLAN_VID_MASK;
+            if !self.vlans.contains(&vid) {
+                return false;
+            }
+        }
+
+        let dest = &frame[..MAC_ADDR_LEN];
+        if dest[0] & 1 != 0 {
+            if dest == BROADCAST_MAC {
+                !self.nobcast
+            } else if self.nomulti {
+                false
+            } else if self.allmulti || self.multicast_overflow {
+                true
+            } else {
+                self.multicast.iter().any(|mac| mac.get_bytes() == dest)
+            }
+        } else if self.nouni {
+            false
+        } else if self.alluni || self.unicast_overflow {
+            true
+        } else {
+            self.mac.map_or(false, |mac| mac.get_bytes() == dest)
+                || self.unicast.iter().any(|mac| mac.get_bytes() == dest)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(dest: &str, vid: Option<u16>) -> Vec<u8> {
+        let mut frame = MacAddr::parse_str(dest).unwrap().get_bytes().to_vec();
+        frame.extend_from_slice(&[0x2e, 0, 0, 0, 0, 1]);
+        if let Some(vid) = vid {
+            frame.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+            frame.extend_from_slice(&vid.to_be_bytes());
+        }
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&[0u8; 46]);
+        frame
+    }
+
+    #[test]
+    fn test_rx_filter_mac() {
+        let mac = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let mut filter = RxFilter::new(Some(mac));
+        assert!(filter.accepts(&frame("52:54:00:00:00:01", None)));
+
+        filter.promisc = false;
+        assert!(filter.accepts(&frame("12:34:56:78:9a:bc", None)));
+        assert!(filter.accepts(&frame("ff:ff:ff:ff:ff:ff", None)));
+        assert!(!filter.accepts(&frame("52:54:00:00:00:01", None)));
+        assert!(!filter.accepts(&frame("01:00:5e:00:00:01", None)));
+
+        filter.set_mac_tables(
+            Some(vec![MacAddr::parse_str("52:54:00:00:00:01").unwrap()]),
+            Some(vec![MacAddr::parse_str("01:00:5e:00:00:01").unwrap()]),
+        );
+        assert!(filter.accepts(&frame("52:54:00:00:00:01", None)));
+        assert!(filter.accepts(&frame("01:00:5e:00:00:01", None)));
+        assert!(!filter.accepts(&frame("01:00:5e:00:00:02", None)));
+
+        filter.allmulti = true;
+        assert!(filter.accepts(&frame("01:00:5e:00:00:02", None)));
+        filter.nomulti = true;
+        assert!(!filter.accepts(&frame("01:00:5e:00:00:01", None)));
+        filter.nobcast = true;
+        assert!(!filter.accepts(&frame("ff:ff:ff:ff:ff:ff", None)));
+        filter.nouni = true;
+        assert!(!filter.accepts(&frame("12:34:56:78:9a:bc", None)));
+
+        let mut filter = RxFilter::new(Some(mac));
+        filter.promisc = false;
+        filter.set_mac_tables(Some(vec![mac; MAC_TABLE_ENTRIES + 1]), Some(Vec::new()));
+        assert!(filter.accepts(&frame("52:54:00:00:00:01", None)));
+        assert!(!filter.accepts(&frame("01:00:5e:00:00:01", None)));
+
+        filter.set_mac_tables(Some(Vec::new()), None);
+        assert!(!filter.accepts(&frame("52:54:00:00:00:01", None)));
+        assert!(filter.accepts(&frame("01:00:5e:00:00:01", None)));
+    }
+
+    #[test]
+    fn test_rx_filter_vlan() {
+        let mac = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let mut filter = RxFilter::new(Some(mac));
+        filter.promisc = false;
+        assert!(filter.accepts(&frame("12:34:56:78:9a:bc", Some(100))));
+
+        filter.set_vlan_filtering(true);
+        assert!(filter.accepts(&frame("12:34:56:78:9a:bc", None)));
+        assert!(!filter.accepts(&frame("12:34:56:78:9a:bc", Some(100))));
+
+        assert!(filter.add_vlan(100));
+        assert!(filter.accepts(&frame("12:34:56:78:9a:bc", Some(100))));
+        assert!(!filter.accepts(&frame("12:34:56:78:9a:bc", Some(200))));
+
+        assert!(filter.del_vlan(100));
+        assert!(!filter.accepts(&frame("12:34:56:78:9a:bc", Some(100))));
+        assert!(!filter.add_vlan(MAX_VLAN_ID + 1));
+    }
+}
@@ -5,14 +5,16 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
-use super::{create_sockaddr, create_socket, Error as NetUtilError, MacAddr};
+use super::{create_inet6_socket, create_sockaddr, create_socket, Error as NetUtilError, MacAddr};
 use mac::MAC_ADDR_LEN;
 use net_gen;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::{Error as IoError, Read, Result as IoResult, Write};
 use std::net;
 use std::os::raw::*;
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
 use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val};
 
 #[derive(Debug)]
@@ -32,10 +34,25 @@ pub enum Error {
     InvalidIfname,
     /// Error parsing MAC data
     MacParsing(()),
+    /// Unable to set the tap file descriptor as non-blocking.
+    SetNonBlocking(IoError),
+    /// Unable to retrieve the interface of a tap file descriptor.
+    GetIfreq(IoError),
+    /// The tap file descriptor doesn't match the expected configuration.
+    InvalidTapFlags,
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
+// Request of the ioctls managing the IPv6 addresses of an interface, the
+// counterpart of the kernel struct in6_ifreq.
+#[repr(C)]
+struct In6Ifreq {
+    ifr6_addr: [u8; 16],
+    ifr6_prefixlen: u32,
+    ifr6_ifindex: c_int,
+}
+
 /// Handle for a network tap interface.
 ///
 /// For now, this simply wraps the file descriptor for the tap device so methods
@@ -153,6 +170,96 @@ impl Tap {
         Self::open_named("vmtap%d", num_queue_pairs)
     }
 
+    /// Wrap a file descriptor of a tap interface opened by another process,
+    /// which must have been configured with a virtio net header, and with
+    /// multiqueue support if more than one queue pair is used.
+    pub fn from_tap_fd(fd: RawFd, num_queue_pairs: usize) -> Result<Tap> {
+        // The descriptor might have been inherited in blocking mode. This is
+        // safe since we check the return value.
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(Error::SetNonBlocking(IoError::last_os_error()));
+        }
+
+        // We own the descriptor from now on.
+        let tap_file = unsafe { File::from_raw_fd(fd) };
+
+        // ioctl is safe since we call it with a valid tap fd and check the return
+        // value.
+        let mut ifreq: net_gen::ifreq = Default::default();
+        let ret = unsafe { ioctl_with_mut_ref(&tap_file, net_gen::TUNGETIFF(), &mut ifreq) };
+        if ret < 0 {
+            return Err(Error::GetIfreq(IoError::last_os_error()));
+        }
+
+        // We only access one field of each union, hence this is safe.
+        let (if_name, flags) = unsafe {
+            let ifrn_name = ifreq.ifr_ifrn.ifrn_name.as_ref();
+            let len = ifrn_name.iter().position(|c| *c == 0).unwrap_or(0);
+            let if_name: Vec<u8> = ifrn_name[..len].iter().map(|c| *c as u8).collect();
+            (
+                if_name,
+                *ifreq.ifr_ifru.ifru_flags.as_ref() as u16 as c_uint,
+            )
+        };
+
+        let required_flags = net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR;
+        if flags & required_flags != required_flags
+            || (num_queue_pairs > 1 && flags & net_gen::IFF_MULTI_QUEUE == 0)
+        {
+            return Err(Error::InvalidTapFlags);
+        }
+
+        Ok(Tap { tap_file, if_name })
+    }
+
+    /// Open a queue of a macvtap interface through its character device.
+    /// Each opening of the device creates a new queue.
+    pub fn open_macvtap(dev_path: &Path, if_name: &str, num_queue_pairs: usize) -> Result<Tap> {
+        let mut terminated_if_name = build_terminated_if_name(if_name)?;
+
+        let tap_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open(dev_path)
+            .map_err(Error::OpenTun)?;
+
+        // The name is ignored by macvtap, only the flags are updated.
+        let mut ifreq: net_gen::ifreq = Default::default();
+        unsafe {
+            let ifru_flags = ifreq.ifr_ifru.ifru_flags.as_mut();
+            *ifru_flags =
+                (net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR) as c_short;
+            if num_queue_pairs > 1 {
+                *ifru_flags |= net_gen::IFF_MULTI_QUEUE as c_short;
+            }
+        }
+
+        // ioctl is safe since we call it with a valid tap fd and check the return
+        // value.
+        let ret = unsafe { ioctl_with_mut_ref(&tap_file, net_gen::TUNSETIFF(), &mut ifreq) };
+        if ret < 0 {
+            return Err(Error::ConfigureTap(IoError::last_os_error()));
+        }
+
+        terminated_if_name.pop();
+        Ok(Tap {
+            tap_file,
+            if_name: terminated_if_name,
+        })
+    }
+
+    /// Wrap a socket exchanging the same frames as a tap, vnet header
+    /// included. No interface is behind it, so the interface ioctls can't be
+    /// used.
+    pub fn from_socket(socket: File) -> Tap {
+        Tap {
+            tap_file: socket,
+            if_name: Vec::new(),
+        }
+    }
+
     /// Set the host-side IP address for the tap interface.
     pub fn set_ip_addr(&self, ip_addr: net::Ipv4Addr) -> Result<()> {
         let sock = create_socket().map_err(Error::NetUtil)?;
@@ -177,6 +284,40 @@ impl Tap {
         Ok(())
     }
 
+    /// Add an IPv6 address to the tap interface, `prefix_len` being the
+    /// length of the prefix of the subnet it belongs to.
+    pub fn set_ipv6_addr(&self, ip_addr: net::Ipv6Addr, prefix_len: u8) -> Result<()> {
+        let sock = create_inet6_socket().map_err(Error::NetUtil)?;
+
+        let mut ifreq = self.get_ifreq();
+
+        // ioctl is safe. Called with a valid sock fd, and we check the return.
+        #[allow(clippy::cast_lossless)]
+        let ret = unsafe {
+            ioctl_with_mut_ref(&sock, net_gen::sockios::SIOCGIFINDEX as c_ulong, &mut ifreq)
+        };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        let in6_ifreq = In6Ifreq {
+            ifr6_addr: ip_addr.octets(),
+            ifr6_prefixlen: u32::from(prefix_len),
+            // We only access one field of the ifru union, hence this is safe.
+            ifr6_ifindex: unsafe { *ifreq.ifr_ifru.ifru_ivalue.as_ref() },
+        };
+
+        // ioctl is safe. Called with a valid sock fd, and we check the return.
+        #[allow(clippy::cast_lossless)]
+        let ret =
+            unsafe { ioctl_with_ref(&sock, net_gen::sockios::SIOCSIFADDR as c_ulong, &in6_ifreq) };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        Ok(())
+    }
+
     /// Set mac addr for tap interface.
     pub fn set_mac_addr(&self, addr: MacAddr) -> Result<()> {
         // Checking if the mac address already matches the desired one
@@ -267,6 +408,28 @@ impl Tap {
         Ok(())
     }
 
+    /// Set the MTU of the tap interface.
+    pub fn set_mtu(&self, mtu: c_int) -> Result<()> {
+        let sock = create_socket().map_err(Error::NetUtil)?;
+
+        let mut ifreq = self.get_ifreq();
+
+        // We only access one field of the ifru union, hence this is safe.
+        unsafe {
+            let ifru_mtu = ifreq.ifr_ifru.ifru_mtu.as_mut();
+            *ifru_mtu = mtu;
+        }
+
+        // ioctl is safe. Called with a valid sock fd, and we check the return.
+        #[allow(clippy::cast_lossless)]
+        let ret = unsafe { ioctl_with_ref(&sock, net_gen::sockios::SIOCSIFMTU as c_ulong, &ifreq) };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        Ok(())
+    }
+
     /// Set the offload flags for the tap interface.
     pub fn set_offload(&self, flags: c_uint) -> Result<()> {
         // ioctl is safe. Called with a valid tap fd, and we check the return.
@@ -349,6 +512,10 @@ impl Tap {
     pub fn get_if_name(&self) -> Vec<u8> {
         self.if_name.clone()
     }
+
+    pub fn get_file(&self) -> &File {
+        &self.tap_file
+    }
 }
 
 impl Read for Tap {
@@ -557,12 +724,42 @@ mod tests {
         assert!(ret.is_ok());
     }
 
+    #[test]
+    fn test_tap_configure_ipv6() {
+        let tap = Tap::new(1).unwrap();
+        let ip_addr: net::Ipv6Addr = "fd00:ce:1::1".parse().unwrap();
+
+        let ret = tap.set_ipv6_addr(ip_addr, 64);
+        assert!(ret.is_ok());
+    }
+
+    #[test]
+    fn test_tap_from_fd() {
+        let tap = Tap::new(1).unwrap();
+        // This is safe since we check the return value.
+        let fd = unsafe { libc::dup(tap.as_raw_fd()) };
+        assert!(fd >= 0);
+
+        // The tap doesn't support multiqueue.
+        assert!(Tap::from_tap_fd(fd, 2).is_err());
+
+        // This is safe since we check the return value.
+        let fd = unsafe { libc::dup(tap.as_raw_fd()) };
+        assert!(fd >= 0);
+        let tap_from_fd = Tap::from_tap_fd(fd, 1).unwrap();
+        assert_eq!(
+            tap_from_fd.get_if_name(),
+            tap_name_to_string(&tap).into_bytes()
+        );
+    }
+
     #[test]
     fn test_set_options() {
         // This line will fail to provide an initialized FD if the test is not run as root.
         let tap = Tap::new(1).unwrap();
         tap.set_vnet_hdr_size(16).unwrap();
         tap.set_offload(0).unwrap();
+        tap.set_mtu(9000).unwrap();
     }
 
     #[test]
@@ -0,0 +1,1060 @@
+// Copyright (c) 2020 Intel Corporation. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+//! User-mode network stack, giving a guest access to the network without
+//! any privilege on the host. It owns a virtual network on which it acts as
+//! the gateway, DHCP and DNS server, and translates the TCP connections and
+//! UDP datagrams of the guest into host sockets.
+
+mod tcp;
+
+use self::tcp::{ConnStatus, TcpConn};
+use super::{vnet_hdr_len, MacAddr, Tap};
+use dhcp::{DhcpServer, DEFAULT_LEASE_TIME, DHCP_SERVER_PORT};
+use libc;
+use packet::{
+    build_arp_reply, build_icmp_echo_reply, build_ipv4_frame, build_tcp_frame, build_udp_frame,
+    parse_arp_request, parse_ipv4, parse_tcp, parse_udp, source_mac, TcpHeader, IPPROTO_ICMP,
+    IPPROTO_TCP, IPPROTO_UDP, TCP_ACK, TCP_RST, TCP_SYN,
+};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use vmm_sys_util::eventfd::EventFd;
+
+/// MAC address of the gateway of the virtual network.
+const GATEWAY_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+const DNS_PORT: u16 = 53;
+/// Ports allocated on the gateway for the connections and datagrams
+/// forwarded from the host.
+const FWD_PORT_FIRST: u16 = 49152;
+/// Frames waiting for the guest to read them, beyond which no more data is
+/// read from the host.
+const MAX_GUEST_QUEUE: usize = 256;
+/// TCP connections, and UDP flows of the guest or of clients forwarded from
+/// the host, beyond which new ones are refused, so that a guest can't
+/// exhaust the sockets of the host.
+const MAX_TCP_CONNS: usize = 1024;
+const MAX_UDP_FLOWS: usize = 256;
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const TICK: Duration = Duration::from_millis(200);
+const EPOLL_EVENTS_LEN: usize = 64;
+const MAX_FRAME_LEN: usize = 65562;
+
+const GUEST_TOKEN: u64 = 0;
+const KILL_TOKEN: u64 = 1;
+const FIRST_TOKEN: u64 = 2;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The network doesn't leave room for the guest address.
+    InvalidNetwork,
+    /// Failed to create the socket pair connected to the device.
+    CreateSocketPair(io::Error),
+    /// Failed to bind the host socket of a forwarding rule.
+    BindHostFwd(HostFwd, io::Error),
+    /// Failed to create the epoll file descriptor.
+    EpollCreate(io::Error),
+    /// Failed to register or unregister a file descriptor with epoll.
+    EpollCtl(io::Error),
+    /// Failed to wait for events.
+    EpollWait(io::Error),
+    /// Failed to read a frame from the device.
+    ReadGuest(io::Error),
+    /// Failed to write a frame to the device.
+    WriteGuest(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostFwdProtocol {
+    Tcp,
+    Udp,
+}
+
+/// Rule forwarding the connections or datagrams received on a host port to
+/// a port of the guest.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HostFwd {
+    pub protocol: HostFwdProtocol,
+    pub host_addr: Ipv4Addr,
+    pub host_port: u16,
+    pub guest_port: u16,
+}
+
+#[derive(Debug)]
+pub enum HostFwdParseError {
+    InvalidValue(String),
+}
+
+impl FromStr for HostFwd {
+    type Err = HostFwdParseError;
+
+    /// Parse a rule formatted as `<tcp|udp>/[<host_addr>/]<host_port>-<guest_port>`,
+    /// the host address defaulting to the loopback one.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || HostFwdParseError::InvalidValue(s.to_owned());
+        let items: Vec<&str> = s.trim().split('/').collect();
+        if items.len() < 2 || items.len() > 3 {
+            return Err(invalid());
+        }
+
+        let protocol = match items[0] {
+            "tcp" => HostFwdProtocol::Tcp,
+            "udp" => HostFwdProtocol::Udp,
+            _ => return Err(invalid()),
+        };
+        let host_addr = if items.len() == 3 {
+            items[1].parse().map_err(|_| invalid())?
+        } else {
+            Ipv4Addr::LOCALHOST
+        };
+        let ports: Vec<&str> = items[items.len() - 1].split('-').collect();
+        if ports.len() != 2 {
+            return Err(invalid());
+        }
+        let host_port = ports[0].parse().map_err(|_| invalid())?;
+        let guest_port = ports[1].parse().map_err(|_| invalid())?;
+
+        Ok(HostFwd {
+            protocol,
+            host_addr,
+            host_port,
+            guest_port,
+        })
+    }
+}
+
+impl fmt::Display for HostFwd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let protocol = match self.protocol {
+            HostFwdProtocol::Tcp => "tcp",
+            HostFwdProtocol::Udp => "udp",
+        };
+        write!(
+            f,
+            "{}/{}/{}-{}",
+            protocol, self.host_addr, self.host_port, self.guest_port
+        )
+    }
+}
+
+pub struct UserNetConfig {
+    /// Address of the gateway, the guest being given the following one.
+    pub ip: Ipv4Addr,
+    pub mask: Ipv4Addr,
+    pub guest_mac: MacAddr,
+    pub hostfwd: Vec<HostFwd>,
+}
+
+/// Addresses of the virtual link between the guest and the gateway, needed
+/// to build the frames sent to the guest.
+struct Link {
+    guest_mac: MacAddr,
+    gateway_mac: MacAddr,
+    guest_ip: Ipv4Addr,
+}
+
+impl Link {
+    fn tcp_frame(&self, src: Ipv4Addr, header: &TcpHeader, payload: &[u8]) -> Vec<u8> {
+        build_tcp_frame(
+            self.guest_mac,
+            self.gateway_mac,
+            src,
+            self.guest_ip,
+            header,
+            payload,
+        )
+    }
+
+    fn udp_frame(&self, src: SocketAddrV4, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+        build_udp_frame(
+            self.guest_mac,
+            self.gateway_mac,
+            src,
+            SocketAddrV4::new(self.guest_ip, dst_port),
+            payload,
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct TcpKey {
+    guest_port: u16,
+    remote: SocketAddrV4,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+struct UdpKey {
+    guest_port: u16,
+    dns: bool,
+}
+
+struct UdpFlow {
+    socket: UdpSocket,
+    token: u64,
+    last_used: Instant,
+}
+
+struct UdpForward {
+    socket: UdpSocket,
+    guest_port: u16,
+}
+
+/// Host client of a UDP forwarding rule, which the guest replies to
+/// through a port of the gateway.
+struct UdpFwdClient {
+    forward: usize,
+    client: SocketAddrV4,
+    last_used: Instant,
+}
+
+/// What an epoll token refers to.
+#[derive(Clone, Copy)]
+enum Source {
+    Tcp(TcpKey),
+    Udp(UdpKey),
+    TcpListener(usize),
+    UdpForward(usize),
+}
+
+fn host_dns_server() -> Option<SocketAddrV4> {
+    let resolv = fs::read_to_string("/etc/resolv.conf").ok()?;
+    resolv
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            if words.next()? != "nameserver" {
+                return None;
+            }
+            words.next()?.parse::<Ipv4Addr>().ok()
+        })
+        .next()
+        .map(|ip| SocketAddrV4::new(ip, DNS_PORT))
+}
+
+fn connect_nonblocking(addr: SocketAddrV4) -> io::Result<TcpStream> {
+    // This is safe since we check the return value.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_INET,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // This is safe; nothing else will use or hold onto the raw fd.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    let sockaddr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: addr.port().to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(*addr.ip()).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    // This is safe since the fd and the address are valid, and we check
+    // the return value.
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(e);
+        }
+    }
+    Ok(stream)
+}
+
+fn epoll_ctl(
+    epoll_fd: RawFd,
+    op: epoll::ControlOptions,
+    fd: RawFd,
+    events: epoll::Events,
+    token: u64,
+) -> Result<()> {
+    epoll::ctl(epoll_fd, op, fd, epoll::Event::new(events, token)).map_err(Error::EpollCtl)
+}
+
+/// User-mode network stack, exchanging frames with the device through a
+/// socket pair, which is presented to the device as a tap.
+pub struct UserNet {
+    guest: File,
+    epoll_fd: RawFd,
+    link: Link,
+    gateway_ip: Ipv4Addr,
+    dhcp: DhcpServer,
+    dns_server: Option<SocketAddrV4>,
+    to_guest: VecDeque<Vec<u8>>,
+    guest_writable: bool,
+    congested: bool,
+    next_token: u64,
+    next_port: u16,
+    sources: HashMap<u64, Source>,
+    tcp_conns: HashMap<TcpKey, TcpConn>,
+    udp_flows: HashMap<UdpKey, UdpFlow>,
+    tcp_listeners: Vec<(TcpListener, u16)>,
+    udp_forwards: Vec<UdpForward>,
+    udp_fwd_clients: HashMap<u16, UdpFwdClient>,
+}
+
+impl UserNet {
+    /// Create the network stack, along with the tap the device must use to
+    /// exchange frames with it. The host ports of the forwarding rules are
+    /// bound right away.
+    pub fn new(config: UserNetConfig) -> Result<(UserNet, Tap)> {
+        let gateway = u32::from(config.ip);
+        let mask = u32::from(config.mask);
+        let guest = gateway.wrapping_add(1);
+        if guest & mask != gateway & mask || guest | mask == !0 {
+            return Err(Error::InvalidNetwork);
+        }
+        let guest_ip = Ipv4Addr::from(guest);
+
+        let mut fds = [0; 2];
+        // This is safe since we check the return value.
+        let ret = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        if ret < 0 {
+            return Err(Error::CreateSocketPair(io::Error::last_os_error()));
+        }
+        // This is safe; nothing else will use or hold onto the raw fds.
+        let (guest_end, device_end) =
+            unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        let mut tcp_listeners = Vec::new();
+        let mut udp_forwards = Vec::new();
+        for fwd in config.hostfwd.iter() {
+            let addr = SocketAddrV4::new(fwd.host_addr, fwd.host_port);
+            match fwd.protocol {
+                HostFwdProtocol::Tcp => {
+                    let listener = TcpListener::bind(addr)
+                        .and_then(|l| l.set_nonblocking(true).map(|_| l))
+                        .map_err(|e| Error::BindHostFwd(fwd.clone(), e))?;
+                    tcp_listeners.push((listener, fwd.guest_port));
+                }
+                HostFwdProtocol::Udp => {
+                    let socket = UdpSocket::bind(addr)
+                        .and_then(|s| s.set_nonblocking(true).map(|_| s))
+                        .map_err(|e| Error::BindHostFwd(fwd.clone(), e))?;
+                    udp_forwards.push(UdpForward {
+                        socket,
+                        guest_port: fwd.guest_port,
+                    });
+                }
+            }
+        }
+
+        let gateway_mac = MacAddr::from_bytes_unchecked(&GATEWAY_MAC);
+        let dns_server = host_dns_server();
+        let dhcp = DhcpServer {
+            server_ip: config.ip,
+            server_mac: gateway_mac,
+            client_ip: guest_ip,
+            mask: config.mask,
+            router: Some(config.ip),
+            dns: dns_server.map(|_| config.ip).into_iter().collect(),
+            hostname: None,
+            lease_time: DEFAULT_LEASE_TIME,
+        };
+
+        let user_net = UserNet {
+            guest: guest_end,
+            epoll_fd: -1,
+            link: Link {
+                guest_mac: config.guest_mac,
+                gateway_mac,
+                guest_ip,
+            },
+            gateway_ip: config.ip,
+            dhcp,
+            dns_server,
+            to_guest: VecDeque::new(),
+            guest_writable: false,
+            congested: false,
+            next_token: FIRST_TOKEN,
+            next_port: FWD_PORT_FIRST,
+            sources: HashMap::new(),
+            tcp_conns: HashMap::new(),
+            udp_flows: HashMap::new(),
+            tcp_listeners,
+            udp_forwards,
+            udp_fwd_clients: HashMap::new(),
+        };
+
+        Ok((user_net, Tap::from_socket(device_end)))
+    }
+
+    fn add_token(&mut self, source: Source) -> u64 {
+        let token = self.next_token;
+        self.next_token += 1;
+        self.sources.insert(token, source);
+        token
+    }
+
+    // Allocate a port of the gateway for a connection or a client forwarded
+    // from the host.
+    fn alloc_port(&mut self) -> u16 {
+        loop {
+            let port = self.next_port;
+            self.next_port = self.next_port.checked_add(1).unwrap_or(FWD_PORT_FIRST);
+            let remote = SocketAddrV4::new(self.gateway_ip, port);
+            if !self.udp_fwd_clients.contains_key(&port)
+                && !self.tcp_conns.keys().any(|k| k.remote == remote)
+            {
+                return port;
+            }
+        }
+    }
+
+    fn queue_frame(&mut self, frame: Vec<u8>) {
+        let mut buf = vec![0u8; vnet_hdr_len()];
+        buf.extend_from_slice(&frame);
+        self.to_guest.push_back(buf);
+    }
+
+    fn queue_frames(&mut self, frames: Vec<Vec<u8>>) {
+        for frame in frames {
+            self.queue_frame(frame);
+        }
+    }
+
+    fn flush_guest(&mut self) -> Result<()> {
+        while let Some(frame) = self.to_guest.front() {
+            match self.guest.write(frame) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::WriteGuest(e)),
+            }
+            self.to_guest.pop_front();
+        }
+
+        let writable = !self.to_guest.is_empty();
+        if writable != self.guest_writable {
+            let mut events = epoll::Events::EPOLLIN;
+            if writable {
+                events |= epoll::Events::EPOLLOUT;
+            }
+            epoll_ctl(
+                self.epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_MOD,
+                self.guest.as_raw_fd(),
+                events,
+                GUEST_TOKEN,
+            )?;
+            self.guest_writable = writable;
+        }
+
+        // Stop reading from the TCP connections while the guest doesn't
+        // keep up.
+        let congested = self.to_guest.len() >= MAX_GUEST_QUEUE;
+        if congested != self.congested {
+            self.congested = congested;
+            let keys: Vec<TcpKey> = self.tcp_conns.keys().cloned().collect();
+            for key in keys {
+                self.update_tcp_interest(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn update_tcp_interest(&mut self, key: TcpKey) -> Result<()> {
+        let epoll_fd = self.epoll_fd;
+        let can_read = !self.congested;
+        let conn = match self.tcp_conns.get_mut(&key) {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+        let events = conn.interest(can_read);
+        let fd = conn.stream.as_raw_fd();
+        match conn.registered {
+            Some(registered) if registered == events => {}
+            // Unregister the socket rather than waiting for no event, as a
+            // hang up would still be reported.
+            Some(_) if events.is_empty() => {
+                epoll_ctl(
+                    epoll_fd,
+                    epoll::ControlOptions::EPOLL_CTL_DEL,
+                    fd,
+                    events,
+                    conn.token,
+                )?;
+                conn.registered = None;
+            }
+            Some(_) => {
+                epoll_ctl(
+                    epoll_fd,
+                    epoll::ControlOptions::EPOLL_CTL_MOD,
+                    fd,
+                    events,
+                    conn.token,
+                )?;
+                conn.registered = Some(events);
+            }
+            None if events.is_empty() => {}
+            None => {
+                epoll_ctl(
+                    epoll_fd,
+                    epoll::ControlOptions::EPOLL_CTL_ADD,
+                    fd,
+                    events,
+                    conn.token,
+                )?;
+                conn.registered = Some(events);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_tcp_conn(&mut self, key: TcpKey) {
+        if let Some(conn) = self.tcp_conns.remove(&key) {
+            // Closing the socket removes it from the epoll set.
+            self.sources.remove(&conn.token);
+        }
+    }
+
+    fn tcp_status(&mut self, key: TcpKey, status: ConnStatus, frames: Vec<Vec<u8>>) -> Result<()> {
+        self.queue_frames(frames);
+        match status {
+            ConnStatus::Open => self.update_tcp_interest(key),
+            ConnStatus::Closed => {
+                self.remove_tcp_conn(key);
+                Ok(())
+            }
+        }
+    }
+
+    fn process_guest(&mut self) -> Result<bool> {
+        let mut buf = vec![0u8; MAX_FRAME_LEN];
+        let hdr_len = vnet_hdr_len();
+        loop {
+            let len = match self.guest.read(&mut buf) {
+                // The device has been removed.
+                Ok(0) => return Ok(false),
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::ReadGuest(e)),
+            };
+            if len > hdr_len {
+                self.process_frame(&buf[hdr_len..len])?;
+            }
+        }
+    }
+
+    fn process_frame(&mut self, frame: &[u8]) -> Result<()> {
+        if let Some(mac) = source_mac(frame) {
+            self.link.guest_mac = mac;
+        }
+
+        if let Some(request) = parse_arp_request(frame) {
+            if request.target_ip == self.gateway_ip {
+                let reply = build_arp_reply(&request, self.link.gateway_mac);
+                self.queue_frame(reply);
+            }
+            return Ok(());
+        }
+
+        if DhcpServer::is_dhcp_request(frame) {
+            if let Some(reply) = self.dhcp.process(frame) {
+                self.queue_frame(reply);
+            }
+            return Ok(());
+        }
+
+        let ip = match parse_ipv4(frame) {
+            Some(ip) => ip,
+            None => return Ok(()),
+        };
+        if ip.src != self.link.guest_ip {
+            return Ok(());
+        }
+
+        match ip.protocol {
+            IPPROTO_ICMP if ip.dst == self.gateway_ip => {
+                if let Some(reply) = build_icmp_echo_reply(ip.payload) {
+                    let frame = build_ipv4_frame(
+                        self.link.guest_mac,
+                        self.link.gateway_mac,
+                        ip.dst,
+                        ip.src,
+                        IPPROTO_ICMP,
+                        &reply,
+                    );
+                    self.queue_frame(frame);
+                }
+                Ok(())
+            }
+            IPPROTO_TCP => match parse_tcp(ip.payload) {
+                Some((header, payload)) => self.process_tcp(ip.dst, &header, payload),
+                None => Ok(()),
+            },
+            IPPROTO_UDP => match parse_udp(ip.payload) {
+                Some(udp) => {
+                    self.process_udp(ip.dst, udp.src_port, udp.dst_port, udp.payload);
+                    Ok(())
+                }
+                None => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    // Address of the host the guest reaches through the given destination,
+    // the gateway standing for the host itself.
+    fn host_addr(&self, dst: Ipv4Addr) -> Option<Ipv4Addr> {
+        if dst == self.gateway_ip {
+            Some(Ipv4Addr::LOCALHOST)
+        } else if dst.is_broadcast() || dst.is_multicast() || dst.is_unspecified() {
+            None
+        } else if u32::from(dst) & u32::from(self.dhcp.mask)
+            == u32::from(self.gateway_ip) & u32::from(self.dhcp.mask)
+        {
+            // Nothing else lives on the virtual network.
+            None
+        } else {
+            Some(dst)
+        }
+    }
+
+    fn process_tcp(&mut self, dst: Ipv4Addr, header: &TcpHeader, payload: &[u8]) -> Result<()> {
+        let key = TcpKey {
+            guest_port: header.src_port,
+            remote: SocketAddrV4::new(dst, header.dst_port),
+        };
+        let mut frames = Vec::new();
+
+        if let Some(conn) = self.tcp_conns.get_mut(&key) {
+            let status = conn.process_segment(header, payload, &self.link, &mut frames);
+            return self.tcp_status(key, status, frames);
+        }
+
+        if header.flags & TCP_RST != 0 {
+            return Ok(());
+        }
+
+        let stream = if header.flags & (TCP_SYN | TCP_ACK) == TCP_SYN
+            && self.tcp_conns.len() < MAX_TCP_CONNS
+        {
+            self.host_addr(dst)
+                .and_then(|addr| connect_nonblocking(SocketAddrV4::new(addr, header.dst_port)).ok())
+        } else {
+            None
+        };
+        match stream {
+            Some(stream) => {
+                let token = self.add_token(Source::Tcp(key));
+                let conn = TcpConn::new_outbound(stream, token, key.remote, header);
+                self.tcp_conns.insert(key, conn);
+                self.update_tcp_interest(key)
+            }
+            None => {
+                // Refuse the connection, or reset the unknown one.
+                let (seq, flags) = if header.flags & TCP_ACK != 0 {
+                    (header.ack, TCP_RST)
+                } else {
+                    (0, TCP_RST | TCP_ACK)
+                };
+                let mut len = payload.len() as u32;
+                if header.flags & TCP_SYN != 0 {
+                    len += 1;
+                }
+                let reset = TcpHeader {
+                    src_port: header.dst_port,
+                    dst_port: header.src_port,
+                    seq,
+                    ack: header.seq.wrapping_add(len),
+                    flags,
+                    window: 0,
+                    mss: None,
+                };
+                let frame = self.link.tcp_frame(dst, &reset, &[]);
+                self.queue_frame(frame);
+                Ok(())
+            }
+        }
+    }
+
+    fn udp_flow(&mut self, key: UdpKey) -> Option<&mut UdpFlow> {
+        if !self.udp_flows.contains_key(&key) {
+            if self.udp_flows.len() >= MAX_UDP_FLOWS {
+                return None;
+            }
+            let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+                .and_then(|s| s.set_nonblocking(true).map(|_| s))
+                .ok()?;
+            let token = self.next_token;
+            epoll_ctl(
+                self.epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                socket.as_raw_fd(),
+                epoll::Events::EPOLLIN,
+                token,
+            )
+            .ok()?;
+            self.add_token(Source::Udp(key));
+            self.udp_flows.insert(
+                key,
+                UdpFlow {
+                    socket,
+                    token,
+                    last_used: Instant::now(),
+                },
+            );
+        }
+        let flow = self.udp_flows.get_mut(&key)?;
+        flow.last_used = Instant::now();
+        Some(flow)
+    }
+
+    // Datagrams are dropped whenever they can't be forwarded, as UDP
+    // doesn't guarantee their delivery.
+    fn process_udp(&mut self, dst: Ipv4Addr, src_port: u16, dst_port: u16, payload: &[u8]) {
+        if dst == self.gateway_ip {
+            if let Some(client) = self.udp_fwd_clients.get_mut(&dst_port) {
+                client.last_used = Instant::now();
+                let forward = &self.udp_forwards[client.forward];
+                if src_port == forward.guest_port {
+                    let _ = forward.socket.send_to(payload, client.client);
+                }
+                return;
+            }
+
+            if dst_port == DNS_PORT {
+                if let Some(dns_server) = self.dns_server {
+                    let key = UdpKey {
+                        guest_port: src_port,
+                        dns: true,
+                    };
+                    if let Some(flow) = self.udp_flow(key) {
+                        let _ = flow.socket.send_to(payload, dns_server);
+                    }
+                }
+                return;
+            }
+        }
+
+        if dst_port == DHCP_SERVER_PORT && dst.is_broadcast() {
+            return;
+        }
+        let addr = match self.host_addr(dst) {
+            Some(addr) => SocketAddrV4::new(addr, dst_port),
+            None => return,
+        };
+        let key = UdpKey {
+            guest_port: src_port,
+            dns: false,
+        };
+        if let Some(flow) = self.udp_flow(key) {
+            let _ = flow.socket.send_to(payload, addr);
+        }
+    }
+
+    fn process_udp_flow(&mut self, key: UdpKey) {
+        let mut buf = vec![0u8; MAX_FRAME_LEN];
+        let mut frames = Vec::new();
+        if let Some(flow) = self.udp_flows.get_mut(&key) {
+            while let Ok((len, src)) = flow.socket.recv_from(&mut buf) {
+                let src = match src {
+                    SocketAddr::V4(src) => src,
+                    SocketAddr::V6(_) => continue,
+                };
+                // Present the replies as coming from the gateway when they
+                // come from the DNS server or the host itself.
+                let src = if key.dns {
+                    SocketAddrV4::new(self.gateway_ip, DNS_PORT)
+                } else if src.ip().is_loopback() {
+                    SocketAddrV4::new(self.gateway_ip, src.port())
+                } else {
+                    src
+                };
+                flow.last_used = Instant::now();
+                frames.push(self.link.udp_frame(src, key.guest_port, &buf[..len]));
+            }
+        }
+        self.queue_udp_frames(frames);
+    }
+
+    fn queue_udp_frames(&mut self, frames: Vec<Vec<u8>>) {
+        for frame in frames {
+            if self.to_guest.len() < MAX_GUEST_QUEUE {
+                self.queue_frame(frame);
+            }
+        }
+    }
+
+    fn process_udp_forward(&mut self, index: usize) {
+        let mut buf = vec![0u8; MAX_FRAME_LEN];
+        let mut frames = Vec::new();
+        loop {
+            let (len, client) = match self.udp_forwards[index].socket.recv_from(&mut buf) {
+                Ok((len, SocketAddr::V4(client))) => (len, client),
+                Ok(_) => continue,
+                Err(_) => break,
+            };
+            let existing = self
+                .udp_fwd_clients
+                .iter()
+                .find(|(_, c)| c.forward == index && c.client == client)
+                .map(|(port, _)| *port);
+            let port = match existing {
+                Some(port) => port,
+                None if self.udp_fwd_clients.len() >= MAX_UDP_FLOWS => continue,
+                None => {
+                    let port = self.alloc_port();
+                    self.udp_fwd_clients.insert(
+                        port,
+                        UdpFwdClient {
+                            forward: index,
+                            client,
+                            last_used: Instant::now(),
+                        },
+                    );
+                    port
+                }
+            };
+            if let Some(client) = self.udp_fwd_clients.get_mut(&port) {
+                client.last_used = Instant::now();
+            }
+            let guest_port = self.udp_forwards[index].guest_port;
+            frames.push(self.link.udp_frame(
+                SocketAddrV4::new(self.gateway_ip, port),
+                guest_port,
+                &buf[..len],
+            ));
+        }
+        self.queue_udp_frames(frames);
+    }
+
+    fn process_tcp_listener(&mut self, index: usize) -> Result<()> {
+        loop {
+            let stream = match self.tcp_listeners[index].0.accept() {
+                Ok((stream, _)) => stream,
+                Err(_) => return Ok(()),
+            };
+            // Closing the stream refuses the connection beyond the limit.
+            if self.tcp_conns.len() >= MAX_TCP_CONNS || stream.set_nonblocking(true).is_err() {
+                continue;
+            }
+            let port = self.alloc_port();
+            let key = TcpKey {
+                guest_port: self.tcp_listeners[index].1,
+                remote: SocketAddrV4::new(self.gateway_ip, port),
+            };
+            let token = self.add_token(Source::Tcp(key));
+            let mut frames = Vec::new();
+            let conn = TcpConn::new_inbound(
+                stream,
+                token,
+                key.guest_port,
+                key.remote,
+                &self.link,
+                &mut frames,
+            );
+            self.tcp_conns.insert(key, conn);
+            self.tcp_status(key, ConnStatus::Open, frames)?;
+        }
+    }
+
+    fn process_tcp_socket(&mut self, key: TcpKey, events: epoll::Events) -> Result<()> {
+        let mut frames = Vec::new();
+        let status = match self.tcp_conns.get_mut(&key) {
+            Some(conn) if conn.is_connecting() => conn.connected(&self.link, &mut frames),
+            Some(conn) => {
+                let mut status = ConnStatus::Open;
+                if events.intersects(epoll::Events::EPOLLOUT | epoll::Events::EPOLLERR) {
+                    status = conn.process_writable(&self.link, &mut frames);
+                }
+                if status == ConnStatus::Open
+                    && events.intersects(
+                        epoll::Events::EPOLLIN | epoll::Events::EPOLLHUP | epoll::Events::EPOLLERR,
+                    )
+                {
+                    status = conn.process_readable(&self.link, &mut frames);
+                }
+                status
+            }
+            None => return Ok(()),
+        };
+        self.tcp_status(key, status, frames)
+    }
+
+    fn process_timeouts(&mut self) -> Result<()> {
+        let mut frames = Vec::new();
+        let mut closed = Vec::new();
+        for (key, conn) in self.tcp_conns.iter_mut() {
+            if conn.process_timeout(&self.link, &mut frames) == ConnStatus::Closed {
+                closed.push(*key);
+            }
+        }
+        self.queue_frames(frames);
+        for key in closed {
+            self.remove_tcp_conn(key);
+        }
+
+        let expired: Vec<UdpKey> = self
+            .udp_flows
+            .iter()
+            .filter(|(_, flow)| flow.last_used.elapsed() > UDP_IDLE_TIMEOUT)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in expired {
+            if let Some(flow) = self.udp_flows.remove(&key) {
+                self.sources.remove(&flow.token);
+            }
+        }
+        self.udp_fwd_clients
+            .retain(|_, client| client.last_used.elapsed() <= UDP_IDLE_TIMEOUT);
+        Ok(())
+    }
+
+    fn register_sources(&mut self, kill_evt: &EventFd) -> Result<()> {
+        self.epoll_fd = epoll::create(true).map_err(Error::EpollCreate)?;
+        let epoll_fd = self.epoll_fd;
+        epoll_ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.guest.as_raw_fd(),
+            epoll::Events::EPOLLIN,
+            GUEST_TOKEN,
+        )?;
+        epoll_ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            kill_evt.as_raw_fd(),
+            epoll::Events::EPOLLIN,
+            KILL_TOKEN,
+        )?;
+        for index in 0..self.tcp_listeners.len() {
+            let token = self.add_token(Source::TcpListener(index));
+            epoll_ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                self.tcp_listeners[index].0.as_raw_fd(),
+                epoll::Events::EPOLLIN,
+                token,
+            )?;
+        }
+        for index in 0..self.udp_forwards.len() {
+            let token = self.add_token(Source::UdpForward(index));
+            epoll_ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                self.udp_forwards[index].socket.as_raw_fd(),
+                epoll::Events::EPOLLIN,
+                token,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Run the network stack until the kill event is triggered, or the
+    /// device end of the socket pair is closed.
+    pub fn run(&mut self, kill_evt: EventFd) -> Result<()> {
+        self.register_sources(&kill_evt)?;
+
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+        let mut last_tick = Instant::now();
+        loop {
+            let num_events =
+                match epoll::wait(self.epoll_fd, TICK.as_millis() as i32, &mut events[..]) {
+                    Ok(num_events) => num_events,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(Error::EpollWait(e)),
+                };
+
+            for event in events.iter().take(num_events) {
+                let evset = epoll::Events::from_bits_truncate(event.events);
+                match event.data {
+                    KILL_TOKEN => return Ok(()),
+                    GUEST_TOKEN => {
+                        if evset.contains(epoll::Events::EPOLLIN) && !self.process_guest()? {
+                            return Ok(());
+                        }
+                        if evset.contains(epoll::Events::EPOLLHUP) {
+                            return Ok(());
+                        }
+                    }
+                    token => match self.sources.get(&token).cloned() {
+                        Some(Source::Tcp(key)) => self.process_tcp_socket(key, evset)?,
+                        Some(Source::Udp(key)) => self.process_udp_flow(key),
+                        Some(Source::TcpListener(index)) => self.process_tcp_listener(index)?,
+                        Some(Source::UdpForward(index)) => self.process_udp_forward(index),
+                        None => {}
+                    },
+                }
+            }
+
+            if last_tick.elapsed() >= TICK {
+                last_tick = Instant::now();
+                self.process_timeouts()?;
+            }
+            self.flush_guest()?;
+        }
+    }
+}
+
+impl Drop for UserNet {
+    fn drop(&mut self) {
+        if self.epoll_fd >= 0 {
+            // This is safe since the fd is owned by the network stack.
+            unsafe { libc::close(self.epoll_fd) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hostfwd_parsing() {
+        assert_eq!(
+            "tcp/8022-22".parse::<HostFwd>().unwrap(),
+            HostFwd {
+                protocol: HostFwdProtocol::Tcp,
+                host_addr: Ipv4Addr::LOCALHOST,
+                host_port: 8022,
+                guest_port: 22,
+            }
+        );
+        let fwd = "udp/0.0.0.0/5353-53".parse::<HostFwd>().unwrap();
+        assert_eq!(
+            fwd,
+            HostFwd {
+                protocol: HostFwdProtocol::Udp,
+                host_addr: Ipv4Addr::UNSPECIFIED,
+                host_port: 5353,
+                guest_port: 53,
+            }
+        );
+        assert_eq!(fwd.to_string().parse::<HostFwd>().unwrap(), fwd);
+        assert!("sctp/8022-22".parse::<HostFwd>().is_err());
+        assert!("tcp/8022".parse::<HostFwd>().is_err());
+        assert!("tcp/localhost/8022-22".parse::<HostFwd>().is_err());
+    }
+}
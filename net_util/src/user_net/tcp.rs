@@ -0,0 +1,569 @@
+// Copyright (c) 2020 Intel Corporation. All rights reserved.
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use super::Link;
+use packet::{TcpHeader, TCP_ACK, TCP_FIN, TCP_PSH, TCP_RST, TCP_SYN};
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddrV4, TcpStream};
+use std::time::{Duration, Instant};
+
+/// Maximum segment size advertised to the guest.
+const MSS: u16 = 1460;
+/// Maximum segment size assumed when the guest doesn't advertise one.
+const DEFAULT_MSS: u16 = 536;
+/// Data received from the host and not yet acknowledged by the guest.
+const MAX_SEND_BUF: usize = 256 * 1024;
+/// Data received from the guest and not yet written to the host, which is
+/// also the receive window advertised to the guest, as no window scaling is
+/// negotiated.
+const MAX_RECV_BUF: usize = 65535;
+/// Time without acknowledgment after which the segments in flight are sent
+/// again.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Time without any progress after which a connection being established,
+/// closed in one direction, or whose data isn't consumed, is reset.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(75);
+const HALF_CLOSED_TIMEOUT: Duration = Duration::from_secs(60);
+const STALLED_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Maximum segment size used toward the guest, out of the one it advertised.
+/// Values below the default are raised to it, as a null segment size would
+/// never let any data through.
+fn guest_mss(advertised: Option<u16>) -> usize {
+    let mss = advertised.unwrap_or(DEFAULT_MSS);
+    usize::from(cmp::max(cmp::min(mss, MSS), DEFAULT_MSS))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    /// The guest sent a SYN, and the connection to the host is in progress.
+    Connecting,
+    /// The connection was accepted on the host, a SYN was sent to the guest.
+    SynSent,
+    /// The host connection completed, a SYN-ACK was sent to the guest.
+    SynReceived,
+    Established,
+}
+
+/// Whether a connection is still alive after processing an event.
+#[derive(Debug, PartialEq)]
+pub(super) enum ConnStatus {
+    Open,
+    Closed,
+}
+
+/// A TCP connection terminated on both sides: towards the guest by
+/// exchanging segments, towards the host through a socket.
+pub(super) struct TcpConn {
+    pub stream: TcpStream,
+    pub token: u64,
+    /// Events the socket is currently registered for, if any.
+    pub registered: Option<epoll::Events>,
+    state: State,
+    guest_port: u16,
+    /// Remote endpoint, as seen by the guest.
+    remote: SocketAddrV4,
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_max: u32,
+    snd_wnd: u16,
+    mss: usize,
+    send_buf: VecDeque<u8>,
+    rcv_nxt: u32,
+    recv_buf: Vec<u8>,
+    last_adv_wnd: u16,
+    host_eof: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    guest_fin: bool,
+    host_shutdown: bool,
+    last_progress: Instant,
+    /// Last time data, or a FIN, went through in either direction.
+    last_activity: Instant,
+}
+
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+impl TcpConn {
+    fn new(
+        stream: TcpStream,
+        token: u64,
+        state: State,
+        guest_port: u16,
+        remote: SocketAddrV4,
+    ) -> Self {
+        let iss = rand::random::<u32>();
+        TcpConn {
+            stream,
+            token,
+            registered: None,
+            state,
+            guest_port,
+            remote,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_max: iss,
+            snd_wnd: 0,
+            mss: usize::from(DEFAULT_MSS),
+            send_buf: VecDeque::new(),
+            rcv_nxt: 0,
+            recv_buf: Vec::new(),
+            last_adv_wnd: 0,
+            host_eof: false,
+            fin_sent: false,
+            fin_acked: false,
+            guest_fin: false,
+            host_shutdown: false,
+            last_progress: Instant::now(),
+            last_activity: Instant::now(),
+        }
+    }
+
+    /// Connection initiated by the guest through the given SYN, the host
+    /// socket being connected asynchronously.
+    pub fn new_outbound(
+        stream: TcpStream,
+        token: u64,
+        remote: SocketAddrV4,
+        syn: &TcpHeader,
+    ) -> Self {
+        let mut conn = Self::new(stream, token, State::Connecting, syn.src_port, remote);
+        conn.rcv_nxt = syn.seq.wrapping_add(1);
+        conn.snd_wnd = syn.window;
+        conn.mss = guest_mss(syn.mss);
+        conn
+    }
+
+    /// Connection accepted on the host, to be established with the guest.
+    pub fn new_inbound(
+        stream: TcpStream,
+        token: u64,
+        guest_port: u16,
+        remote: SocketAddrV4,
+        link: &Link,
+        out: &mut Vec<Vec<u8>>,
+    ) -> Self {
+        let mut conn = Self::new(stream, token, State::SynSent, guest_port, remote);
+        conn.send_syn(link, out);
+        conn
+    }
+
+    fn rcv_wnd(&self) -> u16 {
+        (MAX_RECV_BUF - self.recv_buf.len()) as u16
+    }
+
+    fn send_segment(
+        &mut self,
+        link: &Link,
+        out: &mut Vec<Vec<u8>>,
+        seq: u32,
+        flags: u8,
+        payload: &[u8],
+    ) {
+        let window = self.rcv_wnd();
+        self.last_adv_wnd = window;
+        let header = TcpHeader {
+            src_port: self.remote.port(),
+            dst_port: self.guest_port,
+            seq,
+            ack: if flags & TCP_ACK != 0 {
+                self.rcv_nxt
+            } else {
+                0
+            },
+            flags,
+            window,
+            mss: if flags & TCP_SYN != 0 {
+                Some(MSS)
+            } else {
+                None
+            },
+        };
+        out.push(link.tcp_frame(*self.remote.ip(), &header, payload));
+    }
+
+    fn send_ack(&mut self, link: &Link, out: &mut Vec<Vec<u8>>) {
+        let seq = self.snd_nxt;
+        self.send_segment(link, out, seq, TCP_ACK, &[]);
+    }
+
+    fn send_syn(&mut self, link: &Link, out: &mut Vec<Vec<u8>>) {
+        let (seq, flags) = match self.state {
+            State::SynSent => (self.snd_una, TCP_SYN),
+            _ => (self.snd_una, TCP_SYN | TCP_ACK),
+        };
+        self.send_segment(link, out, seq, flags, &[]);
+        self.snd_nxt = self.snd_una.wrapping_add(1);
+        self.snd_max = self.snd_nxt;
+        self.last_progress = Instant::now();
+    }
+
+    /// Reset the connection with the guest, which is then closed.
+    pub fn reset(&mut self, link: &Link, out: &mut Vec<Vec<u8>>) -> ConnStatus {
+        let seq = self.snd_nxt;
+        self.send_segment(link, out, seq, TCP_RST | TCP_ACK, &[]);
+        ConnStatus::Closed
+    }
+
+    /// Events of the host socket the connection must be notified about.
+    pub fn interest(&self, can_read: bool) -> epoll::Events {
+        if self.state == State::Connecting {
+            return epoll::Events::EPOLLOUT;
+        }
+
+        let mut events = epoll::Events::empty();
+        if can_read
+            && self.state == State::Established
+            && !self.host_eof
+            && self.send_buf.len() < MAX_SEND_BUF
+        {
+            events |= epoll::Events::EPOLLIN;
+        }
+        if !self.recv_buf.is_empty() {
+            events |= epoll::Events::EPOLLOUT;
+        }
+        events
+    }
+
+    /// Handle the completion of the connection to the host.
+    pub fn connected(&mut self, link: &Link, out: &mut Vec<Vec<u8>>) -> ConnStatus {
+        match self.stream.take_error() {
+            Ok(None) => {
+                self.state = State::SynReceived;
+                self.send_syn(link, out);
+                ConnStatus::Open
+            }
+            Ok(Some(e)) | Err(e) => {
+                debug!("Failed to connect to {}: {}", self.remote, e);
+                self.reset(link, out)
+            }
+        }
+    }
+
+    pub fn is_connecting(&self) -> bool {
+        self.state == State::Connecting
+    }
+
+    /// Handle a segment received from the guest.
+    pub fn process_segment(
+        &mut self,
+        header: &TcpHeader,
+        payload: &[u8],
+        link: &Link,
+        out: &mut Vec<Vec<u8>>,
+    ) -> ConnStatus {
+        if header.flags & TCP_RST != 0 {
+            return ConnStatus::Closed;
+        }
+
+        match self.state {
+            State::Connecting => return ConnStatus::Open,
+            State::SynSent => {
+                if header.flags & (TCP_SYN | TCP_ACK) != TCP_SYN | TCP_ACK
+                    || header.ack != self.snd_nxt
+                {
+                    return ConnStatus::Open;
+                }
+                self.rcv_nxt = header.seq.wrapping_add(1);
+                self.mss = guest_mss(header.mss);
+                self.snd_una = header.ack;
+                self.snd_wnd = header.window;
+                self.state = State::Established;
+                self.last_activity = Instant::now();
+                self.send_ack(link, out);
+                return ConnStatus::Open;
+            }
+            State::SynReceived => {
+                if header.flags & TCP_SYN != 0 {
+                    self.send_syn(link, out);
+                    return ConnStatus::Open;
+                }
+                if header.flags & TCP_ACK == 0 || header.ack != self.snd_nxt {
+                    return ConnStatus::Open;
+                }
+                self.snd_una = header.ack;
+                self.state = State::Established;
+                self.last_activity = Instant::now();
+            }
+            State::Established => {}
+        }
+
+        if header.flags & TCP_ACK != 0 {
+            self.process_ack(header);
+        }
+
+        let mut need_ack = false;
+        if !payload.is_empty() {
+            // Only accept the data in sequence, as no segment gets lost or
+            // reordered between the guest and the VMM.
+            let offset = self.rcv_nxt.wrapping_sub(header.seq) as usize;
+            if !self.guest_fin && offset < payload.len() && !seq_lt(self.rcv_nxt, header.seq) {
+                let len = cmp::min(payload.len() - offset, usize::from(self.rcv_wnd()));
+                self.recv_buf
+                    .extend_from_slice(&payload[offset..offset + len]);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(len as u32);
+                if len > 0 {
+                    self.last_activity = Instant::now();
+                }
+            }
+            need_ack = true;
+        }
+
+        if header.flags & TCP_FIN != 0
+            && !self.guest_fin
+            && header.seq.wrapping_add(payload.len() as u32) == self.rcv_nxt
+        {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.guest_fin = true;
+            self.last_activity = Instant::now();
+            need_ack = true;
+        }
+
+        if self.flush_host().is_err() {
+            return self.reset(link, out);
+        }
+        if need_ack {
+            self.send_ack(link, out);
+        }
+        self.transmit(link, out);
+
+        self.status()
+    }
+
+    fn process_ack(&mut self, header: &TcpHeader) {
+        self.snd_wnd = header.window;
+        if !seq_lt(self.snd_una, header.ack) || seq_lt(self.snd_max, header.ack) {
+            return;
+        }
+
+        let acked = header.ack.wrapping_sub(self.snd_una) as usize;
+        let data_acked = cmp::min(acked, self.send_buf.len());
+        self.send_buf.drain(..data_acked);
+        if acked > data_acked {
+            self.fin_acked = true;
+        }
+        self.snd_una = header.ack;
+        if seq_lt(self.snd_nxt, self.snd_una) {
+            self.snd_nxt = self.snd_una;
+        }
+        self.last_progress = Instant::now();
+        self.last_activity = self.last_progress;
+    }
+
+    // Send the data and FIN allowed by the window of the guest.
+    fn transmit(&mut self, link: &Link, out: &mut Vec<Vec<u8>>) {
+        if self.state != State::Established {
+            return;
+        }
+
+        let window = usize::from(self.snd_wnd);
+        loop {
+            let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            if in_flight >= self.send_buf.len() || in_flight >= window {
+                break;
+            }
+            let len = cmp::min(
+                self.mss,
+                cmp::min(self.send_buf.len() - in_flight, window - in_flight),
+            );
+            let payload: Vec<u8> = self
+                .send_buf
+                .iter()
+                .skip(in_flight)
+                .take(len)
+                .cloned()
+                .collect();
+            let seq = self.snd_nxt;
+            self.send_segment(link, out, seq, TCP_ACK | TCP_PSH, &payload);
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+        }
+
+        let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        if self.host_eof && !self.fin_sent && !self.fin_acked && in_flight == self.send_buf.len() {
+            let seq = self.snd_nxt;
+            self.send_segment(link, out, seq, TCP_FIN | TCP_ACK, &[]);
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.fin_sent = true;
+        }
+
+        if seq_lt(self.snd_max, self.snd_nxt) {
+            self.snd_max = self.snd_nxt;
+        }
+    }
+
+    // Write the data received from the guest to the host.
+    fn flush_host(&mut self) -> io::Result<()> {
+        while !self.recv_buf.is_empty() {
+            match self.stream.write(&self.recv_buf) {
+                Ok(n) => {
+                    self.recv_buf.drain(..n);
+                    self.last_activity = Instant::now();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if self.guest_fin && self.recv_buf.is_empty() && !self.host_shutdown {
+            self.host_shutdown = true;
+            self.stream.shutdown(Shutdown::Write)?;
+        }
+        Ok(())
+    }
+
+    /// Handle the host socket being writable.
+    pub fn process_writable(&mut self, link: &Link, out: &mut Vec<Vec<u8>>) -> ConnStatus {
+        if self.flush_host().is_err() {
+            return self.reset(link, out);
+        }
+        // Let the guest know it can send again if the window had closed.
+        if usize::from(self.last_adv_wnd) < self.mss && usize::from(self.rcv_wnd()) >= self.mss {
+            self.send_ack(link, out);
+        }
+        self.status()
+    }
+
+    /// Handle the host socket being readable.
+    pub fn process_readable(&mut self, link: &Link, out: &mut Vec<Vec<u8>>) -> ConnStatus {
+        let mut buf = [0u8; 16384];
+        while !self.host_eof && self.send_buf.len() < MAX_SEND_BUF {
+            let len = cmp::min(buf.len(), MAX_SEND_BUF - self.send_buf.len());
+            match self.stream.read(&mut buf[..len]) {
+                Ok(0) => {
+                    self.host_eof = true;
+                    self.last_activity = Instant::now();
+                }
+                Ok(n) => {
+                    self.send_buf.extend(&buf[..n]);
+                    self.last_activity = Instant::now();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return self.reset(link, out),
+            }
+        }
+        self.transmit(link, out);
+        self.status()
+    }
+
+    // Whether the connection made no progress for too long. Idle
+    // connections established in both directions are kept, as nothing is
+    // expected from them.
+    fn is_stalled(&self) -> bool {
+        let timeout = if self.state != State::Established {
+            HANDSHAKE_TIMEOUT
+        } else if self.guest_fin || self.host_eof {
+            HALF_CLOSED_TIMEOUT
+        } else if self.snd_max != self.snd_una || !self.recv_buf.is_empty() {
+            STALLED_TIMEOUT
+        } else {
+            return false;
+        };
+        self.last_activity.elapsed() >= timeout
+    }
+
+    /// Send again what wasn't acknowledged in time, and reset the connection
+    /// if it stalled.
+    pub fn process_timeout(&mut self, link: &Link, out: &mut Vec<Vec<u8>>) -> ConnStatus {
+        if self.is_stalled() {
+            debug!("Resetting stalled connection to {}", self.remote);
+            return self.reset(link, out);
+        }
+
+        if self.snd_nxt == self.snd_una || self.last_progress.elapsed() < RETRANSMIT_TIMEOUT {
+            return ConnStatus::Open;
+        }
+
+        match self.state {
+            State::SynSent | State::SynReceived => self.send_syn(link, out),
+            State::Established => {
+                self.snd_nxt = self.snd_una;
+                self.fin_sent = false;
+                self.last_progress = Instant::now();
+                self.transmit(link, out);
+            }
+            State::Connecting => {}
+        }
+        ConnStatus::Open
+    }
+
+    fn status(&self) -> ConnStatus {
+        if self.guest_fin && self.fin_acked && self.recv_buf.is_empty() {
+            ConnStatus::Closed
+        } else {
+            ConnStatus::Open
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, TcpListener};
+    use MacAddr;
+
+    #[test]
+    fn test_guest_mss() {
+        assert_eq!(guest_mss(None), 536);
+        assert_eq!(guest_mss(Some(0)), 536);
+        assert_eq!(guest_mss(Some(100)), 536);
+        assert_eq!(guest_mss(Some(1000)), 1000);
+        assert_eq!(guest_mss(Some(9000)), 1460);
+    }
+
+    #[test]
+    fn test_outbound_null_mss() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let syn = TcpHeader {
+            src_port: 1234,
+            dst_port: 80,
+            seq: 0,
+            ack: 0,
+            flags: TCP_SYN,
+            window: 65535,
+            mss: Some(0),
+        };
+        let remote = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 80);
+        let conn = TcpConn::new_outbound(stream, 0, remote, &syn);
+        assert_eq!(conn.mss, usize::from(DEFAULT_MSS));
+    }
+
+    #[test]
+    fn test_half_closed_timeout() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let syn = TcpHeader {
+            src_port: 1234,
+            dst_port: 80,
+            seq: 0,
+            ack: 0,
+            flags: TCP_SYN,
+            window: 65535,
+            mss: None,
+        };
+        let remote = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 80);
+        let link = Link {
+            guest_mac: MacAddr::local_random(),
+            gateway_mac: MacAddr::local_random(),
+            guest_ip: Ipv4Addr::new(10, 0, 2, 15),
+        };
+        let mut out = Vec::new();
+
+        let mut conn = TcpConn::new_outbound(stream, 0, remote, &syn);
+        conn.state = State::Established;
+        conn.last_activity -= STALLED_TIMEOUT;
+        // An idle connection is kept, until it is closed in one direction.
+        assert_eq!(conn.process_timeout(&link, &mut out), ConnStatus::Open);
+        conn.guest_fin = true;
+        assert_eq!(conn.process_timeout(&link, &mut out), ConnStatus::Closed);
+        // The guest is told about the connection being reset.
+        assert_eq!(out.len(), 1);
+    }
+}
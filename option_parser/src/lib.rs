@@ -15,6 +15,7 @@ pub struct OptionParser {
 struct OptionParserValue {
     value: Option<String>,
     requires_value: bool,
+    uri: bool,
 }
 
 #[derive(Debug)]
@@ -52,13 +53,16 @@ impl OptionParser {
         let options_list: Vec<&str> = input.trim().split(',').collect();
 
         for option in options_list.iter() {
-            let parts: Vec<&str> = option.split('=').collect();
+            let parts: Vec<&str> = option.splitn(2, '=').collect();
 
             match self.options.get_mut(parts[0]) {
                 None => return Err(OptionParserError::UnknownOption(parts[0].to_owned())),
                 Some(value) => {
                     if value.requires_value {
-                        if parts.len() != 2 {
+                        // Only URIs can hold a '=', in their query.
+                        if parts.len() != 2
+                            || (parts[1].contains('=') && !(value.uri && parts[1].contains("://")))
+                        {
                             return Err(OptionParserError::InvalidSyntax((*option).to_owned()));
                         }
                         value.value = Some(parts[1].trim().to_owned());
@@ -78,6 +82,22 @@ impl OptionParser {
             OptionParserValue {
                 value: None,
                 requires_value: true,
+                uri: false,
+            },
+        );
+
+        self
+    }
+
+    /// Add an option whose value can be a URI, which unlike other values may
+    /// contain '='.
+    pub fn add_uri(&mut self, option: &str) -> &mut Self {
+        self.options.insert(
+            option.to_owned(),
+            OptionParserValue {
+                value: None,
+                requires_value: true,
+                uri: true,
             },
         );
 
@@ -90,6 +110,7 @@ impl OptionParser {
             OptionParserValue {
                 value: None,
                 requires_value: false,
+                uri: false,
             },
         );
 
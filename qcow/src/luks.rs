@@ -0,0 +1,697 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! LUKS1 encrypted disks, as created by `cryptsetup luksFormat --type luks1`.
+//!
+//! The LUKS header is looked up at the start of the underlying disk, which
+//! can be any image format, and the payload following it is transparently
+//! decrypted. Only AES in XTS mode is supported, which is what cryptsetup
+//! uses by default.
+
+use std::cmp::min;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::ManuallyDrop;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::Arc;
+
+use aes::cipher::generic_array::GenericArray;
+use aes::{Aes128, Aes256, BlockCipher, NewBlockCipher};
+use byteorder::{BigEndian, ByteOrder};
+use hmac::Hmac;
+use libc::EINVAL;
+use sha2::{Digest, Sha256, Sha512};
+
+const SECTOR_SIZE: u64 = 512;
+const AES_BLOCK_SIZE: usize = 16;
+
+const LUKS_MAGIC: &[u8; 6] = b"LUKS\xba\xbe";
+const LUKS_VERSION: u16 = 1;
+const LUKS_HEADER_SIZE: usize = 592;
+const LUKS_DIGEST_SIZE: usize = 20;
+const LUKS_SALT_SIZE: usize = 32;
+const LUKS_NUM_KEYS: usize = 8;
+const LUKS_KEY_ENABLED: u32 = 0x00ac_71f3;
+// Anti-forensic stripes are only a few thousands in practice, this bounds the
+// memory allocated for a corrupted header.
+const LUKS_MAX_STRIPES: u32 = 1 << 16;
+
+// Header field offsets.
+const HEADER_OFFSET_VERSION: usize = 6;
+const HEADER_OFFSET_CIPHER_NAME: usize = 8;
+const HEADER_OFFSET_CIPHER_MODE: usize = 40;
+const HEADER_OFFSET_HASH_SPEC: usize = 72;
+const HEADER_OFFSET_PAYLOAD_OFFSET: usize = 104;
+const HEADER_OFFSET_KEY_BYTES: usize = 108;
+const HEADER_OFFSET_MK_DIGEST: usize = 112;
+const HEADER_OFFSET_MK_DIGEST_SALT: usize = 132;
+const HEADER_OFFSET_MK_DIGEST_ITER: usize = 164;
+const HEADER_OFFSET_KEY_SLOTS: usize = 208;
+const HEADER_NAME_SIZE: usize = 32;
+
+// Key slot field offsets, relative to the start of the slot.
+const KEY_SLOT_SIZE: usize = 48;
+const KEY_SLOT_OFFSET_ITERATIONS: usize = 4;
+const KEY_SLOT_OFFSET_SALT: usize = 8;
+const KEY_SLOT_OFFSET_KEY_MATERIAL: usize = 40;
+const KEY_SLOT_OFFSET_STRIPES: usize = 44;
+
+// Requests are split in chunks of this size, bounding the size of the
+// buffers used to encrypt and decrypt data.
+const MAX_CHUNK_SIZE: u64 = 1 << 20;
+
+#[derive(Debug)]
+pub enum Error {
+    /// `InvalidHeader` - The header fields are inconsistent.
+    InvalidHeader,
+    /// `InvalidMagic` - The disk doesn't start with a LUKS header.
+    InvalidMagic,
+    /// `InvalidPassphrase` - The passphrase doesn't unlock any key slot.
+    InvalidPassphrase,
+    /// `ReadingHeader` - Error reading the header or the key material.
+    ReadingHeader(io::Error),
+    /// `UnsupportedCipher` - Only AES in XTS mode is supported.
+    UnsupportedCipher(String, String),
+    /// `UnsupportedHash` - The hash used to derive keys isn't supported.
+    UnsupportedHash(String),
+    /// `UnsupportedKeySize` - The master key size doesn't fit AES-XTS.
+    UnsupportedKeySize(u32),
+    /// `UnsupportedVersion` - Only LUKS1 headers are supported.
+    UnsupportedVersion(u16),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidHeader => write!(f, "invalid LUKS header"),
+            InvalidMagic => write!(f, "invalid LUKS magic"),
+            InvalidPassphrase => write!(f, "no LUKS key slot matches the passphrase"),
+            ReadingHeader(e) => write!(f, "failed to read LUKS header: {}", e),
+            UnsupportedCipher(name, mode) => {
+                write!(f, "unsupported LUKS cipher {}-{}", name, mode)
+            }
+            UnsupportedHash(hash) => write!(f, "unsupported LUKS hash {}", hash),
+            UnsupportedKeySize(size) => write!(f, "unsupported LUKS key size {}", size),
+            UnsupportedVersion(v) => write!(f, "unsupported LUKS version {}", v),
+        }
+    }
+}
+
+/// Reads the passphrase stored in the file `fd` refers to. The descriptor
+/// stays open and its offset isn't changed, so that it can be read again when
+/// the disk is reopened.
+pub fn read_passphrase_fd(fd: RawFd) -> io::Result<Vec<u8>> {
+    // Safe because the file isn't dropped, leaving the descriptor untouched.
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    let mut passphrase = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = file.read_at(&mut buf, passphrase.len() as u64)?;
+        if len == 0 {
+            return Ok(passphrase);
+        }
+        passphrase.extend_from_slice(&buf[..len]);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum HashSpec {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl HashSpec {
+    fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "sha1" => Ok(HashSpec::Sha1),
+            "sha256" => Ok(HashSpec::Sha256),
+            "sha512" => Ok(HashSpec::Sha512),
+            _ => Err(Error::UnsupportedHash(name.to_string())),
+        }
+    }
+
+    fn digest_size(self) -> usize {
+        match self {
+            HashSpec::Sha1 => 20,
+            HashSpec::Sha256 => 32,
+            HashSpec::Sha512 => 64,
+        }
+    }
+
+    fn digest(self, parts: &[&[u8]]) -> Vec<u8> {
+        fn digest<D: Digest>(parts: &[&[u8]]) -> Vec<u8> {
+            let mut d = D::new();
+            for part in parts {
+                d.update(part);
+            }
+            d.finalize().to_vec()
+        }
+
+        match self {
+            HashSpec::Sha1 => digest::<sha1::Sha1>(parts),
+            HashSpec::Sha256 => digest::<Sha256>(parts),
+            HashSpec::Sha512 => digest::<Sha512>(parts),
+        }
+    }
+
+    fn pbkdf2(self, password: &[u8], salt: &[u8], iterations: u32, key: &mut [u8]) {
+        match self {
+            HashSpec::Sha1 => pbkdf2::pbkdf2::<Hmac<sha1::Sha1>>(password, salt, iterations, key),
+            HashSpec::Sha256 => pbkdf2::pbkdf2::<Hmac<Sha256>>(password, salt, iterations, key),
+            HashSpec::Sha512 => pbkdf2::pbkdf2::<Hmac<Sha512>>(password, salt, iterations, key),
+        }
+    }
+}
+
+// Diffuses `data` by hashing each digest sized chunk along with its index.
+fn diffuse(hash: HashSpec, data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(hash.digest_size()).enumerate() {
+        let digest = hash.digest(&[&(i as u32).to_be_bytes(), chunk]);
+        let len = chunk.len();
+        chunk.copy_from_slice(&digest[..len]);
+    }
+}
+
+// Recovers a key split into `stripes` by the LUKS anti-forensic splitter.
+fn af_merge(hash: HashSpec, material: &[u8], key_len: usize, stripes: usize) -> Vec<u8> {
+    let mut key = vec![0u8; key_len];
+    for (i, stripe) in material.chunks(key_len).take(stripes).enumerate() {
+        key.iter_mut().zip(stripe).for_each(|(k, s)| *k ^= s);
+        if i < stripes - 1 {
+            diffuse(hash, &mut key);
+        }
+    }
+    key
+}
+
+#[allow(clippy::large_enum_variant)]
+enum Aes {
+    Aes128(Aes128),
+    Aes256(Aes256),
+}
+
+impl Aes {
+    fn new(key: &[u8]) -> Self {
+        if key.len() == 16 {
+            Aes::Aes128(Aes128::new(GenericArray::from_slice(key)))
+        } else {
+            Aes::Aes256(Aes256::new(GenericArray::from_slice(key)))
+        }
+    }
+
+    fn encrypt(&self, block: &mut [u8]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            Aes::Aes128(c) => c.encrypt_block(block),
+            Aes::Aes256(c) => c.encrypt_block(block),
+        }
+    }
+
+    fn decrypt(&self, block: &mut [u8]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            Aes::Aes128(c) => c.decrypt_block(block),
+            Aes::Aes256(c) => c.decrypt_block(block),
+        }
+    }
+}
+
+/// AES in XTS mode, using the sector number as tweak.
+struct Xts {
+    data: Aes,
+    tweak: Aes,
+    // Whether the whole sector number is used, or only its low 32 bits.
+    plain64: bool,
+}
+
+impl Xts {
+    // The key holds the data key followed by the tweak key, both of the
+    // same size.
+    fn new(key: &[u8], plain64: bool) -> Self {
+        let (data, tweak) = key.split_at(key.len() / 2);
+        Xts {
+            data: Aes::new(data),
+            tweak: Aes::new(tweak),
+            plain64,
+        }
+    }
+
+    fn crypt(&self, first_sector: u64, data: &mut [u8], encrypt: bool) {
+        for (i, sector) in data.chunks_mut(SECTOR_SIZE as usize).enumerate() {
+            let mut iv = first_sector + i as u64;
+            if !self.plain64 {
+                iv &= 0xffff_ffff;
+            }
+            let mut tweak = [0u8; AES_BLOCK_SIZE];
+            tweak[..8].copy_from_slice(&iv.to_le_bytes());
+            self.tweak.encrypt(&mut tweak);
+
+            for block in sector.chunks_mut(AES_BLOCK_SIZE) {
+                block.iter_mut().zip(&tweak).for_each(|(b, t)| *b ^= t);
+                if encrypt {
+                    self.data.encrypt(block);
+                } else {
+                    self.data.decrypt(block);
+                }
+                block.iter_mut().zip(&tweak).for_each(|(b, t)| *b ^= t);
+
+                // Multiply the tweak by the primitive element of GF(2^128).
+                let carry = tweak[AES_BLOCK_SIZE - 1] >> 7;
+                for j in (1..AES_BLOCK_SIZE).rev() {
+                    tweak[j] = (tweak[j] << 1) | (tweak[j - 1] >> 7);
+                }
+                tweak[0] = (tweak[0] << 1) ^ (carry * 0x87);
+            }
+        }
+    }
+
+    fn encrypt(&self, first_sector: u64, data: &mut [u8]) {
+        self.crypt(first_sector, data, true)
+    }
+
+    fn decrypt(&self, first_sector: u64, data: &mut [u8]) {
+        self.crypt(first_sector, data, false)
+    }
+}
+
+// Returns the NUL terminated string stored in a header field.
+fn header_string(field: &[u8]) -> String {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).into_owned()
+}
+
+// Tries the passphrase against each active key slot, returning the master key
+// once one of them unlocks it.
+fn unlock<D: Read + Seek>(
+    disk: &mut D,
+    header: &[u8],
+    hash: HashSpec,
+    key_bytes: usize,
+    plain64: bool,
+    passphrase: &[u8],
+) -> Result<Vec<u8>> {
+    let mk_digest = &header[HEADER_OFFSET_MK_DIGEST..][..LUKS_DIGEST_SIZE];
+    let mk_digest_salt = &header[HEADER_OFFSET_MK_DIGEST_SALT..][..LUKS_SALT_SIZE];
+    let mk_digest_iter = BigEndian::read_u32(&header[HEADER_OFFSET_MK_DIGEST_ITER..]);
+
+    for i in 0..LUKS_NUM_KEYS {
+        let slot = &header[HEADER_OFFSET_KEY_SLOTS + i * KEY_SLOT_SIZE..][..KEY_SLOT_SIZE];
+        if BigEndian::read_u32(slot) != LUKS_KEY_ENABLED {
+            continue;
+        }
+        let iterations = BigEndian::read_u32(&slot[KEY_SLOT_OFFSET_ITERATIONS..]);
+        let salt = &slot[KEY_SLOT_OFFSET_SALT..][..LUKS_SALT_SIZE];
+        let material_offset =
+            u64::from(BigEndian::read_u32(&slot[KEY_SLOT_OFFSET_KEY_MATERIAL..])) * SECTOR_SIZE;
+        let stripes = BigEndian::read_u32(&slot[KEY_SLOT_OFFSET_STRIPES..]);
+        if iterations == 0 || stripes == 0 || stripes > LUKS_MAX_STRIPES {
+            return Err(Error::InvalidHeader);
+        }
+
+        let mut slot_key = vec![0u8; key_bytes];
+        hash.pbkdf2(passphrase, salt, iterations, &mut slot_key);
+
+        // The key material is encrypted with the slot key, numbering sectors
+        // from its start.
+        let material_len = key_bytes * stripes as usize;
+        let sectors = (material_len as u64 + SECTOR_SIZE - 1) / SECTOR_SIZE;
+        let mut material = vec![0u8; (sectors * SECTOR_SIZE) as usize];
+        disk.seek(SeekFrom::Start(material_offset))
+            .map_err(Error::ReadingHeader)?;
+        disk.read_exact(&mut material)
+            .map_err(Error::ReadingHeader)?;
+        Xts::new(&slot_key, plain64).decrypt(0, &mut material);
+
+        let key = af_merge(hash, &material[..material_len], key_bytes, stripes as usize);
+        let mut digest = [0u8; LUKS_DIGEST_SIZE];
+        hash.pbkdf2(&key, mk_digest_salt, mk_digest_iter, &mut digest);
+        if digest == mk_digest {
+            return Ok(key);
+        }
+    }
+
+    Err(Error::InvalidPassphrase)
+}
+
+/// A disk whose content is encrypted with LUKS, exposing the decrypted
+/// payload. Clones share the same keys, each keeping its own position.
+#[derive(Clone)]
+pub struct LuksDisk<D> {
+    disk: D,
+    cipher: Arc<Xts>,
+    payload_offset: u64,
+    size: u64,
+    position: u64,
+}
+
+impl<D: Read + Write + Seek> LuksDisk<D> {
+    /// Opens the LUKS encrypted `disk`, unlocking it with `passphrase`.
+    pub fn new(mut disk: D, passphrase: &[u8]) -> Result<Self> {
+        let mut header = [0u8; LUKS_HEADER_SIZE];
+        disk.seek(SeekFrom::Start(0))
+            .map_err(Error::ReadingHeader)?;
+        disk.read_exact(&mut header).map_err(Error::ReadingHeader)?;
+        if &header[..LUKS_MAGIC.len()] != LUKS_MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        let version = BigEndian::read_u16(&header[HEADER_OFFSET_VERSION..]);
+        if version != LUKS_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let cipher_name = header_string(&header[HEADER_OFFSET_CIPHER_NAME..][..HEADER_NAME_SIZE]);
+        let cipher_mode = header_string(&header[HEADER_OFFSET_CIPHER_MODE..][..HEADER_NAME_SIZE]);
+        let plain64 = match (cipher_name.as_str(), cipher_mode.as_str()) {
+            ("aes", "xts-plain64") => true,
+            ("aes", "xts-plain") => false,
+            _ => return Err(Error::UnsupportedCipher(cipher_name, cipher_mode)),
+        };
+        let hash = HashSpec::from_name(&header_string(
+            &header[HEADER_OFFSET_HASH_SPEC..][..HEADER_NAME_SIZE],
+        ))?;
+        let key_bytes = BigEndian::read_u32(&header[HEADER_OFFSET_KEY_BYTES..]);
+        if key_bytes != 32 && key_bytes != 64 {
+            return Err(Error::UnsupportedKeySize(key_bytes));
+        }
+
+        let payload_offset =
+            u64::from(BigEndian::read_u32(&header[HEADER_OFFSET_PAYLOAD_OFFSET..])) * SECTOR_SIZE;
+        let disk_size = disk.seek(SeekFrom::End(0)).map_err(Error::ReadingHeader)?;
+        if payload_offset < LUKS_HEADER_SIZE as u64 || payload_offset > disk_size {
+            return Err(Error::InvalidHeader);
+        }
+
+        let key = unlock(
+            &mut disk,
+            &header,
+            hash,
+            key_bytes as usize,
+            plain64,
+            passphrase,
+        )?;
+
+        Ok(LuksDisk {
+            disk,
+            cipher: Arc::new(Xts::new(&key, plain64)),
+            payload_offset,
+            // Only whole sectors can be decrypted.
+            size: (disk_size - payload_offset) / SECTOR_SIZE * SECTOR_SIZE,
+            position: 0,
+        })
+    }
+
+    /// Returns the size of the decrypted payload.
+    pub fn virtual_size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the disk holding the encrypted payload.
+    pub fn disk(&self) -> &D {
+        &self.disk
+    }
+
+    fn read_sectors(&mut self, sector: u64, data: &mut [u8]) -> io::Result<()> {
+        self.disk
+            .seek(SeekFrom::Start(self.payload_offset + sector * SECTOR_SIZE))?;
+        self.disk.read_exact(data)?;
+        self.cipher.decrypt(sector, data);
+        Ok(())
+    }
+
+    // Returns the first sector and the offset within it of the current
+    // position, along with the number of bytes to handle at once out of
+    // `len`.
+    fn chunk(&self, len: usize) -> (u64, usize, usize) {
+        let sector = self.position / SECTOR_SIZE;
+        let offset = self.position % SECTOR_SIZE;
+        let len = min(len as u64, self.size.saturating_sub(self.position));
+        let len = min(len, MAX_CHUNK_SIZE - offset);
+        (sector, offset as usize, len as usize)
+    }
+}
+
+impl<D: Read + Write + Seek> Read for LuksDisk<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (sector, offset, len) = self.chunk(buf.len());
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let sectors = (offset + len + SECTOR_SIZE as usize - 1) / SECTOR_SIZE as usize;
+        let mut data = vec![0u8; sectors * SECTOR_SIZE as usize];
+        self.read_sectors(sector, &mut data)?;
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl<D: Read + Write + Seek> Write for LuksDisk<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (sector, offset, len) = self.chunk(buf.len());
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let sectors = (offset + len + SECTOR_SIZE as usize - 1) / SECTOR_SIZE as usize;
+        let mut data = vec![0u8; sectors * SECTOR_SIZE as usize];
+        // Sectors partially written must be completed with their current
+        // content before being encrypted again.
+        if offset != 0 || len % SECTOR_SIZE as usize != 0 {
+            self.read_sectors(sector, &mut data)?;
+        }
+        data[offset..offset + len].copy_from_slice(&buf[..len]);
+        self.cipher.encrypt(sector, &mut data);
+        self.disk
+            .seek(SeekFrom::Start(self.payload_offset + sector * SECTOR_SIZE))?;
+        self.disk.write_all(&data)?;
+        self.position += len as u64;
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.disk.flush()
+    }
+}
+
+impl<D> Seek for LuksDisk<D> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos: Option<u64> = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(off) => {
+                if off < 0 {
+                    self.size.checked_sub(off.wrapping_neg() as u64)
+                } else {
+                    self.size.checked_add(off as u64)
+                }
+            }
+            SeekFrom::Current(off) => {
+                if off < 0 {
+                    self.position.checked_sub(off.wrapping_neg() as u64)
+                } else {
+                    self.position.checked_add(off as u64)
+                }
+            }
+        };
+
+        if let Some(new_pos) = new_pos {
+            self.position = new_pos;
+            Ok(new_pos)
+        } else {
+            Err(io::Error::from_raw_os_error(EINVAL))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const KEY_SLOT_ITERATIONS: u32 = 1000;
+    const MK_DIGEST_ITERATIONS: u32 = 1000;
+    const STRIPES: u32 = 4000;
+    const PAYLOAD_OFFSET_SECTORS: u32 = 4096;
+
+    // Splits `key` with the anti-forensic splitter, using `random` as the
+    // random stripes.
+    fn af_split(hash: HashSpec, key: &[u8], random: &[u8], stripes: usize) -> Vec<u8> {
+        let mut material = random[..key.len() * (stripes - 1)].to_vec();
+        let mut d = vec![0u8; key.len()];
+        for stripe in material.chunks(key.len()) {
+            d.iter_mut().zip(stripe).for_each(|(d, s)| *d ^= s);
+            diffuse(hash, &mut d);
+        }
+        material.extend(d.iter().zip(key).map(|(d, k)| d ^ k));
+        material
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+            .collect()
+    }
+
+    // Formats a LUKS1 disk the way cryptsetup does, with a single key slot.
+    fn format(
+        size: u64,
+        passphrase: &[u8],
+        key_bytes: usize,
+        hash_name: &str,
+        mode: &str,
+    ) -> Cursor<Vec<u8>> {
+        let hash = HashSpec::from_name(hash_name).unwrap();
+        let plain64 = mode == "xts-plain64";
+        let master_key = pattern(key_bytes, 1);
+        let mut disk = vec![0u8; (u64::from(PAYLOAD_OFFSET_SECTORS) * SECTOR_SIZE + size) as usize];
+
+        let h = &mut disk[..LUKS_HEADER_SIZE];
+        h[..6].copy_from_slice(LUKS_MAGIC);
+        BigEndian::write_u16(&mut h[HEADER_OFFSET_VERSION..], LUKS_VERSION);
+        h[HEADER_OFFSET_CIPHER_NAME..][..3].copy_from_slice(b"aes");
+        h[HEADER_OFFSET_CIPHER_MODE..][..mode.len()].copy_from_slice(mode.as_bytes());
+        h[HEADER_OFFSET_HASH_SPEC..][..hash_name.len()].copy_from_slice(hash_name.as_bytes());
+        BigEndian::write_u32(
+            &mut h[HEADER_OFFSET_PAYLOAD_OFFSET..],
+            PAYLOAD_OFFSET_SECTORS,
+        );
+        BigEndian::write_u32(&mut h[HEADER_OFFSET_KEY_BYTES..], key_bytes as u32);
+        let mk_digest_salt = pattern(LUKS_SALT_SIZE, 2);
+        h[HEADER_OFFSET_MK_DIGEST_SALT..][..LUKS_SALT_SIZE].copy_from_slice(&mk_digest_salt);
+        BigEndian::write_u32(&mut h[HEADER_OFFSET_MK_DIGEST_ITER..], MK_DIGEST_ITERATIONS);
+        hash.pbkdf2(
+            &master_key,
+            &mk_digest_salt,
+            MK_DIGEST_ITERATIONS,
+            &mut h[HEADER_OFFSET_MK_DIGEST..][..LUKS_DIGEST_SIZE],
+        );
+
+        let material_sector = 8;
+        let slot_salt = pattern(LUKS_SALT_SIZE, 3);
+        let slot = &mut h[HEADER_OFFSET_KEY_SLOTS..][..KEY_SLOT_SIZE];
+        BigEndian::write_u32(slot, LUKS_KEY_ENABLED);
+        BigEndian::write_u32(&mut slot[KEY_SLOT_OFFSET_ITERATIONS..], KEY_SLOT_ITERATIONS);
+        slot[KEY_SLOT_OFFSET_SALT..][..LUKS_SALT_SIZE].copy_from_slice(&slot_salt);
+        BigEndian::write_u32(&mut slot[KEY_SLOT_OFFSET_KEY_MATERIAL..], material_sector);
+        BigEndian::write_u32(&mut slot[KEY_SLOT_OFFSET_STRIPES..], STRIPES);
+
+        let mut slot_key = vec![0u8; key_bytes];
+        hash.pbkdf2(passphrase, &slot_salt, KEY_SLOT_ITERATIONS, &mut slot_key);
+        let random = pattern(key_bytes * STRIPES as usize, 4);
+        let mut material = af_split(hash, &master_key, &random, STRIPES as usize);
+        material.resize(
+            (material.len() + SECTOR_SIZE as usize - 1) / SECTOR_SIZE as usize
+                * SECTOR_SIZE as usize,
+            0,
+        );
+        Xts::new(&slot_key, plain64).encrypt(0, &mut material);
+        let material_offset = (u64::from(material_sector) * SECTOR_SIZE) as usize;
+        disk[material_offset..material_offset + material.len()].copy_from_slice(&material);
+
+        Cursor::new(disk)
+    }
+
+    #[test]
+    fn xts_test_vector() {
+        // First vector from IEEE 1619, with all zero keys and data.
+        let mut data = [0u8; 32];
+        Xts::new(&[0u8; 32], true).encrypt(0, &mut data);
+        assert_eq!(
+            data,
+            [
+                0x91, 0x7c, 0xf6, 0x9e, 0xbd, 0x68, 0xb2, 0xec, 0x9b, 0x9f, 0xe9, 0xa3, 0xea, 0xdd,
+                0xa6, 0x92, 0xcd, 0x43, 0xd2, 0xf5, 0x95, 0x98, 0xed, 0x85, 0x8c, 0x02, 0xc2, 0x65,
+                0x2f, 0xbf, 0x92, 0x2e,
+            ]
+        );
+    }
+
+    #[test]
+    fn af_split_merge() {
+        let hash = HashSpec::Sha256;
+        let key = pattern(64, 5);
+        let random = pattern(64 * 10, 6);
+        let material = af_split(hash, &key, &random, 10);
+        assert_eq!(af_merge(hash, &material, 64, 10), key);
+    }
+
+    #[test]
+    fn unlock_and_read_write() {
+        for (key_bytes, hash, mode) in &[
+            (64, "sha256", "xts-plain64"),
+            (32, "sha1", "xts-plain"),
+            (64, "sha512", "xts-plain64"),
+        ] {
+            let disk = format(0x10000, b"secret", *key_bytes, hash, mode);
+            let mut luks = LuksDisk::new(disk, b"secret").unwrap();
+            assert_eq!(luks.virtual_size(), 0x10000);
+
+            let mut before = vec![0u8; 1000];
+            luks.read_exact(&mut before).unwrap();
+
+            // Unaligned write spanning several sectors.
+            let data = pattern(1500, 7);
+            luks.seek(SeekFrom::Start(1000)).unwrap();
+            luks.write_all(&data).unwrap();
+
+            let mut buf = vec![0u8; 1500];
+            luks.seek(SeekFrom::Start(1000)).unwrap();
+            luks.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, data);
+
+            // Data around the write is preserved.
+            let mut buf = vec![0u8; 1000];
+            luks.seek(SeekFrom::Start(0)).unwrap();
+            luks.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, before);
+
+            // The data is stored encrypted.
+            let raw = luks.disk.get_ref();
+            let payload = &raw[(PAYLOAD_OFFSET_SECTORS as usize * 512)..];
+            assert!(!payload.windows(data.len()).any(|w| w == &data[..]));
+
+            // Nothing is read or written past the end of the payload.
+            luks.seek(SeekFrom::End(-1)).unwrap();
+            assert_eq!(luks.write(&[1, 2]).unwrap(), 1);
+            assert_eq!(luks.read(&mut buf).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let disk = format(0x10000, b"secret", 64, "sha256", "xts-plain64");
+        match LuksDisk::new(disk, b"wrong") {
+            Err(Error::InvalidPassphrase) => {}
+            _ => panic!("wrong passphrase unlocked the disk"),
+        }
+    }
+
+    #[test]
+    fn not_luks() {
+        match LuksDisk::new(Cursor::new(vec![0u8; 0x10000]), b"secret") {
+            Err(Error::InvalidMagic) => {}
+            _ => panic!("unformatted disk accepted"),
+        }
+    }
+
+    #[test]
+    fn passphrase_fd() {
+        use std::os::unix::io::AsRawFd;
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"secret").unwrap();
+        assert_eq!(read_passphrase_fd(file.as_raw_fd()).unwrap(), b"secret");
+        // The descriptor can be read again.
+        assert_eq!(read_passphrase_fd(file.as_raw_fd()).unwrap(), b"secret");
+    }
+}
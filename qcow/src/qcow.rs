@@ -5,10 +5,13 @@
 #[macro_use]
 extern crate log;
 
+pub mod luks;
 mod qcow_raw_file;
 mod raw_file;
 mod refcount;
 mod vec_cache;
+pub mod vhd;
+pub mod vhdx;
 
 use crate::qcow_raw_file::QcowRawFile;
 use crate::refcount::RefCount;
@@ -18,24 +21,33 @@ use libc::{EINVAL, ENOSPC, ENOTSUP};
 use remain::sorted;
 use std::cmp::{max, min};
 use std::fmt::{self, Display};
+use std::fs::{self, OpenOptions};
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use vmm_sys_util::{
     file_traits::FileSetLen, file_traits::FileSync, seek_hole::SeekHole, write_zeroes::PunchHole,
     write_zeroes::WriteZeroes,
 };
 
+pub use crate::luks::LuksDisk;
 pub use crate::raw_file::RawFile;
+pub use crate::vhd::FixedVhdFile;
+pub use crate::vhdx::VhdxFile;
 
 #[sorted]
 #[derive(Debug)]
 pub enum Error {
-    BackingFilesNotSupported,
+    BackingFile(Box<Error>),
+    BackingFileTooLong(u32),
+    BackingFilesDisabled,
     CompressedBlocksNotSupported,
     EvictingCache(io::Error),
     FileTooBig(u64),
     GettingFileSize(io::Error),
     GettingRefcount(refcount::Error),
+    InvalidBackingFileName,
     InvalidClusterIndex,
     InvalidClusterSize,
     InvalidIndex,
@@ -45,9 +57,11 @@ pub enum Error {
     InvalidOffset(u64),
     InvalidRefcountTableOffset,
     InvalidRefcountTableSize(u64),
+    MaxNestingDepthExceeded,
     NoFreeClusters,
     NoRefcountClusters,
     NotEnoughSpaceForRefcounts,
+    OpeningBackingFile(io::Error),
     OpeningFile(io::Error),
     ReadingData(io::Error),
     ReadingHeader(io::Error),
@@ -60,11 +74,16 @@ pub enum Error {
     SeekingFile(io::Error),
     SettingFileSize(io::Error),
     SettingRefcountRefcount(io::Error),
+    ShrinkNotSupported,
     SizeTooSmallForNumberOfClusters,
+    SyncingCaches(io::Error),
     TooManyL1Entries(u64),
     TooManyRefcounts(u64),
+    UnsupportedBackingFileFormat,
     UnsupportedRefcountOrder,
     UnsupportedVersion(u32),
+    Vhd(vhd::Error),
+    Vhdx(vhdx::Error),
     WritingData(io::Error),
     WritingHeader(io::Error),
 }
@@ -78,7 +97,9 @@ impl Display for Error {
 
         #[sorted]
         match self {
-            BackingFilesNotSupported => write!(f, "backing files not supported"),
+            BackingFile(e) => write!(f, "backing file: {}", e),
+            BackingFileTooLong(size) => write!(f, "backing file name too long: {}", size),
+            BackingFilesDisabled => write!(f, "backing files support is disabled"),
             CompressedBlocksNotSupported => write!(f, "compressed blocks not supported"),
             EvictingCache(e) => write!(f, "failed to evict cache: {}", e),
             FileTooBig(size) => write!(
@@ -88,6 +109,7 @@ impl Display for Error {
             ),
             GettingFileSize(e) => write!(f, "failed to get file size: {}", e),
             GettingRefcount(e) => write!(f, "failed to get refcount: {}", e),
+            InvalidBackingFileName => write!(f, "invalid backing file name"),
             InvalidClusterIndex => write!(f, "invalid cluster index"),
             InvalidClusterSize => write!(f, "invalid cluster size"),
             InvalidIndex => write!(f, "invalid index"),
@@ -97,9 +119,11 @@ impl Display for Error {
             InvalidOffset(_) => write!(f, "invalid offset"),
             InvalidRefcountTableOffset => write!(f, "invalid refcount table offset"),
             InvalidRefcountTableSize(size) => write!(f, "invalid refcount table size: {}", size),
+            MaxNestingDepthExceeded => write!(f, "backing file chain too long"),
             NoFreeClusters => write!(f, "no free clusters"),
             NoRefcountClusters => write!(f, "no refcount clusters"),
             NotEnoughSpaceForRefcounts => write!(f, "not enough space for refcounts"),
+            OpeningBackingFile(e) => write!(f, "failed to open backing file: {}", e),
             OpeningFile(e) => write!(f, "failed to open file: {}", e),
             ReadingData(e) => write!(f, "failed to read data: {}", e),
             ReadingHeader(e) => write!(f, "failed to read header: {}", e),
@@ -112,20 +136,28 @@ impl Display for Error {
             SeekingFile(e) => write!(f, "failed to seek file: {}", e),
             SettingFileSize(e) => write!(f, "failed to set file size: {}", e),
             SettingRefcountRefcount(e) => write!(f, "failed to set refcount refcount: {}", e),
+            ShrinkNotSupported => write!(f, "shrinking the image is not supported"),
             SizeTooSmallForNumberOfClusters => write!(f, "size too small for number of clusters"),
+            SyncingCaches(e) => write!(f, "failed to sync caches: {}", e),
             TooManyL1Entries(count) => write!(f, "l1 entry table too large: {}", count),
             TooManyRefcounts(count) => write!(f, "ref count table too large: {}", count),
+            UnsupportedBackingFileFormat => write!(f, "unsupported backing file format"),
             UnsupportedRefcountOrder => write!(f, "unsupported refcount order"),
             UnsupportedVersion(v) => write!(f, "unsupported version: {}", v),
+            Vhd(e) => write!(f, "VHD error: {}", e),
+            Vhdx(e) => write!(f, "VHDX error: {}", e),
             WritingData(e) => write!(f, "failed to write data: {}", e),
             WritingHeader(e) => write!(f, "failed to write header: {}", e),
         }
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ImageType {
     Raw,
     Qcow2,
+    FixedVhd,
+    Vhdx,
 }
 
 // Maximum data size supported.
@@ -146,6 +178,11 @@ const MAX_RAM_POINTER_TABLE_SIZE: u64 = 35_000_000;
 // Only support 2 byte refcounts, 2^refcount_order bits.
 const DEFAULT_REFCOUNT_ORDER: u32 = 4;
 
+// Maximum length of a backing file name, same limit as qemu.
+const MAX_BACKING_FILE_SIZE: u32 = 1023;
+// Maximum number of images a backing chain can be made of, to bail out of loops.
+const MAX_NESTING_DEPTH: u32 = 16;
+
 const V2_BARE_HEADER_SIZE: u32 = 72;
 const V3_BARE_HEADER_SIZE: u32 = 104;
 
@@ -349,6 +386,101 @@ impl QcowHeader {
     }
 }
 
+// Image providing the content of the clusters a qcow file hasn't allocated. It is only ever read.
+#[derive(Clone, Debug)]
+enum BackingFile {
+    Raw(RawFile),
+    Qcow(Box<QcowFile>),
+}
+
+impl BackingFile {
+    fn size(&mut self) -> std::io::Result<u64> {
+        match self {
+            BackingFile::Raw(file) => file.seek(SeekFrom::End(0)),
+            BackingFile::Qcow(qcow) => qcow.seek(SeekFrom::End(0)),
+        }
+    }
+
+    // Opens the backing file found at `path`, as an image of type `image_type`, or of the type
+    // detected from its content when none is given.
+    fn open(
+        path: &Path,
+        image_type: Option<ImageType>,
+        max_nesting_depth: u32,
+        supports_backing_files: bool,
+    ) -> Result<BackingFile> {
+        let file = OpenOptions::new()
+            .read(true)
+            .open(path)
+            .map_err(Error::OpeningBackingFile)?;
+        let mut raw_file = RawFile::new(file, false);
+        let image_type = match image_type {
+            Some(image_type) => image_type,
+            None => detect_image_type(&mut raw_file)?,
+        };
+        match image_type {
+            ImageType::Raw => Ok(BackingFile::Raw(raw_file)),
+            ImageType::Qcow2 => {
+                let qcow = QcowFile::from_with_nesting_depth(
+                    raw_file,
+                    max_nesting_depth,
+                    supports_backing_files,
+                )
+                .map_err(|e| Error::BackingFile(Box::new(e)))?;
+                Ok(BackingFile::Qcow(Box::new(qcow)))
+            }
+            _ => Err(Error::UnsupportedBackingFileFormat),
+        }
+    }
+
+    // Fills `buf` with the content found at `address`, which reads as zeros past the end of the
+    // backing file.
+    fn read_at(&mut self, address: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        fn read_zero_filled<F: Read + Seek>(
+            file: &mut F,
+            address: u64,
+            buf: &mut [u8],
+        ) -> std::io::Result<()> {
+            let size = file.seek(SeekFrom::End(0))?;
+            let count = if address < size {
+                min(buf.len() as u64, size - address) as usize
+            } else {
+                0
+            };
+            if count > 0 {
+                file.seek(SeekFrom::Start(address))?;
+                file.read_exact(&mut buf[..count])?;
+            }
+            for b in &mut buf[count..] {
+                *b = 0;
+            }
+            Ok(())
+        }
+
+        match self {
+            BackingFile::Raw(file) => read_zero_filled(file, address, buf),
+            BackingFile::Qcow(qcow) => read_zero_filled(qcow.as_mut(), address, buf),
+        }
+    }
+}
+
+// Returns the path of the backing file `name` refers to. Relative names are relative to the
+// directory holding the image, found through the file descriptor it was opened with.
+fn backing_file_path(file: &RawFile, name: &str) -> PathBuf {
+    let path = Path::new(name);
+    if path.is_absolute() {
+        return path.to_path_buf();
+    }
+
+    match fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())) {
+        Ok(image_path) => match image_path.parent() {
+            Some(dir) => dir.join(path),
+            None => path.to_path_buf(),
+        },
+        Err(_) => path.to_path_buf(),
+    }
+}
+
 fn max_refcount_clusters(refcount_order: u32, cluster_size: u32, num_clusters: u32) -> u64 {
     // Use u64 as the product of the u32 inputs can overflow.
     let refcount_bytes = (0x01 << u64::from(refcount_order)) / 8;
@@ -370,7 +502,7 @@ fn max_refcount_clusters(refcount_order: u32, cluster_size: u32, num_clusters: u
 /// # use qcow::{self, QcowFile, RawFile};
 /// # fn test(file: std::fs::File) -> std::io::Result<()> {
 ///     let mut raw_img = RawFile::new(file, false);
-///     let mut q = QcowFile::from(raw_img).expect("Can't open qcow file");
+///     let mut q = QcowFile::from(raw_img, false).expect("Can't open qcow file");
 ///     let mut buf = [0u8; 12];
 ///     q.seek(SeekFrom::Start(10 as u64))?;
 ///     q.read(&mut buf[..])?;
@@ -390,12 +522,27 @@ pub struct QcowFile {
     // List of unreferenced clusters available to be used. unref clusters become available once the
     // removal of references to them have been synced to disk.
     avail_clusters: Vec<u64>,
-    //TODO(dgreid) Add support for backing files. - backing_file: Option<Box<QcowFile<T>>>,
+    backing_file: Option<BackingFile>,
+    // Whether clusters read from the backing file get copied into this image.
+    copy_on_read: bool,
 }
 
 impl QcowFile {
     /// Creates a QcowFile from `file`. File must be a valid qcow2 image.
-    pub fn from(mut file: RawFile) -> Result<QcowFile> {
+    ///
+    /// The backing file the image refers to, if any, is opened read-only. A relative backing file
+    /// name is looked up from the directory holding the image. Since the name stored in the image
+    /// can point at any file on the host, images referring to a backing file are rejected unless
+    /// `supports_backing_files` is set.
+    pub fn from(file: RawFile, supports_backing_files: bool) -> Result<QcowFile> {
+        Self::from_with_nesting_depth(file, MAX_NESTING_DEPTH, supports_backing_files)
+    }
+
+    fn from_with_nesting_depth(
+        mut file: RawFile,
+        max_nesting_depth: u32,
+        supports_backing_files: bool,
+    ) -> Result<QcowFile> {
         let header = QcowHeader::new(&mut file)?;
 
         // Only v2 and v3 files are supported.
@@ -412,17 +559,48 @@ impl QcowFile {
         if cluster_bits < MIN_CLUSTER_BITS || cluster_bits > MAX_CLUSTER_BITS {
             return Err(Error::InvalidClusterSize);
         }
-        let cluster_size = 0x01u64 << cluster_bits;
 
         // Limit the total size of the disk.
         if header.size > MAX_QCOW_FILE_SIZE {
             return Err(Error::FileTooBig(header.size));
         }
 
-        // No current support for backing files.
-        if header.backing_file_offset != 0 {
-            return Err(Error::BackingFilesNotSupported);
-        }
+        let backing_file = if header.backing_file_offset != 0 {
+            if !supports_backing_files {
+                return Err(Error::BackingFilesDisabled);
+            }
+            if max_nesting_depth == 0 {
+                return Err(Error::MaxNestingDepthExceeded);
+            }
+            if header.backing_file_size > MAX_BACKING_FILE_SIZE {
+                return Err(Error::BackingFileTooLong(header.backing_file_size));
+            }
+            file.seek(SeekFrom::Start(header.backing_file_offset))
+                .map_err(Error::SeekingFile)?;
+            let mut name = vec![0u8; header.backing_file_size as usize];
+            file.read_exact(&mut name).map_err(Error::ReadingHeader)?;
+            let name = String::from_utf8(name).map_err(|_| Error::InvalidBackingFileName)?;
+            Some(BackingFile::open(
+                &backing_file_path(&file, &name),
+                None,
+                max_nesting_depth - 1,
+                supports_backing_files,
+            )?)
+        } else {
+            None
+        };
+
+        Self::from_header(file, header, backing_file)
+    }
+
+    // Loads the tables of the image described by `header`, reading the clusters it hasn't
+    // allocated from `backing_file`.
+    fn from_header(
+        mut file: RawFile,
+        header: QcowHeader,
+        backing_file: Option<BackingFile>,
+    ) -> Result<QcowFile> {
+        let cluster_size = 0x01u64 << header.cluster_bits;
 
         // Only support two byte refcounts.
         let refcount_bits: u64 = 0x01u64
@@ -437,7 +615,6 @@ impl QcowFile {
         if header.refcount_table_clusters == 0 {
             return Err(Error::NoRefcountClusters);
         }
-        offset_is_cluster_boundary(header.backing_file_offset, header.cluster_bits)?;
         offset_is_cluster_boundary(header.l1_table_offset, header.cluster_bits)?;
         offset_is_cluster_boundary(header.snapshots_offset, header.cluster_bits)?;
         // refcount table must be a cluster boundary, and within the file's virtual or actual size.
@@ -526,6 +703,8 @@ impl QcowFile {
             current_offset: 0,
             unref_clusters: Vec::new(),
             avail_clusters: Vec::new(),
+            backing_file,
+            copy_on_read: false,
         };
 
         // Check that the L1 and refcount tables fit in a 64bit address space.
@@ -544,12 +723,64 @@ impl QcowFile {
     }
 
     /// Creates a new QcowFile at the given path.
-    pub fn new(mut file: RawFile, version: u32, virtual_size: u64) -> Result<QcowFile> {
+    pub fn new(file: RawFile, version: u32, virtual_size: u64) -> Result<QcowFile> {
         let header = QcowHeader::create_for_size(version, virtual_size);
+        Self::new_from_header(file, header, None)
+    }
+
+    /// Creates a new QcowFile at the given path, as an overlay of the image found at
+    /// `backing_file_path`. The new image has the same virtual size as the backing file.
+    ///
+    /// The backing file is opened as an image of type `backing_file_type`, or of the type
+    /// detected from its content when none is given. Only when `supports_backing_files` is set
+    /// can the backing file itself refer to another backing file.
+    pub fn new_from_backing(
+        file: RawFile,
+        version: u32,
+        backing_file_path: &Path,
+        backing_file_type: Option<ImageType>,
+        supports_backing_files: bool,
+    ) -> Result<QcowFile> {
+        let name = backing_file_path
+            .to_str()
+            .ok_or(Error::InvalidBackingFileName)?;
+        if name.len() > MAX_BACKING_FILE_SIZE as usize {
+            return Err(Error::BackingFileTooLong(name.len() as u32));
+        }
+        let mut backing_file = BackingFile::open(
+            backing_file_path,
+            backing_file_type,
+            MAX_NESTING_DEPTH - 1,
+            supports_backing_files,
+        )?;
+        let virtual_size = backing_file.size().map_err(Error::GettingFileSize)?;
+
+        let mut header = QcowHeader::create_for_size(version, virtual_size);
+        // The name is stored right after the header, in the first cluster.
+        header.backing_file_offset = u64::from(header.header_size);
+        header.backing_file_size = name.len() as u32;
+        Self::new_from_header(file, header, Some((name, backing_file)))
+    }
+
+    fn new_from_header(
+        mut file: RawFile,
+        header: QcowHeader,
+        backing_file: Option<(&str, BackingFile)>,
+    ) -> Result<QcowFile> {
         file.seek(SeekFrom::Start(0)).map_err(Error::SeekingFile)?;
         header.write_to(&mut file)?;
+        let backing_file = match backing_file {
+            Some((name, backing_file)) => {
+                file.seek(SeekFrom::Start(header.backing_file_offset))
+                    .map_err(Error::SeekingFile)?;
+                file.write_all(name.as_bytes())
+                    .map_err(Error::WritingHeader)?;
+                Some(backing_file)
+            }
+            None => None,
+        };
 
-        let mut qcow = Self::from(file)?;
+        let mut qcow = Self::from_header(file, header, backing_file)?;
 
         // Set the refcount for each refcount table cluster.
         let cluster_size = 0x01u64 << qcow.header.cluster_bits;
@@ -569,11 +800,236 @@ impl QcowFile {
         Ok(qcow)
     }
 
+    /// Grows the virtual size of the image to `new_size` bytes.
+    ///
+    /// The L1 and refcount tables are moved to the end of the file when their current location
+    /// can't hold the number of entries needed for the new size. Shrinking isn't supported.
+    pub fn resize(&mut self, new_size: u64) -> Result<()> {
+        if new_size < self.virtual_size() {
+            return Err(Error::ShrinkNotSupported);
+        }
+        if new_size == self.virtual_size() {
+            return Ok(());
+        }
+        if new_size > MAX_QCOW_FILE_SIZE {
+            return Err(Error::FileTooBig(new_size));
+        }
+
+        // Everything cached must be on disk before the tables are copied around.
+        self.sync_caches().map_err(Error::SyncingCaches)?;
+
+        let cluster_size = self.raw_file.cluster_size();
+        let pointer_size = size_of::<u64>() as u64;
+
+        let num_clusters = div_round_up_u64(new_size, cluster_size);
+        let num_l2_clusters = div_round_up_u64(num_clusters, self.l2_entries);
+        if num_l2_clusters > MAX_RAM_POINTER_TABLE_SIZE {
+            return Err(Error::TooManyL1Entries(num_l2_clusters));
+        }
+        // Same computation as in `from()` so that reopening the file reads the whole table.
+        let refcount_clusters = max_refcount_clusters(
+            self.header.refcount_order,
+            cluster_size as u32,
+            (num_clusters
+                + div_round_up_u64(num_l2_clusters, cluster_size)
+                + num_l2_clusters
+                + div_round_up_u64(size_of::<QcowHeader>() as u64, cluster_size))
+                as u32,
+        );
+
+        let old_l1_clusters =
+            div_round_up_u64(u64::from(self.header.l1_size) * pointer_size, cluster_size);
+        let new_l1_clusters = div_round_up_u64(num_l2_clusters * pointer_size, cluster_size);
+        let old_ref_table_clusters = u64::from(self.header.refcount_table_clusters);
+        let new_ref_table_clusters =
+            div_round_up_u64(refcount_clusters * pointer_size, cluster_size);
+
+        let file_size = self
+            .raw_file
+            .file_mut()
+            .metadata()
+            .map_err(Error::GettingFileSize)?
+            .len();
+        let mut file_end = div_round_up_u64(file_size, cluster_size) * cluster_size;
+
+        let mut new_clusters = Vec::new();
+        let mut old_clusters = Vec::new();
+        let mut header = self.header;
+
+        if new_l1_clusters > old_l1_clusters {
+            for i in 0..old_l1_clusters {
+                old_clusters.push(header.l1_table_offset + i * cluster_size);
+            }
+            header.l1_table_offset = file_end;
+            for i in 0..new_l1_clusters {
+                new_clusters.push(header.l1_table_offset + i * cluster_size);
+            }
+            file_end += new_l1_clusters * cluster_size;
+        }
+
+        let ref_table_entries = min(
+            old_ref_table_clusters * cluster_size / pointer_size,
+            refcount_clusters,
+        );
+        let ref_table = self
+            .raw_file
+            .read_pointer_table(header.refcount_table_offset, ref_table_entries, None)
+            .map_err(Error::ReadingPointers)?;
+        if new_ref_table_clusters > old_ref_table_clusters {
+            for i in 0..old_ref_table_clusters {
+                old_clusters.push(header.refcount_table_offset + i * cluster_size);
+            }
+            header.refcount_table_offset = file_end;
+            header.refcount_table_clusters = new_ref_table_clusters as u32;
+            for i in 0..new_ref_table_clusters {
+                new_clusters.push(header.refcount_table_offset + i * cluster_size);
+            }
+            file_end += new_ref_table_clusters * cluster_size;
+        }
+
+        if file_end > file_size {
+            self.raw_file
+                .file_mut()
+                .set_len(file_end)
+                .map_err(Error::SettingFileSize)?;
+        }
+
+        let mut l1_table = self.l1_table.get_values().to_vec();
+        l1_table.resize(num_l2_clusters as usize, 0);
+        self.raw_file
+            .write_pointer_table(header.l1_table_offset, &l1_table, 0)
+            .map_err(Error::WritingHeader)?;
+        self.raw_file
+            .write_pointer_table(header.refcount_table_offset, &ref_table, 0)
+            .map_err(Error::WritingHeader)?;
+
+        header.size = new_size;
+        header.l1_size = num_l2_clusters as u32;
+        self.raw_file
+            .file_mut()
+            .seek(SeekFrom::Start(0))
+            .map_err(Error::SeekingFile)?;
+        header.write_to(self.raw_file.file_mut())?;
+        // write_to() zeroes the last bytes of the refcount table to size the file, restore them.
+        self.raw_file
+            .write_pointer_table(header.refcount_table_offset, &ref_table, 0)
+            .map_err(Error::WritingHeader)?;
+        self.raw_file
+            .file_mut()
+            .sync_all()
+            .map_err(Error::SyncingCaches)?;
+
+        // Reload the tables from the updated file, keeping the backing file already opened, then
+        // account for the moved tables.
+        let mut file = self.raw_file.file_mut().clone();
+        let header = QcowHeader::new(&mut file)?;
+        let mut qcow = QcowFile::from_header(file, header, self.backing_file.clone())?;
+        qcow.current_offset = self.current_offset;
+        qcow.copy_on_read = self.copy_on_read;
+        qcow.avail_clusters
+            .retain(|addr| !new_clusters.contains(addr));
+        for addr in new_clusters {
+            let mut unref_clusters = qcow
+                .set_cluster_refcount(addr, 1)
+                .map_err(Error::SettingRefcountRefcount)?;
+            qcow.unref_clusters.append(&mut unref_clusters);
+        }
+        for addr in old_clusters {
+            let mut unref_clusters = qcow
+                .set_cluster_refcount(addr, 0)
+                .map_err(Error::SettingRefcountRefcount)?;
+            qcow.unref_clusters.append(&mut unref_clusters);
+            // As when deallocating clusters, the file system may not support
+            // punching holes, the old tables being unreferenced anyway.
+            if let Err(e) = qcow.raw_file.file_mut().punch_hole(addr, cluster_size) {
+                warn!(
+                    "Failed to deallocate the old table cluster at {:#x}: {}",
+                    addr, e
+                );
+            }
+            qcow.unref_clusters.push(addr);
+        }
+        qcow.flush().map_err(Error::SyncingCaches)?;
+
+        *self = qcow;
+
+        Ok(())
+    }
+
+    /// Returns true if clusters not allocated in this image are read from a backing file.
+    pub fn has_backing_file(&self) -> bool {
+        self.backing_file.is_some()
+    }
+
+    /// Sets whether clusters read from the backing file are copied into this image, so that
+    /// subsequent reads of the same data don't reach the backing file anymore.
+    pub fn set_copy_on_read(&mut self, copy_on_read: bool) {
+        self.copy_on_read = copy_on_read;
+    }
+
+    /// Copies the cluster holding `address` from the backing file into this image, unless it is
+    /// already allocated or reads as zeros. Returns the number of bytes up to the next cluster.
+    pub fn stream_cluster(&mut self, address: u64) -> std::io::Result<u64> {
+        if address >= self.virtual_size() {
+            return Err(std::io::Error::from_raw_os_error(EINVAL));
+        }
+
+        let cluster_size = self.raw_file.cluster_size();
+        let cluster_start = address - self.raw_file.cluster_offset(address);
+        let count =
+            min(cluster_size, self.virtual_size() - cluster_start) - (address - cluster_start);
+
+        if self.file_offset_read(address)?.is_some() {
+            return Ok(count);
+        }
+        if let Some(backing_file) = &mut self.backing_file {
+            let mut data = vec![0u8; cluster_size as usize];
+            backing_file.read_at(cluster_start, &mut data)?;
+            // Unallocated clusters read as zeros once the backing file is gone.
+            if data.iter().any(|b| *b != 0) {
+                self.cluster_offset_write(cluster_start, Some(&data))?;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Removes the reference to the backing file from the image. Clusters that haven't been
+    /// copied by `stream_cluster()` beforehand read as zeros afterwards.
+    pub fn drop_backing_file(&mut self) -> Result<()> {
+        if self.backing_file.is_none() {
+            return Ok(());
+        }
+
+        // The streamed clusters must be reachable before the backing file is forgotten.
+        self.sync_caches().map_err(Error::SyncingCaches)?;
+
+        let file = self.raw_file.file_mut();
+        // The backing file offset and size follow the magic and version in the header.
+        file.seek(SeekFrom::Start(8)).map_err(Error::SeekingFile)?;
+        file.write_u64::<BigEndian>(0)
+            .map_err(Error::WritingHeader)?;
+        file.write_u32::<BigEndian>(0)
+            .map_err(Error::WritingHeader)?;
+        file.sync_all().map_err(Error::SyncingCaches)?;
+
+        self.header.backing_file_offset = 0;
+        self.header.backing_file_size = 0;
+        self.backing_file = None;
+
+        Ok(())
+    }
+
     /// Returns the `QcowHeader` for this file.
     pub fn header(&self) -> &QcowHeader {
         &self.header
     }
 
+    /// Returns the size of the clusters guest data is allocated in.
+    pub fn cluster_size(&self) -> u64 {
+        self.raw_file.cluster_size()
+    }
+
     /// Returns the L1 lookup table for this file. This is only useful for debugging.
     pub fn l1_table(&self) -> &[u64] {
         &self.l1_table.get_values()
@@ -943,9 +1399,9 @@ impl QcowFile {
         (address / self.raw_file.cluster_size()) % self.l2_entries
     }
 
-    // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters have
-    // yet to be allocated, return None.
-    fn file_offset_read(&mut self, address: u64) -> std::io::Result<Option<u64>> {
+    /// Gets the offset of the given guest address in the host file. If L1, L2, or data clusters
+    /// have yet to be allocated, return None.
+    pub fn file_offset_read(&mut self, address: u64) -> std::io::Result<Option<u64>> {
         if address >= self.virtual_size() as u64 {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -986,9 +1442,17 @@ impl QcowFile {
         Ok(Some(cluster_addr + self.raw_file.cluster_offset(address)))
     }
 
-    // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters need
-    // to be allocated, they will be.
-    fn file_offset_write(&mut self, address: u64) -> std::io::Result<u64> {
+    /// Gets the offset of the given guest address in the host file. If L1, L2, or data clusters
+    /// need to be allocated, they will be. Newly allocated data clusters are zeroed, or filled
+    /// from the backing file, before this returns, so the caller can write any part of the cluster
+    /// through the host file directly.
+    pub fn file_offset_write(&mut self, address: u64) -> std::io::Result<u64> {
+        self.cluster_offset_write(address, None)
+    }
+
+    // Same as `file_offset_write()`, except that a newly allocated data cluster is filled with
+    // `data` if provided, saving a read of the backing file the caller already did.
+    fn cluster_offset_write(&mut self, address: u64, data: Option<&[u8]>) -> std::io::Result<u64> {
         if address >= self.virtual_size() as u64 {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -1031,6 +1495,7 @@ impl QcowFile {
                 // Need to allocate a data cluster
                 let cluster_addr = self.append_data_cluster()?;
                 self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                self.fill_data_cluster(address, cluster_addr, data)?;
                 cluster_addr
             }
             a => a,
@@ -1044,6 +1509,32 @@ impl QcowFile {
         Ok(cluster_addr + self.raw_file.cluster_offset(address))
     }
 
+    // Initializes the freshly allocated `cluster_addr` for the cluster holding `address` with
+    // `data`, or with the content of the backing file. The cluster is already zeroed otherwise.
+    fn fill_data_cluster(
+        &mut self,
+        address: u64,
+        cluster_addr: u64,
+        data: Option<&[u8]>,
+    ) -> std::io::Result<()> {
+        let mut backing_data;
+        let data = match (data, &mut self.backing_file) {
+            (Some(data), _) => data,
+            (None, Some(backing_file)) => {
+                backing_data = vec![0u8; self.raw_file.cluster_size() as usize];
+                let cluster_start = address - self.raw_file.cluster_offset(address);
+                backing_file.read_at(cluster_start, &mut backing_data)?;
+                &backing_data
+            }
+            (None, None) => return Ok(()),
+        };
+
+        self.raw_file
+            .file_mut()
+            .seek(SeekFrom::Start(cluster_addr))?;
+        self.raw_file.file_mut().write_all(data)
+    }
+
     // Updates the l1 and l2 tables to point to the new `cluster_addr`.
     fn update_cluster_addr(
         &mut self,
@@ -1262,7 +1753,13 @@ impl QcowFile {
             let curr_addr = address + nwritten as u64;
             let count = self.limit_range_cluster(curr_addr, write_count - nwritten);
 
-            if count == self.raw_file.cluster_size() as usize {
+            if self.backing_file.is_some() {
+                // Deallocated clusters would expose the content of the backing file, write zeros
+                // into this image instead.
+                let offset = self.file_offset_write(curr_addr)?;
+                self.raw_file.file_mut().seek(SeekFrom::Start(offset))?;
+                self.raw_file.file_mut().write_zeroes(count)?;
+            } else if count == self.raw_file.cluster_size() as usize {
                 // Full cluster - deallocate the storage.
                 self.deallocate_cluster(curr_addr)?;
             } else {
@@ -1399,6 +1896,12 @@ impl Drop for QcowFile {
     }
 }
 
+impl AsRawFd for QcowFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.raw_file.file().as_raw_fd()
+    }
+}
+
 impl Read for QcowFile {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let address: u64 = self.current_offset as u64;
@@ -1407,14 +1910,21 @@ impl Read for QcowFile {
         let mut nread: usize = 0;
         while nread < read_count {
             let curr_addr = address + nread as u64;
-            let file_offset = self.file_offset_read(curr_addr)?;
+            let mut file_offset = self.file_offset_read(curr_addr)?;
             let count = self.limit_range_cluster(curr_addr, read_count - nread);
 
+            if file_offset.is_none() && self.copy_on_read && self.backing_file.is_some() {
+                // Pull the whole cluster in from the backing file.
+                file_offset = Some(self.file_offset_write(curr_addr)?);
+            }
+
             if let Some(offset) = file_offset {
                 self.raw_file.file_mut().seek(SeekFrom::Start(offset))?;
                 self.raw_file
                     .file_mut()
                     .read_exact(&mut buf[nread..(nread + count)])?;
+            } else if let Some(backing_file) = &mut self.backing_file {
+                backing_file.read_at(curr_addr, &mut buf[nread..(nread + count)])?;
             } else {
                 // Previously unwritten region, return zeros
                 for b in &mut buf[nread..(nread + count)] {
@@ -1527,7 +2037,13 @@ impl PunchHole for QcowFile {
 
 impl SeekHole for QcowFile {
     fn seek_hole(&mut self, offset: u64) -> io::Result<Option<u64>> {
-        match self.find_allocated_cluster(offset, false) {
+        // Clusters this image doesn't allocate hold the backing file data, not holes.
+        let find_result = if self.backing_file.is_some() {
+            Ok(None)
+        } else {
+            self.find_allocated_cluster(offset, false)
+        };
+        match find_result {
             Err(e) => Err(e),
             Ok(None) => {
                 if offset < self.virtual_size() {
@@ -1544,7 +2060,12 @@ impl SeekHole for QcowFile {
     }
 
     fn seek_data(&mut self, offset: u64) -> io::Result<Option<u64>> {
-        match self.find_allocated_cluster(offset, true) {
+        let find_result = if self.backing_file.is_some() && offset < self.virtual_size() {
+            Ok(Some(offset))
+        } else {
+            self.find_allocated_cluster(offset, true)
+        };
+        match find_result {
             Err(e) => Err(e),
             Ok(None) => Ok(None),
             Ok(Some(o)) => {
@@ -1659,6 +2180,15 @@ where
                 .map_err(Error::SettingFileSize)?;
             convert_reader_writer(reader, &mut dst_writer, src_size)
         }
+        ImageType::FixedVhd => {
+            let mut dst_writer = FixedVhdFile::create(dst_file, src_size).map_err(Error::Vhd)?;
+            convert_reader_writer(reader, &mut dst_writer, src_size)
+        }
+        ImageType::Vhdx => {
+            let mut dst_writer = VhdxFile::new(dst_file, src_size).map_err(Error::Vhdx)?;
+            convert_reader_writer(reader, &mut dst_writer, src_size)?;
+            dst_writer.flush().map_err(Error::WritingData)
+        }
     }
 }
 
@@ -1669,7 +2199,7 @@ pub fn convert(mut src_file: RawFile, dst_file: RawFile, dst_type: ImageType) ->
     let src_type = detect_image_type(&mut src_file)?;
     match src_type {
         ImageType::Qcow2 => {
-            let mut src_reader = QcowFile::from(src_file)?;
+            let mut src_reader = QcowFile::from(src_file, false)?;
             convert_reader(&mut src_reader, dst_file, dst_type)
         }
         ImageType::Raw => {
@@ -1677,10 +2207,19 @@ pub fn convert(mut src_file: RawFile, dst_file: RawFile, dst_type: ImageType) ->
             let mut src_reader = src_file;
             convert_reader(&mut src_reader, dst_file, dst_type)
         }
+        ImageType::FixedVhd => {
+            let mut src_reader = FixedVhdFile::new(src_file).map_err(Error::Vhd)?;
+            convert_reader(&mut src_reader, dst_file, dst_type)
+        }
+        ImageType::Vhdx => {
+            let mut src_reader = VhdxFile::from(src_file).map_err(Error::Vhdx)?;
+            convert_reader(&mut src_reader, dst_file, dst_type)
+        }
     }
 }
 
-/// Detect the type of an image file by checking for a valid qcow2 header.
+/// Detect the type of an image file by checking for a valid qcow2 header, a VHDX file
+/// identifier or a fixed VHD footer.
 pub fn detect_image_type(file: &mut RawFile) -> Result<ImageType> {
     let orig_seek = file
         .seek(SeekFrom::Current(0))
@@ -1689,6 +2228,10 @@ pub fn detect_image_type(file: &mut RawFile) -> Result<ImageType> {
     let magic = file.read_u32::<BigEndian>().map_err(Error::ReadingHeader)?;
     let image_type = if magic == QCOW_MAGIC {
         ImageType::Qcow2
+    } else if vhdx::is_vhdx(file).map_err(Error::ReadingHeader)? {
+        ImageType::Vhdx
+    } else if vhd::is_fixed_vhd(file).map_err(Error::ReadingHeader)? {
+        ImageType::FixedVhd
     } else {
         ImageType::Raw
     };
@@ -1700,8 +2243,9 @@ pub fn detect_image_type(file: &mut RawFile) -> Result<ImageType> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs::File;
     use std::io::{Read, Seek, SeekFrom, Write};
-    use tempfile::tempfile;
+    use tempfile::{tempdir, tempfile};
 
     fn valid_header_v3() -> Vec<u8> {
         vec![
@@ -1790,6 +2334,59 @@ mod tests {
         testfn(qcow_file); // File closed when the function exits.
     }
 
+    // Creates a qcow image of `size` bytes at `path`, backed by the file named `backing_name`.
+    fn create_overlay(path: &Path, backing_name: &str, size: u64) {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+        drop(QcowFile::new(RawFile::new(file.try_clone().unwrap(), false), 3, size).unwrap());
+
+        // Store the name right after the header, in the first cluster.
+        let mut raw = RawFile::new(file, false);
+        raw.seek(SeekFrom::Start(u64::from(V3_BARE_HEADER_SIZE)))
+            .unwrap();
+        raw.write_all(backing_name.as_bytes()).unwrap();
+        raw.seek(SeekFrom::Start(8)).unwrap();
+        raw.write_u64::<BigEndian>(u64::from(V3_BARE_HEADER_SIZE))
+            .unwrap();
+        raw.write_u32::<BigEndian>(backing_name.len() as u32)
+            .unwrap();
+    }
+
+    fn open_overlay(path: &Path) -> QcowFile {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        QcowFile::from(RawFile::new(file, false), true).unwrap()
+    }
+
+    // Returns the content of the raw backing file used by the tests, spanning 3.5 clusters.
+    fn backing_data() -> Vec<u8> {
+        (0..0x3_8000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn with_overlay_file<F>(mut testfn: F)
+    where
+        F: FnMut(&Path, QcowFile),
+    {
+        let dir = tempdir().unwrap();
+        let backing_path = dir.path().join("backing.raw");
+        File::create(&backing_path)
+            .unwrap()
+            .write_all(&backing_data())
+            .unwrap();
+        let overlay_path = dir.path().join("overlay.qcow2");
+        create_overlay(&overlay_path, "backing.raw", 0x10_0000);
+
+        testfn(&overlay_path, open_overlay(&overlay_path));
+    }
+
     #[test]
     fn default_header_v2() {
         let header = QcowHeader::create_for_size(2, 0x10_0000);
@@ -1798,7 +2395,7 @@ mod tests {
             .write_to(&mut disk_file)
             .expect("Failed to write header to temporary file.");
         disk_file.seek(SeekFrom::Start(0)).unwrap();
-        QcowFile::from(disk_file).expect("Failed to create Qcow from default Header");
+        QcowFile::from(disk_file, false).expect("Failed to create Qcow from default Header");
     }
 
     #[test]
@@ -1809,7 +2406,7 @@ mod tests {
             .write_to(&mut disk_file)
             .expect("Failed to write header to temporary file.");
         disk_file.seek(SeekFrom::Start(0)).unwrap();
-        QcowFile::from(disk_file).expect("Failed to create Qcow from default Header");
+        QcowFile::from(disk_file, false).expect("Failed to create Qcow from default Header");
     }
 
     #[test]
@@ -1841,7 +2438,7 @@ mod tests {
         let mut header = valid_header_v3();
         header[99] = 2;
         with_basic_file(&header, |disk_file: RawFile| {
-            QcowFile::from(disk_file).expect_err("Invalid refcount order worked.");
+            QcowFile::from(disk_file, false).expect_err("Invalid refcount order worked.");
         });
     }
 
@@ -1850,7 +2447,7 @@ mod tests {
         let mut header = valid_header_v3();
         header[23] = 3;
         with_basic_file(&header, |disk_file: RawFile| {
-            QcowFile::from(disk_file).expect_err("Failed to create file.");
+            QcowFile::from(disk_file, false).expect_err("Failed to create file.");
         });
     }
 
@@ -1858,7 +2455,7 @@ mod tests {
     fn test_header_huge_file() {
         let header = test_huge_header();
         with_basic_file(&header, |disk_file: RawFile| {
-            QcowFile::from(disk_file).expect_err("Failed to create file.");
+            QcowFile::from(disk_file, false).expect_err("Failed to create file.");
         });
     }
 
@@ -1867,7 +2464,7 @@ mod tests {
         let mut header = valid_header_v3();
         &mut header[24..32].copy_from_slice(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x1e]);
         with_basic_file(&header, |disk_file: RawFile| {
-            QcowFile::from(disk_file).expect_err("Failed to create file.");
+            QcowFile::from(disk_file, false).expect_err("Failed to create file.");
         });
     }
 
@@ -1876,7 +2473,7 @@ mod tests {
         let mut header = valid_header_v3();
         header[36] = 0x12;
         with_basic_file(&header, |disk_file: RawFile| {
-            QcowFile::from(disk_file).expect_err("Failed to create file.");
+            QcowFile::from(disk_file, false).expect_err("Failed to create file.");
         });
     }
 
@@ -1888,7 +2485,7 @@ mod tests {
         header[31] = 0;
         // 1 TB with the min cluster size makes the arrays too big, it should fail.
         with_basic_file(&header, |disk_file: RawFile| {
-            QcowFile::from(disk_file).expect_err("Failed to create file.");
+            QcowFile::from(disk_file, false).expect_err("Failed to create file.");
         });
     }
 
@@ -1902,7 +2499,7 @@ mod tests {
         // set cluster_bits
         header[23] = 16;
         with_basic_file(&header, |disk_file: RawFile| {
-            let mut qcow = QcowFile::from(disk_file).expect("Failed to create file.");
+            let mut qcow = QcowFile::from(disk_file, false).expect("Failed to create file.");
             qcow.seek(SeekFrom::Start(0x100_0000_0000 - 8))
                 .expect("Failed to seek.");
             let value = 0x0000_0040_3f00_ffffu64;
@@ -1916,7 +2513,8 @@ mod tests {
         let mut header = valid_header_v3();
         &mut header[56..60].copy_from_slice(&[0x02, 0x00, 0xe8, 0xff]);
         with_basic_file(&header, |disk_file: RawFile| {
-            QcowFile::from(disk_file).expect_err("Created disk with crazy refcount clusters");
+            QcowFile::from(disk_file, false)
+                .expect_err("Created disk with crazy refcount clusters");
         });
     }
 
@@ -1925,14 +2523,14 @@ mod tests {
         let mut header = valid_header_v3();
         &mut header[48..56].copy_from_slice(&[0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x02, 0x00]);
         with_basic_file(&header, |disk_file: RawFile| {
-            QcowFile::from(disk_file).expect_err("Created disk with crazy refcount offset");
+            QcowFile::from(disk_file, false).expect_err("Created disk with crazy refcount offset");
         });
     }
 
     #[test]
     fn write_read_start() {
         with_basic_file(&valid_header_v3(), |disk_file: RawFile| {
-            let mut q = QcowFile::from(disk_file).unwrap();
+            let mut q = QcowFile::from(disk_file, false).unwrap();
             q.write(b"test first bytes")
                 .expect("Failed to write test string.");
             let mut buf = [0u8; 4];
@@ -1945,7 +2543,7 @@ mod tests {
     #[test]
     fn offset_write_read() {
         with_basic_file(&valid_header_v3(), |disk_file: RawFile| {
-            let mut q = QcowFile::from(disk_file).unwrap();
+            let mut q = QcowFile::from(disk_file, false).unwrap();
             let b = [0x55u8; 0x1000];
             q.seek(SeekFrom::Start(0xfff2000)).expect("Failed to seek.");
             q.write(&b).expect("Failed to write test string.");
@@ -1959,7 +2557,7 @@ mod tests {
     #[test]
     fn write_zeroes_read() {
         with_basic_file(&valid_header_v3(), |disk_file: RawFile| {
-            let mut q = QcowFile::from(disk_file).unwrap();
+            let mut q = QcowFile::from(disk_file, false).unwrap();
             // Write some test data.
             let b = [0x55u8; 0x1000];
             q.seek(SeekFrom::Start(0xfff2000)).expect("Failed to seek.");
@@ -1985,7 +2583,7 @@ mod tests {
         // valid_header uses cluster_bits = 12, which corresponds to a cluster size of 4096.
         const CHUNK_SIZE: usize = 4096 * 2 + 512;
         with_basic_file(&valid_header_v3(), |disk_file: RawFile| {
-            let mut q = QcowFile::from(disk_file).unwrap();
+            let mut q = QcowFile::from(disk_file, false).unwrap();
             // Write some test data.
             let b = [0x55u8; CHUNK_SIZE];
             q.seek(SeekFrom::Start(0)).expect("Failed to seek.");
@@ -2006,11 +2604,11 @@ mod tests {
     #[test]
     fn test_header() {
         with_basic_file(&valid_header_v2(), |disk_file: RawFile| {
-            let q = QcowFile::from(disk_file).unwrap();
+            let q = QcowFile::from(disk_file, false).unwrap();
             assert_eq!(q.virtual_size(), 0x20_0000_0000);
         });
         with_basic_file(&valid_header_v3(), |disk_file: RawFile| {
-            let q = QcowFile::from(disk_file).unwrap();
+            let q = QcowFile::from(disk_file, false).unwrap();
             assert_eq!(q.virtual_size(), 0x20_0000_0000);
         });
     }
@@ -2018,7 +2616,7 @@ mod tests {
     #[test]
     fn read_small_buffer() {
         with_basic_file(&valid_header_v3(), |disk_file: RawFile| {
-            let mut q = QcowFile::from(disk_file).unwrap();
+            let mut q = QcowFile::from(disk_file, false).unwrap();
             let mut b = [5u8; 16];
             q.seek(SeekFrom::Start(1000)).expect("Failed to seek.");
             q.read(&mut b).expect("Failed to read.");
@@ -2030,7 +2628,7 @@ mod tests {
     #[test]
     fn replay_ext4() {
         with_basic_file(&valid_header_v3(), |disk_file: RawFile| {
-            let mut q = QcowFile::from(disk_file).unwrap();
+            let mut q = QcowFile::from(disk_file, false).unwrap();
             const BUF_SIZE: usize = 0x1000;
             let mut b = [0u8; BUF_SIZE];
 
@@ -2590,6 +3188,125 @@ mod tests {
         });
     }
 
+    #[test]
+    fn resize_grows_virtual_size() {
+        with_default_file(0x10_0000, false, |mut q: QcowFile| {
+            let b = [0x55u8; 0x1000];
+            q.write_all(&b).expect("Failed to write test string.");
+            q.resize(0x4000_0000).expect("Failed to resize.");
+            assert_eq!(
+                q.seek(SeekFrom::End(0)).expect("Failed to seek."),
+                0x4000_0000
+            );
+            q.seek(SeekFrom::Start(0x3fff_f000))
+                .expect("Failed to seek.");
+            q.write_all(&b).expect("Failed to write past the old size.");
+            q.flush().expect("Failed to flush.");
+
+            let mut reopened = QcowFile::from(q.raw_file.file_mut().clone(), false)
+                .expect("Failed to reopen resized file.");
+            assert_eq!(reopened.header().size, 0x4000_0000);
+            let mut buf = [0u8; 0x1000];
+            for offset in &[0, 0x3fff_f000] {
+                reopened
+                    .seek(SeekFrom::Start(*offset))
+                    .expect("Failed to seek.");
+                reopened.read_exact(&mut buf).expect("Failed to read.");
+                assert_eq!(&buf[..], &b[..]);
+            }
+        });
+    }
+
+    #[test]
+    fn resize_shrink_rejected() {
+        with_default_file(0x10_0000, false, |mut q: QcowFile| {
+            assert!(q.resize(0x1000).is_err());
+            assert_eq!(q.header().size, 0x10_0000);
+        });
+    }
+
+    #[test]
+    fn file_offset_direct_access() {
+        with_default_file(0x10_0000, false, |mut q: QcowFile| {
+            assert_eq!(q.file_offset_read(0x2_0200).expect("Failed to map."), None);
+
+            let offset = q.file_offset_write(0x2_0200).expect("Failed to allocate.");
+            assert_eq!(offset % q.cluster_size(), 0x200);
+            assert_eq!(
+                q.file_offset_read(0x2_0200).expect("Failed to map."),
+                Some(offset)
+            );
+
+            let b = [0xaau8; 0x200];
+            let ret = unsafe {
+                libc::pwrite(
+                    q.as_raw_fd(),
+                    b.as_ptr() as *const libc::c_void,
+                    b.len(),
+                    offset as libc::off_t,
+                )
+            };
+            assert_eq!(ret, b.len() as isize);
+
+            let mut buf = [0x55u8; 0x400];
+            q.seek(SeekFrom::Start(0x2_0000)).expect("Failed to seek.");
+            q.read_exact(&mut buf).expect("Failed to read.");
+            assert!(buf[..0x200].iter().all(|v| *v == 0));
+            assert_eq!(&buf[0x200..], &b[..]);
+        });
+    }
+
+    fn convert_round_trip(dst_type: ImageType) {
+        let mut src = RawFile::new(tempfile().expect("failed to create tempfile"), false);
+        src.set_len(0x400_0000).unwrap();
+        let b = [0x5au8; 0x1000];
+        src.seek(SeekFrom::Start(0x200_0000)).unwrap();
+        src.write_all(&b).unwrap();
+
+        let converted = RawFile::new(tempfile().expect("failed to create tempfile"), false);
+        convert(src, converted.clone(), dst_type).expect("failed to convert image");
+
+        let back = RawFile::new(tempfile().expect("failed to create tempfile"), false);
+        convert(converted, back.clone(), ImageType::Raw).expect("failed to convert back");
+
+        let mut back = back;
+        assert_eq!(back.seek(SeekFrom::End(0)).unwrap(), 0x400_0000);
+        let mut buf = [0u8; 0x2000];
+        back.seek(SeekFrom::Start(0x1ff_f000)).unwrap();
+        back.read_exact(&mut buf).unwrap();
+        assert!(buf[..0x1000].iter().all(|v| *v == 0));
+        assert_eq!(&buf[0x1000..], &b[..]);
+    }
+
+    #[test]
+    fn convert_vhdx() {
+        convert_round_trip(ImageType::Vhdx);
+    }
+
+    #[test]
+    fn convert_fixed_vhd() {
+        convert_round_trip(ImageType::FixedVhd);
+    }
+
+    #[test]
+    fn detect_vhd_types() {
+        let file = RawFile::new(tempfile().expect("failed to create tempfile"), false);
+        VhdxFile::new(file.clone(), 0x10_0000).expect("failed to create vhdx");
+        let mut file = file;
+        assert!(matches!(
+            detect_image_type(&mut file).unwrap(),
+            ImageType::Vhdx
+        ));
+
+        let file = RawFile::new(tempfile().expect("failed to create tempfile"), false);
+        FixedVhdFile::create(file.clone(), 0x10_0000).expect("failed to create vhd");
+        let mut file = file;
+        assert!(matches!(
+            detect_image_type(&mut file).unwrap(),
+            ImageType::FixedVhd
+        ));
+    }
+
     #[test]
     fn rebuild_refcounts() {
         with_basic_file(&valid_header_v3(), |mut disk_file: RawFile| {
@@ -2601,4 +3318,180 @@ mod tests {
                 .expect("Failed to rebuild recounts.");
         });
     }
+
+    #[test]
+    fn backing_file_read() {
+        with_overlay_file(|_, mut q| {
+            assert!(q.has_backing_file());
+            let mut buf = vec![0u8; 0x10_0000];
+            q.read_exact(&mut buf).expect("Failed to read.");
+            let backing = backing_data();
+            assert_eq!(&buf[..backing.len()], &backing[..]);
+            // Past the end of the backing file, the image reads as zeros.
+            assert!(buf[backing.len()..].iter().all(|b| *b == 0));
+            // Reads don't allocate anything without copy-on-read.
+            assert_eq!(q.file_offset_read(0).unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn backing_file_partial_write() {
+        with_overlay_file(|path, mut q| {
+            let b = [0x55u8; 0x200];
+            q.seek(SeekFrom::Start(0x1_0400)).expect("Failed to seek.");
+            q.write_all(&b).expect("Failed to write.");
+            q.flush().expect("Failed to flush.");
+
+            let mut expected = backing_data();
+            expected[0x1_0400..0x1_0600].copy_from_slice(&b);
+            let mut reopened = open_overlay(path);
+            let mut buf = vec![0u8; expected.len()];
+            reopened.read_exact(&mut buf).expect("Failed to read.");
+            assert_eq!(buf, expected);
+
+            // The backing file is left untouched.
+            let backing = fs::read(path.parent().unwrap().join("backing.raw")).unwrap();
+            assert_eq!(backing, backing_data());
+        });
+    }
+
+    #[test]
+    fn backing_file_write_zeroes() {
+        with_overlay_file(|_, mut q| {
+            q.punch_hole(0x8000, 0x2_0000)
+                .expect("Failed to punch hole.");
+            let mut buf = vec![0u8; 0x3_8000];
+            q.read_exact(&mut buf).expect("Failed to read.");
+            let backing = backing_data();
+            assert_eq!(&buf[..0x8000], &backing[..0x8000]);
+            assert!(buf[0x8000..0x2_8000].iter().all(|b| *b == 0));
+            assert_eq!(&buf[0x2_8000..], &backing[0x2_8000..]);
+        });
+    }
+
+    #[test]
+    fn backing_file_copy_on_read() {
+        with_overlay_file(|_, mut q| {
+            q.set_copy_on_read(true);
+            q.seek(SeekFrom::Start(0x1_0010)).expect("Failed to seek.");
+            let mut buf = [0u8; 0x10];
+            q.read_exact(&mut buf).expect("Failed to read.");
+            assert_eq!(&buf[..], &backing_data()[0x1_0010..0x1_0020]);
+            assert!(q.file_offset_read(0x1_0000).unwrap().is_some());
+            assert_eq!(q.file_offset_read(0).unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn backing_file_stream() {
+        with_overlay_file(|path, mut q| {
+            let mut offset = 0;
+            while offset < 0x10_0000 {
+                offset += q.stream_cluster(offset).expect("Failed to stream.");
+            }
+            q.drop_backing_file().expect("Failed to drop backing file.");
+            assert!(!q.has_backing_file());
+            drop(q);
+
+            fs::remove_file(path.parent().unwrap().join("backing.raw")).unwrap();
+            let mut reopened = open_overlay(path);
+            assert!(!reopened.has_backing_file());
+            let mut buf = vec![0u8; 0x10_0000];
+            reopened.read_exact(&mut buf).expect("Failed to read.");
+            let backing = backing_data();
+            assert_eq!(&buf[..backing.len()], &backing[..]);
+            assert!(buf[backing.len()..].iter().all(|b| *b == 0));
+            // Clusters reading as zeros aren't copied.
+            assert_eq!(reopened.file_offset_read(0x8_0000).unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn backing_file_chain() {
+        with_overlay_file(|path, q| {
+            drop(q);
+            let top_path = path.parent().unwrap().join("top.qcow2");
+            create_overlay(&top_path, path.to_str().unwrap(), 0x10_0000);
+            let mut top = open_overlay(&top_path);
+            let mut buf = vec![0u8; 0x3_8000];
+            top.read_exact(&mut buf).expect("Failed to read.");
+            assert_eq!(buf, backing_data());
+        });
+    }
+
+    #[test]
+    fn backing_file_loop() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("loop.qcow2");
+        create_overlay(&path, "loop.qcow2", 0x10_0000);
+        let file = File::open(&path).unwrap();
+        assert!(QcowFile::from(RawFile::new(file, false), true).is_err());
+    }
+
+    #[test]
+    fn new_from_backing() {
+        let dir = tempdir().unwrap();
+        let backing_path = dir.path().join("backing.raw");
+        File::create(&backing_path)
+            .unwrap()
+            .write_all(&backing_data())
+            .unwrap();
+
+        let file = RawFile::new(tempfile().unwrap(), false);
+        let mut q = QcowFile::new_from_backing(file, 3, &backing_path, None, false)
+            .expect("Failed to create overlay.");
+        assert!(q.has_backing_file());
+        assert_eq!(q.seek(SeekFrom::End(0)).unwrap(), 0x3_8000);
+
+        let b = [0x55u8; 0x1000];
+        q.seek(SeekFrom::Start(0x2_0000)).expect("Failed to seek.");
+        q.write_all(&b).expect("Failed to write.");
+        q.flush().expect("Failed to flush.");
+
+        let mut reopened =
+            QcowFile::from(q.raw_file.file_mut().clone(), true).expect("Failed to reopen overlay.");
+        let mut expected = backing_data();
+        expected[0x2_0000..0x2_1000].copy_from_slice(&b);
+        let mut buf = vec![0u8; expected.len()];
+        reopened.read_exact(&mut buf).expect("Failed to read.");
+        assert_eq!(buf, expected);
+        assert_eq!(fs::read(&backing_path).unwrap(), backing_data());
+    }
+
+    #[test]
+    fn backing_files_disabled() {
+        with_overlay_file(|path, q| {
+            drop(q);
+            let file = File::open(path).unwrap();
+            match QcowFile::from(RawFile::new(file, false), false) {
+                Err(Error::BackingFilesDisabled) => {}
+                _ => panic!("Opened an image with a backing file."),
+            }
+        });
+    }
+
+    #[test]
+    fn new_from_backing_forced_raw() {
+        with_overlay_file(|path, q| {
+            drop(q);
+            // The backing file looks like a qcow2 image referring to another backing file, yet it
+            // is exposed as is when its type is forced to raw.
+            let file = RawFile::new(tempfile().unwrap(), false);
+            let mut overlay =
+                QcowFile::new_from_backing(file, 3, path, Some(ImageType::Raw), false)
+                    .expect("Failed to create overlay.");
+            let mut buf = [0u8; 4];
+            overlay.read_exact(&mut buf).expect("Failed to read.");
+            assert_eq!(u32::from_be_bytes(buf), QCOW_MAGIC);
+
+            let file = RawFile::new(tempfile().unwrap(), false);
+            match QcowFile::new_from_backing(file, 3, path, None, false) {
+                Err(Error::BackingFile(e)) => match *e {
+                    Error::BackingFilesDisabled => {}
+                    e => panic!("Unexpected error: {}", e),
+                },
+                _ => panic!("Opened a backing file with a backing file."),
+            }
+        });
+    }
 }
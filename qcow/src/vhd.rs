@@ -0,0 +1,445 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Fixed VHD images: the guest data stored as is, followed by a 512 bytes
+//! footer describing the disk.
+
+use std::cmp::min;
+use std::fmt::{self, Display};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder};
+use libc::EINVAL;
+use vmm_sys_util::seek_hole::SeekHole;
+
+use crate::RawFile;
+
+const FOOTER_SIZE: u64 = 512;
+const FOOTER_COOKIE: &[u8; 8] = b"conectix";
+const FOOTER_FEATURES: u32 = 0x2;
+const FOOTER_VERSION: u32 = 0x0001_0000;
+const FOOTER_FIXED_DATA_OFFSET: u64 = 0xffff_ffff_ffff_ffff;
+const FOOTER_CREATOR_APP: &[u8; 4] = b"ch  ";
+const FOOTER_CREATOR_HOST_OS: u32 = 0x5769_326b; // "Wi2k", as expected by Hyper-V.
+const DISK_TYPE_FIXED: u32 = 2;
+// Seconds between the Unix epoch and the VHD one (January 1, 2000).
+const VHD_EPOCH_OFFSET: u64 = 946_684_800;
+
+// Footer field offsets.
+const FOOTER_OFFSET_FEATURES: usize = 8;
+const FOOTER_OFFSET_VERSION: usize = 12;
+const FOOTER_OFFSET_DATA_OFFSET: usize = 16;
+const FOOTER_OFFSET_TIMESTAMP: usize = 24;
+const FOOTER_OFFSET_CREATOR_APP: usize = 28;
+const FOOTER_OFFSET_CREATOR_HOST_OS: usize = 36;
+const FOOTER_OFFSET_ORIGINAL_SIZE: usize = 40;
+const FOOTER_OFFSET_CURRENT_SIZE: usize = 48;
+const FOOTER_OFFSET_GEOMETRY: usize = 56;
+const FOOTER_OFFSET_DISK_TYPE: usize = 60;
+const FOOTER_OFFSET_CHECKSUM: usize = 64;
+const FOOTER_OFFSET_UNIQUE_ID: usize = 68;
+
+#[derive(Debug)]
+pub enum Error {
+    /// `InvalidChecksum` - The footer checksum doesn't match its content.
+    InvalidChecksum,
+    /// `InvalidCookie` - The footer doesn't start with the VHD cookie.
+    InvalidCookie,
+    /// `InvalidSize` - The size stored in the footer doesn't fit the file.
+    InvalidSize(u64),
+    /// `ReadingFooter` - Error reading the footer from the file.
+    ReadingFooter(io::Error),
+    /// `UnsupportedDiskType` - Only fixed VHD images are supported.
+    UnsupportedDiskType(u32),
+    /// `WritingFooter` - Error writing the footer to the file.
+    WritingFooter(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            InvalidChecksum => write!(f, "invalid VHD footer checksum"),
+            InvalidCookie => write!(f, "invalid VHD footer cookie"),
+            InvalidSize(size) => write!(f, "invalid VHD disk size {}", size),
+            ReadingFooter(e) => write!(f, "failed to read VHD footer: {}", e),
+            UnsupportedDiskType(t) => write!(f, "unsupported VHD disk type {}", t),
+            WritingFooter(e) => write!(f, "failed to write VHD footer: {}", e),
+        }
+    }
+}
+
+// The checksum is the one's complement of the sum of all bytes in the
+// footer, without the checksum field itself.
+fn footer_checksum(footer: &[u8]) -> u32 {
+    let sum = footer
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i < FOOTER_OFFSET_CHECKSUM || *i >= FOOTER_OFFSET_CHECKSUM + 4)
+        .fold(0u32, |sum, (_, b)| sum.wrapping_add(u32::from(*b)));
+    !sum
+}
+
+// Computes the CHS geometry stored in the footer, following the algorithm
+// from the VHD specification.
+fn disk_geometry(size: u64) -> (u16, u8, u8) {
+    let total_sectors = min(size / 512, 65535 * 16 * 255);
+    let (sectors_per_track, heads, cylinder_times_heads) = if total_sectors >= 65535 * 16 * 63 {
+        (255, 16, total_sectors / 255)
+    } else {
+        let mut sectors_per_track = 17;
+        let mut cylinder_times_heads = total_sectors / sectors_per_track;
+        let mut heads = (cylinder_times_heads + 1023) / 1024;
+        if heads < 4 {
+            heads = 4;
+        }
+        if cylinder_times_heads >= heads * 1024 || heads > 16 {
+            sectors_per_track = 31;
+            heads = 16;
+            cylinder_times_heads = total_sectors / sectors_per_track;
+        }
+        if cylinder_times_heads >= heads * 1024 {
+            sectors_per_track = 63;
+            heads = 16;
+            cylinder_times_heads = total_sectors / sectors_per_track;
+        }
+        (sectors_per_track, heads, cylinder_times_heads)
+    };
+
+    (
+        (cylinder_times_heads / heads) as u16,
+        heads as u8,
+        sectors_per_track as u8,
+    )
+}
+
+fn build_footer(size: u64) -> [u8; FOOTER_SIZE as usize] {
+    let mut footer = [0u8; FOOTER_SIZE as usize];
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs().saturating_sub(VHD_EPOCH_OFFSET))
+        .unwrap_or(0);
+    let (cylinders, heads, sectors_per_track) = disk_geometry(size);
+
+    footer[..8].copy_from_slice(FOOTER_COOKIE);
+    BigEndian::write_u32(&mut footer[FOOTER_OFFSET_FEATURES..], FOOTER_FEATURES);
+    BigEndian::write_u32(&mut footer[FOOTER_OFFSET_VERSION..], FOOTER_VERSION);
+    BigEndian::write_u64(
+        &mut footer[FOOTER_OFFSET_DATA_OFFSET..],
+        FOOTER_FIXED_DATA_OFFSET,
+    );
+    BigEndian::write_u32(&mut footer[FOOTER_OFFSET_TIMESTAMP..], timestamp as u32);
+    footer[FOOTER_OFFSET_CREATOR_APP..FOOTER_OFFSET_CREATOR_APP + 4]
+        .copy_from_slice(FOOTER_CREATOR_APP);
+    BigEndian::write_u32(
+        &mut footer[FOOTER_OFFSET_CREATOR_HOST_OS..],
+        FOOTER_CREATOR_HOST_OS,
+    );
+    BigEndian::write_u64(&mut footer[FOOTER_OFFSET_ORIGINAL_SIZE..], size);
+    BigEndian::write_u64(&mut footer[FOOTER_OFFSET_CURRENT_SIZE..], size);
+    BigEndian::write_u16(&mut footer[FOOTER_OFFSET_GEOMETRY..], cylinders);
+    footer[FOOTER_OFFSET_GEOMETRY + 2] = heads;
+    footer[FOOTER_OFFSET_GEOMETRY + 3] = sectors_per_track;
+    BigEndian::write_u32(&mut footer[FOOTER_OFFSET_DISK_TYPE..], DISK_TYPE_FIXED);
+    // Derive a unique id from the creation time and size, there is no need
+    // for it to be cryptographically random.
+    BigEndian::write_u64(&mut footer[FOOTER_OFFSET_UNIQUE_ID..], timestamp);
+    BigEndian::write_u64(&mut footer[FOOTER_OFFSET_UNIQUE_ID + 8..], size);
+
+    let checksum = footer_checksum(&footer);
+    BigEndian::write_u32(&mut footer[FOOTER_OFFSET_CHECKSUM..], checksum);
+    footer
+}
+
+/// Returns true if the file ends with a fixed VHD footer.
+pub fn is_fixed_vhd(file: &mut RawFile) -> io::Result<bool> {
+    let len = file.seek(SeekFrom::End(0))?;
+    if len < FOOTER_SIZE {
+        return Ok(false);
+    }
+
+    let mut footer = [0u8; FOOTER_SIZE as usize];
+    file.seek(SeekFrom::Start(len - FOOTER_SIZE))?;
+    file.read_exact(&mut footer)?;
+
+    Ok(&footer[..8] == FOOTER_COOKIE
+        && BigEndian::read_u32(&footer[FOOTER_OFFSET_DISK_TYPE..]) == DISK_TYPE_FIXED)
+}
+
+/// A fixed VHD image. Guest data is laid out in the file as in a raw image,
+/// the trailing footer is hidden from the guest.
+#[derive(Clone, Debug)]
+pub struct FixedVhdFile {
+    file: RawFile,
+    size: u64,
+    position: u64,
+}
+
+impl FixedVhdFile {
+    /// Opens an existing fixed VHD image, validating its footer.
+    pub fn new(mut file: RawFile) -> Result<FixedVhdFile> {
+        let len = file.seek(SeekFrom::End(0)).map_err(Error::ReadingFooter)?;
+        if len < FOOTER_SIZE {
+            return Err(Error::InvalidCookie);
+        }
+
+        let mut footer = [0u8; FOOTER_SIZE as usize];
+        file.seek(SeekFrom::Start(len - FOOTER_SIZE))
+            .map_err(Error::ReadingFooter)?;
+        file.read_exact(&mut footer).map_err(Error::ReadingFooter)?;
+
+        if &footer[..8] != FOOTER_COOKIE {
+            return Err(Error::InvalidCookie);
+        }
+        if BigEndian::read_u32(&footer[FOOTER_OFFSET_CHECKSUM..]) != footer_checksum(&footer) {
+            return Err(Error::InvalidChecksum);
+        }
+        let disk_type = BigEndian::read_u32(&footer[FOOTER_OFFSET_DISK_TYPE..]);
+        if disk_type != DISK_TYPE_FIXED {
+            return Err(Error::UnsupportedDiskType(disk_type));
+        }
+        let size = BigEndian::read_u64(&footer[FOOTER_OFFSET_CURRENT_SIZE..]);
+        if size > len - FOOTER_SIZE {
+            return Err(Error::InvalidSize(size));
+        }
+
+        Ok(FixedVhdFile {
+            file,
+            size,
+            position: 0,
+        })
+    }
+
+    /// Creates a fixed VHD image of `size` bytes in the given empty file.
+    pub fn create(file: RawFile, size: u64) -> Result<FixedVhdFile> {
+        let mut vhd = FixedVhdFile {
+            file,
+            size: 0,
+            position: 0,
+        };
+        vhd.resize(size)?;
+        Ok(vhd)
+    }
+
+    /// Changes the size of the disk, moving the footer at the new end of
+    /// the file.
+    pub fn resize(&mut self, size: u64) -> Result<()> {
+        if size % 512 != 0 {
+            return Err(Error::InvalidSize(size));
+        }
+
+        let old_len = self
+            .file
+            .seek(SeekFrom::End(0))
+            .map_err(Error::WritingFooter)?;
+        let footer = build_footer(size);
+        self.file
+            .set_len(size + FOOTER_SIZE)
+            .map_err(Error::WritingFooter)?;
+        self.file
+            .seek(SeekFrom::Start(size))
+            .map_err(Error::WritingFooter)?;
+        self.file.write_all(&footer).map_err(Error::WritingFooter)?;
+        self.file.sync_all().map_err(Error::WritingFooter)?;
+
+        // When growing, the former footer becomes visible to the guest.
+        // It's only cleared once the new one is in place, so that the image
+        // stays valid whatever happens.
+        let end = min(old_len, size);
+        if end > self.size {
+            let zeroes = [0u8; 4096];
+            self.file
+                .seek(SeekFrom::Start(self.size))
+                .map_err(Error::WritingFooter)?;
+            let mut offset = self.size;
+            while offset < end {
+                let count = min(end - offset, zeroes.len() as u64) as usize;
+                self.file
+                    .write_all(&zeroes[..count])
+                    .map_err(Error::WritingFooter)?;
+                offset += count as u64;
+            }
+            self.file.sync_all().map_err(Error::WritingFooter)?;
+        }
+        self.size = size;
+        self.position = min(self.position, size);
+
+        Ok(())
+    }
+
+    /// Returns the size of the disk exposed to the guest.
+    pub fn virtual_size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl AsRawFd for FixedVhdFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Read for FixedVhdFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = min(buf.len() as u64, self.size - self.position) as usize;
+        if count == 0 {
+            return Ok(0);
+        }
+
+        self.file.seek(SeekFrom::Start(self.position))?;
+        let nread = self.file.read(&mut buf[..count])?;
+        self.position += nread as u64;
+        Ok(nread)
+    }
+}
+
+impl Write for FixedVhdFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Never let the guest overwrite the footer.
+        let count = min(buf.len() as u64, self.size - self.position) as usize;
+        if count == 0 {
+            return Ok(0);
+        }
+
+        self.file.seek(SeekFrom::Start(self.position))?;
+        let nwritten = self.file.write(&buf[..count])?;
+        self.position += nwritten as u64;
+        Ok(nwritten)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for FixedVhdFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(off) => {
+                if off < 0 {
+                    self.size.checked_sub(off.wrapping_neg() as u64)
+                } else {
+                    self.size.checked_add(off as u64)
+                }
+            }
+            SeekFrom::Current(off) => {
+                if off < 0 {
+                    self.position.checked_sub(off.wrapping_neg() as u64)
+                } else {
+                    self.position.checked_add(off as u64)
+                }
+            }
+        };
+
+        match new_position {
+            Some(p) if p <= self.size => {
+                self.position = p;
+                Ok(p)
+            }
+            _ => Err(io::Error::from_raw_os_error(EINVAL)),
+        }
+    }
+}
+
+impl SeekHole for FixedVhdFile {
+    fn seek_hole(&mut self, offset: u64) -> io::Result<Option<u64>> {
+        if offset >= self.size {
+            return Ok(None);
+        }
+
+        // The footer is always data, so a hole found past the disk end
+        // means there is none left in the guest visible part.
+        let hole = match self.file.seek_hole(offset)? {
+            Some(o) => min(o, self.size),
+            None => self.size,
+        };
+        self.position = hole;
+        Ok(Some(hole))
+    }
+
+    fn seek_data(&mut self, offset: u64) -> io::Result<Option<u64>> {
+        if offset >= self.size {
+            return Ok(None);
+        }
+
+        match self.file.seek_data(offset)? {
+            Some(o) if o < self.size => {
+                self.position = o;
+                Ok(Some(o))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempfile;
+
+    fn with_vhd_file<F>(size: u64, mut testfn: F)
+    where
+        F: FnMut(FixedVhdFile),
+    {
+        let file = tempfile().expect("failed to create tempfile");
+        let vhd =
+            FixedVhdFile::create(RawFile::new(file, false), size).expect("failed to create vhd");
+        testfn(vhd);
+    }
+
+    #[test]
+    fn create_and_reopen() {
+        with_vhd_file(0x10_0000, |vhd| {
+            let mut raw = vhd.file.clone();
+            assert!(is_fixed_vhd(&mut raw).unwrap());
+            let reopened = FixedVhdFile::new(raw).expect("failed to reopen vhd");
+            assert_eq!(reopened.virtual_size(), 0x10_0000);
+        });
+    }
+
+    #[test]
+    fn footer_not_writable() {
+        with_vhd_file(0x1000, |mut vhd| {
+            vhd.seek(SeekFrom::Start(0xe00)).unwrap();
+            let b = [0x55u8; 0x400];
+            assert_eq!(vhd.write(&b).unwrap(), 0x200);
+            assert_eq!(vhd.write(&b).unwrap(), 0);
+
+            let mut raw = vhd.file.clone();
+            FixedVhdFile::new(raw.clone()).expect("footer was overwritten");
+            let mut buf = [0u8; 0x200];
+            raw.seek(SeekFrom::Start(0xe00)).unwrap();
+            raw.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf[..], &b[..0x200]);
+        });
+    }
+
+    #[test]
+    fn resize_moves_footer() {
+        with_vhd_file(0x1000, |mut vhd| {
+            vhd.resize(0x2000).expect("failed to resize");
+            assert_eq!(vhd.seek(SeekFrom::End(0)).unwrap(), 0x2000);
+            let reopened = FixedVhdFile::new(vhd.file.clone()).expect("failed to reopen vhd");
+            assert_eq!(reopened.virtual_size(), 0x2000);
+
+            // The former footer doesn't leak into the grown disk.
+            let mut buf = [0x55u8; 0x1000];
+            vhd.seek(SeekFrom::Start(0x1000)).unwrap();
+            vhd.read_exact(&mut buf).unwrap();
+            assert!(buf.iter().all(|v| *v == 0));
+        });
+    }
+
+    #[test]
+    fn geometry() {
+        assert_eq!(disk_geometry(127 * 1024 * 1024), (1019, 15, 17));
+        assert_eq!(disk_geometry(0x100_0000_0000), (65535, 16, 255));
+    }
+}
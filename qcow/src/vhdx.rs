@@ -0,0 +1,1301 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! VHDX images, as described by the MS-VHDX specification. Dynamic images
+//! are supported, differencing ones (with a parent) are not.
+
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use byteorder::{ByteOrder, LittleEndian};
+use libc::EINVAL;
+use vmm_sys_util::seek_hole::SeekHole;
+
+use crate::RawFile;
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+
+const FILE_IDENTIFIER_SIGNATURE: &[u8; 8] = b"vhdxfile";
+const HEADER_SIGNATURE: u32 = 0x6461_6568; // "head"
+const REGION_TABLE_SIGNATURE: u32 = 0x6967_6572; // "regi"
+const METADATA_SIGNATURE: &[u8; 8] = b"metadata";
+const LOG_ENTRY_SIGNATURE: u32 = 0x6567_6f6c; // "loge"
+const LOG_DATA_DESCRIPTOR_SIGNATURE: u32 = 0x6373_6564; // "desc"
+const LOG_ZERO_DESCRIPTOR_SIGNATURE: u32 = 0x6f72_657a; // "zero"
+const LOG_DATA_SECTOR_SIGNATURE: u32 = 0x6174_6164; // "data"
+
+const HEADER_1_OFFSET: u64 = 64 * KIB;
+const HEADER_2_OFFSET: u64 = 128 * KIB;
+const HEADER_SIZE: usize = 4 * KIB as usize;
+const REGION_TABLE_1_OFFSET: u64 = 192 * KIB;
+const REGION_TABLE_2_OFFSET: u64 = 256 * KIB;
+const REGION_TABLE_SIZE: usize = 64 * KIB as usize;
+const REGION_TABLE_MAX_ENTRIES: u32 = 2047;
+const METADATA_TABLE_SIZE: usize = 64 * KIB as usize;
+const METADATA_TABLE_MAX_ENTRIES: u16 = 2047;
+const LOG_SECTOR_SIZE: u64 = 4 * KIB;
+const LOG_ENTRY_HEADER_SIZE: usize = 64;
+const LOG_DESCRIPTOR_SIZE: usize = 32;
+const LOG_DATA_SECTOR_PAYLOAD: usize = 4084;
+
+const BAT_REGION_GUID: &str = "2dc27766-f623-4200-9d64-115e9bfd4a08";
+const METADATA_REGION_GUID: &str = "8b7ca206-4790-4b9a-b8fe-575f050f886e";
+const FILE_PARAMETERS_GUID: &str = "caa16737-fa36-4d43-b3b6-33f0aa44e76b";
+const VIRTUAL_DISK_SIZE_GUID: &str = "2fa54224-cd1b-4876-b211-5dbed83bf4b8";
+const VIRTUAL_DISK_ID_GUID: &str = "beca12ab-b2e6-4523-93ef-c309e000c746";
+const LOGICAL_SECTOR_SIZE_GUID: &str = "8141bf1d-a96f-4709-ba47-f233a8faab5f";
+const PHYSICAL_SECTOR_SIZE_GUID: &str = "cda348c7-445d-4471-9cc9-e9885251c556";
+
+const FILE_PARAMETERS_HAS_PARENT: u32 = 1 << 1;
+const METADATA_IS_VIRTUAL_DISK: u32 = 1 << 1;
+const METADATA_IS_REQUIRED: u32 = 1 << 2;
+
+const BAT_STATE_MASK: u64 = 0x7;
+const BAT_OFFSET_MASK: u64 = !(MIB - 1);
+const PAYLOAD_BLOCK_FULLY_PRESENT: u64 = 6;
+const PAYLOAD_BLOCK_PARTIALLY_PRESENT: u64 = 7;
+
+// Layout used when creating new images.
+const DEFAULT_BLOCK_SIZE: u32 = 32 * MIB as u32;
+const DEFAULT_LOGICAL_SECTOR_SIZE: u32 = 512;
+const DEFAULT_PHYSICAL_SECTOR_SIZE: u32 = 4096;
+const DEFAULT_LOG_OFFSET: u64 = MIB;
+const DEFAULT_LOG_SIZE: u32 = MIB as u32;
+const DEFAULT_METADATA_OFFSET: u64 = 2 * MIB;
+const DEFAULT_METADATA_SIZE: u32 = MIB as u32;
+const DEFAULT_BAT_OFFSET: u64 = 3 * MIB;
+
+#[derive(Debug)]
+pub enum Error {
+    /// `DifferencingNotSupported` - Images with a parent are not supported.
+    DifferencingNotSupported,
+    /// `InvalidBatRegion` - The BAT region is too small for the disk size.
+    InvalidBatRegion,
+    /// `InvalidBlockSize` - The payload block size is out of bounds.
+    InvalidBlockSize(u32),
+    /// `InvalidDiskSize` - The virtual disk size is invalid.
+    InvalidDiskSize(u64),
+    /// `InvalidHeaders` - None of the headers is valid.
+    InvalidHeaders,
+    /// `InvalidLog` - The log could not be replayed.
+    InvalidLog,
+    /// `InvalidMetadata` - The metadata region is corrupted.
+    InvalidMetadata,
+    /// `InvalidRegionTable` - None of the region tables is valid.
+    InvalidRegionTable,
+    /// `InvalidSectorSize` - The logical sector size is neither 512 nor 4096.
+    InvalidSectorSize(u32),
+    /// `InvalidSignature` - The file doesn't start with the VHDX identifier.
+    InvalidSignature,
+    /// `MissingMetadata` - A required metadata item is missing.
+    MissingMetadata(&'static str),
+    /// `MissingRegion` - A required region is missing.
+    MissingRegion(&'static str),
+    /// `ReadingFile` - Error reading the image metadata.
+    ReadingFile(io::Error),
+    /// `ReplayingLog` - Error writing the log entries to their final location.
+    ReplayingLog(io::Error),
+    /// `UnsupportedMetadata` - A required metadata item is unknown.
+    UnsupportedMetadata,
+    /// `UnsupportedRegion` - A required region is unknown.
+    UnsupportedRegion,
+    /// `WritingFile` - Error writing the image metadata.
+    WritingFile(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            DifferencingNotSupported => write!(f, "differencing VHDX images are not supported"),
+            InvalidBatRegion => write!(f, "VHDX BAT region is too small"),
+            InvalidBlockSize(size) => write!(f, "invalid VHDX block size {}", size),
+            InvalidDiskSize(size) => write!(f, "invalid VHDX disk size {}", size),
+            InvalidHeaders => write!(f, "no valid VHDX header"),
+            InvalidLog => write!(f, "invalid VHDX log"),
+            InvalidMetadata => write!(f, "invalid VHDX metadata region"),
+            InvalidRegionTable => write!(f, "no valid VHDX region table"),
+            InvalidSectorSize(size) => write!(f, "invalid VHDX logical sector size {}", size),
+            InvalidSignature => write!(f, "invalid VHDX file signature"),
+            MissingMetadata(item) => write!(f, "missing VHDX metadata item: {}", item),
+            MissingRegion(region) => write!(f, "missing VHDX region: {}", region),
+            ReadingFile(e) => write!(f, "failed to read VHDX file: {}", e),
+            ReplayingLog(e) => write!(f, "failed to replay VHDX log: {}", e),
+            UnsupportedMetadata => write!(f, "unsupported required VHDX metadata item"),
+            UnsupportedRegion => write!(f, "unsupported required VHDX region"),
+            WritingFile(e) => write!(f, "failed to write VHDX file: {}", e),
+        }
+    }
+}
+
+// CRC-32C (Castagnoli), used for all VHDX checksums.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// Validates a structure whose checksum is stored at `offset`, computed with
+// the checksum field itself set to zero.
+fn checksum_valid(data: &[u8], offset: usize) -> bool {
+    let mut copy = data.to_vec();
+    let expected = LittleEndian::read_u32(&copy[offset..]);
+    LittleEndian::write_u32(&mut copy[offset..], 0);
+    crc32c(&copy) == expected
+}
+
+fn set_checksum(data: &mut [u8], offset: usize) {
+    LittleEndian::write_u32(&mut data[offset..], 0);
+    let checksum = crc32c(data);
+    LittleEndian::write_u32(&mut data[offset..], checksum);
+}
+
+type Guid = [u8; 16];
+
+// Parses a GUID from its textual form into its on-disk mixed endian layout.
+fn guid_from_str(guid: &str) -> Guid {
+    let hex: Vec<u8> = guid
+        .chars()
+        .filter(|c| *c != '-')
+        .collect::<Vec<char>>()
+        .chunks(2)
+        .map(|c| u8::from_str_radix(&c.iter().collect::<String>(), 16).unwrap())
+        .collect();
+    let mut out = [0u8; 16];
+    out[0..4].copy_from_slice(&[hex[3], hex[2], hex[1], hex[0]]);
+    out[4..6].copy_from_slice(&[hex[5], hex[4]]);
+    out[6..8].copy_from_slice(&[hex[7], hex[6]]);
+    out[8..16].copy_from_slice(&hex[8..16]);
+    out
+}
+
+fn random_guid() -> io::Result<Guid> {
+    let mut guid = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut guid)?;
+    // Version 4, variant 1.
+    guid[7] = (guid[7] & 0x0f) | 0x40;
+    guid[8] = (guid[8] & 0x3f) | 0x80;
+    Ok(guid)
+}
+
+fn read_at(file: &mut RawFile, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+fn write_at(file: &mut RawFile, offset: u64, buf: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buf)
+}
+
+/// Returns true if the file starts with the VHDX file type identifier.
+pub fn is_vhdx(file: &mut RawFile) -> io::Result<bool> {
+    let mut signature = [0u8; 8];
+    file.seek(SeekFrom::Start(0))?;
+    match file.read_exact(&mut signature) {
+        Ok(()) => Ok(&signature == FILE_IDENTIFIER_SIGNATURE),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct VhdxHeader {
+    sequence_number: u64,
+    file_write_guid: Guid,
+    data_write_guid: Guid,
+    log_guid: Guid,
+    log_version: u16,
+    version: u16,
+    log_length: u32,
+    log_offset: u64,
+}
+
+impl VhdxHeader {
+    fn read(file: &mut RawFile, offset: u64) -> Result<Option<VhdxHeader>> {
+        let mut buf = vec![0u8; HEADER_SIZE];
+        read_at(file, offset, &mut buf).map_err(Error::ReadingFile)?;
+        if LittleEndian::read_u32(&buf[0..]) != HEADER_SIGNATURE || !checksum_valid(&buf, 4) {
+            return Ok(None);
+        }
+
+        let mut header = VhdxHeader {
+            sequence_number: LittleEndian::read_u64(&buf[8..]),
+            log_version: LittleEndian::read_u16(&buf[64..]),
+            version: LittleEndian::read_u16(&buf[66..]),
+            log_length: LittleEndian::read_u32(&buf[68..]),
+            log_offset: LittleEndian::read_u64(&buf[72..]),
+            ..Default::default()
+        };
+        header.file_write_guid.copy_from_slice(&buf[16..32]);
+        header.data_write_guid.copy_from_slice(&buf[32..48]);
+        header.log_guid.copy_from_slice(&buf[48..64]);
+
+        Ok(Some(header))
+    }
+
+    fn write(&self, file: &mut RawFile, offset: u64) -> Result<()> {
+        let mut buf = vec![0u8; HEADER_SIZE];
+        LittleEndian::write_u32(&mut buf[0..], HEADER_SIGNATURE);
+        LittleEndian::write_u64(&mut buf[8..], self.sequence_number);
+        buf[16..32].copy_from_slice(&self.file_write_guid);
+        buf[32..48].copy_from_slice(&self.data_write_guid);
+        buf[48..64].copy_from_slice(&self.log_guid);
+        LittleEndian::write_u16(&mut buf[64..], self.log_version);
+        LittleEndian::write_u16(&mut buf[66..], self.version);
+        LittleEndian::write_u32(&mut buf[68..], self.log_length);
+        LittleEndian::write_u64(&mut buf[72..], self.log_offset);
+        set_checksum(&mut buf, 4);
+
+        write_at(file, offset, &buf).map_err(Error::WritingFile)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Region {
+    offset: u64,
+    length: u32,
+}
+
+fn read_region_table(file: &mut RawFile, offset: u64) -> Result<Option<HashMap<Guid, Region>>> {
+    let mut buf = vec![0u8; REGION_TABLE_SIZE];
+    read_at(file, offset, &mut buf).map_err(Error::ReadingFile)?;
+    let entry_count = LittleEndian::read_u32(&buf[8..]);
+    if LittleEndian::read_u32(&buf[0..]) != REGION_TABLE_SIGNATURE
+        || !checksum_valid(&buf, 4)
+        || entry_count > REGION_TABLE_MAX_ENTRIES
+    {
+        return Ok(None);
+    }
+
+    let known = [
+        guid_from_str(BAT_REGION_GUID),
+        guid_from_str(METADATA_REGION_GUID),
+    ];
+    let mut regions = HashMap::new();
+    for i in 0..entry_count as usize {
+        let entry = &buf[16 + i * 32..16 + (i + 1) * 32];
+        let mut guid = [0u8; 16];
+        guid.copy_from_slice(&entry[0..16]);
+        let required = LittleEndian::read_u32(&entry[28..]) & 1 != 0;
+        if !known.contains(&guid) {
+            if required {
+                return Err(Error::UnsupportedRegion);
+            }
+            continue;
+        }
+        regions.insert(
+            guid,
+            Region {
+                offset: LittleEndian::read_u64(&entry[16..]),
+                length: LittleEndian::read_u32(&entry[24..]),
+            },
+        );
+    }
+
+    Ok(Some(regions))
+}
+
+fn write_region_table(file: &mut RawFile, offset: u64, regions: &[(Guid, Region)]) -> Result<()> {
+    let mut buf = vec![0u8; REGION_TABLE_SIZE];
+    LittleEndian::write_u32(&mut buf[0..], REGION_TABLE_SIGNATURE);
+    LittleEndian::write_u32(&mut buf[8..], regions.len() as u32);
+    for (i, (guid, region)) in regions.iter().enumerate() {
+        let entry = &mut buf[16 + i * 32..16 + (i + 1) * 32];
+        entry[0..16].copy_from_slice(guid);
+        LittleEndian::write_u64(&mut entry[16..], region.offset);
+        LittleEndian::write_u32(&mut entry[24..], region.length);
+        LittleEndian::write_u32(&mut entry[28..], 1);
+    }
+    set_checksum(&mut buf, 4);
+
+    write_at(file, offset, &buf).map_err(Error::WritingFile)
+}
+
+#[derive(Debug)]
+struct LogDescriptor {
+    zero: bool,
+    trailing_bytes: u32,
+    leading_bytes: u64,
+    zero_length: u64,
+    file_offset: u64,
+}
+
+#[derive(Debug)]
+struct LogEntry {
+    sequence_number: u64,
+    length: u32,
+    tail: u32,
+    last_file_offset: u64,
+    descriptors: Vec<LogDescriptor>,
+    // Content of the data sectors, in the order of the data descriptors.
+    data: Vec<Vec<u8>>,
+}
+
+// The log is a circular buffer, entries can wrap around its end.
+fn read_log(file: &mut RawFile, header: &VhdxHeader, offset: u64, buf: &mut [u8]) -> Result<()> {
+    let log_length = u64::from(header.log_length);
+    let first = min(buf.len() as u64, log_length - offset) as usize;
+    read_at(file, header.log_offset + offset, &mut buf[..first]).map_err(Error::ReadingFile)?;
+    if first < buf.len() {
+        read_at(file, header.log_offset, &mut buf[first..]).map_err(Error::ReadingFile)?;
+    }
+    Ok(())
+}
+
+fn read_log_entry(
+    file: &mut RawFile,
+    header: &VhdxHeader,
+    offset: u64,
+) -> Result<Option<LogEntry>> {
+    let mut hdr = vec![0u8; LOG_SECTOR_SIZE as usize];
+    read_log(file, header, offset, &mut hdr)?;
+    let length = LittleEndian::read_u32(&hdr[8..]);
+    if LittleEndian::read_u32(&hdr[0..]) != LOG_ENTRY_SIGNATURE
+        || hdr[32..48] != header.log_guid
+        || length == 0
+        || u64::from(length) % LOG_SECTOR_SIZE != 0
+        || length > header.log_length
+    {
+        return Ok(None);
+    }
+
+    let mut buf = vec![0u8; length as usize];
+    read_log(file, header, offset, &mut buf)?;
+    if !checksum_valid(&buf, 4) {
+        return Ok(None);
+    }
+
+    let sequence_number = LittleEndian::read_u64(&buf[16..]);
+    let descriptor_count = LittleEndian::read_u32(&buf[24..]) as usize;
+    let descriptors_end = LOG_ENTRY_HEADER_SIZE + descriptor_count * LOG_DESCRIPTOR_SIZE;
+    let mut data_offset = ((descriptors_end as u64 + LOG_SECTOR_SIZE - 1) / LOG_SECTOR_SIZE
+        * LOG_SECTOR_SIZE) as usize;
+    if data_offset > buf.len() {
+        return Ok(None);
+    }
+
+    let mut descriptors = Vec::with_capacity(descriptor_count);
+    let mut data = Vec::new();
+    for i in 0..descriptor_count {
+        let d = &buf[LOG_ENTRY_HEADER_SIZE + i * LOG_DESCRIPTOR_SIZE..];
+        if LittleEndian::read_u64(&d[24..]) != sequence_number {
+            return Ok(None);
+        }
+        match LittleEndian::read_u32(&d[0..]) {
+            LOG_ZERO_DESCRIPTOR_SIGNATURE => descriptors.push(LogDescriptor {
+                zero: true,
+                trailing_bytes: 0,
+                leading_bytes: 0,
+                zero_length: LittleEndian::read_u64(&d[8..]),
+                file_offset: LittleEndian::read_u64(&d[16..]),
+            }),
+            LOG_DATA_DESCRIPTOR_SIGNATURE => {
+                if data_offset + LOG_SECTOR_SIZE as usize > buf.len() {
+                    return Ok(None);
+                }
+                let sector = &buf[data_offset..data_offset + LOG_SECTOR_SIZE as usize];
+                let sequence_high = u64::from(LittleEndian::read_u32(&sector[4..]));
+                let sequence_low = u64::from(LittleEndian::read_u32(&sector[4092..]));
+                if LittleEndian::read_u32(&sector[0..]) != LOG_DATA_SECTOR_SIGNATURE
+                    || (sequence_high << 32 | sequence_low) != sequence_number
+                {
+                    return Ok(None);
+                }
+                data.push(sector[8..8 + LOG_DATA_SECTOR_PAYLOAD].to_vec());
+                data_offset += LOG_SECTOR_SIZE as usize;
+
+                descriptors.push(LogDescriptor {
+                    zero: false,
+                    trailing_bytes: LittleEndian::read_u32(&d[4..]),
+                    leading_bytes: LittleEndian::read_u64(&d[8..]),
+                    zero_length: 0,
+                    file_offset: LittleEndian::read_u64(&d[16..]),
+                })
+            }
+            _ => return Ok(None),
+        }
+    }
+
+    Ok(Some(LogEntry {
+        sequence_number,
+        length,
+        tail: LittleEndian::read_u32(&buf[12..]),
+        last_file_offset: LittleEndian::read_u64(&buf[56..]),
+        descriptors,
+        data,
+    }))
+}
+
+// Replays the active sequence of the log, making the metadata updates it
+// holds durable in their final location.
+fn replay_log(file: &mut RawFile, header: &VhdxHeader) -> Result<()> {
+    let log_length = u64::from(header.log_length);
+    if log_length == 0 || log_length % MIB != 0 {
+        return Err(Error::InvalidLog);
+    }
+
+    // The entry with the highest sequence number is the head of the active
+    // sequence, its tail tells where the sequence starts.
+    let mut head: Option<(u64, LogEntry)> = None;
+    let mut offset = 0;
+    while offset < log_length {
+        if let Some(entry) = read_log_entry(file, header, offset)? {
+            if head
+                .as_ref()
+                .map_or(true, |(_, h)| entry.sequence_number > h.sequence_number)
+            {
+                head = Some((offset, entry));
+            }
+        }
+        offset += LOG_SECTOR_SIZE;
+    }
+    // Without any valid entry, the log is empty.
+    let (head_offset, head) = match head {
+        Some(head) => head,
+        None => return Ok(()),
+    };
+
+    let mut entries = Vec::new();
+    let mut offset = u64::from(head.tail);
+    loop {
+        let entry = read_log_entry(file, header, offset)?.ok_or(Error::InvalidLog)?;
+        if let Some(prev) = entries.last() {
+            let prev: &LogEntry = prev;
+            if entry.sequence_number != prev.sequence_number + 1 {
+                return Err(Error::InvalidLog);
+            }
+        }
+        let next = (offset + u64::from(entry.length)) % log_length;
+        entries.push(entry);
+        if offset == head_offset {
+            break;
+        }
+        if entries.len() as u64 > log_length / LOG_SECTOR_SIZE {
+            return Err(Error::InvalidLog);
+        }
+        offset = next;
+    }
+
+    // The entries can only target the file, as extended up to the last
+    // offset recorded by the head of the sequence.
+    let len = file.seek(SeekFrom::End(0)).map_err(Error::ReplayingLog)?;
+    let limit = max(len, head.last_file_offset);
+    for entry in entries.iter() {
+        for descriptor in entry.descriptors.iter() {
+            let length = if descriptor.zero {
+                descriptor.zero_length
+            } else {
+                LOG_SECTOR_SIZE
+            };
+            if descriptor.file_offset % LOG_SECTOR_SIZE != 0
+                || length % LOG_SECTOR_SIZE != 0
+                || descriptor
+                    .file_offset
+                    .checked_add(length)
+                    .map_or(true, |end| end > limit)
+            {
+                return Err(Error::InvalidLog);
+            }
+        }
+    }
+
+    let zeroes = vec![0u8; MIB as usize];
+    for entry in entries.iter() {
+        let mut data = entry.data.iter();
+        for descriptor in entry.descriptors.iter() {
+            if descriptor.zero {
+                let mut offset = descriptor.file_offset;
+                let end = offset + descriptor.zero_length;
+                while offset < end {
+                    let count = min(end - offset, MIB) as usize;
+                    write_at(file, offset, &zeroes[..count]).map_err(Error::ReplayingLog)?;
+                    offset += count as u64;
+                }
+            } else {
+                let payload = data.next().ok_or(Error::InvalidLog)?;
+                let mut sector = vec![0u8; LOG_SECTOR_SIZE as usize];
+                LittleEndian::write_u64(&mut sector[0..], descriptor.leading_bytes);
+                sector[8..8 + LOG_DATA_SECTOR_PAYLOAD].copy_from_slice(payload);
+                LittleEndian::write_u32(&mut sector[4092..], descriptor.trailing_bytes);
+                write_at(file, descriptor.file_offset, &sector).map_err(Error::ReplayingLog)?;
+            }
+        }
+    }
+
+    if len < head.last_file_offset {
+        file.set_len(head.last_file_offset)
+            .map_err(Error::ReplayingLog)?;
+    }
+    file.sync_all().map_err(Error::ReplayingLog)
+}
+
+/// A dynamic VHDX image. Payload blocks are allocated at the end of the file
+/// the first time they are written.
+#[derive(Clone, Debug)]
+pub struct VhdxFile {
+    file: RawFile,
+    header: VhdxHeader,
+    // Offset of the header to overwrite on the next update, the one which
+    // is not current.
+    next_header_offset: u64,
+    header_updated: bool,
+    virtual_size: u64,
+    block_size: u64,
+    chunk_ratio: u64,
+    bat_offset: u64,
+    bat: Vec<u64>,
+    position: u64,
+    // Where the next log entry is written, and its sequence number.
+    log_position: u64,
+    log_sequence_number: u64,
+}
+
+impl VhdxFile {
+    /// Opens an existing VHDX image, replaying its log if needed.
+    pub fn from(mut file: RawFile) -> Result<VhdxFile> {
+        if !is_vhdx(&mut file).map_err(Error::ReadingFile)? {
+            return Err(Error::InvalidSignature);
+        }
+
+        let header_1 = VhdxHeader::read(&mut file, HEADER_1_OFFSET)?;
+        let header_2 = VhdxHeader::read(&mut file, HEADER_2_OFFSET)?;
+        let (mut header, next_header_offset) = match (header_1, header_2) {
+            (Some(h1), Some(h2)) => {
+                if h1.sequence_number >= h2.sequence_number {
+                    (h1, HEADER_2_OFFSET)
+                } else {
+                    (h2, HEADER_1_OFFSET)
+                }
+            }
+            (Some(h1), None) => (h1, HEADER_2_OFFSET),
+            (None, Some(h2)) => (h2, HEADER_1_OFFSET),
+            (None, None) => return Err(Error::InvalidHeaders),
+        };
+
+        let mut vhdx = VhdxFile {
+            file,
+            header: header.clone(),
+            next_header_offset,
+            header_updated: false,
+            virtual_size: 0,
+            block_size: 0,
+            chunk_ratio: 0,
+            bat_offset: 0,
+            bat: Vec::new(),
+            position: 0,
+            log_position: 0,
+            log_sequence_number: 0,
+        };
+
+        if header.log_guid != [0u8; 16] {
+            replay_log(&mut vhdx.file, &header)?;
+            header.log_guid = [0u8; 16];
+            vhdx.header = header;
+            vhdx.update_header(false)?;
+        }
+
+        let regions = match read_region_table(&mut vhdx.file, REGION_TABLE_1_OFFSET)? {
+            Some(regions) => regions,
+            None => read_region_table(&mut vhdx.file, REGION_TABLE_2_OFFSET)?
+                .ok_or(Error::InvalidRegionTable)?,
+        };
+        let bat_region = *regions
+            .get(&guid_from_str(BAT_REGION_GUID))
+            .ok_or(Error::MissingRegion("BAT"))?;
+        let metadata_region = *regions
+            .get(&guid_from_str(METADATA_REGION_GUID))
+            .ok_or(Error::MissingRegion("metadata"))?;
+
+        vhdx.read_metadata(metadata_region)?;
+
+        let data_blocks = (vhdx.virtual_size + vhdx.block_size - 1) / vhdx.block_size;
+        let bat_entries = if data_blocks == 0 {
+            0
+        } else {
+            data_blocks + (data_blocks - 1) / vhdx.chunk_ratio
+        };
+        if bat_entries * 8 > u64::from(bat_region.length) {
+            return Err(Error::InvalidBatRegion);
+        }
+        let mut bat = vec![0u8; (bat_entries * 8) as usize];
+        read_at(&mut vhdx.file, bat_region.offset, &mut bat).map_err(Error::ReadingFile)?;
+        vhdx.bat = bat.chunks(8).map(LittleEndian::read_u64).collect();
+        vhdx.bat_offset = bat_region.offset;
+
+        Ok(vhdx)
+    }
+
+    /// Creates a new dynamic VHDX image of `virtual_size` bytes in the given
+    /// empty file.
+    pub fn new(mut file: RawFile, virtual_size: u64) -> Result<VhdxFile> {
+        if virtual_size == 0 || virtual_size % u64::from(DEFAULT_LOGICAL_SECTOR_SIZE) != 0 {
+            return Err(Error::InvalidDiskSize(virtual_size));
+        }
+
+        let block_size = u64::from(DEFAULT_BLOCK_SIZE);
+        let chunk_ratio =
+            (1u64 << 23) * u64::from(DEFAULT_LOGICAL_SECTOR_SIZE) / u64::from(DEFAULT_BLOCK_SIZE);
+        let data_blocks = (virtual_size + block_size - 1) / block_size;
+        let bat_entries = data_blocks + (data_blocks - 1) / chunk_ratio;
+        let bat_length = (bat_entries * 8 + MIB - 1) / MIB * MIB;
+
+        file.set_len(DEFAULT_BAT_OFFSET + bat_length)
+            .map_err(Error::WritingFile)?;
+
+        // File type identifier, followed by the creator as UTF-16.
+        let mut identifier = vec![0u8; 64 * KIB as usize];
+        identifier[0..8].copy_from_slice(FILE_IDENTIFIER_SIGNATURE);
+        for (i, c) in "Cloud Hypervisor".encode_utf16().enumerate() {
+            LittleEndian::write_u16(&mut identifier[8 + i * 2..], c);
+        }
+        write_at(&mut file, 0, &identifier).map_err(Error::WritingFile)?;
+
+        let regions = [
+            (
+                guid_from_str(BAT_REGION_GUID),
+                Region {
+                    offset: DEFAULT_BAT_OFFSET,
+                    length: bat_length as u32,
+                },
+            ),
+            (
+                guid_from_str(METADATA_REGION_GUID),
+                Region {
+                    offset: DEFAULT_METADATA_OFFSET,
+                    length: DEFAULT_METADATA_SIZE,
+                },
+            ),
+        ];
+        write_region_table(&mut file, REGION_TABLE_1_OFFSET, &regions)?;
+        write_region_table(&mut file, REGION_TABLE_2_OFFSET, &regions)?;
+
+        // Metadata table, with the items stored right after it.
+        let disk_id = random_guid().map_err(Error::WritingFile)?;
+        let mut metadata = vec![0u8; METADATA_TABLE_SIZE + 64];
+        metadata[0..8].copy_from_slice(METADATA_SIGNATURE);
+        let mut file_parameters = [0u8; 8];
+        LittleEndian::write_u32(&mut file_parameters[0..], DEFAULT_BLOCK_SIZE);
+        let mut disk_size = [0u8; 8];
+        LittleEndian::write_u64(&mut disk_size, virtual_size);
+        let mut logical_sector_size = [0u8; 4];
+        LittleEndian::write_u32(&mut logical_sector_size, DEFAULT_LOGICAL_SECTOR_SIZE);
+        let mut physical_sector_size = [0u8; 4];
+        LittleEndian::write_u32(&mut physical_sector_size, DEFAULT_PHYSICAL_SECTOR_SIZE);
+        let items: [(&str, &[u8], u32); 5] = [
+            (FILE_PARAMETERS_GUID, &file_parameters, METADATA_IS_REQUIRED),
+            (
+                VIRTUAL_DISK_SIZE_GUID,
+                &disk_size,
+                METADATA_IS_REQUIRED | METADATA_IS_VIRTUAL_DISK,
+            ),
+            (
+                VIRTUAL_DISK_ID_GUID,
+                &disk_id,
+                METADATA_IS_REQUIRED | METADATA_IS_VIRTUAL_DISK,
+            ),
+            (
+                LOGICAL_SECTOR_SIZE_GUID,
+                &logical_sector_size,
+                METADATA_IS_REQUIRED | METADATA_IS_VIRTUAL_DISK,
+            ),
+            (
+                PHYSICAL_SECTOR_SIZE_GUID,
+                &physical_sector_size,
+                METADATA_IS_REQUIRED | METADATA_IS_VIRTUAL_DISK,
+            ),
+        ];
+        LittleEndian::write_u16(&mut metadata[10..], items.len() as u16);
+        let mut item_offset = METADATA_TABLE_SIZE;
+        for (i, (guid, data, flags)) in items.iter().enumerate() {
+            let entry = &mut metadata[32 + i * 32..32 + (i + 1) * 32];
+            entry[0..16].copy_from_slice(&guid_from_str(guid));
+            LittleEndian::write_u32(&mut entry[16..], item_offset as u32);
+            LittleEndian::write_u32(&mut entry[20..], data.len() as u32);
+            LittleEndian::write_u32(&mut entry[24..], *flags);
+            metadata[item_offset..item_offset + data.len()].copy_from_slice(data);
+            item_offset += data.len();
+        }
+        write_at(&mut file, DEFAULT_METADATA_OFFSET, &metadata).map_err(Error::WritingFile)?;
+
+        let header = VhdxHeader {
+            sequence_number: 0,
+            file_write_guid: random_guid().map_err(Error::WritingFile)?,
+            data_write_guid: random_guid().map_err(Error::WritingFile)?,
+            log_guid: [0u8; 16],
+            log_version: 0,
+            version: 1,
+            log_length: DEFAULT_LOG_SIZE,
+            log_offset: DEFAULT_LOG_OFFSET,
+        };
+        header.write(&mut file, HEADER_1_OFFSET)?;
+        let header = VhdxHeader {
+            sequence_number: 1,
+            ..header
+        };
+        header.write(&mut file, HEADER_2_OFFSET)?;
+        file.sync_all().map_err(Error::WritingFile)?;
+
+        VhdxFile::from(file)
+    }
+
+    /// Returns the size of the disk exposed to the guest.
+    pub fn virtual_size(&self) -> u64 {
+        self.virtual_size
+    }
+
+    fn read_metadata(&mut self, region: Region) -> Result<()> {
+        let mut table = vec![0u8; METADATA_TABLE_SIZE];
+        read_at(&mut self.file, region.offset, &mut table).map_err(Error::ReadingFile)?;
+        let entry_count = LittleEndian::read_u16(&table[10..]);
+        if &table[0..8] != METADATA_SIGNATURE || entry_count > METADATA_TABLE_MAX_ENTRIES {
+            return Err(Error::InvalidMetadata);
+        }
+
+        let mut items = HashMap::new();
+        for i in 0..entry_count as usize {
+            let entry = &table[32 + i * 32..32 + (i + 1) * 32];
+            let mut guid = [0u8; 16];
+            guid.copy_from_slice(&entry[0..16]);
+            let offset = LittleEndian::read_u32(&entry[16..]);
+            let length = LittleEndian::read_u32(&entry[20..]);
+            let flags = LittleEndian::read_u32(&entry[24..]);
+            if u64::from(offset) + u64::from(length) > u64::from(region.length) {
+                return Err(Error::InvalidMetadata);
+            }
+            let mut data = vec![0u8; length as usize];
+            read_at(&mut self.file, region.offset + u64::from(offset), &mut data)
+                .map_err(Error::ReadingFile)?;
+            items.insert(guid, (data, flags));
+        }
+
+        let known = [
+            FILE_PARAMETERS_GUID,
+            VIRTUAL_DISK_SIZE_GUID,
+            VIRTUAL_DISK_ID_GUID,
+            LOGICAL_SECTOR_SIZE_GUID,
+            PHYSICAL_SECTOR_SIZE_GUID,
+        ]
+        .iter()
+        .map(|g| guid_from_str(g))
+        .collect::<Vec<Guid>>();
+        if items
+            .iter()
+            .any(|(guid, (_, flags))| flags & METADATA_IS_REQUIRED != 0 && !known.contains(guid))
+        {
+            return Err(Error::UnsupportedMetadata);
+        }
+
+        let item = |guid: &str, name: &'static str, len: usize| -> Result<&[u8]> {
+            match items.get(&guid_from_str(guid)) {
+                Some((data, _)) if data.len() >= len => Ok(&data[..len]),
+                Some(_) => Err(Error::InvalidMetadata),
+                None => Err(Error::MissingMetadata(name)),
+            }
+        };
+
+        let file_parameters = item(FILE_PARAMETERS_GUID, "file parameters", 8)?;
+        let block_size = LittleEndian::read_u32(&file_parameters[0..]);
+        if LittleEndian::read_u32(&file_parameters[4..]) & FILE_PARAMETERS_HAS_PARENT != 0 {
+            return Err(Error::DifferencingNotSupported);
+        }
+        if !block_size.is_power_of_two() || block_size < MIB as u32 || block_size > 256 * MIB as u32
+        {
+            return Err(Error::InvalidBlockSize(block_size));
+        }
+
+        let logical_sector_size =
+            LittleEndian::read_u32(item(LOGICAL_SECTOR_SIZE_GUID, "logical sector size", 4)?);
+        if logical_sector_size != 512 && logical_sector_size != 4096 {
+            return Err(Error::InvalidSectorSize(logical_sector_size));
+        }
+
+        let virtual_size =
+            LittleEndian::read_u64(item(VIRTUAL_DISK_SIZE_GUID, "virtual disk size", 8)?);
+        if virtual_size % u64::from(logical_sector_size) != 0 {
+            return Err(Error::InvalidDiskSize(virtual_size));
+        }
+
+        self.block_size = u64::from(block_size);
+        self.chunk_ratio = (1u64 << 23) * u64::from(logical_sector_size) / self.block_size;
+        self.virtual_size = virtual_size;
+
+        Ok(())
+    }
+
+    // Writes the current header, with a new sequence number, over the
+    // non-current one. When `new_write_guids` is set, the file and data
+    // write GUIDs are regenerated, as required before the first write to
+    // an opened image.
+    fn update_header(&mut self, new_write_guids: bool) -> Result<()> {
+        self.header.sequence_number += 1;
+        if new_write_guids {
+            self.header.file_write_guid = random_guid().map_err(Error::WritingFile)?;
+            self.header.data_write_guid = random_guid().map_err(Error::WritingFile)?;
+        }
+        self.header.write(&mut self.file, self.next_header_offset)?;
+        self.file.sync_all().map_err(Error::WritingFile)?;
+
+        self.next_header_offset = if self.next_header_offset == HEADER_1_OFFSET {
+            HEADER_2_OFFSET
+        } else {
+            HEADER_1_OFFSET
+        };
+
+        Ok(())
+    }
+
+    fn bat_index(&self, address: u64) -> usize {
+        let block = address / self.block_size;
+        (block + block / self.chunk_ratio) as usize
+    }
+
+    // Gets the offset of the given guest address in the host file, or None
+    // if the block holding it reads as zeros.
+    fn file_offset_read(&self, address: u64) -> io::Result<Option<u64>> {
+        let entry = self.bat[self.bat_index(address)];
+        match entry & BAT_STATE_MASK {
+            PAYLOAD_BLOCK_FULLY_PRESENT => {
+                Ok(Some((entry & BAT_OFFSET_MASK) + address % self.block_size))
+            }
+            // Only meaningful for differencing images.
+            PAYLOAD_BLOCK_PARTIALLY_PRESENT => Err(io::Error::from_raw_os_error(EINVAL)),
+            _ => Ok(None),
+        }
+    }
+
+    // Gets the offset of the given guest address in the host file, allocating
+    // the block holding it if needed.
+    fn file_offset_write(&mut self, address: u64) -> io::Result<u64> {
+        if let Some(offset) = self.file_offset_read(address)? {
+            return Ok(offset);
+        }
+
+        if !self.header_updated {
+            // Start a new log, the entries written by previous sessions
+            // having already been replayed.
+            self.header.log_guid = random_guid()?;
+            self.update_header(true)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            self.header_updated = true;
+        }
+
+        // New blocks are appended at the next MB boundary. Extending the file
+        // zeroes the block, so a crash before the data lands never exposes
+        // stale content.
+        let len = self.file.seek(SeekFrom::End(0))?;
+        let block_offset = (len + MIB - 1) / MIB * MIB;
+        self.file.set_len(block_offset + self.block_size)?;
+
+        let index = self.bat_index(address);
+        self.bat[index] = block_offset | PAYLOAD_BLOCK_FULLY_PRESENT;
+        self.write_bat_sector(index, len, block_offset + self.block_size)?;
+
+        Ok(block_offset + address % self.block_size)
+    }
+
+    // Writes the BAT sector holding the given entry, going through the log
+    // so that a crash can't leave it torn.
+    fn write_bat_sector(
+        &mut self,
+        index: usize,
+        flushed_file_offset: u64,
+        last_file_offset: u64,
+    ) -> io::Result<()> {
+        let entries_per_sector = (LOG_SECTOR_SIZE / 8) as usize;
+        let first = index / entries_per_sector * entries_per_sector;
+        let mut sector = vec![0u8; LOG_SECTOR_SIZE as usize];
+        for (i, entry) in self.bat[first..min(first + entries_per_sector, self.bat.len())]
+            .iter()
+            .enumerate()
+        {
+            LittleEndian::write_u64(&mut sector[i * 8..], *entry);
+        }
+        let file_offset = self.bat_offset + first as u64 * 8;
+
+        self.write_log_entry(file_offset, &sector, flushed_file_offset, last_file_offset)?;
+        write_at(&mut self.file, file_offset, &sector)?;
+        self.file.sync_all()
+    }
+
+    // Appends an entry updating a single sector to the log, and makes it
+    // durable. Since the previous entries have been applied, the entry is
+    // the whole active sequence.
+    fn write_log_entry(
+        &mut self,
+        file_offset: u64,
+        sector: &[u8],
+        flushed_file_offset: u64,
+        last_file_offset: u64,
+    ) -> io::Result<()> {
+        let entry_length = 2 * LOG_SECTOR_SIZE;
+        let log_length = u64::from(self.header.log_length);
+        if log_length < entry_length || log_length % LOG_SECTOR_SIZE != 0 {
+            return Err(io::Error::from_raw_os_error(EINVAL));
+        }
+        if self.log_position + entry_length > log_length {
+            self.log_position = 0;
+        }
+        self.log_sequence_number += 1;
+        let sequence_number = self.log_sequence_number;
+
+        let mut entry = vec![0u8; entry_length as usize];
+        LittleEndian::write_u32(&mut entry[0..], LOG_ENTRY_SIGNATURE);
+        LittleEndian::write_u32(&mut entry[8..], entry_length as u32);
+        LittleEndian::write_u32(&mut entry[12..], self.log_position as u32);
+        LittleEndian::write_u64(&mut entry[16..], sequence_number);
+        LittleEndian::write_u32(&mut entry[24..], 1);
+        entry[32..48].copy_from_slice(&self.header.log_guid);
+        LittleEndian::write_u64(&mut entry[48..], flushed_file_offset);
+        LittleEndian::write_u64(&mut entry[56..], last_file_offset);
+
+        // The first 8 and last 4 bytes of the sector are kept by the
+        // descriptor, their place in the data sector holding its signature
+        // and the sequence number.
+        let descriptor = &mut entry[LOG_ENTRY_HEADER_SIZE..];
+        LittleEndian::write_u32(&mut descriptor[0..], LOG_DATA_DESCRIPTOR_SIGNATURE);
+        descriptor[4..8].copy_from_slice(&sector[4092..4096]);
+        descriptor[8..16].copy_from_slice(&sector[0..8]);
+        LittleEndian::write_u64(&mut descriptor[16..], file_offset);
+        LittleEndian::write_u64(&mut descriptor[24..], sequence_number);
+        let data = &mut entry[LOG_SECTOR_SIZE as usize..];
+        LittleEndian::write_u32(&mut data[0..], LOG_DATA_SECTOR_SIGNATURE);
+        LittleEndian::write_u32(&mut data[4..], (sequence_number >> 32) as u32);
+        data[8..8 + LOG_DATA_SECTOR_PAYLOAD].copy_from_slice(&sector[8..4092]);
+        LittleEndian::write_u32(&mut data[4092..], sequence_number as u32);
+        set_checksum(&mut entry, 4);
+
+        write_at(
+            &mut self.file,
+            self.header.log_offset + self.log_position,
+            &entry,
+        )?;
+        self.file.sync_all()?;
+        self.log_position += entry_length;
+
+        Ok(())
+    }
+
+    fn limit_range(&self, address: u64, count: usize) -> usize {
+        let block_end = (address / self.block_size + 1) * self.block_size;
+        min(
+            count as u64,
+            min(block_end, self.virtual_size).saturating_sub(address),
+        ) as usize
+    }
+
+    // Finds the first address at or after `address` in a block which is
+    // allocated or not, as requested.
+    fn find_block(&self, address: u64, allocated: bool) -> io::Result<Option<u64>> {
+        let mut block_addr = address;
+        while block_addr < self.virtual_size {
+            if self.file_offset_read(block_addr)?.is_some() == allocated {
+                return Ok(Some(block_addr));
+            }
+            block_addr = (block_addr / self.block_size + 1) * self.block_size;
+        }
+        Ok(None)
+    }
+}
+
+impl AsRawFd for VhdxFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Read for VhdxFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.limit_range(self.position, buf.len());
+        if count == 0 {
+            return Ok(0);
+        }
+
+        match self.file_offset_read(self.position)? {
+            Some(offset) => {
+                self.file.seek(SeekFrom::Start(offset))?;
+                self.file.read_exact(&mut buf[..count])?;
+            }
+            None => {
+                for b in buf[..count].iter_mut() {
+                    *b = 0;
+                }
+            }
+        }
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Write for VhdxFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.limit_range(self.position, buf.len());
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let offset = self.file_offset_write(self.position)?;
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(&buf[..count])?;
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+}
+
+impl Seek for VhdxFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(off) => {
+                if off < 0 {
+                    self.virtual_size.checked_sub(off.wrapping_neg() as u64)
+                } else {
+                    self.virtual_size.checked_add(off as u64)
+                }
+            }
+            SeekFrom::Current(off) => {
+                if off < 0 {
+                    self.position.checked_sub(off.wrapping_neg() as u64)
+                } else {
+                    self.position.checked_add(off as u64)
+                }
+            }
+        };
+
+        match new_position {
+            Some(p) if p <= self.virtual_size => {
+                self.position = p;
+                Ok(p)
+            }
+            _ => Err(io::Error::from_raw_os_error(EINVAL)),
+        }
+    }
+}
+
+impl SeekHole for VhdxFile {
+    fn seek_hole(&mut self, offset: u64) -> io::Result<Option<u64>> {
+        if offset >= self.virtual_size {
+            return Ok(None);
+        }
+
+        let hole = self.find_block(offset, false)?.unwrap_or(self.virtual_size);
+        self.position = hole;
+        Ok(Some(hole))
+    }
+
+    fn seek_data(&mut self, offset: u64) -> io::Result<Option<u64>> {
+        let data = self.find_block(offset, true)?;
+        if let Some(o) = data {
+            self.position = o;
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempfile;
+
+    fn with_vhdx_file<F>(size: u64, mut testfn: F)
+    where
+        F: FnMut(VhdxFile),
+    {
+        let file = tempfile().expect("failed to create tempfile");
+        let vhdx = VhdxFile::new(RawFile::new(file, false), size).expect("failed to create vhdx");
+        testfn(vhdx);
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn guid_layout() {
+        assert_eq!(
+            guid_from_str(BAT_REGION_GUID),
+            [
+                0x66, 0x77, 0xc2, 0x2d, 0x23, 0xf6, 0x00, 0x42, 0x9d, 0x64, 0x11, 0x5e, 0x9b, 0xfd,
+                0x4a, 0x08
+            ]
+        );
+    }
+
+    #[test]
+    fn create_and_reopen() {
+        with_vhdx_file(0x400_0000, |vhdx| {
+            assert_eq!(vhdx.virtual_size(), 0x400_0000);
+            assert_eq!(vhdx.block_size, u64::from(DEFAULT_BLOCK_SIZE));
+            assert_eq!(vhdx.bat.len(), 2);
+            let reopened = VhdxFile::from(vhdx.file.clone()).expect("failed to reopen vhdx");
+            assert_eq!(reopened.virtual_size(), 0x400_0000);
+        });
+    }
+
+    #[test]
+    fn write_read_across_blocks() {
+        with_vhdx_file(0x400_0000, |mut vhdx| {
+            let b = [0x5au8; 0x2000];
+            let address = u64::from(DEFAULT_BLOCK_SIZE) - 0x1000;
+            vhdx.seek(SeekFrom::Start(address)).unwrap();
+            vhdx.write_all(&b).expect("failed to write");
+
+            let mut reopened = VhdxFile::from(vhdx.file.clone()).expect("failed to reopen vhdx");
+            let mut buf = [0u8; 0x3000];
+            reopened.seek(SeekFrom::Start(address - 0x1000)).unwrap();
+            reopened.read_exact(&mut buf).expect("failed to read");
+            assert!(buf[..0x1000].iter().all(|v| *v == 0));
+            assert_eq!(&buf[0x1000..], &b[..]);
+        });
+    }
+
+    #[test]
+    fn unallocated_reads_zeros() {
+        with_vhdx_file(0x10_0000, |mut vhdx| {
+            let mut buf = [0x55u8; 0x200];
+            vhdx.read_exact(&mut buf).expect("failed to read");
+            assert!(buf.iter().all(|v| *v == 0));
+            assert_eq!(vhdx.seek_data(0).unwrap(), None);
+            assert_eq!(vhdx.seek_hole(0).unwrap(), Some(0));
+        });
+    }
+
+    #[test]
+    fn header_sequence_updated_on_first_write() {
+        with_vhdx_file(0x10_0000, |mut vhdx| {
+            let sequence_number = vhdx.header.sequence_number;
+            vhdx.write_all(&[1u8; 0x200]).unwrap();
+            vhdx.write_all(&[1u8; 0x200]).unwrap();
+            assert_eq!(vhdx.header.sequence_number, sequence_number + 1);
+        });
+    }
+
+    #[test]
+    fn log_replay() {
+        with_vhdx_file(0x10_0000, |vhdx| {
+            let mut file = vhdx.file.clone();
+            let log_guid = [0x11u8; 16];
+
+            // Single entry updating the first sector of the data region,
+            // past the BAT.
+            let target = 0x50_0000u64;
+            let entry_length = 2 * LOG_SECTOR_SIZE as usize;
+            let mut entry = vec![0u8; entry_length];
+            LittleEndian::write_u32(&mut entry[0..], LOG_ENTRY_SIGNATURE);
+            LittleEndian::write_u32(&mut entry[8..], entry_length as u32);
+            LittleEndian::write_u32(&mut entry[12..], 0);
+            LittleEndian::write_u64(&mut entry[16..], 7);
+            LittleEndian::write_u32(&mut entry[24..], 1);
+            entry[32..48].copy_from_slice(&log_guid);
+            LittleEndian::write_u64(&mut entry[48..], target + LOG_SECTOR_SIZE);
+            LittleEndian::write_u64(&mut entry[56..], target + LOG_SECTOR_SIZE);
+            let d = &mut entry[64..96];
+            LittleEndian::write_u32(&mut d[0..], LOG_DATA_DESCRIPTOR_SIGNATURE);
+            LittleEndian::write_u32(&mut d[4..], 0xdead_beef);
+            LittleEndian::write_u64(&mut d[8..], 0x0123_4567_89ab_cdef);
+            LittleEndian::write_u64(&mut d[16..], target);
+            LittleEndian::write_u64(&mut d[24..], 7);
+            let sector = &mut entry[LOG_SECTOR_SIZE as usize..];
+            LittleEndian::write_u32(&mut sector[0..], LOG_DATA_SECTOR_SIGNATURE);
+            LittleEndian::write_u32(&mut sector[4..], 0);
+            for b in sector[8..4092].iter_mut() {
+                *b = 0xa5;
+            }
+            LittleEndian::write_u32(&mut sector[4092..], 7);
+            set_checksum(&mut entry, 4);
+            write_at(&mut file, DEFAULT_LOG_OFFSET, &entry).unwrap();
+
+            let mut header = vhdx.header.clone();
+            header.sequence_number += 1;
+            header.log_guid = log_guid;
+            header.write(&mut file, vhdx.next_header_offset).unwrap();
+
+            let reopened = VhdxFile::from(file.clone()).expect("failed to replay log");
+            assert_eq!(reopened.header.log_guid, [0u8; 16]);
+
+            let mut buf = vec![0u8; LOG_SECTOR_SIZE as usize];
+            read_at(&mut file, target, &mut buf).unwrap();
+            assert_eq!(LittleEndian::read_u64(&buf[0..]), 0x0123_4567_89ab_cdef);
+            assert!(buf[8..4092].iter().all(|v| *v == 0xa5));
+            assert_eq!(LittleEndian::read_u32(&buf[4092..]), 0xdead_beef);
+        });
+    }
+
+    #[test]
+    fn log_replay_rejects_out_of_bounds_zero_descriptor() {
+        with_vhdx_file(0x10_0000, |vhdx| {
+            let mut file = vhdx.file.clone();
+            let log_guid = [0x22u8; 16];
+
+            let entry_length = LOG_SECTOR_SIZE as usize;
+            let mut entry = vec![0u8; entry_length];
+            LittleEndian::write_u32(&mut entry[0..], LOG_ENTRY_SIGNATURE);
+            LittleEndian::write_u32(&mut entry[8..], entry_length as u32);
+            LittleEndian::write_u64(&mut entry[16..], 1);
+            LittleEndian::write_u32(&mut entry[24..], 1);
+            entry[32..48].copy_from_slice(&log_guid);
+            let d = &mut entry[64..96];
+            LittleEndian::write_u32(&mut d[0..], LOG_ZERO_DESCRIPTOR_SIGNATURE);
+            LittleEndian::write_u64(&mut d[8..], 1 << 40);
+            LittleEndian::write_u64(&mut d[16..], 0);
+            LittleEndian::write_u64(&mut d[24..], 1);
+            set_checksum(&mut entry, 4);
+            write_at(&mut file, DEFAULT_LOG_OFFSET, &entry).unwrap();
+
+            let mut header = vhdx.header.clone();
+            header.sequence_number += 1;
+            header.log_guid = log_guid;
+            header.write(&mut file, vhdx.next_header_offset).unwrap();
+
+            match VhdxFile::from(file) {
+                Err(Error::InvalidLog) => {}
+                _ => panic!("the log entry should be rejected"),
+            }
+        });
+    }
+
+    #[test]
+    fn bat_update_replayed_from_log() {
+        with_vhdx_file(0x400_0000, |mut vhdx| {
+            let address = u64::from(DEFAULT_BLOCK_SIZE);
+            vhdx.seek(SeekFrom::Start(address)).unwrap();
+            vhdx.write_all(&[0x5au8; 0x200]).unwrap();
+            assert_ne!(vhdx.header.log_guid, [0u8; 16]);
+
+            // Lose the in place BAT update, as if the host crashed right
+            // after the log entry was written.
+            let mut file = vhdx.file.clone();
+            write_at(&mut file, vhdx.bat_offset, &[0u8; 16]).unwrap();
+
+            let mut reopened = VhdxFile::from(file).expect("failed to replay log");
+            assert_eq!(reopened.header.log_guid, [0u8; 16]);
+            let mut buf = [0u8; 0x200];
+            reopened.seek(SeekFrom::Start(address)).unwrap();
+            reopened.read_exact(&mut buf).expect("failed to read");
+            assert!(buf.iter().all(|v| *v == 0x5a));
+        });
+    }
+}
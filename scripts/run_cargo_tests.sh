@@ -13,6 +13,7 @@ time which cargo-audit || cargo install cargo-audit
 time cargo clippy --all-targets --all-features -- -D warnings
 time cargo rustc --bin cloud-hypervisor -- -D warnings
 time cargo rustc -p vhost_user_net --bin vhost_user_net -- -D warnings
+time cargo rustc -p vhost_user_switch --bin vhost_user_switch -- -D warnings
 time cargo test
 time cargo audit
 time cargo clippy --all-targets --no-default-features --features "pci,acpi,kvm" -- -D warnings
@@ -11,8 +11,10 @@ extern crate vmm;
 use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use std::fmt;
 use std::io::{Read, Write};
+use std::os::unix::io::RawFd;
 use std::os::unix::net::UnixStream;
 use std::process;
+use vmm_sys_util::sock_ctrl_msg::ScmSocket;
 
 #[derive(Debug)]
 enum Error {
@@ -24,6 +26,8 @@ enum Error {
     InvalidCPUCount(std::num::ParseIntError),
     InvalidMemorySize(std::num::ParseIntError),
     InvalidBalloonSize(std::num::ParseIntError),
+    InvalidDiskSize(std::num::ParseIntError),
+    InvalidSnaplen(std::num::ParseIntError),
     AddDeviceConfig(vmm::config::Error),
     AddDiskConfig(vmm::config::Error),
     AddFsConfig(vmm::config::Error),
@@ -51,6 +55,8 @@ impl fmt::Display for Error {
             InvalidCPUCount(e) => write!(f, "Error parsing CPU count: {}", e),
             InvalidMemorySize(e) => write!(f, "Error parsing memory size: {}", e),
             InvalidBalloonSize(e) => write!(f, "Error parsing balloon size: {}", e),
+            InvalidDiskSize(e) => write!(f, "Error parsing disk size: {}", e),
+            InvalidSnaplen(e) => write!(f, "Error parsing snapshot length: {}", e),
             AddDeviceConfig(e) => write!(f, "Error parsing device syntax: {}", e),
             AddDiskConfig(e) => write!(f, "Error parsing disk syntax: {}", e),
             AddFsConfig(e) => write!(f, "Error parsing filesystem syntax: {}", e),
@@ -170,29 +176,41 @@ fn simple_api_command(
     c: &str,
     request_body: Option<&str>,
 ) -> Result<(), Error> {
-    socket
-        .write_all(
-            format!(
-                "{} /api/v1/vm.{} HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n",
-                method, c
-            )
-            .as_bytes(),
-        )
-        .map_err(Error::Socket)?;
+    simple_api_command_with_fds(socket, method, c, request_body, &[])
+}
+
+fn simple_api_command_with_fds(
+    socket: &mut UnixStream,
+    method: &str,
+    c: &str,
+    request_body: Option<&str>,
+    fds: &[RawFd],
+) -> Result<(), Error> {
+    let mut request = format!(
+        "{} /api/v1/vm.{} HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n",
+        method, c
+    );
 
     if let Some(request_body) = request_body {
-        socket
-            .write_all(format!("Content-Length: {}\r\n", request_body.len()).as_bytes())
-            .map_err(Error::Socket)?;
+        request.push_str(&format!("Content-Length: {}\r\n", request_body.len()));
     }
 
-    socket.write_all(b"\r\n").map_err(Error::Socket)?;
+    request.push_str("\r\n");
 
     if let Some(request_body) = request_body {
-        socket
-            .write_all(request_body.as_bytes())
-            .map_err(Error::Socket)?;
+        request.push_str(request_body);
+    }
+
+    // The file descriptors are passed along the first bytes of the request.
+    let mut sent = 0;
+    if !fds.is_empty() {
+        sent = socket
+            .send_with_fds(&[request.as_bytes()], fds)
+            .map_err(|e| Error::Socket(std::io::Error::from_raw_os_error(e.errno())))?;
     }
+    socket
+        .write_all(&request.as_bytes()[sent..])
+        .map_err(Error::Socket)?;
 
     socket.flush().map_err(Error::Socket)?;
 
@@ -240,6 +258,82 @@ fn resize_api_command(
     )
 }
 
+fn resize_disk_api_command(socket: &mut UnixStream, id: &str, size: &str) -> Result<(), Error> {
+    let resize_disk = vmm::api::VmResizeDiskData {
+        id: id.to_owned(),
+        desired_size: size.parse().map_err(Error::InvalidDiskSize)?,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "resize-disk",
+        Some(&serde_json::to_string(&resize_disk).unwrap()),
+    )
+}
+
+fn stream_disk_api_command(socket: &mut UnixStream, id: &str) -> Result<(), Error> {
+    let stream_disk = vmm::api::VmStreamDiskData { id: id.to_owned() };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "stream-disk",
+        Some(&serde_json::to_string(&stream_disk).unwrap()),
+    )
+}
+
+fn set_link_api_command(socket: &mut UnixStream, id: &str, state: &str) -> Result<(), Error> {
+    let set_link = vmm::api::VmSetLinkData {
+        id: id.to_owned(),
+        up: state == "up",
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "set-link",
+        Some(&serde_json::to_string(&set_link).unwrap()),
+    )
+}
+
+fn start_net_capture_api_command(
+    socket: &mut UnixStream,
+    id: &str,
+    path: &str,
+    snaplen: Option<&str>,
+) -> Result<(), Error> {
+    let snaplen: Option<u32> = if let Some(snaplen) = snaplen {
+        Some(snaplen.parse().map_err(Error::InvalidSnaplen)?)
+    } else {
+        None
+    };
+
+    let start_net_capture = vmm::api::VmStartNetCaptureData {
+        id: id.to_owned(),
+        path: path.into(),
+        snaplen,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "start-net-capture",
+        Some(&serde_json::to_string(&start_net_capture).unwrap()),
+    )
+}
+
+fn stop_net_capture_api_command(socket: &mut UnixStream, id: &str) -> Result<(), Error> {
+    let stop_net_capture = vmm::api::VmStopNetCaptureData { id: id.to_owned() };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "stop-net-capture",
+        Some(&serde_json::to_string(&stop_net_capture).unwrap()),
+    )
+}
+
 fn add_device_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
     let device_config = vmm::config::DeviceConfig::parse(config).map_err(Error::AddDeviceConfig)?;
 
@@ -298,11 +392,16 @@ fn add_pmem_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Err
 fn add_net_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
     let net_config = vmm::config::NetConfig::parse(config).map_err(Error::AddNetConfig)?;
 
-    simple_api_command(
+    // The tap file descriptors are passed to the VMM, which uses them in
+    // place of the ones given through the configuration.
+    let fds = net_config.fds.clone().unwrap_or_default();
+
+    simple_api_command_with_fds(
         socket,
         "PUT",
         "add-net",
         Some(&serde_json::to_string(&net_config).unwrap()),
+        &fds,
     )
 }
 
@@ -363,6 +462,65 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .unwrap()
                 .value_of("balloon"),
         ),
+        Some("resize-disk") => resize_disk_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("resize-disk")
+                .unwrap()
+                .value_of("disk")
+                .unwrap(),
+            matches
+                .subcommand_matches("resize-disk")
+                .unwrap()
+                .value_of("size")
+                .unwrap(),
+        ),
+        Some("stream-disk") => stream_disk_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("stream-disk")
+                .unwrap()
+                .value_of("disk")
+                .unwrap(),
+        ),
+        Some("set-link") => set_link_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("set-link")
+                .unwrap()
+                .value_of("net")
+                .unwrap(),
+            matches
+                .subcommand_matches("set-link")
+                .unwrap()
+                .value_of("state")
+                .unwrap(),
+        ),
+        Some("start-net-capture") => start_net_capture_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("start-net-capture")
+                .unwrap()
+                .value_of("net")
+                .unwrap(),
+            matches
+                .subcommand_matches("start-net-capture")
+                .unwrap()
+                .value_of("path")
+                .unwrap(),
+            matches
+                .subcommand_matches("start-net-capture")
+                .unwrap()
+                .value_of("snaplen"),
+        ),
+        Some("stop-net-capture") => stop_net_capture_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("stop-net-capture")
+                .unwrap()
+                .value_of("net")
+                .unwrap(),
+        ),
         Some("add-device") => add_device_api_command(
             &mut socket,
             matches
@@ -541,6 +699,98 @@ fn main() {
                         .number_of_values(1),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("resize-disk")
+                .about("Grow a disk of the VM")
+                .arg(
+                    Arg::with_name("disk")
+                        .long("disk")
+                        .help("Disk identifier")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("size")
+                        .long("size")
+                        .help("New disk size (in bytes)")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stream-disk")
+                .about("Copy the backing file content of a disk into its image")
+                .arg(
+                    Arg::with_name("disk")
+                        .long("disk")
+                        .help("Disk identifier")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("set-link")
+                .about("Set the link of a network device up or down")
+                .arg(
+                    Arg::with_name("net")
+                        .long("net")
+                        .help("Network device identifier")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("state")
+                        .long("state")
+                        .help("Link state")
+                        .takes_value(true)
+                        .possible_values(&["up", "down"])
+                        .number_of_values(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("start-net-capture")
+                .about("Start capturing the frames of a network device to a pcap file")
+                .arg(
+                    Arg::with_name("net")
+                        .long("net")
+                        .help("Network device identifier")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .long("path")
+                        .help("Path of the pcap file, written by the VMM")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("snaplen")
+                        .long("snaplen")
+                        .help("Maximum number of bytes recorded per frame")
+                        .takes_value(true)
+                        .number_of_values(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stop-net-capture")
+                .about("Stop capturing the frames of a network device")
+                .arg(
+                    Arg::with_name("net")
+                        .long("net")
+                        .help("Network device identifier")
+                        .takes_value(true)
+                        .number_of_values(1)
+                        .required(true),
+                ),
+        )
         .subcommand(SubCommand::with_name("resume").about("Resume the VM"))
         .subcommand(SubCommand::with_name("shutdown").about("Shutdown the VM"))
         .subcommand(
@@ -4595,6 +4595,165 @@ mod tests {
             });
         }
 
+        #[cfg_attr(not(feature = "mmio"), test)]
+        #[cfg(target_arch = "x86_64")]
+        fn test_disk_hotplug_snapshot_unsupported() {
+            test_block!(tb, "", {
+                let mut focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+                let guest = Guest::new(&mut focal);
+
+                let kernel_path = direct_kernel_boot_path().unwrap();
+
+                let api_socket = temp_api_path(&guest.tmp_dir);
+
+                let mut child = GuestCommand::new(&guest)
+                    .args(&["--api-socket", &api_socket])
+                    .args(&["--cpus", "boot=1"])
+                    .args(&["--memory", "size=512M,shared=on"])
+                    .args(&["--kernel", kernel_path.to_str().unwrap()])
+                    .args(&["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+                    .default_disks()
+                    .default_net()
+                    .spawn()
+                    .unwrap();
+
+                thread::sleep(std::time::Duration::new(20, 0));
+
+                // The base image would be written by the backend or the NBD
+                // server, the snapshot mode must be refused.
+                let mut blk_file_path = dirs::home_dir().unwrap();
+                blk_file_path.push("workloads");
+                blk_file_path.push("blk.img");
+                aver!(
+                    tb,
+                    !remote_command(
+                        &api_socket,
+                        "add-disk",
+                        Some(
+                            format!(
+                                "path={},vhost_user=true,snapshot=on,id=test0",
+                                blk_file_path.to_str().unwrap()
+                            )
+                            .as_str()
+                        ),
+                    )
+                );
+                aver!(
+                    tb,
+                    !remote_command(
+                        &api_socket,
+                        "add-disk",
+                        Some("path=nbd://127.0.0.1:10809/disk0,snapshot=on,id=test1"),
+                    )
+                );
+
+                thread::sleep(std::time::Duration::new(10, 0));
+
+                // Check /dev/vdc is not there and the VM is still running.
+                aver_eq!(
+                    tb,
+                    guest
+                        .ssh_command("lsblk | grep -c vdc")
+                        .unwrap_or_default()
+                        .trim()
+                        .parse::<u32>()
+                        .unwrap_or(1),
+                    0
+                );
+
+                let _ = child.kill();
+                let _ = child.wait();
+                Ok(())
+            });
+        }
+
+        #[test]
+        fn test_disk_resize() {
+            test_block!(tb, "", {
+                let mut focal = UbuntuDiskConfig::new(FOCAL_IMAGE_NAME.to_string());
+                let guest = Guest::new(&mut focal);
+
+                let kernel_path = direct_kernel_boot_path().unwrap();
+
+                let api_socket = temp_api_path(&guest.tmp_dir);
+
+                // Create a 16MiB raw disk which is going to be grown to 32MiB.
+                let resize_disk_path = guest.tmp_dir.path().join("resize.img");
+                fs::File::create(&resize_disk_path)
+                    .unwrap()
+                    .set_len(16 << 20)
+                    .unwrap();
+
+                let mut child = GuestCommand::new(&guest)
+                    .args(&["--api-socket", &api_socket])
+                    .args(&["--cpus", "boot=1"])
+                    .args(&["--memory", "size=512M"])
+                    .args(&["--kernel", kernel_path.to_str().unwrap()])
+                    .args(&["--cmdline", DIRECT_KERNEL_BOOT_CMDLINE])
+                    .args(&[
+                        "--disk",
+                        format!(
+                            "path={}",
+                            guest.disk_config.disk(DiskType::OperatingSystem).unwrap()
+                        )
+                        .as_str(),
+                        format!(
+                            "path={}",
+                            guest.disk_config.disk(DiskType::CloudInit).unwrap()
+                        )
+                        .as_str(),
+                        format!("path={},id=test0", resize_disk_path.to_str().unwrap()).as_str(),
+                    ])
+                    .default_net()
+                    .spawn()
+                    .unwrap();
+
+                thread::sleep(std::time::Duration::new(20, 0));
+
+                // Check /dev/vdc exists and the block size is 16M.
+                aver_eq!(
+                    tb,
+                    guest
+                        .ssh_command("lsblk | grep vdc | grep -c 16M")
+                        .unwrap_or_default()
+                        .trim()
+                        .parse::<u32>()
+                        .unwrap_or_default(),
+                    1
+                );
+
+                let mut cmd = Command::new(clh_command("ch-remote"));
+                cmd.args(&[
+                    &format!("--api-socket={}", api_socket),
+                    "resize-disk",
+                    "--disk=test0",
+                    &format!("--size={}", 32 << 20),
+                ]);
+                aver!(
+                    tb,
+                    cmd.status().expect("Failed to launch ch-remote").success()
+                );
+
+                thread::sleep(std::time::Duration::new(5, 0));
+
+                // Check the guest has been notified about the new capacity.
+                aver_eq!(
+                    tb,
+                    guest
+                        .ssh_command("lsblk | grep vdc | grep -c 32M")
+                        .unwrap_or_default()
+                        .trim()
+                        .parse::<u32>()
+                        .unwrap_or_default(),
+                    1
+                );
+
+                let _ = child.kill();
+                let _ = child.wait();
+                Ok(())
+            });
+        }
+
         #[cfg_attr(not(feature = "mmio"), test)]
         #[cfg(target_arch = "x86_64")]
         fn test_pmem_hotplug() {
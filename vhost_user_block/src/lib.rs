@@ -13,13 +13,15 @@ extern crate log;
 extern crate vhost_rs;
 extern crate vhost_user_backend;
 
+use block_util::nbd::{is_nbd_uri, NbdDisk};
+use block_util::stats::SharedBlockStats;
 use block_util::{build_disk_image_id, Request, VirtioBlockConfig};
 use libc::EFD_NONBLOCK;
 use log::*;
 use option_parser::{OptionParser, OptionParserError, Toggle};
-use qcow::{self, ImageType, QcowFile};
-use std::fs::File;
+use qcow::{self, FixedVhdFile, ImageType, LuksDisk, QcowFile, VhdxFile};
 use std::fs::OpenOptions;
+use std::fs::{self, File};
 use std::io::Read;
 use std::io::{Seek, SeekFrom, Write};
 use std::num::Wrapping;
@@ -70,12 +72,17 @@ enum Error {
     PathParameterMissing,
     /// No socket provided
     SocketParameterMissing,
+    /// Both key file and key fd provided
+    KeyFileAndFd,
+    /// Failed to open the statistics file
+    OpenStatsFile(io::Error),
 }
 
 pub const SYNTAX: &str = "vhost-user-block backend parameters \
  \"path=<image_path>,socket=<socket_path>,num_queues=<number_of_queues>,\
  queue_size=<size_of_each_queue>,readonly=true|false,direct=true|false,\
- poll_queue=true|false\"";
+ poll_queue=true|false,key_file=<luks_passphrase_file>,key_fd=<luks_passphrase_fd>,\
+ copy_on_read=true|false,backing_files=true|false,stats_file=<statistics_file>\"";
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -99,6 +106,7 @@ struct VhostUserBlkThread {
     event_idx: bool,
     kill_evt: EventFd,
     writeback: Arc<AtomicBool>,
+    stats: Option<Arc<SharedBlockStats>>,
 }
 
 impl VhostUserBlkThread {
@@ -107,6 +115,7 @@ impl VhostUserBlkThread {
         disk_image_id: Vec<u8>,
         disk_nsectors: u64,
         writeback: Arc<AtomicBool>,
+        stats: Option<Arc<SharedBlockStats>>,
     ) -> Result<Self> {
         Ok(VhostUserBlkThread {
             mem: None,
@@ -116,6 +125,7 @@ impl VhostUserBlkThread {
             event_idx: false,
             kill_evt: EventFd::new(EFD_NONBLOCK).map_err(Error::CreateKillEventFd)?,
             writeback,
+            stats,
         })
     }
 
@@ -129,10 +139,15 @@ impl VhostUserBlkThread {
         while let Some(head) = vring.mut_queue().iter(mem).next() {
             debug!("got an element in the queue");
             let len;
+            let mut completed = None;
             match Request::parse(&head, mem) {
                 Ok(mut request) => {
                     debug!("element is a valid request");
                     request.set_writeback(self.writeback.load(Ordering::SeqCst));
+                    let start = Instant::now();
+                    if let Some(stats) = &self.stats {
+                        stats.request_started();
+                    }
                     let status = match request.execute(
                         &mut self.disk_image.lock().unwrap().deref_mut(),
                         self.disk_nsectors,
@@ -149,6 +164,7 @@ impl VhostUserBlkThread {
                         }
                     };
                     mem.write_obj(status, request.status_addr).unwrap();
+                    completed = Some((request.request_type, start));
                 }
                 Err(err) => {
                     error!("failed to parse available descriptor chain: {:?}", err);
@@ -173,6 +189,10 @@ impl VhostUserBlkThread {
                 vring.signal_used_queue().unwrap();
                 used_any = true;
             }
+
+            if let (Some(stats), Some((request_type, start))) = (&self.stats, completed) {
+                stats.request_completed(request_type, start.elapsed());
+            }
         }
 
         used_any
@@ -191,6 +211,7 @@ struct VhostUserBlkBackend {
 }
 
 impl VhostUserBlkBackend {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         image_path: String,
         num_queues: usize,
@@ -198,24 +219,41 @@ impl VhostUserBlkBackend {
         direct: bool,
         poll_queue: bool,
         queue_size: usize,
+        key: Option<Vec<u8>>,
+        copy_on_read: bool,
+        backing_files: bool,
+        stats_file: Option<String>,
     ) -> Result<Self> {
-        let mut options = OpenOptions::new();
-        options.read(true);
-        options.write(!rdonly);
-        if direct {
-            options.custom_flags(libc::O_DIRECT);
-        }
-        let image: File = options.open(&image_path).unwrap();
-        let mut raw_img: qcow::RawFile = qcow::RawFile::new(image, direct);
-
         let image_id = build_disk_image_id(&PathBuf::from(&image_path));
-        let image_type = qcow::detect_image_type(&mut raw_img).unwrap();
-        let image = match image_type {
-            ImageType::Raw => Arc::new(Mutex::new(raw_img)) as Arc<Mutex<dyn DiskFile>>,
-            ImageType::Qcow2 => {
-                Arc::new(Mutex::new(QcowFile::from(raw_img).unwrap())) as Arc<Mutex<dyn DiskFile>>
+        let image: Box<dyn DiskFile> = if is_nbd_uri(&image_path) {
+            Box::new(NbdDisk::new(&image_path).unwrap())
+        } else {
+            let mut options = OpenOptions::new();
+            options.read(true);
+            options.write(!rdonly);
+            if direct {
+                options.custom_flags(libc::O_DIRECT);
+            }
+            let image: File = options.open(&image_path).unwrap();
+            let mut raw_img: qcow::RawFile = qcow::RawFile::new(image, direct);
+
+            let image_type = qcow::detect_image_type(&mut raw_img).unwrap();
+            match image_type {
+                ImageType::Raw => Box::new(raw_img),
+                ImageType::Qcow2 => {
+                    let mut qcow_img = QcowFile::from(raw_img, backing_files).unwrap();
+                    qcow_img.set_copy_on_read(copy_on_read);
+                    Box::new(qcow_img)
+                }
+                ImageType::FixedVhd => Box::new(FixedVhdFile::new(raw_img).unwrap()),
+                ImageType::Vhdx => Box::new(VhdxFile::from(raw_img).unwrap()),
             }
         };
+        let image: Arc<Mutex<dyn DiskFile>> = if let Some(key) = key {
+            Arc::new(Mutex::new(LuksDisk::new(image, &key).unwrap()))
+        } else {
+            Arc::new(Mutex::new(image))
+        };
 
         let nsectors = (image.lock().unwrap().seek(SeekFrom::End(0)).unwrap() as u64) / SECTOR_SIZE;
         let mut config = VirtioBlockConfig::default();
@@ -229,6 +267,20 @@ impl VhostUserBlkBackend {
         config.num_queues = num_queues as u16;
         config.writeback = 1;
 
+        let stats = if let Some(stats_file) = stats_file {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(stats_file)
+                .map_err(Error::OpenStatsFile)?;
+            Some(Arc::new(
+                SharedBlockStats::new(file).map_err(Error::OpenStatsFile)?,
+            ))
+        } else {
+            None
+        };
+
         let mut queues_per_thread = Vec::new();
         let mut threads = Vec::new();
         let writeback = Arc::new(AtomicBool::new(true));
@@ -238,6 +290,7 @@ impl VhostUserBlkBackend {
                 image_id.clone(),
                 nsectors,
                 writeback.clone(),
+                stats.clone(),
             )?);
             threads.push(thread);
             queues_per_thread.push(0b1 << i);
@@ -421,19 +474,29 @@ struct VhostUserBlkBackendConfig {
     readonly: bool,
     direct: bool,
     poll_queue: bool,
+    key_file: Option<String>,
+    key_fd: Option<i32>,
+    copy_on_read: bool,
+    backing_files: bool,
+    stats_file: Option<String>,
 }
 
 impl VhostUserBlkBackendConfig {
     fn parse(backend: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
         parser
-            .add("path")
+            .add_uri("path")
             .add("readonly")
             .add("direct")
             .add("num_queues")
             .add("queue_size")
             .add("socket")
-            .add("poll_queue");
+            .add("poll_queue")
+            .add("key_file")
+            .add("key_fd")
+            .add("copy_on_read")
+            .add("backing_files")
+            .add("stats_file");
         parser.parse(backend).map_err(Error::FailedConfigParse)?;
 
         let path = parser.get("path").ok_or(Error::PathParameterMissing)?;
@@ -461,6 +524,22 @@ impl VhostUserBlkBackendConfig {
             .convert("queue_size")
             .map_err(Error::FailedConfigParse)?
             .unwrap_or(1024);
+        let key_file = parser.get("key_file");
+        let key_fd = parser.convert("key_fd").map_err(Error::FailedConfigParse)?;
+        if key_file.is_some() && key_fd.is_some() {
+            return Err(Error::KeyFileAndFd);
+        }
+        let copy_on_read = parser
+            .convert::<Toggle>("copy_on_read")
+            .map_err(Error::FailedConfigParse)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let backing_files = parser
+            .convert::<Toggle>("backing_files")
+            .map_err(Error::FailedConfigParse)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let stats_file = parser.get("stats_file");
 
         Ok(VhostUserBlkBackendConfig {
             path,
@@ -470,6 +549,11 @@ impl VhostUserBlkBackendConfig {
             direct,
             poll_queue,
             queue_size,
+            key_file,
+            key_fd,
+            copy_on_read,
+            backing_files,
+            stats_file,
         })
     }
 }
@@ -483,6 +567,21 @@ pub fn start_block_backend(backend_command: &str) {
         }
     };
 
+    let key = if let Some(key_file) = &backend_config.key_file {
+        Some(fs::read(key_file))
+    } else if let Some(key_fd) = backend_config.key_fd {
+        Some(qcow::luks::read_passphrase_fd(key_fd))
+    } else {
+        None
+    };
+    let key = match key.transpose() {
+        Ok(key) => key,
+        Err(e) => {
+            println!("Failed reading disk key {:?}", e);
+            process::exit(1);
+        }
+    };
+
     let blk_backend = Arc::new(RwLock::new(
         VhostUserBlkBackend::new(
             backend_config.path,
@@ -491,6 +590,10 @@ pub fn start_block_backend(backend_command: &str) {
             backend_config.direct,
             backend_config.poll_queue,
             backend_config.queue_size,
+            key,
+            backend_config.copy_on_read,
+            backend_config.backing_files,
+            backend_config.stats_file,
         )
         .unwrap(),
     ));
@@ -14,13 +14,15 @@ extern crate vhost_user_backend;
 use libc::{self, EFD_NONBLOCK};
 use log::*;
 use net_util::{
-    open_tap, MacAddr, NetCounters, NetQueuePair, OpenTapError, RxVirtio, Tap, TxVirtio,
+    open_tap, MacAddr, NetCounters, NetOffloads, NetQueuePair, OpenTapError, PcapCapture, RxVirtio,
+    Tap, TxVirtio,
 };
-use option_parser::{OptionParser, OptionParserError};
+use option_parser::{OptionParser, OptionParserError, Toggle};
 use std::fmt;
 use std::io::{self};
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
 use std::process;
 use std::sync::{Arc, Mutex, RwLock};
 use std::vec::Vec;
@@ -70,11 +72,16 @@ pub enum Error {
     SocketParameterMissing,
     /// Underlying QueuePair error
     NetQueuePair(net_util::NetQueuePairError),
+    /// Failed to start the packet capture
+    StartCapture(io::Error),
 }
 
 pub const SYNTAX: &str = "vhost-user-net backend parameters \
-\"ip=<ip_addr>,mask=<net_mask>,socket=<socket_path>,\
-num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,tap=<if_name>\"";
+\"ip=<ip_addr>,mask=<net_mask>,ipv4=on|off,ipv6=<ipv6_addr>,\
+ipv6_prefix=<ipv6_prefix_len>,socket=<socket_path>,\
+num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,tap=<if_name>,\
+mtu=<mtu>,csum=on|off,tso=on|off,tso6=on|off,ufo=on|off,ecn=on|off,\
+pcap=<pcap_file>,pcap_snaplen=<bytes_per_frame>\"";
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -98,19 +105,27 @@ struct VhostUserNetThread {
 
 impl VhostUserNetThread {
     /// Create a new virtio network device with the given TAP interface.
-    fn new(tap: Tap) -> Result<Self> {
+    fn new(tap: Tap, capture: &PcapCapture) -> Result<Self> {
+        let mut rx = RxVirtio::new();
+        rx.capture = capture.clone();
+        let mut tx = TxVirtio::new();
+        tx.capture = capture.clone();
+
         Ok(VhostUserNetThread {
             vring_worker: None,
             kill_evt: EventFd::new(EFD_NONBLOCK).map_err(Error::CreateKillEventFd)?,
             net: NetQueuePair {
                 mem: None,
                 tap,
-                rx: RxVirtio::new(),
-                tx: TxVirtio::new(),
+                rx,
+                tx,
                 rx_tap_listening: false,
                 epoll_fd: None,
                 counters: NetCounters::default(),
                 tap_event_id: 2,
+                rx_filter: None,
+                rss: None,
+                link_status: None,
             },
         })
     }
@@ -126,30 +141,46 @@ pub struct VhostUserNetBackend {
     num_queues: usize,
     queue_size: u16,
     queues_per_thread: Vec<u64>,
+    offloads: NetOffloads,
 }
 
 impl VhostUserNetBackend {
+    #[allow(clippy::too_many_arguments)]
     fn new(
-        ip_addr: Ipv4Addr,
+        ip_addr: Option<Ipv4Addr>,
         host_mac: MacAddr,
-        netmask: Ipv4Addr,
+        netmask: Option<Ipv4Addr>,
+        ipv6_addr: Option<(Ipv6Addr, u8)>,
         num_queues: usize,
         queue_size: u16,
         ifname: Option<&str>,
+        mtu: Option<u16>,
+        offloads: NetOffloads,
+        pcap: Option<(PathBuf, Option<u32>)>,
     ) -> Result<Self> {
         let mut taps = open_tap(
             ifname,
-            Some(ip_addr),
-            Some(netmask),
+            ip_addr,
+            netmask,
+            ipv6_addr,
             &mut Some(host_mac),
             num_queues / 2,
+            mtu,
+            offloads,
         )
         .map_err(Error::OpenTap)?;
 
+        // The capture is shared by the queue pairs, and runs until the
+        // backend exits.
+        let capture = PcapCapture::default();
+        if let Some((path, snaplen)) = pcap {
+            capture.start(&path, snaplen).map_err(Error::StartCapture)?;
+        }
+
         let mut queues_per_thread = Vec::new();
         let mut threads = Vec::new();
         for (i, tap) in taps.drain(..).enumerate() {
-            let thread = Mutex::new(VhostUserNetThread::new(tap)?);
+            let thread = Mutex::new(VhostUserNetThread::new(tap, &capture)?);
             threads.push(thread);
             queues_per_thread.push(0b11 << (i * 2));
         }
@@ -159,6 +190,7 @@ impl VhostUserNetBackend {
             num_queues,
             queue_size,
             queues_per_thread,
+            offloads,
         })
     }
 }
@@ -173,17 +205,20 @@ impl VhostUserBackend for VhostUserNetBackend {
     }
 
     fn features(&self) -> u64 {
-        1 << VIRTIO_NET_F_GUEST_CSUM
-            | 1 << VIRTIO_NET_F_CSUM
-            | 1 << VIRTIO_NET_F_GUEST_TSO4
-            | 1 << VIRTIO_NET_F_GUEST_UFO
-            | 1 << VIRTIO_NET_F_HOST_TSO4
-            | 1 << VIRTIO_NET_F_HOST_UFO
+        self.offloads.virtio_features()
+            | 1 << VIRTIO_NET_F_MRG_RXBUF
             | 1 << VIRTIO_F_VERSION_1
             | 1 << VIRTIO_RING_F_EVENT_IDX
             | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits()
     }
 
+    fn acked_features(&mut self, features: u64) {
+        let mergeable = features & 1 << VIRTIO_NET_F_MRG_RXBUF != 0;
+        for thread in self.threads.iter() {
+            thread.lock().unwrap().net.rx.mergeable = mergeable;
+        }
+    }
+
     fn protocol_features(&self) -> VhostUserProtocolFeatures {
         VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::REPLY_ACK
     }
@@ -275,10 +310,17 @@ pub struct VhostUserNetBackendConfig {
     pub ip: Ipv4Addr,
     pub host_mac: MacAddr,
     pub mask: Ipv4Addr,
+    pub ipv4: bool,
+    pub ipv6: Option<Ipv6Addr>,
+    pub ipv6_prefix: u8,
     pub socket: String,
     pub num_queues: usize,
     pub queue_size: u16,
     pub tap: Option<String>,
+    pub mtu: Option<u16>,
+    pub offloads: NetOffloads,
+    pub pcap: Option<PathBuf>,
+    pub pcap_snaplen: Option<u32>,
 }
 
 impl VhostUserNetBackendConfig {
@@ -290,9 +332,20 @@ impl VhostUserNetBackendConfig {
             .add("ip")
             .add("host_mac")
             .add("mask")
+            .add("ipv4")
+            .add("ipv6")
+            .add("ipv6_prefix")
             .add("queue_size")
             .add("num_queues")
-            .add("socket");
+            .add("socket")
+            .add("mtu")
+            .add("csum")
+            .add("tso")
+            .add("tso6")
+            .add("ufo")
+            .add("ecn")
+            .add("pcap")
+            .add("pcap_snaplen");
 
         parser.parse(backend).map_err(Error::FailedConfigParse)?;
 
@@ -309,6 +362,15 @@ impl VhostUserNetBackendConfig {
             .convert("mask")
             .map_err(Error::FailedConfigParse)?
             .unwrap_or_else(|| Ipv4Addr::new(255, 255, 255, 0));
+        let ipv4 = parser
+            .convert::<Toggle>("ipv4")
+            .map_err(Error::FailedConfigParse)?
+            .map_or(true, |t| t.0);
+        let ipv6 = parser.convert("ipv6").map_err(Error::FailedConfigParse)?;
+        let ipv6_prefix = parser
+            .convert("ipv6_prefix")
+            .map_err(Error::FailedConfigParse)?
+            .unwrap_or(64);
         let queue_size = parser
             .convert("queue_size")
             .map_err(Error::FailedConfigParse)?
@@ -318,15 +380,51 @@ impl VhostUserNetBackendConfig {
             .map_err(Error::FailedConfigParse)?
             .unwrap_or(2);
         let socket = parser.get("socket").ok_or(Error::SocketParameterMissing)?;
+        let mtu = parser.convert("mtu").map_err(Error::FailedConfigParse)?;
+        let defaults = NetOffloads::default();
+        let offloads = NetOffloads {
+            csum: parser
+                .convert::<Toggle>("csum")
+                .map_err(Error::FailedConfigParse)?
+                .map_or(defaults.csum, |t| t.0),
+            tso: parser
+                .convert::<Toggle>("tso")
+                .map_err(Error::FailedConfigParse)?
+                .map_or(defaults.tso, |t| t.0),
+            tso6: parser
+                .convert::<Toggle>("tso6")
+                .map_err(Error::FailedConfigParse)?
+                .map_or(defaults.tso6, |t| t.0),
+            ufo: parser
+                .convert::<Toggle>("ufo")
+                .map_err(Error::FailedConfigParse)?
+                .map_or(defaults.ufo, |t| t.0),
+            ecn: parser
+                .convert::<Toggle>("ecn")
+                .map_err(Error::FailedConfigParse)?
+                .map_or(defaults.ecn, |t| t.0),
+        };
+
+        let pcap = parser.get("pcap").map(PathBuf::from);
+        let pcap_snaplen = parser
+            .convert("pcap_snaplen")
+            .map_err(Error::FailedConfigParse)?;
 
         Ok(VhostUserNetBackendConfig {
             ip,
             host_mac,
             mask,
+            ipv4,
+            ipv6,
+            ipv6_prefix,
             socket,
             num_queues,
             queue_size,
             tap,
+            mtu,
+            offloads,
+            pcap,
+            pcap_snaplen,
         })
     }
 }
@@ -346,14 +444,30 @@ pub fn start_net_backend(backend_command: &str) {
         None
     };
 
+    let (ip, mask) = if backend_config.ipv4 {
+        (Some(backend_config.ip), Some(backend_config.mask))
+    } else {
+        (None, None)
+    };
+    let ipv6_addr = backend_config
+        .ipv6
+        .map(|ip| (ip, backend_config.ipv6_prefix));
+
     let net_backend = Arc::new(RwLock::new(
         VhostUserNetBackend::new(
-            backend_config.ip,
+            ip,
             backend_config.host_mac,
-            backend_config.mask,
+            mask,
+            ipv6_addr,
             backend_config.num_queues,
             backend_config.queue_size,
             tap,
+            backend_config.mtu,
+            backend_config.offloads,
+            backend_config
+                .pcap
+                .clone()
+                .map(|path| (path, backend_config.pcap_snaplen)),
         )
         .unwrap(),
     ));
@@ -0,0 +1,499 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! vhost-user-net backend acting as an L2 switch between the guests
+//! connected to its sockets, one socket per port. The frames are copied
+//! from the transmit queue of the sending guest to the receive queue of the
+//! receiving one, without going through the host kernel unless they are
+//! forwarded to the optional uplink tap. They are only queued by the switch
+//! while the receiving guest has no buffer available.
+
+extern crate log;
+extern crate net_util;
+extern crate vhost_rs;
+extern crate vhost_user_backend;
+
+mod switch;
+
+use libc::EFD_NONBLOCK;
+use log::*;
+use net_util::{open_tap, NetOffloads, OpenTapError};
+use option_parser::{OptionParser, OptionParserError};
+use std::cmp;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::process;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use switch::{PortId, Switch, ETH_HLEN, MAX_BUFFER_SIZE, VNET_HDR_LEN};
+use vhost_rs::vhost_user::message::*;
+use vhost_rs::vhost_user::{Error as VhostUserError, Listener};
+use vhost_user_backend::{VhostUserBackend, VhostUserDaemon, Vring};
+use virtio_bindings::bindings::virtio_net::*;
+use vm_memory::{Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
+use vm_virtio::Queue;
+use vmm_sys_util::eventfd::EventFd;
+
+pub type Result<T> = std::result::Result<T, Error>;
+pub type VhostUserBackendResult<T> = std::result::Result<T, std::io::Error>;
+
+// The guest has made a buffer available to receive a frame into.
+const RX_QUEUE_EVENT: u16 = 0;
+// The guest has frames to send.
+const TX_QUEUE_EVENT: u16 = 1;
+// Frames have been forwarded to the port by the switch.
+const PORT_EVENT: u16 = 2;
+// The vring worker must stop, the guest having disconnected.
+const EXIT_EVENT: u16 = 3;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to create kill eventfd
+    CreateKillEventFd(io::Error),
+    /// Failed to create the ports of the switch.
+    CreatePorts(io::Error),
+    /// Failed to create the vhost-user listener.
+    CreateListener(VhostUserError),
+    /// Failed to create or run the vhost-user daemon.
+    Daemon(vhost_user_backend::Error),
+    /// Failed to create the epoll fd of the uplink.
+    EpollCreateFd(io::Error),
+    /// Failed to add event.
+    EpollCtl(io::Error),
+    /// Fail to wait event.
+    EpollWait(io::Error),
+    /// Failed to parse configuration string
+    FailedConfigParse(OptionParserError),
+    /// Failed to signal used queue.
+    FailedSignalingUsedQueue(io::Error),
+    /// Failed to handle event other than input event.
+    HandleEventNotEpollIn,
+    /// Failed to handle unknown event.
+    HandleEventUnknownEvent,
+    /// No memory configured.
+    NoMemoryConfigured,
+    /// Open tap device failed.
+    OpenTap(OpenTapError),
+    /// Failed to read from the uplink tap.
+    ReadUplink(io::Error),
+    /// Failed to register the event of the port.
+    RegisterListener(io::Error),
+    /// No socket provided
+    SocketParameterMissing,
+    /// Failed to stop the vring worker.
+    StopWorker(io::Error),
+}
+
+pub const SYNTAX: &str = "vhost-user-switch backend parameters \
+\"socket=<socket_path>[:<socket_path>...],queue_size=<size_of_each_queue>,tap=<if_name>\"";
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "vhost_user_switch_error: {:?}", self)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl std::convert::From<Error> for std::io::Error {
+    fn from(e: Error) -> Self {
+        std::io::Error::new(io::ErrorKind::Other, e)
+    }
+}
+
+// Copies the content of the buffers into `buf`, as much as fits. Returns the
+// number of bytes copied.
+fn read_buffers(mem: &GuestMemoryMmap, buffers: &[(GuestAddress, usize)], buf: &mut [u8]) -> usize {
+    let mut count = 0;
+    for (addr, len) in buffers {
+        let len = cmp::min(*len, buf.len() - count);
+        if let Err(e) = mem.read_slice(&mut buf[count..count + len], *addr) {
+            error!("Failed to read slice: {:?}", e);
+            break;
+        }
+        count += len;
+        if count == buf.len() {
+            break;
+        }
+    }
+    count
+}
+
+struct SwitchPortThread {
+    mem: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
+    // Buffers of the frame being sent by the guest.
+    buffers: Vec<(GuestAddress, usize)>,
+    // Frames sent by the guest which aren't copied straight into the
+    // receive queue of another guest.
+    frame_buf: Vec<u8>,
+    kill_evt: EventFd,
+}
+
+impl SwitchPortThread {
+    // Hands the frames sent by the guest over to the switch. Returns whether
+    // the guest must be notified.
+    fn process_tx(&mut self, switch: &Switch, index: usize, queue: &mut Queue) -> Result<bool> {
+        let mem = self
+            .mem
+            .as_ref()
+            .ok_or(Error::NoMemoryConfigured)
+            .map(|m| m.memory())?;
+
+        let from = PortId::Guest(index);
+        let mut used = false;
+        while let Some(avail_desc) = queue.iter(&mem).next() {
+            let head_index = avail_desc.index;
+            let mut next_desc = Some(avail_desc);
+            self.buffers.clear();
+            while let Some(desc) = next_desc {
+                if desc.is_write_only() {
+                    break;
+                }
+                self.buffers.push((desc.addr, desc.len as usize));
+                next_desc = desc.next_descriptor();
+            }
+
+            // The destination is looked up from the Ethernet header, so that
+            // a frame to a known guest can be copied straight into its
+            // receive queue.
+            let mut hdr = [0u8; VNET_HDR_LEN + ETH_HLEN];
+            if read_buffers(&mem, &self.buffers, &mut hdr) == hdr.len() {
+                let buffers = &self.buffers;
+                match switch.lookup(from, &hdr[VNET_HDR_LEN..]) {
+                    Some(PortId::Guest(to)) if to != index => switch
+                        .port(to)
+                        .deliver(|buf| read_buffers(&mem, buffers, buf)),
+                    to => {
+                        let len = read_buffers(&mem, buffers, &mut self.frame_buf);
+                        switch.send(from, to, &self.frame_buf[VNET_HDR_LEN..len]);
+                    }
+                }
+            }
+
+            queue.add_used(&mem, head_index, 0);
+            used = true;
+        }
+
+        Ok(used)
+    }
+}
+
+pub struct SwitchPortBackend {
+    switch: Arc<Switch>,
+    index: usize,
+    queue_size: u16,
+    thread: Mutex<SwitchPortThread>,
+}
+
+impl SwitchPortBackend {
+    /// Create the backend serving the guest connected to the port `index`
+    /// of the switch.
+    fn new(switch: Arc<Switch>, index: usize, queue_size: u16) -> Result<Self> {
+        Ok(SwitchPortBackend {
+            switch,
+            index,
+            queue_size,
+            thread: Mutex::new(SwitchPortThread {
+                mem: None,
+                buffers: Vec::new(),
+                frame_buf: vec![0u8; MAX_BUFFER_SIZE],
+                kill_evt: EventFd::new(EFD_NONBLOCK).map_err(Error::CreateKillEventFd)?,
+            }),
+        })
+    }
+}
+
+impl VhostUserBackend for SwitchPortBackend {
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn max_queue_size(&self) -> usize {
+        self.queue_size as usize
+    }
+
+    // No offload is offered, since the frames are forwarded as they are to
+    // guests which may not support them.
+    fn features(&self) -> u64 {
+        1 << VIRTIO_NET_F_MRG_RXBUF
+            | 1 << VIRTIO_F_VERSION_1
+            | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits()
+    }
+
+    fn acked_features(&mut self, features: u64) {
+        self.switch
+            .port(self.index)
+            .set_mergeable(features & 1 << VIRTIO_NET_F_MRG_RXBUF != 0);
+    }
+
+    fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        VhostUserProtocolFeatures::MQ | VhostUserProtocolFeatures::REPLY_ACK
+    }
+
+    fn set_event_idx(&mut self, _enabled: bool) {}
+
+    fn update_memory(&mut self, mem: GuestMemoryMmap) -> VhostUserBackendResult<()> {
+        let mem = GuestMemoryAtomic::new(mem);
+        self.thread.lock().unwrap().mem = Some(mem.clone());
+        self.switch.port(self.index).set_memory(mem);
+        Ok(())
+    }
+
+    fn handle_event(
+        &self,
+        device_event: u16,
+        evset: epoll::Events,
+        vrings: &[Arc<RwLock<Vring>>],
+        _thread_id: usize,
+    ) -> VhostUserBackendResult<bool> {
+        if evset != epoll::Events::EPOLLIN {
+            return Err(Error::HandleEventNotEpollIn.into());
+        }
+
+        match device_event {
+            RX_QUEUE_EVENT | PORT_EVENT => {
+                if device_event == PORT_EVENT {
+                    // The eventfd only wakes the port up, the frames being
+                    // counted by the switch.
+                    let _ = self.switch.port(self.index).evt().read();
+                }
+                // The receive queue isn't locked here, since the port locks
+                // it after its own state, as done when other ports deliver
+                // frames to it.
+                self.switch
+                    .port(self.index)
+                    .process_rx(&vrings[0])
+                    .map_err(Error::FailedSignalingUsedQueue)?;
+            }
+            TX_QUEUE_EVENT => {
+                let mut thread = self.thread.lock().unwrap();
+                let mut vring = vrings[1].write().unwrap();
+                if thread.process_tx(&self.switch, self.index, vring.mut_queue())? {
+                    vring
+                        .signal_used_queue()
+                        .map_err(Error::FailedSignalingUsedQueue)?
+                }
+            }
+            _ => return Err(Error::HandleEventUnknownEvent.into()),
+        }
+
+        Ok(false)
+    }
+
+    fn exit_event(&self, _thread_index: usize) -> Option<(EventFd, Option<u16>)> {
+        Some((
+            self.thread.lock().unwrap().kill_evt.try_clone().unwrap(),
+            Some(EXIT_EVENT),
+        ))
+    }
+
+    fn queues_per_thread(&self) -> Vec<u64> {
+        vec![0b11]
+    }
+}
+
+pub struct VhostUserSwitchBackendConfig {
+    pub sockets: Vec<String>,
+    pub queue_size: u16,
+    pub tap: Option<String>,
+}
+
+impl VhostUserSwitchBackendConfig {
+    pub fn parse(backend: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+
+        parser.add("socket").add("queue_size").add("tap");
+
+        parser.parse(backend).map_err(Error::FailedConfigParse)?;
+
+        let sockets: Vec<String> = parser
+            .get("socket")
+            .ok_or(Error::SocketParameterMissing)?
+            .split(':')
+            .map(|socket| socket.to_owned())
+            .collect();
+        if sockets.iter().any(|socket| socket.is_empty()) {
+            return Err(Error::SocketParameterMissing);
+        }
+        let queue_size = parser
+            .convert("queue_size")
+            .map_err(Error::FailedConfigParse)?
+            .unwrap_or(256);
+        let tap = parser.get("tap");
+
+        Ok(VhostUserSwitchBackendConfig {
+            sockets,
+            queue_size,
+            tap,
+        })
+    }
+}
+
+// Serves the guests successively connecting to the port, until an error
+// occurs.
+fn run_port(switch: Arc<Switch>, index: usize, socket: &str, queue_size: u16) -> Result<()> {
+    loop {
+        let port_backend = Arc::new(RwLock::new(SwitchPortBackend::new(
+            switch.clone(),
+            index,
+            queue_size,
+        )?));
+        let listener = Listener::new(socket, true).map_err(Error::CreateListener)?;
+        let mut port_daemon = VhostUserDaemon::new(
+            format!("vhost-user-switch-port{}", index),
+            port_backend.clone(),
+        )
+        .map_err(Error::Daemon)?;
+
+        for vring_worker in port_daemon.get_vring_workers() {
+            vring_worker
+                .register_listener(
+                    switch.port(index).evt().as_raw_fd(),
+                    epoll::Events::EPOLLIN,
+                    u64::from(PORT_EVENT),
+                )
+                .map_err(Error::RegisterListener)?;
+        }
+
+        port_daemon.start(listener).map_err(Error::Daemon)?;
+        info!("Guest connected to port {}", index);
+        if let Err(e) = port_daemon.wait() {
+            error!("Error from port {}: {:?}", index, e);
+        }
+        info!("Guest disconnected from port {}", index);
+
+        switch.disconnect(index);
+        port_backend
+            .read()
+            .unwrap()
+            .thread
+            .lock()
+            .unwrap()
+            .kill_evt
+            .write(1)
+            .map_err(Error::StopWorker)?;
+    }
+}
+
+// Hands the frames received on the uplink tap over to the switch.
+fn run_uplink(switch: Arc<Switch>, mut tap: net_util::Tap) -> Result<()> {
+    let epoll_fd = epoll::create(true).map_err(Error::EpollCreateFd)?;
+    // Use 'File' to enforce closing on 'epoll_fd'
+    let epoll_file = unsafe { File::from_raw_fd(epoll_fd) };
+    epoll::ctl(
+        epoll_file.as_raw_fd(),
+        epoll::ControlOptions::EPOLL_CTL_ADD,
+        tap.as_raw_fd(),
+        epoll::Event::new(epoll::Events::EPOLLIN, 0),
+    )
+    .map_err(Error::EpollCtl)?;
+
+    let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); 1];
+    let mut buf = vec![0u8; MAX_BUFFER_SIZE];
+    loop {
+        if let Err(e) = epoll::wait(epoll_file.as_raw_fd(), -1, &mut events[..]) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(Error::EpollWait(e));
+        }
+
+        loop {
+            match tap.read(&mut buf) {
+                Ok(count) if count > VNET_HDR_LEN => {
+                    switch.forward(PortId::Uplink, &buf[VNET_HDR_LEN..count])
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(Error::ReadUplink(e)),
+            }
+        }
+    }
+}
+
+pub fn start_switch_backend(backend_command: &str) {
+    let backend_config = match VhostUserSwitchBackendConfig::parse(backend_command) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed parsing parameters {:?}", e);
+            process::exit(1);
+        }
+    };
+
+    // The frames are forwarded as they are, hence no offload is enabled on
+    // the uplink either.
+    let offloads = NetOffloads {
+        csum: false,
+        tso: false,
+        tso6: false,
+        ufo: false,
+        ecn: false,
+    };
+    let uplink = match backend_config.tap.as_ref().map(|tap| {
+        open_tap(
+            Some(tap.as_str()),
+            None,
+            None,
+            None,
+            &mut None,
+            1,
+            None,
+            offloads,
+        )
+        .map_err(Error::OpenTap)
+    }) {
+        Some(Ok(mut taps)) => Some(taps.remove(0)),
+        Some(Err(e)) => {
+            error!("Failed to open the uplink tap: {:?}", e);
+            process::exit(1);
+        }
+        None => None,
+    };
+
+    let switch = match Switch::new(backend_config.sockets.len(), uplink.clone()) {
+        Ok(switch) => Arc::new(switch),
+        Err(e) => {
+            error!("Failed to create the switch: {:?}", Error::CreatePorts(e));
+            process::exit(1);
+        }
+    };
+
+    if let Some(tap) = uplink {
+        let switch = switch.clone();
+        thread::Builder::new()
+            .name("vhost-user-switch-uplink".to_string())
+            .spawn(move || {
+                if let Err(e) = run_uplink(switch, tap) {
+                    error!("Error running the uplink: {:?}", e);
+                    process::exit(1);
+                }
+            })
+            .unwrap();
+    }
+
+    let mut port_threads = Vec::new();
+    for (index, socket) in backend_config.sockets.into_iter().enumerate() {
+        let switch = switch.clone();
+        let queue_size = backend_config.queue_size;
+        port_threads.push(
+            thread::Builder::new()
+                .name(format!("vhost-user-switch-port{}", index))
+                .spawn(move || {
+                    if let Err(e) = run_port(switch, index, &socket, queue_size) {
+                        error!("Error running port {}: {:?}", index, e);
+                        process::exit(1);
+                    }
+                })
+                .unwrap(),
+        );
+    }
+
+    for port_thread in port_threads {
+        if let Err(e) = port_thread.join() {
+            error!("Error joining port thread: {:?}", e);
+        }
+    }
+}
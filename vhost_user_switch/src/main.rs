@@ -0,0 +1,28 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+#[macro_use(crate_version, crate_authors)]
+extern crate clap;
+extern crate vhost_user_switch;
+
+use clap::{App, Arg};
+use vhost_user_switch::start_switch_backend;
+
+fn main() {
+    let cmd_arguments = App::new("vhost-user-switch backend")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Launch a vhost-user-net backend switching frames between guests.")
+        .arg(
+            Arg::with_name("switch-backend")
+                .long("switch-backend")
+                .help(vhost_user_switch::SYNTAX)
+                .takes_value(true)
+                .min_values(1),
+        )
+        .get_matches();
+
+    let backend_command = cmd_arguments.value_of("switch-backend").unwrap();
+    start_switch_backend(backend_command);
+}
@@ -0,0 +1,497 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Forwarding of the frames between the ports of the switch, according to
+//! the MAC addresses learned from the frames the ports send.
+
+use libc::EFD_NONBLOCK;
+use log::*;
+use net_util::{RxVirtio, Tap};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use vhost_user_backend::Vring;
+use virtio_bindings::bindings::virtio_net::virtio_net_hdr_v1;
+use vm_memory::{GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
+
+/// Size of the virtio net header preceding the frames exchanged with the
+/// guests and the uplink tap.
+pub const VNET_HDR_LEN: usize = std::mem::size_of::<virtio_net_hdr_v1>();
+
+/// Largest frame accepted from a guest or the uplink, virtio net header
+/// included.
+pub const MAX_BUFFER_SIZE: usize = 65562;
+
+const ETH_ALEN: usize = 6;
+/// Size of the Ethernet header, holding the addresses the frames are
+/// switched on.
+pub const ETH_HLEN: usize = 14;
+
+/// An address is forgotten when no frame has been sent from it for this
+/// long, as done by Linux bridges.
+const MAC_AGEING_TIME: Duration = Duration::from_secs(300);
+
+/// Maximum number of addresses the switch remembers.
+const MAX_MAC_ENTRIES: usize = 4096;
+
+/// Maximum number of frames waiting to be received by the guest connected
+/// to a port. Beyond that, the frames are dropped.
+const MAX_PENDING_FRAMES: usize = 256;
+
+type MacAddr = [u8; ETH_ALEN];
+
+fn is_multicast(addr: &MacAddr) -> bool {
+    addr[0] & 1 != 0
+}
+
+/// Port a frame enters or leaves the switch through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PortId {
+    /// Port a guest is connected to through vhost-user.
+    Guest(usize),
+    /// Tap connecting the switch to the host network.
+    Uplink,
+}
+
+/// Ports the unicast addresses have been seen on.
+#[derive(Default)]
+pub struct MacTable {
+    entries: HashMap<MacAddr, (PortId, Instant)>,
+    // While the table is full, the expired entries aren't looked for again
+    // before the oldest entry found by the last search expires.
+    next_expiry: Option<Instant>,
+}
+
+impl MacTable {
+    /// Record that `addr` is reachable through `port`, as of `now`.
+    pub fn learn(&mut self, addr: MacAddr, port: PortId, now: Instant) {
+        if is_multicast(&addr) {
+            return;
+        }
+        if self.entries.len() >= MAX_MAC_ENTRIES && !self.entries.contains_key(&addr) {
+            if self
+                .next_expiry
+                .map_or(false, |next_expiry| now < next_expiry)
+            {
+                return;
+            }
+            self.entries
+                .retain(|_, (_, last_seen)| now.duration_since(*last_seen) < MAC_AGEING_TIME);
+            self.next_expiry = self
+                .entries
+                .values()
+                .map(|(_, last_seen)| *last_seen + MAC_AGEING_TIME)
+                .min();
+            if self.entries.len() >= MAX_MAC_ENTRIES {
+                return;
+            }
+        }
+        self.entries.insert(addr, (port, now));
+    }
+
+    /// Port `addr` is reachable through, if it has been seen recently.
+    pub fn lookup(&self, addr: MacAddr, now: Instant) -> Option<PortId> {
+        match self.entries.get(&addr) {
+            Some((port, last_seen)) if now.duration_since(*last_seen) < MAC_AGEING_TIME => {
+                Some(*port)
+            }
+            _ => None,
+        }
+    }
+
+    /// Forget the addresses seen on `port`.
+    pub fn forget_port(&mut self, port: PortId) {
+        self.entries
+            .retain(|_, (entry_port, _)| *entry_port != port);
+    }
+}
+
+// Receive side of a port, shared by the thread serving the port and the
+// threads of the ports sending frames to it.
+struct PortRx {
+    mem: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
+    // Receive queue of the guest, known once the port has handled an event.
+    vring: Option<Arc<RwLock<Vring>>>,
+    rx: RxVirtio,
+    // Frames waiting for the driver to make buffers available, without
+    // virtio net header.
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl PortRx {
+    // Copies the deferred frame, then the waiting ones, into the receive
+    // queue until the driver runs out of buffers, and notifies the guest.
+    fn process(&mut self) -> io::Result<()> {
+        let (mem, vring) = match (&self.mem, &self.vring) {
+            (Some(mem), Some(vring)) => (mem.memory(), vring.clone()),
+            _ => return Ok(()),
+        };
+        let mut vring = vring.write().unwrap();
+        if !vring.mut_queue().ready {
+            return Ok(());
+        }
+
+        loop {
+            if !self.rx.deferred_frame {
+                let frame = match self.frames.pop_front() {
+                    Some(frame) => frame,
+                    None => break,
+                };
+                let len = std::cmp::min(frame.len(), MAX_BUFFER_SIZE - VNET_HDR_LEN);
+                for b in self.rx.frame_buf[..VNET_HDR_LEN].iter_mut() {
+                    *b = 0;
+                }
+                self.rx.frame_buf[VNET_HDR_LEN..VNET_HDR_LEN + len].copy_from_slice(&frame[..len]);
+                self.rx.bytes_read = VNET_HDR_LEN + len;
+                self.rx.deferred_frame = true;
+            }
+
+            let queue = vring.mut_queue();
+            let used = match queue.iter(&mem).next() {
+                Some(head) if self.rx.mergeable => {
+                    self.rx.process_mergeable_desc_chains(&mem, head, queue)
+                }
+                Some(head) => self.rx.process_desc_chain(&mem, Some(head), queue),
+                None => false,
+            };
+            if !used {
+                break;
+            }
+            self.rx.deferred_frame = false;
+        }
+
+        if self.rx.deferred_irqs {
+            self.rx.deferred_irqs = false;
+            vring.signal_used_queue()?;
+        }
+        Ok(())
+    }
+}
+
+/// Port a guest connects to. The frames forwarded to the port are copied
+/// straight into the receive queue of the guest, and only wait in the port
+/// while the driver has no buffer available, the port being notified
+/// through the eventfd.
+pub struct GuestPort {
+    rx: Mutex<PortRx>,
+    evt: EventFd,
+    connected: AtomicBool,
+}
+
+impl GuestPort {
+    fn new() -> io::Result<Self> {
+        Ok(GuestPort {
+            rx: Mutex::new(PortRx {
+                mem: None,
+                vring: None,
+                rx: RxVirtio::new(),
+                frames: VecDeque::new(),
+            }),
+            evt: EventFd::new(EFD_NONBLOCK)?,
+            connected: AtomicBool::new(false),
+        })
+    }
+
+    /// Hand a frame over to the guest. `write_frame` copies the frame into
+    /// the given buffer, behind room for the virtio net header, and returns
+    /// the length written, header included.
+    pub fn deliver<F>(&self, write_frame: F)
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let mut port_rx = self.rx.lock().unwrap();
+        // Nobody would receive the frame.
+        if !self.connected.load(Ordering::Acquire) {
+            return;
+        }
+        if port_rx.frames.is_empty() && !port_rx.rx.deferred_frame {
+            let len = write_frame(&mut port_rx.rx.frame_buf[..MAX_BUFFER_SIZE]);
+            if len <= VNET_HDR_LEN {
+                return;
+            }
+            for b in port_rx.rx.frame_buf[..VNET_HDR_LEN].iter_mut() {
+                *b = 0;
+            }
+            port_rx.rx.bytes_read = len;
+            port_rx.rx.deferred_frame = true;
+            if let Err(e) = port_rx.process() {
+                error!("Failed to signal the port of a frame: {}", e);
+            }
+            if !port_rx.rx.deferred_frame {
+                return;
+            }
+        } else if port_rx.frames.len() >= MAX_PENDING_FRAMES {
+            debug!("Too many frames pending on the port, dropping frame");
+            return;
+        } else {
+            let mut frame = vec![0u8; MAX_BUFFER_SIZE];
+            let len = write_frame(&mut frame);
+            if len <= VNET_HDR_LEN {
+                return;
+            }
+            frame.truncate(len);
+            frame.drain(..VNET_HDR_LEN);
+            port_rx.frames.push_back(frame);
+        }
+
+        // The frame waits for a buffer, which the port will be told about
+        // by the driver, unless it doesn't know about the queue yet.
+        if let Err(e) = self.evt.write(1) {
+            error!("Failed to notify the port of a frame: {}", e);
+        }
+    }
+
+    fn push(&self, frame: &[u8]) {
+        self.deliver(|buf| {
+            let len = std::cmp::min(frame.len(), buf.len() - VNET_HDR_LEN);
+            buf[VNET_HDR_LEN..VNET_HDR_LEN + len].copy_from_slice(&frame[..len]);
+            VNET_HDR_LEN + len
+        })
+    }
+
+    /// Copy the frames waiting for the guest into its receive queue, given
+    /// the queue if the port doesn't know about it yet.
+    pub fn process_rx(&self, vring: &Arc<RwLock<Vring>>) -> io::Result<()> {
+        let mut port_rx = self.rx.lock().unwrap();
+        if port_rx.vring.is_none() {
+            port_rx.vring = Some(vring.clone());
+        }
+        port_rx.process()
+    }
+
+    pub fn evt(&self) -> &EventFd {
+        &self.evt
+    }
+
+    /// Accept frames for the guest once its memory is known.
+    pub fn set_memory(&self, mem: GuestMemoryAtomic<GuestMemoryMmap>) {
+        self.rx.lock().unwrap().mem = Some(mem);
+        self.connected.store(true, Ordering::Release);
+    }
+
+    /// Whether the driver accepts frames spread across several buffers.
+    pub fn set_mergeable(&self, mergeable: bool) {
+        self.rx.lock().unwrap().rx.mergeable = mergeable;
+    }
+}
+
+/// Switch forwarding the frames between the guests connected to its ports,
+/// and the host network if an uplink tap is provided.
+pub struct Switch {
+    ports: Vec<GuestPort>,
+    uplink: Option<Mutex<Tap>>,
+    mac_table: Mutex<MacTable>,
+}
+
+impl Switch {
+    pub fn new(num_ports: usize, uplink: Option<Tap>) -> io::Result<Self> {
+        let mut ports = Vec::new();
+        for _ in 0..num_ports {
+            ports.push(GuestPort::new()?);
+        }
+
+        Ok(Switch {
+            ports,
+            uplink: uplink.map(Mutex::new),
+            mac_table: Mutex::new(MacTable::default()),
+        })
+    }
+
+    pub fn port(&self, index: usize) -> &GuestPort {
+        &self.ports[index]
+    }
+
+    /// Forward the frame, without virtio net header, received on `from`.
+    pub fn forward(&self, from: PortId, frame: &[u8]) {
+        if frame.len() < ETH_HLEN {
+            return;
+        }
+        let to = self.lookup(from, &frame[..ETH_HLEN]);
+        self.send(from, to, frame);
+    }
+
+    /// Learn the port the source of a frame received on `from` is reachable
+    /// through, given the Ethernet header of the frame, and return the port
+    /// its destination is reachable through, if known.
+    pub fn lookup(&self, from: PortId, eth_hdr: &[u8]) -> Option<PortId> {
+        let dst: MacAddr = eth_hdr[..ETH_ALEN].try_into().unwrap();
+        let src: MacAddr = eth_hdr[ETH_ALEN..2 * ETH_ALEN].try_into().unwrap();
+
+        let now = Instant::now();
+        let mut mac_table = self.mac_table.lock().unwrap();
+        mac_table.learn(src, from, now);
+        mac_table.lookup(dst, now)
+    }
+
+    /// Send the frame, without virtio net header, received on `from` to the
+    /// port returned by `lookup()`. The frames to unknown, broadcast and
+    /// multicast addresses are sent to all the other ports.
+    pub fn send(&self, from: PortId, to: Option<PortId>, frame: &[u8]) {
+        match to {
+            // The destination is on the same side as the sender.
+            Some(to) if to == from => {}
+            Some(to) => self.deliver(to, frame),
+            None => {
+                for index in 0..self.ports.len() {
+                    if from != PortId::Guest(index) {
+                        self.deliver(PortId::Guest(index), frame);
+                    }
+                }
+                if from != PortId::Uplink {
+                    self.deliver(PortId::Uplink, frame);
+                }
+            }
+        }
+    }
+
+    fn deliver(&self, to: PortId, frame: &[u8]) {
+        match to {
+            PortId::Guest(index) => self.ports[index].push(frame),
+            PortId::Uplink => {
+                if let Some(uplink) = &self.uplink {
+                    let mut buf = vec![0u8; VNET_HDR_LEN];
+                    buf.extend_from_slice(frame);
+                    if let Err(e) = uplink.lock().unwrap().write(&buf) {
+                        error!("Failed to write to the uplink tap: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stop forwarding frames to the port, whose guest has disconnected.
+    pub fn disconnect(&self, index: usize) {
+        let port = &self.ports[index];
+        let mut port_rx = port.rx.lock().unwrap();
+        port.connected.store(false, Ordering::Release);
+        port_rx.mem = None;
+        port_rx.vring = None;
+        port_rx.rx = RxVirtio::new();
+        port_rx.frames.clear();
+        drop(port_rx);
+        self.mac_table
+            .lock()
+            .unwrap()
+            .forget_port(PortId::Guest(index));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_memory::GuestAddress;
+
+    const MAC_A: MacAddr = [0x12, 0, 0, 0, 0, 0xa];
+    const MAC_B: MacAddr = [0x12, 0, 0, 0, 0, 0xb];
+    const MAC_C: MacAddr = [0x12, 0, 0, 0, 0, 0xc];
+    const BROADCAST: MacAddr = [0xff; ETH_ALEN];
+
+    fn frame(dst: MacAddr, src: MacAddr) -> Vec<u8> {
+        let mut frame = dst.to_vec();
+        frame.extend_from_slice(&src);
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&[0u8; 46]);
+        frame
+    }
+
+    fn pending(switch: &Switch, index: usize) -> usize {
+        let port_rx = switch.port(index).rx.lock().unwrap();
+        port_rx.frames.len() + port_rx.rx.deferred_frame as usize
+    }
+
+    fn pop(switch: &Switch, index: usize) -> Vec<u8> {
+        let mut port_rx = switch.port(index).rx.lock().unwrap();
+        assert!(port_rx.rx.deferred_frame);
+        let frame = port_rx.rx.frame_buf[VNET_HDR_LEN..port_rx.rx.bytes_read].to_vec();
+        port_rx.rx.deferred_frame = false;
+        frame
+    }
+
+    #[test]
+    fn test_mac_table() {
+        let mut mac_table = MacTable::default();
+        let now = Instant::now();
+        mac_table.learn(MAC_A, PortId::Guest(0), now);
+        mac_table.learn(MAC_B, PortId::Uplink, now);
+        mac_table.learn(BROADCAST, PortId::Guest(1), now);
+        assert_eq!(mac_table.lookup(MAC_A, now), Some(PortId::Guest(0)));
+        assert_eq!(mac_table.lookup(MAC_B, now), Some(PortId::Uplink));
+        assert_eq!(mac_table.lookup(BROADCAST, now), None);
+        assert_eq!(mac_table.lookup(MAC_A, now + MAC_AGEING_TIME), None);
+
+        // The guest moved to another port.
+        mac_table.learn(MAC_A, PortId::Guest(1), now);
+        assert_eq!(mac_table.lookup(MAC_A, now), Some(PortId::Guest(1)));
+        mac_table.forget_port(PortId::Guest(1));
+        assert_eq!(mac_table.lookup(MAC_A, now), None);
+        assert_eq!(mac_table.lookup(MAC_B, now), Some(PortId::Uplink));
+    }
+
+    #[test]
+    fn test_mac_table_full() {
+        let mut mac_table = MacTable::default();
+        let now = Instant::now();
+        for i in 0..MAX_MAC_ENTRIES {
+            let addr = [0x22, 0, 0, 0, (i >> 8) as u8, i as u8];
+            mac_table.learn(addr, PortId::Guest(0), now);
+        }
+
+        // Nothing expires before the ageing time.
+        let later = now + MAC_AGEING_TIME / 2;
+        mac_table.learn(MAC_A, PortId::Guest(1), later);
+        assert_eq!(mac_table.lookup(MAC_A, later), None);
+        assert_eq!(mac_table.next_expiry, Some(now + MAC_AGEING_TIME));
+        mac_table.learn(MAC_B, PortId::Guest(1), later);
+        assert_eq!(mac_table.lookup(MAC_B, later), None);
+
+        // The expired entries make room for the new ones.
+        let later = now + MAC_AGEING_TIME;
+        mac_table.learn(MAC_A, PortId::Guest(1), later);
+        assert_eq!(mac_table.lookup(MAC_A, later), Some(PortId::Guest(1)));
+        assert_eq!(mac_table.entries.len(), 1);
+    }
+
+    #[test]
+    fn test_forward() {
+        let switch = Switch::new(3, None).unwrap();
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        for index in 0..3 {
+            switch
+                .port(index)
+                .set_memory(GuestMemoryAtomic::new(mem.clone()));
+        }
+
+        // Broadcasts are flooded, and teach the switch where A is.
+        switch.forward(PortId::Guest(0), &frame(BROADCAST, MAC_A));
+        assert_eq!(pending(&switch, 0), 0);
+        assert_eq!(pending(&switch, 1), 1);
+        assert_eq!(pending(&switch, 2), 1);
+
+        // The reply only reaches A.
+        switch.forward(PortId::Guest(1), &frame(MAC_A, MAC_B));
+        assert_eq!(pending(&switch, 0), 1);
+        assert_eq!(pending(&switch, 2), 1);
+        assert_eq!(pop(&switch, 0), frame(MAC_A, MAC_B));
+
+        // Unknown addresses are flooded.
+        switch.forward(PortId::Guest(0), &frame(MAC_C, MAC_A));
+        assert_eq!(pending(&switch, 1), 2);
+        assert_eq!(pending(&switch, 2), 2);
+
+        // Nothing is delivered to a disconnected port.
+        switch.disconnect(1);
+        assert_eq!(pending(&switch, 1), 0);
+        switch.forward(PortId::Guest(0), &frame(MAC_B, MAC_A));
+        assert_eq!(pending(&switch, 1), 0);
+        assert_eq!(pending(&switch, 2), 3);
+
+        // Runt frames are dropped.
+        switch.forward(PortId::Guest(0), &BROADCAST);
+        assert_eq!(pending(&switch, 2), 3);
+    }
+}
@@ -16,19 +16,24 @@ use super::{
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::VirtioInterrupt;
 use anyhow::anyhow;
+use block_util::nbd::NbdDisk;
+use block_util::stats::BlockStats;
+use block_util::zoned::{ZoneOperation, Zones, VIRTIO_BLK_F_ZONED, VIRTIO_BLK_ZONED_CONFIG_OFFSET};
 use block_util::{build_disk_image_id, Request, RequestType, VirtioBlockConfig};
 use libc::EFD_NONBLOCK;
+use qcow::{FixedVhdFile, LuksDisk, QcowFile, RawFile, VhdxFile};
 use seccomp::{SeccompAction, SeccompFilter};
 use std::collections::HashMap;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::num::Wrapping;
 use std::ops::DerefMut;
 use std::os::unix::io::AsRawFd;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use virtio_bindings::bindings::virtio_blk::*;
 use virtio_bindings::bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::{
@@ -47,6 +52,9 @@ pub const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
 // New descriptors are pending on the virtio queue.
 const QUEUE_AVAIL_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 
+// Interval at which a paused stream job checks whether it can resume.
+const STREAM_PAUSED_POLL_MS: u64 = 100;
+
 #[derive(Debug)]
 pub enum Error {
     /// Guest gave us bad memory addresses.
@@ -67,22 +75,138 @@ pub enum Error {
     InvalidOffset,
 }
 
-pub trait DiskFile: Read + Seek + Write + Clone {}
-impl<D: Read + Seek + Write + Clone> DiskFile for D {}
+pub trait DiskFile: Read + Seek + Write + Clone {
+    /// Grows the disk image to `size` bytes.
+    fn resize(&mut self, size: u64) -> io::Result<()>;
+
+    /// Returns true if part of the disk content is read from a backing file.
+    fn has_backing_file(&self) -> bool {
+        false
+    }
+
+    /// Copies the data found at `offset` in the backing file into the disk image, returning the
+    /// number of bytes handled.
+    fn stream(&mut self, _offset: u64) -> io::Result<u64> {
+        Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+    }
+
+    /// Stops referring to the backing file, once all of its data has been streamed.
+    fn drop_backing_file(&mut self) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+    }
+
+    /// Returns true if the disk content is reached through a network connection.
+    fn is_remote(&self) -> bool {
+        false
+    }
+}
+
+impl DiskFile for RawFile {
+    fn resize(&mut self, size: u64) -> io::Result<()> {
+        self.set_len(size)
+    }
+}
+
+impl DiskFile for QcowFile {
+    fn resize(&mut self, size: u64) -> io::Result<()> {
+        QcowFile::resize(self, size)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+    }
+
+    fn has_backing_file(&self) -> bool {
+        QcowFile::has_backing_file(self)
+    }
+
+    fn stream(&mut self, offset: u64) -> io::Result<u64> {
+        self.stream_cluster(offset)
+    }
+
+    fn drop_backing_file(&mut self) -> io::Result<()> {
+        QcowFile::drop_backing_file(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+    }
+}
+
+impl DiskFile for FixedVhdFile {
+    fn resize(&mut self, size: u64) -> io::Result<()> {
+        FixedVhdFile::resize(self, size)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+    }
+}
+
+impl DiskFile for VhdxFile {
+    fn resize(&mut self, _size: u64) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+    }
+}
+
+impl DiskFile for NbdDisk {
+    fn resize(&mut self, _size: u64) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+    }
+
+    fn is_remote(&self) -> bool {
+        true
+    }
+}
+
+impl<D: DiskFile> DiskFile for LuksDisk<D> {
+    fn resize(&mut self, _size: u64) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+    }
+
+    fn is_remote(&self) -> bool {
+        self.disk().is_remote()
+    }
+}
+
+/// Grows the disk to `size` bytes through `resize`, once the new size has been
+/// checked against the current capacity, and lets the guest know about it.
+pub(crate) fn resize_disk_image<F>(
+    size: u64,
+    disk_nsectors: &AtomicU64,
+    config: &mut VirtioBlockConfig,
+    interrupt_cb: Option<&Arc<dyn VirtioInterrupt>>,
+    resize: F,
+) -> result::Result<(), DeviceError>
+where
+    F: FnOnce(u64) -> io::Result<()>,
+{
+    let disk_size = disk_nsectors.load(Ordering::Acquire) * SECTOR_SIZE;
+    if size % SECTOR_SIZE != 0 || size < disk_size {
+        return Err(DeviceError::InvalidDiskSize(size));
+    }
+
+    resize(size).map_err(DeviceError::IoError)?;
+
+    let nsectors = size / SECTOR_SIZE;
+    disk_nsectors.store(nsectors, Ordering::Release);
+    config.capacity = nsectors;
+
+    // Let the guest know about the new capacity.
+    if let Some(interrupt_cb) = interrupt_cb {
+        interrupt_cb
+            .trigger(&VirtioInterruptType::Config, None)
+            .map_err(DeviceError::FailedSignalingDriver)?;
+    }
+
+    Ok(())
+}
 
 #[derive(Default, Clone)]
 pub struct BlockCounters {
-    read_bytes: Arc<AtomicU64>,
-    read_ops: Arc<AtomicU64>,
-    write_bytes: Arc<AtomicU64>,
-    write_ops: Arc<AtomicU64>,
+    pub(crate) read_bytes: Arc<AtomicU64>,
+    pub(crate) read_ops: Arc<AtomicU64>,
+    pub(crate) write_bytes: Arc<AtomicU64>,
+    pub(crate) write_ops: Arc<AtomicU64>,
+    pub(crate) stats: Arc<BlockStats>,
 }
 
 struct BlockEpollHandler<T: DiskFile> {
     queue: Queue,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     disk_image: Arc<Mutex<T>>,
-    disk_nsectors: u64,
+    disk_nsectors: Arc<AtomicU64>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     disk_image_id: Vec<u8>,
     kill_evt: EventFd,
@@ -91,6 +215,7 @@ struct BlockEpollHandler<T: DiskFile> {
     writeback: Arc<AtomicBool>,
     counters: BlockCounters,
     queue_evt: EventFd,
+    zones: Option<Arc<Mutex<Zones>>>,
 }
 
 impl<T: DiskFile> BlockEpollHandler<T> {
@@ -107,18 +232,28 @@ impl<T: DiskFile> BlockEpollHandler<T> {
 
         for avail_desc in queue.iter(&mem) {
             let len;
+            let mut completed = None;
             match Request::parse(&avail_desc, &mem) {
                 Ok(mut request) => {
+                    self.counters.stats.request_started();
+                    let start = Instant::now();
                     request.set_writeback(self.writeback.load(Ordering::SeqCst));
 
                     let mut disk_image_locked = self.disk_image.lock().unwrap();
                     let mut disk_image = disk_image_locked.deref_mut();
-                    let status = match request.execute(
-                        &mut disk_image,
-                        self.disk_nsectors,
-                        &mem,
-                        &self.disk_image_id,
-                    ) {
+                    let disk_nsectors = self.disk_nsectors.load(Ordering::Acquire);
+                    let result = if let Some(zones) = &self.zones {
+                        request.execute_zoned(
+                            &mut disk_image,
+                            disk_nsectors,
+                            &mem,
+                            &self.disk_image_id,
+                            &mut zones.lock().unwrap(),
+                        )
+                    } else {
+                        request.execute(&mut disk_image, disk_nsectors, &mem, &self.disk_image_id)
+                    };
+                    let status = match result {
                         Ok(l) => {
                             len = l;
                             match request.request_type {
@@ -126,7 +261,7 @@ impl<T: DiskFile> BlockEpollHandler<T> {
                                     read_bytes += Wrapping(request.data_len as u64);
                                     read_ops += Wrapping(1);
                                 }
-                                RequestType::Out => {
+                                RequestType::Out | RequestType::Zone(ZoneOperation::Append) => {
                                     write_bytes += Wrapping(request.data_len as u64);
                                     write_ops += Wrapping(1);
                                 }
@@ -143,18 +278,26 @@ impl<T: DiskFile> BlockEpollHandler<T> {
                     // We use unwrap because the request parsing process already checked that the
                     // status_addr was valid.
                     mem.write_obj(status, request.status_addr).unwrap();
+                    completed = Some((request.request_type, start));
                 }
                 Err(e) => {
                     error!("Failed to parse available descriptor chain: {:?}", e);
                     len = 0;
                 }
             }
-            used_desc_heads.push((avail_desc.index, len));
+            used_desc_heads.push((avail_desc.index, len, completed));
             used_count += 1;
         }
 
-        for &(desc_index, len) in used_desc_heads.iter() {
+        for &(desc_index, len, completed) in used_desc_heads.iter() {
             queue.add_used(&mem, desc_index, len);
+            // The request only completes from the guest standpoint once it
+            // has been added to the used ring.
+            if let Some((request_type, start)) = completed {
+                self.counters
+                    .stats
+                    .request_completed(request_type, start.elapsed());
+            }
         }
 
         self.counters
@@ -189,10 +332,13 @@ impl<T: DiskFile> BlockEpollHandler<T> {
         mut disk_image: T,
         disk_path: &PathBuf,
     ) -> result::Result<(), DeviceError> {
-        self.disk_nsectors = disk_image
-            .seek(SeekFrom::End(0))
-            .map_err(DeviceError::IoError)?
-            / SECTOR_SIZE;
+        self.disk_nsectors.store(
+            disk_image
+                .seek(SeekFrom::End(0))
+                .map_err(DeviceError::IoError)?
+                / SECTOR_SIZE,
+            Ordering::Release,
+        );
         self.disk_image_id = build_disk_image_id(disk_path);
         self.disk_image = Arc::new(Mutex::new(disk_image));
         Ok(())
@@ -257,13 +403,62 @@ impl<T: DiskFile> EpollHelperHandler for BlockEpollHandler<T> {
     }
 }
 
+// Background job copying the data a disk reads from its backing file into the disk image.
+struct StreamJob {
+    offset: Arc<AtomicU64>,
+    size: u64,
+    failed: Arc<AtomicBool>,
+    kill: Arc<AtomicBool>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl StreamJob {
+    fn in_progress(&self) -> bool {
+        !self.failed.load(Ordering::Acquire) && self.offset.load(Ordering::Acquire) < self.size
+    }
+}
+
+// Streams the whole backing file of `disk_image`, one chunk at a time so that the guest
+// requests can still be served. The job waits while the device is paused.
+fn stream_disk_image<T: DiskFile>(
+    disk_image: &Mutex<T>,
+    size: u64,
+    offset: &AtomicU64,
+    paused: &AtomicBool,
+    kill: &AtomicBool,
+) -> io::Result<()> {
+    let mut current = 0;
+    while current < size {
+        if kill.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        if paused.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(STREAM_PAUSED_POLL_MS));
+            continue;
+        }
+
+        current += disk_image.lock().unwrap().stream(current)?;
+        // Only report the end of the job once the backing file is gone.
+        if current < size {
+            offset.store(current, Ordering::Release);
+        }
+    }
+
+    let mut disk_image = disk_image.lock().unwrap();
+    disk_image.flush()?;
+    disk_image.drop_backing_file()?;
+    offset.store(size, Ordering::Release);
+
+    Ok(())
+}
+
 /// Virtio device for exposing block level read/write operations on a host file.
 pub struct Block<T: DiskFile> {
     id: String,
     kill_evt: Option<EventFd>,
     disk_image: Arc<Mutex<T>>,
     disk_path: PathBuf,
-    disk_nsectors: u64,
+    disk_nsectors: Arc<AtomicU64>,
     avail_features: u64,
     acked_features: u64,
     config: VirtioBlockConfig,
@@ -277,6 +472,8 @@ pub struct Block<T: DiskFile> {
     writeback: Arc<AtomicBool>,
     counters: BlockCounters,
     seccomp_action: SeccompAction,
+    stream: Option<StreamJob>,
+    zones: Option<Arc<Mutex<Zones>>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -286,6 +483,8 @@ pub struct BlockState {
     pub avail_features: u64,
     pub acked_features: u64,
     pub config: VirtioBlockConfig,
+    #[serde(default)]
+    pub zones: Option<Zones>,
 }
 
 impl<T: DiskFile> Block<T> {
@@ -342,7 +541,7 @@ impl<T: DiskFile> Block<T> {
             kill_evt: None,
             disk_image: Arc::new(Mutex::new(disk_image)),
             disk_path,
-            disk_nsectors,
+            disk_nsectors: Arc::new(AtomicU64::new(disk_nsectors)),
             avail_features,
             acked_features: 0u64,
             config,
@@ -356,25 +555,63 @@ impl<T: DiskFile> Block<T> {
             writeback: Arc::new(AtomicBool::new(true)),
             counters: BlockCounters::default(),
             seccomp_action,
+            stream: None,
+            zones: None,
         })
     }
 
+    /// Exposes the disk as a host-managed zoned device, split into zones of `zone_sectors`
+    /// sectors. A maximum number of open or active zones set to 0 means there is no limit.
+    /// The write pointers are loaded from `state_path`, and saved to it with `persist`.
+    pub fn set_zoned(
+        &mut self,
+        zone_sectors: u64,
+        max_open_zones: u32,
+        max_active_zones: u32,
+        state_path: &Path,
+        persist: bool,
+    ) -> io::Result<()> {
+        let zones = Zones::with_state_file(
+            state_path,
+            persist,
+            self.disk_nsectors.load(Ordering::Acquire),
+            zone_sectors,
+            max_open_zones,
+            max_active_zones,
+        )?;
+        self.avail_features |= 1u64 << VIRTIO_BLK_F_ZONED;
+        self.zones = Some(Arc::new(Mutex::new(zones)));
+
+        Ok(())
+    }
+
     fn state(&self) -> BlockState {
         BlockState {
             disk_path: self.disk_path.clone(),
-            disk_nsectors: self.disk_nsectors,
+            disk_nsectors: self.disk_nsectors.load(Ordering::Acquire),
             avail_features: self.avail_features,
             acked_features: self.acked_features,
             config: self.config,
+            zones: self
+                .zones
+                .as_ref()
+                .map(|zones| zones.lock().unwrap().clone()),
         }
     }
 
     fn set_state(&mut self, state: &BlockState) -> io::Result<()> {
         self.disk_path = state.disk_path.clone();
-        self.disk_nsectors = state.disk_nsectors;
+        self.disk_nsectors
+            .store(state.disk_nsectors, Ordering::Release);
         self.avail_features = state.avail_features;
         self.acked_features = state.acked_features;
         self.config = state.config;
+        if let (Some(zones), Some(state_zones)) = (&self.zones, &state.zones) {
+            // Keep saving the restored write pointers to the state file of the disk.
+            zones.lock().unwrap().restore(state_zones.clone())?;
+        } else {
+            self.zones = state.zones.clone().map(|zones| Arc::new(Mutex::new(zones)));
+        }
 
         Ok(())
     }
@@ -407,6 +644,11 @@ impl<T: DiskFile> Drop for Block<T> {
             // Ignore the result because there is nothing we can do about it.
             let _ = kill_evt.write(1);
         }
+
+        if let Some(stream) = self.stream.take() {
+            stream.kill.store(true, Ordering::SeqCst);
+            let _ = stream.thread.join();
+        }
     }
 }
 
@@ -437,7 +679,16 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
     }
 
     fn read_config(&self, offset: u64, data: &mut [u8]) {
-        self.read_config_from_slice(self.config.as_slice(), offset, data);
+        if let Some(zones) = &self.zones {
+            // The zoned characteristics follow the secure erase fields, which
+            // are left unset as secure erase isn't supported.
+            let mut config_space = self.config.as_slice().to_vec();
+            config_space.resize(VIRTIO_BLK_ZONED_CONFIG_OFFSET, 0);
+            config_space.extend_from_slice(zones.lock().unwrap().config().as_slice());
+            self.read_config_from_slice(&config_space, offset, data);
+        } else {
+            self.read_config_from_slice(self.config.as_slice(), offset, data);
+        }
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
@@ -526,7 +777,7 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
                 queue: queues.remove(0),
                 mem: mem.clone(),
                 disk_image: self.disk_image.clone(),
-                disk_nsectors: self.disk_nsectors,
+                disk_nsectors: self.disk_nsectors.clone(),
                 interrupt_cb: interrupt_cb.clone(),
                 disk_image_id: disk_image_id.clone(),
                 kill_evt: kill_evt.try_clone().unwrap(),
@@ -535,6 +786,7 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
                 writeback: self.writeback.clone(),
                 counters: self.counters.clone(),
                 queue_evt,
+                zones: self.zones.clone(),
             };
 
             handler.queue.set_event_idx(event_idx);
@@ -542,10 +794,15 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
             let paused = self.paused.clone();
             let paused_sync = self.paused_sync.clone();
 
-            // Retrieve seccomp filter for virtio_blk thread
-            let virtio_blk_seccomp_filter =
-                get_seccomp_filter(&self.seccomp_action, Thread::VirtioBlk)
-                    .map_err(ActivateError::CreateSeccompFilter)?;
+            // Retrieve seccomp filter for virtio_blk thread, only letting
+            // it reach the network when the disk lives on an NBD server.
+            let thread_type = if self.disk_image.lock().unwrap().is_remote() {
+                Thread::VirtioBlkNbd
+            } else {
+                Thread::VirtioBlk
+            };
+            let virtio_blk_seccomp_filter = get_seccomp_filter(&self.seccomp_action, thread_type)
+                .map_err(ActivateError::CreateSeccompFilter)?;
 
             thread::Builder::new()
                 .name("virtio_blk".to_string())
@@ -610,8 +867,89 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
             Wrapping(self.counters.write_ops.load(Ordering::Acquire)),
         );
 
+        self.counters.stats.add_counters(&mut counters);
+
+        if let Some(stream) = &self.stream {
+            counters.insert(
+                "stream_offset",
+                Wrapping(stream.offset.load(Ordering::Acquire)),
+            );
+            counters.insert("stream_size", Wrapping(stream.size));
+            counters.insert(
+                "stream_failed",
+                Wrapping(stream.failed.load(Ordering::Acquire) as u64),
+            );
+        }
+
         Some(counters)
     }
+
+    fn resize_disk(&mut self, size: u64) -> result::Result<(), DeviceError> {
+        // The zone layout is fixed once the device has been created.
+        if self.zones.is_some() {
+            return Err(DeviceError::IoError(io::Error::from_raw_os_error(
+                libc::ENOTSUP,
+            )));
+        }
+
+        let disk_image = &self.disk_image;
+        resize_disk_image(
+            size,
+            &self.disk_nsectors,
+            &mut self.config,
+            self.interrupt_cb.as_ref(),
+            |size| disk_image.lock().unwrap().resize(size),
+        )
+    }
+
+    fn stream_disk(&mut self) -> result::Result<(), DeviceError> {
+        if let Some(stream) = &self.stream {
+            if stream.in_progress() {
+                return Err(DeviceError::StreamDiskInProgress);
+            }
+        }
+
+        let size = {
+            let mut disk_image = self.disk_image.lock().unwrap();
+            if !disk_image.has_backing_file() {
+                return Err(DeviceError::StreamDiskNotSupported);
+            }
+            disk_image
+                .seek(SeekFrom::End(0))
+                .map_err(DeviceError::IoError)?
+        };
+
+        let offset = Arc::new(AtomicU64::new(0));
+        let failed = Arc::new(AtomicBool::new(false));
+        let kill = Arc::new(AtomicBool::new(false));
+
+        let disk_image = self.disk_image.clone();
+        let paused = self.paused.clone();
+        let thread_offset = offset.clone();
+        let thread_failed = failed.clone();
+        let thread_kill = kill.clone();
+        let thread = thread::Builder::new()
+            .name(format!("{}_stream", self.id))
+            .spawn(move || {
+                if let Err(e) =
+                    stream_disk_image(&disk_image, size, &thread_offset, &paused, &thread_kill)
+                {
+                    error!("Error streaming disk image: {:?}", e);
+                    thread_failed.store(true, Ordering::Release);
+                }
+            })
+            .map_err(DeviceError::IoError)?;
+
+        self.stream = Some(StreamJob {
+            offset,
+            size,
+            failed,
+            kill,
+            thread,
+        });
+
+        Ok(())
+    }
 }
 
 virtio_pausable!(Block, T: 'static + DiskFile + Send);
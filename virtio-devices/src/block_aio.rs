@@ -0,0 +1,147 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
+
+use crate::block_async::{AsyncBlock, AsyncDiskImage, AsyncEngine};
+use block_util::aio::{IoContext, IoEvent};
+use block_util::{ExecuteError, Request};
+use libc::EFD_NONBLOCK;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::result;
+use vm_memory::GuestMemoryMmap;
+use vmm_sys_util::eventfd::EventFd;
+
+/// Virtio device for exposing block level read/write operations on a host
+/// file opened with O_DIRECT, relying on Linux native AIO.
+pub type BlockAio = AsyncBlock<File>;
+
+/// Linux native AIO context processing the requests of a single queue.
+pub struct AioEngine {
+    disk_image_fd: RawFd,
+    io_ctx: IoContext,
+    eventfd: EventFd,
+    events: Vec<IoEvent>,
+}
+
+impl AsyncEngine for AioEngine {
+    fn notifier(&self) -> &EventFd {
+        &self.eventfd
+    }
+
+    fn submit(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        request: &Request,
+        disk_nsectors: u64,
+        disk_id: &[u8],
+        user_data: u64,
+    ) -> result::Result<u32, ExecuteError> {
+        request
+            .execute_aio(
+                mem,
+                &self.io_ctx,
+                disk_nsectors,
+                self.disk_image_fd,
+                disk_id,
+                self.eventfd.as_raw_fd(),
+                user_data,
+            )
+            .map(u32::from)
+    }
+
+    fn completions(&mut self) -> io::Result<Vec<(u64, i64)>> {
+        let count = self.io_ctx.get_events(&mut self.events)?;
+        Ok(self.events[..count]
+            .iter()
+            .map(|event| (event.data, event.res))
+            .collect())
+    }
+
+    fn sync(&mut self) {
+        // Safe because we know the file descriptor is valid.
+        unsafe { libc::fsync(self.disk_image_fd) };
+    }
+}
+
+impl AsyncDiskImage for File {
+    type Engine = AioEngine;
+
+    fn size(&mut self) -> io::Result<u64> {
+        self.seek(SeekFrom::End(0))
+    }
+
+    fn resize(&mut self, size: u64) -> io::Result<()> {
+        self.set_len(size)
+    }
+
+    fn create_engine(&self, queue_size: u32) -> io::Result<AioEngine> {
+        Ok(AioEngine {
+            disk_image_fd: self.as_raw_fd(),
+            io_ctx: IoContext::new(queue_size)?,
+            eventfd: EventFd::new(EFD_NONBLOCK)?,
+            events: vec![IoEvent::default(); queue_size as usize],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block_util::RequestType;
+    use std::thread;
+    use std::time::Duration;
+    use vm_memory::{Bytes, GuestAddress};
+
+    fn wait_completions(engine: &mut AioEngine) -> Vec<(u64, i64)> {
+        loop {
+            let completions = engine.completions().unwrap();
+            if !completions.is_empty() {
+                return completions;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn aio_engine_write_read() {
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(0x10000).unwrap();
+        let mut engine = match file.create_engine(4) {
+            Ok(engine) => engine,
+            // Linux native AIO can be disabled on the host.
+            Err(_) => return,
+        };
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        mem.write_slice(&[0xa5; 512], GuestAddress(0x1000)).unwrap();
+
+        let mut request = Request {
+            request_type: RequestType::Out,
+            sector: 8,
+            data_addr: GuestAddress(0x1000),
+            data_len: 512,
+            status_addr: GuestAddress(0x3000),
+            writeback: true,
+        };
+        assert_eq!(engine.submit(&mem, &request, 128, &[], 1).unwrap(), 1);
+        assert_eq!(wait_completions(&mut engine), vec![(1, 512)]);
+
+        request.request_type = RequestType::In;
+        request.data_addr = GuestAddress(0x2000);
+        assert_eq!(engine.submit(&mem, &request, 128, &[], 2).unwrap(), 1);
+        assert_eq!(wait_completions(&mut engine), vec![(2, 512)]);
+        let mut data = [0u8; 512];
+        mem.read_slice(&mut data, GuestAddress(0x2000)).unwrap();
+        assert!(data.iter().all(|&b| b == 0xa5));
+
+        // Flushes complete synchronously.
+        request.request_type = RequestType::Flush;
+        assert_eq!(engine.submit(&mem, &request, 128, &[], 3).unwrap(), 0);
+
+        // Requests beyond the end of the disk are rejected before submission.
+        request.request_type = RequestType::In;
+        request.sector = 128;
+        assert!(engine.submit(&mem, &request, 128, &[], 4).is_err());
+    }
+}
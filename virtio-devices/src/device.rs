@@ -10,6 +10,7 @@ use crate::{ActivateResult, Error, Queue};
 use std::collections::HashMap;
 use std::io::Write;
 use std::num::Wrapping;
+use std::path::Path;
 use std::sync::Arc;
 use vm_memory::{GuestAddress, GuestMemoryAtomic, GuestMemoryMmap, GuestUsize};
 use vm_virtio::VirtioDeviceType;
@@ -151,6 +152,44 @@ pub trait VirtioDevice: Send {
         None
     }
 
+    /// Grows the disk backing this device to `size` bytes and notifies the
+    /// guest about the new capacity.
+    fn resize_disk(&mut self, _size: u64) -> std::result::Result<(), Error> {
+        Err(Error::ResizeDiskNotSupported)
+    }
+
+    /// Starts copying the content of the backing file of the disk into the
+    /// disk image, in the background. The backing file is dropped at the end.
+    fn stream_disk(&mut self) -> std::result::Result<(), Error> {
+        Err(Error::StreamDiskNotSupported)
+    }
+
+    /// Sets the link state of a network device and notifies the guest.
+    fn set_link_up(&mut self, _up: bool) -> std::result::Result<(), Error> {
+        Err(Error::SetLinkNotSupported)
+    }
+
+    /// Starts recording the frames of a network device to a pcap file, each
+    /// frame being truncated to `snaplen` bytes if given.
+    fn start_capture(
+        &mut self,
+        _path: &Path,
+        _snaplen: Option<u32>,
+    ) -> std::result::Result<(), Error> {
+        Err(Error::CaptureNotSupported)
+    }
+
+    /// Stops recording the frames of a network device.
+    fn stop_capture(&mut self) -> std::result::Result<(), Error> {
+        Err(Error::CaptureNotSupported)
+    }
+
+    /// Detaches the backend of a network device from the bridge it was
+    /// attached to, if any.
+    fn detach_from_bridge(&mut self) -> std::result::Result<(), Error> {
+        Ok(())
+    }
+
     /// Helper to allow common implementation of read_config
     fn read_config_from_slice(&self, config: &[u8], offset: u64, mut data: &mut [u8]) {
         let config_len = config.len() as u64;
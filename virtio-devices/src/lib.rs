@@ -31,6 +31,8 @@ use std::io;
 mod device;
 pub mod balloon;
 pub mod block;
+pub mod block_aio;
+pub mod block_async;
 #[cfg(feature = "io_uring")]
 pub mod block_io_uring;
 mod console;
@@ -43,11 +45,14 @@ mod pmem;
 mod rng;
 pub mod seccomp_filters;
 pub mod transport;
+pub mod vhost_net;
 pub mod vhost_user;
 pub mod vsock;
 
 pub use self::balloon::*;
 pub use self::block::*;
+pub use self::block_aio::*;
+pub use self::block_async::*;
 #[cfg(feature = "io_uring")]
 pub use self::block_io_uring::*;
 pub use self::console::*;
@@ -124,10 +129,21 @@ pub enum Error {
     EpollWait(io::Error),
     FailedSignalingDriver(io::Error),
     VhostUserUpdateMemory(vhost_user::Error),
+    VhostNetUpdateMemory(vhost_net::Error),
+    VhostNetSetLink(vhost_net::Error),
     EventfdError(io::Error),
     SetShmRegionsNotSupported,
     EpollHander(String),
     NoMemoryConfigured,
     NetQueuePair(::net_util::NetQueuePairError),
     ApplySeccompFilter(seccomp::Error),
+    ResizeDiskNotSupported,
+    InvalidDiskSize(u64),
+    StreamDiskNotSupported,
+    StreamDiskInProgress,
+    SetLinkNotSupported,
+    CaptureNotSupported,
+    StartCapture(io::Error),
+    CaptureNotRunning,
+    DetachBridge(::net_util::BridgeError),
 }
@@ -6,8 +6,8 @@
 // found in the THIRD-PARTY file.
 
 use super::net_util::{
-    build_net_config_space, build_net_config_space_with_mq, CtrlVirtio, NetCtrlEpollHandler,
-    VirtioNetConfig,
+    build_net_config_space, build_net_config_space_with_mq, build_net_config_space_with_rss,
+    CtrlVirtio, NetCtrlEpollHandler, VirtioNetConfig, VIRTIO_NET_F_HASH_REPORT, VIRTIO_NET_F_RSS,
 };
 use super::Error as DeviceError;
 use super::{
@@ -15,20 +15,25 @@ use super::{
     VirtioDevice, VirtioDeviceType, VirtioInterruptType, EPOLL_HELPER_EVENT_LAST,
 };
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::vhost_net::VhostNetQueuePair;
 use crate::VirtioInterrupt;
 use anyhow::anyhow;
 use libc::EFD_NONBLOCK;
 use net_util::{
-    open_tap, MacAddr, NetCounters, NetQueuePair, OpenTapError, RxVirtio, Tap, TxVirtio,
+    attach_to_bridge, detach_from_bridge, open_tap, BridgeError, DhcpServer, HostFwd, MacAddr,
+    NetCounters, NetOffloads, NetQueuePair, OpenTapError, PcapCapture, Rss, RssSteering, RxFilter,
+    RxVirtio, SteeredFrames, Tap, TxVirtio, UserNet, UserNetConfig, UserNetError,
 };
 use seccomp::{SeccompAction, SeccompFilter};
 use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::num::Wrapping;
 use std::os::unix::io::AsRawFd;
+use std::path::Path;
 use std::result;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Barrier};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, Barrier, RwLock};
 use std::thread;
 use std::vec::Vec;
 use virtio_bindings::bindings::virtio_net::*;
@@ -46,11 +51,23 @@ pub const RX_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 pub const TX_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 2;
 // A frame is available for reading from the tap device to receive in the guest.
 pub const RX_TAP_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 3;
+// Frames have been steered to the queue pair by another one.
+pub const RX_STEERED_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 4;
 
 #[derive(Debug)]
 pub enum Error {
     /// Failed to open taps.
     OpenTap(OpenTapError),
+    /// Failed to create the user-mode network stack.
+    UserNet(UserNetError),
+    /// Failed to create the kill EventFd of the user-mode network stack.
+    CreateKillEventFd(io::Error),
+    /// Failed to create the seccomp filter of the user-mode network stack.
+    CreateSeccompFilter(seccomp::SeccompError),
+    /// Failed to spawn the user-mode network stack thread.
+    SpawnUserNet(io::Error),
+    /// Failed to attach the tap to a bridge.
+    AttachBridge(BridgeError),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -116,6 +133,11 @@ impl NetEpollHandler {
         } else {
             info!("Not signalling TX queue");
         }
+
+        // Deliver the replies to the DHCP requests just sent by the guest.
+        if self.net.has_pending_rx() {
+            self.handle_rx_tap_event()?;
+        }
         Ok(())
     }
 
@@ -154,6 +176,13 @@ impl NetEpollHandler {
             info!("Listener registered at start");
         }
 
+        if let Some(steering) = &self.net.rss {
+            helper.add_event(
+                steering.steered_frames[steering.index].evt().as_raw_fd(),
+                RX_STEERED_EVENT,
+            )?;
+        }
+
         // The NetQueuePair needs the epoll fd.
         self.net.epoll_fd = Some(helper.as_raw_fd());
 
@@ -187,6 +216,17 @@ impl EpollHelperHandler for NetEpollHandler {
                     return true;
                 }
             }
+            RX_STEERED_EVENT => {
+                if let Some(steering) = &self.net.rss {
+                    if let Err(e) = steering.steered_frames[steering.index].evt().read() {
+                        error!("Failed to get steered frames event: {:?}", e);
+                    }
+                }
+                if let Err(e) = self.handle_rx_tap_event() {
+                    error!("Error processing steered frames: {:?}", e);
+                    return true;
+                }
+            }
             _ => {
                 error!("Unknown event: {}", ev_type);
                 return true;
@@ -213,6 +253,29 @@ pub struct Net {
     queue_size: Vec<u16>,
     counters: NetCounters,
     seccomp_action: SeccompAction,
+    guest_mac: Option<MacAddr>,
+    rx_filter: Arc<RwLock<RxFilter>>,
+    // Status reported through the configuration space, shared with the
+    // control queue which acknowledges the announcements.
+    status: Arc<AtomicU16>,
+    // Stops the user-mode network stack the device is connected to, if any.
+    user_net_kill_evt: Option<EventFd>,
+    // Answers the DHCP requests of the guest, if enabled.
+    dhcp_server: Option<DhcpServer>,
+    // Records the frames going through the device, shared with the queue
+    // pairs so that the capture can be started at any time.
+    capture: PcapCapture,
+    // Name of the tap, kept since the taps are moved to the queue pairs.
+    tap_if_name: String,
+    // Bridge the tap has been attached to, if any.
+    bridge: Option<String>,
+    // Whether the queue pairs should be handed over to vhost-net.
+    vhost_net: bool,
+    // Queue pairs handled by vhost-net, if it could be used.
+    vhost_net_queue_pairs: Option<Vec<VhostNetQueuePair>>,
+    // Hashing and steering of the received frames, programmed through the
+    // control queue.
+    rss: Arc<RwLock<Rss>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -221,10 +284,15 @@ pub struct NetState {
     pub acked_features: u64,
     pub config: VirtioNetConfig,
     pub queue_size: Vec<u16>,
+    #[serde(default)]
+    pub rx_filter: RxFilter,
+    #[serde(default)]
+    pub rss: Rss,
 }
 
 impl Net {
     /// Create a new virtio network device with the given TAP interface.
+    #[allow(clippy::too_many_arguments)]
     pub fn new_with_tap(
         id: String,
         taps: Vec<Tap>,
@@ -232,17 +300,24 @@ impl Net {
         iommu: bool,
         num_queues: usize,
         queue_size: u16,
+        mtu: Option<u16>,
+        offloads: NetOffloads,
+        dhcp_server: Option<DhcpServer>,
         seccomp_action: SeccompAction,
     ) -> Result<Self> {
-        let mut avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
-            | 1 << VIRTIO_NET_F_CSUM
-            | 1 << VIRTIO_NET_F_GUEST_TSO4
-            | 1 << VIRTIO_NET_F_GUEST_UFO
-            | 1 << VIRTIO_NET_F_HOST_TSO4
-            | 1 << VIRTIO_NET_F_HOST_UFO
+        let mut avail_features = offloads.virtio_features()
+            | 1 << VIRTIO_NET_F_MRG_RXBUF
             | 1 << VIRTIO_RING_F_EVENT_IDX
             | 1 << VIRTIO_F_VERSION_1;
 
+        // The control queue can be used to filter the received frames.
+        avail_features |= 1 << VIRTIO_NET_F_CTRL_RX
+            | 1 << VIRTIO_NET_F_CTRL_VLAN
+            | 1 << VIRTIO_NET_F_CTRL_RX_EXTRA
+            | 1 << VIRTIO_NET_F_CTRL_MAC_ADDR
+            | 1 << VIRTIO_NET_F_STATUS
+            | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE;
+
         if iommu {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
         }
@@ -251,11 +326,21 @@ impl Net {
         let queue_num = num_queues + 1;
 
         let mut config = VirtioNetConfig::default();
+        config.status = VIRTIO_NET_S_LINK_UP as u16;
+        if let Some(mtu) = mtu {
+            config.mtu = mtu;
+            avail_features |= 1 << VIRTIO_NET_F_MTU;
+        }
         if let Some(mac) = guest_mac {
             build_net_config_space(&mut config, mac, num_queues, &mut avail_features);
         } else {
             build_net_config_space_with_mq(&mut config, num_queues, &mut avail_features);
         }
+        build_net_config_space_with_rss(&mut config, &mut avail_features);
+
+        let tap_if_name = String::from_utf8_lossy(&taps[0].get_if_name())
+            .trim_end_matches('\0')
+            .to_owned();
 
         Ok(Net {
             id,
@@ -274,26 +359,55 @@ impl Net {
             queue_size: vec![queue_size; queue_num],
             counters: NetCounters::default(),
             seccomp_action,
+            guest_mac,
+            rx_filter: Arc::new(RwLock::new(RxFilter::new(guest_mac))),
+            status: Arc::new(AtomicU16::new(VIRTIO_NET_S_LINK_UP as u16)),
+            user_net_kill_evt: None,
+            dhcp_server,
+            capture: PcapCapture::default(),
+            tap_if_name,
+            bridge: None,
+            vhost_net: false,
+            vhost_net_queue_pairs: None,
+            rss: Arc::new(RwLock::new(Rss::default())),
         })
     }
 
     /// Create a new virtio network device with the given IP address and
-    /// netmask.
+    /// netmask, and the given IPv6 address and prefix length. The replies of the DHCP server, if any, are sent from the MAC
+    /// address of the tap.
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
         if_name: Option<&str>,
         ip_addr: Option<Ipv4Addr>,
         netmask: Option<Ipv4Addr>,
+        ipv6_addr: Option<(Ipv6Addr, u8)>,
         guest_mac: Option<MacAddr>,
         host_mac: &mut Option<MacAddr>,
         iommu: bool,
         num_queues: usize,
         queue_size: u16,
+        mtu: Option<u16>,
+        offloads: NetOffloads,
+        mut dhcp_server: Option<DhcpServer>,
         seccomp_action: SeccompAction,
     ) -> Result<Self> {
-        let taps = open_tap(if_name, ip_addr, netmask, host_mac, num_queues / 2)
-            .map_err(Error::OpenTap)?;
+        let taps = open_tap(
+            if_name,
+            ip_addr,
+            netmask,
+            ipv6_addr,
+            host_mac,
+            num_queues / 2,
+            mtu,
+            offloads,
+        )
+        .map_err(Error::OpenTap)?;
+
+        if let (Some(dhcp_server), Some(host_mac)) = (dhcp_server.as_mut(), *host_mac) {
+            dhcp_server.server_mac = host_mac;
+        }
 
         Self::new_with_tap(
             id,
@@ -302,16 +416,87 @@ impl Net {
             iommu,
             num_queues,
             queue_size,
+            mtu,
+            offloads,
+            dhcp_server,
             seccomp_action,
         )
     }
 
+    /// Create a new virtio network device connected to a user-mode network
+    /// stack, the given IP address being the one of its gateway. The stack
+    /// handles plain frames on a single queue pair, hence no offload is
+    /// offered to the guest.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_user(
+        id: String,
+        guest_mac: MacAddr,
+        iommu: bool,
+        queue_size: u16,
+        mtu: Option<u16>,
+        ip_addr: Ipv4Addr,
+        netmask: Ipv4Addr,
+        hostfwd: Vec<HostFwd>,
+        seccomp_action: SeccompAction,
+    ) -> Result<Self> {
+        let (mut user_net, tap) = UserNet::new(UserNetConfig {
+            ip: ip_addr,
+            mask: netmask,
+            guest_mac,
+            hostfwd,
+        })
+        .map_err(Error::UserNet)?;
+
+        let kill_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::CreateKillEventFd)?;
+        let thread_kill_evt = kill_evt.try_clone().map_err(Error::CreateKillEventFd)?;
+        let virtio_net_user_seccomp_filter =
+            get_seccomp_filter(&seccomp_action, Thread::VirtioNetUser)
+                .map_err(Error::CreateSeccompFilter)?;
+        thread::Builder::new()
+            .name("virtio_net_user".to_string())
+            .spawn(move || {
+                if let Err(e) = SeccompFilter::apply(virtio_net_user_seccomp_filter) {
+                    error!("Error applying seccomp filter: {:?}", e);
+                } else if let Err(e) = user_net.run(thread_kill_evt) {
+                    error!("Error running user-mode network stack: {:?}", e);
+                }
+            })
+            .map_err(Error::SpawnUserNet)?;
+
+        let offloads = NetOffloads {
+            csum: false,
+            tso: false,
+            tso6: false,
+            ufo: false,
+            ecn: false,
+        };
+        let mut net = Self::new_with_tap(
+            id,
+            vec![tap],
+            Some(guest_mac),
+            iommu,
+            2,
+            queue_size,
+            mtu,
+            offloads,
+            None,
+            seccomp_action,
+        )?;
+        net.user_net_kill_evt = Some(kill_evt);
+
+        Ok(net)
+    }
+
     fn state(&self) -> NetState {
+        let mut config = self.config;
+        config.status = self.status.load(Ordering::Acquire);
         NetState {
             avail_features: self.avail_features,
             acked_features: self.acked_features,
-            config: self.config,
+            config,
             queue_size: self.queue_size.clone(),
+            rx_filter: self.rx_filter.read().unwrap().clone(),
+            rss: self.rss.read().unwrap().clone(),
         }
     }
 
@@ -320,9 +505,99 @@ impl Net {
         self.acked_features = state.acked_features;
         self.config = state.config;
         self.queue_size = state.queue_size.clone();
+        *self.rx_filter.write().unwrap() = state.rx_filter.clone();
+        *self.rss.write().unwrap() = state.rss.clone();
+
+        // Ask the guest to announce itself, so that the network learns about
+        // its new location once it has been restored or migrated.
+        let mut status = self.config.status;
+        if self.acked_features & 1 << VIRTIO_NET_F_GUEST_ANNOUNCE != 0 {
+            status |= VIRTIO_NET_S_ANNOUNCE as u16;
+        }
+        self.status.store(status, Ordering::Release);
+
+        Ok(())
+    }
+}
+
+impl Net {
+    /// Attach the tap of the device to a Linux or Open vSwitch bridge. The
+    /// tap is detached when the device is removed or shut down.
+    pub fn attach_to_bridge(&mut self, bridge: &str) -> Result<()> {
+        attach_to_bridge(bridge, &self.tap_if_name).map_err(Error::AttachBridge)?;
+        self.bridge = Some(bridge.to_owned());
+        Ok(())
+    }
+
+    /// Hand the queue pairs over to the vhost-net kernel driver when the
+    /// device is activated. The frames are processed by the device itself
+    /// if vhost-net can't be used. Since vhost-net neither hashes, steers
+    /// nor filters the frames, RSS, hash reporting and the receive filters
+    /// aren't offered to the guest, and neither are the link status and the
+    /// announcements it carries.
+    pub fn set_vhost_net(&mut self, vhost_net: bool) {
+        self.vhost_net = vhost_net;
+        if vhost_net {
+            self.avail_features &= !(1 << VIRTIO_NET_F_RSS
+                | 1 << VIRTIO_NET_F_HASH_REPORT
+                | 1 << VIRTIO_NET_F_CTRL_RX
+                | 1 << VIRTIO_NET_F_CTRL_RX_EXTRA
+                | 1 << VIRTIO_NET_F_CTRL_VLAN
+                | 1 << VIRTIO_NET_F_CTRL_MAC_ADDR
+                | 1 << VIRTIO_NET_F_STATUS
+                | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE);
+        }
+    }
+
+    fn link_up(&self) -> bool {
+        self.status.load(Ordering::Acquire) & VIRTIO_NET_S_LINK_UP as u16 != 0
+    }
+
+    // Start exchanging the frames of the queue pairs handed over to
+    // vhost-net, unless the link is down or the device paused, in which case
+    // they are stopped.
+    fn update_vhost_net_backend(&mut self) -> crate::vhost_net::Result<()> {
+        let start = self.link_up() && !self.paused.load(Ordering::SeqCst);
+        if let Some(queue_pairs) = &mut self.vhost_net_queue_pairs {
+            for queue_pair in queue_pairs {
+                if start {
+                    queue_pair.start()?;
+                } else {
+                    queue_pair.stop()?;
+                }
+            }
+        }
 
         Ok(())
     }
+
+    fn setup_vhost_net(
+        &self,
+        mem: &GuestMemoryAtomic<GuestMemoryMmap>,
+        interrupt_cb: &Arc<dyn VirtioInterrupt>,
+        taps: &[Tap],
+        queues: &[Queue],
+        queue_evts: &[EventFd],
+    ) -> crate::vhost_net::Result<Vec<VhostNetQueuePair>> {
+        let mut queue_pairs = Vec::new();
+        for (i, tap) in taps.iter().enumerate() {
+            let mut queue_pair = VhostNetQueuePair::new(
+                tap.clone(),
+                mem,
+                &queues[i * 2..i * 2 + 2],
+                &queue_evts[i * 2..i * 2 + 2],
+                interrupt_cb,
+                self.acked_features,
+            )?;
+            // No frame goes through a link set down.
+            if self.link_up() {
+                queue_pair.start()?;
+            }
+            queue_pairs.push(queue_pair);
+        }
+
+        Ok(queue_pairs)
+    }
 }
 
 impl Drop for Net {
@@ -331,6 +606,10 @@ impl Drop for Net {
             // Ignore the result because there is nothing we can do about it.
             let _ = kill_evt.write(1);
         }
+        if let Some(kill_evt) = self.user_net_kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
     }
 }
 
@@ -360,7 +639,14 @@ impl VirtioDevice for Net {
     }
 
     fn read_config(&self, offset: u64, data: &mut [u8]) {
-        self.read_config_from_slice(self.config.as_slice(), offset, data);
+        let mut config = self.config;
+        // The driver may have changed the MAC address through the control
+        // queue.
+        if let Some(mac) = self.rx_filter.read().unwrap().mac() {
+            config.mac.copy_from_slice(mac.get_bytes());
+        }
+        config.status = self.status.load(Ordering::Acquire);
+        self.read_config_from_slice(config.as_slice(), offset, data);
     }
 
     fn activate(
@@ -411,6 +697,29 @@ impl VirtioDevice for Net {
             }
             self.queue_evts = Some(tmp_queue_evts);
 
+            let vhost_net_queue_pairs = if self.vhost_net {
+                match self.setup_vhost_net(&mem, &interrupt_cb, &taps, &queues, &queue_evts) {
+                    Ok(queue_pairs) => Some(queue_pairs),
+                    Err(e) => {
+                        warn!(
+                            "Failed to set up vhost-net, processing the frames in userspace: {:?}",
+                            e
+                        );
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            // There is no thread to pause for the queue pairs handled by
+            // vhost-net.
+            let num_queue_pair_threads = if vhost_net_queue_pairs.is_some() {
+                0
+            } else {
+                taps.len()
+            };
+            self.paused_sync = Arc::new(Barrier::new(num_queue_pair_threads + 1));
+
             let queue_num = queues.len();
             if (self.acked_features & 1 << VIRTIO_NET_F_CTRL_VQ) != 0 && queue_num % 2 != 0 {
                 let cvq_queue = queues.remove(queue_num - 1);
@@ -420,7 +729,13 @@ impl VirtioDevice for Net {
                     mem: mem.clone(),
                     kill_evt: kill_evt.try_clone().unwrap(),
                     pause_evt: pause_evt.try_clone().unwrap(),
-                    ctrl_q: CtrlVirtio::new(cvq_queue, cvq_queue_evt),
+                    ctrl_q: CtrlVirtio::new(
+                        cvq_queue,
+                        cvq_queue_evt,
+                        Some(self.rx_filter.clone()),
+                        Some(self.status.clone()),
+                        Some(self.rss.clone()),
+                    ),
                     epoll_fd: 0,
                 };
 
@@ -428,7 +743,7 @@ impl VirtioDevice for Net {
                 // Let's update the barrier as we need 1 for each RX/TX pair +
                 // 1 for the control queue + 1 for the main thread signalling
                 // the pause.
-                self.paused_sync = Arc::new(Barrier::new(taps.len() + 2));
+                self.paused_sync = Arc::new(Barrier::new(num_queue_pair_threads + 2));
                 let paused_sync = self.paused_sync.clone();
 
                 // Retrieve seccomp filter for virtio_net_ctl thread
@@ -452,11 +767,34 @@ impl VirtioDevice for Net {
             }
 
             let event_idx = self.acked_features & 1 << VIRTIO_RING_F_EVENT_IDX != 0;
+            let mergeable = self.acked_features & 1 << VIRTIO_NET_F_MRG_RXBUF != 0;
+            self.rx_filter
+                .write()
+                .unwrap()
+                .set_vlan_filtering(self.acked_features & 1 << VIRTIO_NET_F_CTRL_VLAN != 0);
+
+            let hash_report = self.acked_features & 1 << VIRTIO_NET_F_HASH_REPORT != 0;
+
+            self.vhost_net_queue_pairs = vhost_net_queue_pairs;
+
+            let mut steered_frames = Vec::new();
+            for _ in 0..num_queue_pair_threads {
+                steered_frames.push(Arc::new(SteeredFrames::new().map_err(|e| {
+                    error!("failed creating steered frames EventFd: {}", e);
+                    ActivateError::BadActivate
+                })?));
+            }
 
             let mut epoll_threads = Vec::new();
-            for _ in 0..taps.len() {
-                let rx = RxVirtio::new();
-                let tx = TxVirtio::new();
+            for i in 0..num_queue_pair_threads {
+                let mut rx = RxVirtio::new();
+                rx.mergeable = mergeable;
+                rx.capture = self.capture.clone();
+                rx.hash_report = hash_report;
+                let mut tx = TxVirtio::new();
+                tx.dhcp_server = self.dhcp_server.clone();
+                tx.capture = self.capture.clone();
+                tx.hash_report = hash_report;
                 let rx_tap_listening = false;
 
                 let mut queue_pair = Vec::new();
@@ -479,6 +817,13 @@ impl VirtioDevice for Net {
                         rx_tap_listening,
                         counters: self.counters.clone(),
                         tap_event_id: RX_TAP_EVENT,
+                        rx_filter: Some(self.rx_filter.clone()),
+                        rss: Some(RssSteering {
+                            rss: self.rss.clone(),
+                            index: i,
+                            steered_frames: steered_frames.clone(),
+                        }),
+                        link_status: Some(self.status.clone()),
                     },
                     queue_pair,
                     queue_evt_pair,
@@ -512,6 +857,13 @@ impl VirtioDevice for Net {
 
             self.epoll_threads = Some(epoll_threads);
 
+            // A restored device has an announcement pending.
+            if self.status.load(Ordering::Acquire) & VIRTIO_NET_S_ANNOUNCE as u16 != 0 {
+                if let Err(e) = interrupt_cb.trigger(&VirtioInterruptType::Config, None) {
+                    error!("Failed to request the guest announcement: {:?}", e);
+                }
+            }
+
             return Ok(());
         }
         Err(ActivateError::BadActivate)
@@ -528,6 +880,14 @@ impl VirtioDevice for Net {
             let _ = kill_evt.write(1);
         }
 
+        // Dropping the queue pairs stops vhost-net from processing them.
+        self.vhost_net_queue_pairs = None;
+
+        *self.rx_filter.write().unwrap() = RxFilter::new(self.guest_mac);
+        *self.rss.write().unwrap() = Rss::default();
+        self.status
+            .fetch_and(!(VIRTIO_NET_S_ANNOUNCE as u16), Ordering::AcqRel);
+
         // Return the interrupt and queue EventFDs
         Some((
             self.interrupt_cb.take().unwrap(),
@@ -535,6 +895,79 @@ impl VirtioDevice for Net {
         ))
     }
 
+    fn set_link_up(&mut self, up: bool) -> result::Result<(), DeviceError> {
+        if up {
+            self.status
+                .fetch_or(VIRTIO_NET_S_LINK_UP as u16, Ordering::AcqRel);
+        } else {
+            self.status
+                .fetch_and(!(VIRTIO_NET_S_LINK_UP as u16), Ordering::AcqRel);
+        }
+
+        // The queue pairs handed over to vhost-net don't look at the link
+        // state, they have to be stopped for the frames to stop flowing.
+        self.update_vhost_net_backend()
+            .map_err(DeviceError::VhostNetSetLink)?;
+
+        // Let the guest know about the new link state.
+        if let Some(interrupt_cb) = &self.interrupt_cb {
+            interrupt_cb
+                .trigger(&VirtioInterruptType::Config, None)
+                .map_err(DeviceError::FailedSignalingDriver)?;
+        }
+
+        Ok(())
+    }
+
+    fn detach_from_bridge(&mut self) -> result::Result<(), DeviceError> {
+        if let Some(bridge) = self.bridge.take() {
+            detach_from_bridge(&bridge, &self.tap_if_name).map_err(DeviceError::DetachBridge)?;
+        }
+
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {
+        if let Err(e) = self.detach_from_bridge() {
+            error!("Failed to detach tap from bridge: {:?}", e);
+        }
+    }
+
+    fn start_capture(
+        &mut self,
+        path: &Path,
+        snaplen: Option<u32>,
+    ) -> result::Result<(), DeviceError> {
+        // The frames handled by vhost-net never reach the device.
+        if self.vhost_net_queue_pairs.is_some() {
+            return Err(DeviceError::CaptureNotSupported);
+        }
+
+        self.capture
+            .start(path, snaplen)
+            .map_err(DeviceError::StartCapture)
+    }
+
+    fn stop_capture(&mut self) -> result::Result<(), DeviceError> {
+        if self.capture.stop() {
+            Ok(())
+        } else {
+            Err(DeviceError::CaptureNotRunning)
+        }
+    }
+
+    fn update_memory(&mut self, mem: &GuestMemoryMmap) -> result::Result<(), DeviceError> {
+        if let Some(queue_pairs) = &mut self.vhost_net_queue_pairs {
+            for queue_pair in queue_pairs {
+                queue_pair
+                    .update_memory(mem)
+                    .map_err(DeviceError::VhostNetUpdateMemory)?;
+            }
+        }
+
+        Ok(())
+    }
+
     fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
         let mut counters = HashMap::new();
 
@@ -546,6 +979,10 @@ impl VirtioDevice for Net {
             "rx_frames",
             Wrapping(self.counters.rx_frames.load(Ordering::Acquire)),
         );
+        counters.insert(
+            "rx_dropped",
+            Wrapping(self.counters.rx_dropped.load(Ordering::Acquire)),
+        );
         counters.insert(
             "tx_bytes",
             Wrapping(self.counters.tx_bytes.load(Ordering::Acquire)),
@@ -559,7 +996,29 @@ impl VirtioDevice for Net {
     }
 }
 
-virtio_ctrl_q_pausable!(Net);
+virtio_pausable_trait!(Net);
+
+impl Pausable for Net {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.virtio_pause()?;
+
+        // vhost-net keeps processing the queues until it is stopped.
+        self.update_vhost_net_backend()
+            .map_err(|e| MigratableError::Pause(anyhow!("{:?}", e)))
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.virtio_resume()?;
+
+        if let Some(ctrl_queue_epoll_thread) = &self.ctrl_queue_epoll_thread {
+            ctrl_queue_epoll_thread.thread().unpark();
+        }
+
+        self.update_vhost_net_backend()
+            .map_err(|e| MigratableError::Resume(anyhow!("{:?}", e)))
+    }
+}
+
 impl Snapshottable for Net {
     fn id(&self) -> String {
         self.id.clone()
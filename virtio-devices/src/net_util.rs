@@ -6,24 +6,38 @@ use super::{
     DescriptorChain, EpollHelper, EpollHelperError, EpollHelperHandler, Queue,
     EPOLL_HELPER_EVENT_LAST,
 };
-use net_util::MacAddr;
+use net_util::{
+    MacAddr, Rss, RxFilter, MAC_ADDR_LEN, MAC_TABLE_ENTRIES, RSS_MAX_INDIRECTION_TABLE_LEN,
+    RSS_MAX_KEY_SIZE, RSS_SUPPORTED_HASH_TYPES,
+};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::convert::TryInto;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::sync::atomic::AtomicBool;
-use std::sync::{Arc, Barrier};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, Barrier, RwLock};
 use virtio_bindings::bindings::virtio_net::*;
 use vm_memory::{
-    ByteValued, Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryError, GuestMemoryMmap,
+    Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
+    GuestMemoryError, GuestMemoryMmap,
 };
 use vmm_sys_util::eventfd::EventFd;
 
 type Result<T> = std::result::Result<T, Error>;
 
-const QUEUE_SIZE: usize = 256;
+// Largest command gathered from the control queue, which is enough for two
+// full MAC filter tables. Only larger MAC filter tables are accepted, and
+// handled as overflowing.
+const MAX_CTRL_CMD_SIZE: usize = 4096;
 
 // Event available on the control queue.
 const CTRL_QUEUE_EVENT: u16 = EPOLL_HELPER_EVENT_LAST + 1;
 
+// Receive-side scaling and hash reporting, which the bindings predate.
+pub const VIRTIO_NET_F_HASH_REPORT: u32 = 57;
+pub const VIRTIO_NET_F_RSS: u32 = 60;
+const VIRTIO_NET_CTRL_MQ_RSS_CONFIG: u32 = 1;
+const VIRTIO_NET_CTRL_MQ_HASH_CONFIG: u32 = 2;
+
 #[repr(C, packed)]
 #[derive(Copy, Clone, Debug, Default, Deserialize)]
 pub struct VirtioNetConfig {
@@ -33,6 +47,12 @@ pub struct VirtioNetConfig {
     pub mtu: u16,
     pub speed: u32,
     pub duplex: u8,
+    #[serde(default)]
+    pub rss_max_key_size: u8,
+    #[serde(default)]
+    pub rss_max_indirection_table_length: u16,
+    #[serde(default)]
+    pub supported_hash_types: u32,
 }
 
 // We must explicitly implement Serialize since the structure is packed and
@@ -51,14 +71,23 @@ impl Serialize for VirtioNetConfig {
         let mtu = self.mtu;
         let speed = self.speed;
         let duplex = self.duplex;
+        let rss_max_key_size = self.rss_max_key_size;
+        let rss_max_indirection_table_length = self.rss_max_indirection_table_length;
+        let supported_hash_types = self.supported_hash_types;
 
-        let mut virtio_net_config = serializer.serialize_struct("VirtioNetConfig", 17)?;
+        let mut virtio_net_config = serializer.serialize_struct("VirtioNetConfig", 24)?;
         virtio_net_config.serialize_field("mac", &mac)?;
         virtio_net_config.serialize_field("status", &status)?;
         virtio_net_config.serialize_field("max_virtqueue_pairs", &max_virtqueue_pairs)?;
         virtio_net_config.serialize_field("mtu", &mtu)?;
         virtio_net_config.serialize_field("speed", &speed)?;
         virtio_net_config.serialize_field("duplex", &duplex)?;
+        virtio_net_config.serialize_field("rss_max_key_size", &rss_max_key_size)?;
+        virtio_net_config.serialize_field(
+            "rss_max_indirection_table_length",
+            &rss_max_indirection_table_length,
+        )?;
+        virtio_net_config.serialize_field("supported_hash_types", &supported_hash_types)?;
         virtio_net_config.end()
     }
 }
@@ -68,27 +97,38 @@ unsafe impl ByteValued for VirtioNetConfig {}
 
 #[derive(Debug)]
 pub enum Error {
-    /// Read process MQ.
-    FailedProcessMQ,
     /// Read queue failed.
     GuestMemory(GuestMemoryError),
+    /// Ctrl command larger than MAX_CTRL_CMD_SIZE
+    CtlCmdTooLarge(usize),
     /// Invalid ctrl class
-    InvalidCtlClass,
+    InvalidCtlClass(u8),
     /// Invalid ctrl command
-    InvalidCtlCmd,
+    InvalidCtlCmd(u8),
     /// Invalid descriptor
     InvalidDesc,
+    /// Invalid ctrl command data
+    InvalidCtlData,
+    /// Invalid MAC filter table
+    InvalidMacTable,
     /// Invalid queue pairs number
     InvalidQueuePairsNum,
+    /// Invalid VLAN ID
+    InvalidVlanId(u16),
+    /// Invalid RSS or hash configuration
+    InvalidRssConfig,
     /// No memory passed in.
     NoMemory,
-    /// No ueue pairs nummber.
-    NoQueuePairsNum,
+    /// No status descriptor in the ctrl command.
+    NoStatusDesc,
 }
 
 pub struct CtrlVirtio {
     pub queue_evt: EventFd,
     pub queue: Queue,
+    pub rx_filter: Option<Arc<RwLock<RxFilter>>>,
+    pub status: Option<Arc<AtomicU16>>,
+    pub rss: Option<Arc<RwLock<Rss>>>,
 }
 
 impl std::clone::Clone for CtrlVirtio {
@@ -96,68 +136,371 @@ impl std::clone::Clone for CtrlVirtio {
         CtrlVirtio {
             queue_evt: self.queue_evt.try_clone().unwrap(),
             queue: self.queue.clone(),
+            rx_filter: self.rx_filter.clone(),
+            status: self.status.clone(),
+            rss: self.rss.clone(),
         }
     }
 }
 
+// Control queue command, as laid out in the device readable descriptors.
+struct CtrlCmd {
+    // Beginning of the command, up to MAX_CTRL_CMD_SIZE bytes.
+    data: Vec<u8>,
+    // Guest memory areas holding the whole command.
+    areas: Vec<(GuestAddress, usize)>,
+    len: usize,
+    status_addr: GuestAddress,
+}
+
+impl CtrlCmd {
+    // Walks the descriptor chain, up to the status descriptor.
+    fn new(head: DescriptorChain) -> Result<Self> {
+        let mut areas = Vec::new();
+        let mut len = 0usize;
+        let mut next_desc = Some(head);
+        while let Some(desc) = next_desc {
+            if desc.is_write_only() {
+                return Ok(CtrlCmd {
+                    data: Vec::new(),
+                    areas,
+                    len,
+                    status_addr: desc.addr,
+                });
+            }
+            areas.push((desc.addr, desc.len as usize));
+            len += desc.len as usize;
+            next_desc = desc.next_descriptor();
+        }
+
+        Err(Error::NoStatusDesc)
+    }
+
+    // Gathers the beginning of the command.
+    fn gather(&mut self, mem: &GuestMemoryMmap) -> Result<()> {
+        let mut data = vec![0; std::cmp::min(self.len, MAX_CTRL_CMD_SIZE)];
+        self.read_at(mem, 0, &mut data)?;
+        self.data = data;
+
+        Ok(())
+    }
+
+    // Reads the part of the command starting at `offset`, including beyond
+    // the gathered data.
+    fn read_at(&self, mem: &GuestMemoryMmap, mut offset: usize, mut buf: &mut [u8]) -> Result<()> {
+        for (addr, len) in self.areas.iter() {
+            if buf.is_empty() {
+                break;
+            }
+            if offset >= *len {
+                offset -= len;
+                continue;
+            }
+            let count = std::cmp::min(len - offset, buf.len());
+            let addr = addr.checked_add(offset as u64).ok_or(Error::InvalidDesc)?;
+            mem.read_slice(&mut buf[..count], addr)
+                .map_err(Error::GuestMemory)?;
+            buf = &mut buf[count..];
+            offset = 0;
+        }
+        if !buf.is_empty() {
+            return Err(Error::InvalidCtlData);
+        }
+
+        Ok(())
+    }
+
+    // Reads a MAC filter table, made of the number of entries followed by
+    // the addresses, at `offset`. Returns the table, or None if it has more
+    // entries than the filter can hold, and the offset following it.
+    fn read_mac_table(
+        &self,
+        mem: &GuestMemoryMmap,
+        offset: usize,
+    ) -> Result<(Option<Vec<MacAddr>>, usize)> {
+        let mut entries = [0u8; 4];
+        self.read_at(mem, offset, &mut entries)
+            .map_err(|_| Error::InvalidMacTable)?;
+        let entries = u32::from_le_bytes(entries) as usize;
+        let len = entries
+            .checked_mul(MAC_ADDR_LEN)
+            .filter(|len| offset + 4 + len <= self.len)
+            .ok_or(Error::InvalidMacTable)?;
+        if entries > MAC_TABLE_ENTRIES {
+            return Ok((None, offset + 4 + len));
+        }
+
+        let mut table = vec![0u8; len];
+        self.read_at(mem, offset + 4, &mut table)?;
+        let table = table
+            .chunks(MAC_ADDR_LEN)
+            .map(MacAddr::from_bytes_unchecked)
+            .collect();
+
+        Ok((Some(table), offset + 4 + len))
+    }
+}
+
+// Reads a hash key, made of its length followed by the key itself, from the
+// beginning of `data`.
+fn parse_hash_key(data: &[u8]) -> Result<Vec<u8>> {
+    let len = *data.first().ok_or(Error::InvalidCtlData)?;
+    if len > RSS_MAX_KEY_SIZE {
+        return Err(Error::InvalidRssConfig);
+    }
+    let len = usize::from(len);
+    data.get(1..len + 1)
+        .map(|key| key.to_vec())
+        .ok_or(Error::InvalidCtlData)
+}
+
 impl CtrlVirtio {
-    pub fn new(queue: Queue, queue_evt: EventFd) -> Self {
-        CtrlVirtio { queue_evt, queue }
+    /// Create the control queue handler. The RX mode, MAC and VLAN commands
+    /// are only supported when a filter is provided, the announcements can
+    /// only be acknowledged when the device status is provided, and RSS can
+    /// only be configured when its state is provided.
+    pub fn new(
+        queue: Queue,
+        queue_evt: EventFd,
+        rx_filter: Option<Arc<RwLock<RxFilter>>>,
+        status: Option<Arc<AtomicU16>>,
+        rss: Option<Arc<RwLock<Rss>>>,
+    ) -> Self {
+        CtrlVirtio {
+            queue_evt,
+            queue,
+            rx_filter,
+            status,
+            rss,
+        }
     }
 
-    fn process_mq(&self, mem: &GuestMemoryMmap, avail_desc: DescriptorChain) -> Result<()> {
-        let mq_desc = if avail_desc.has_next() {
-            avail_desc.next_descriptor().unwrap()
-        } else {
-            return Err(Error::NoQueuePairsNum);
+    fn process_announce(&self, cmd: u8) -> Result<()> {
+        let status = match &self.status {
+            Some(status) => status,
+            None => return Err(Error::InvalidCtlClass(VIRTIO_NET_CTRL_ANNOUNCE as u8)),
         };
-        let queue_pairs = mem
-            .read_obj::<u16>(mq_desc.addr)
-            .map_err(Error::GuestMemory)?;
+        if u32::from(cmd) != VIRTIO_NET_CTRL_ANNOUNCE_ACK {
+            return Err(Error::InvalidCtlCmd(cmd));
+        }
+        status.fetch_and(!(VIRTIO_NET_S_ANNOUNCE as u16), Ordering::AcqRel);
+
+        Ok(())
+    }
+
+    fn process_mq(&self, cmd: u8, data: &[u8]) -> Result<()> {
+        let cmd_u32 = u32::from(cmd);
+        if cmd_u32 != VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET {
+            let mut rss = match &self.rss {
+                Some(rss) => rss.write().unwrap(),
+                None => return Err(Error::InvalidCtlCmd(cmd)),
+            };
+            return match cmd_u32 {
+                VIRTIO_NET_CTRL_MQ_RSS_CONFIG => self.process_rss_config(&mut rss, data),
+                VIRTIO_NET_CTRL_MQ_HASH_CONFIG => self.process_hash_config(&mut rss, data),
+                _ => Err(Error::InvalidCtlCmd(cmd)),
+            };
+        }
+        if data.len() < 2 {
+            return Err(Error::InvalidCtlData);
+        }
+        let queue_pairs = u16::from_le_bytes([data[0], data[1]]);
         if (queue_pairs < VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN as u16)
             || (queue_pairs > VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX as u16)
         {
             return Err(Error::InvalidQueuePairsNum);
         }
-        let status_desc = if mq_desc.has_next() {
-            mq_desc.next_descriptor().unwrap()
-        } else {
-            return Err(Error::NoQueuePairsNum);
+
+        Ok(())
+    }
+
+    // The command is made of the hash types, the indirection table mask, the
+    // unclassified queue, the indirection table, the number of transmit
+    // queues the driver uses, and the hash key.
+    fn process_rss_config(&self, rss: &mut Rss, data: &[u8]) -> Result<()> {
+        if data.len() < 8 {
+            return Err(Error::InvalidCtlData);
+        }
+        let hash_types = u32::from_le_bytes(data[..4].try_into().unwrap());
+        let table_len = usize::from(u16::from_le_bytes([data[4], data[5]])) + 1;
+        let unclassified_queue = u16::from_le_bytes([data[6], data[7]]);
+        if table_len > usize::from(RSS_MAX_INDIRECTION_TABLE_LEN) {
+            return Err(Error::InvalidRssConfig);
+        }
+
+        let data = &data[8..];
+        if data.len() < table_len * 2 + 2 {
+            return Err(Error::InvalidCtlData);
+        }
+        let table: Vec<u16> = data[..table_len * 2]
+            .chunks(2)
+            .map(|entry| u16::from_le_bytes([entry[0], entry[1]]))
+            .collect();
+        if table
+            .iter()
+            .chain(std::iter::once(&unclassified_queue))
+            .any(|queue| *queue >= VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX as u16)
+        {
+            return Err(Error::InvalidRssConfig);
+        }
+
+        let data = &data[table_len * 2..];
+        let max_tx_vq = u16::from_le_bytes([data[0], data[1]]);
+        if (max_tx_vq < VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN as u16)
+            || (max_tx_vq > VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX as u16)
+        {
+            return Err(Error::InvalidQueuePairsNum);
+        }
+        let key = parse_hash_key(&data[2..])?;
+
+        if !rss.set_rss(hash_types, key, table, unclassified_queue) {
+            return Err(Error::InvalidRssConfig);
+        }
+
+        Ok(())
+    }
+
+    // The command is made of the hash types, 8 reserved bytes, and the hash
+    // key.
+    fn process_hash_config(&self, rss: &mut Rss, data: &[u8]) -> Result<()> {
+        if data.len() < 12 {
+            return Err(Error::InvalidCtlData);
+        }
+        let hash_types = u32::from_le_bytes(data[..4].try_into().unwrap());
+        let key = parse_hash_key(&data[12..])?;
+
+        if !rss.set_hash_config(hash_types, key) {
+            return Err(Error::InvalidRssConfig);
+        }
+
+        Ok(())
+    }
+
+    fn process_rx_mode(&self, rx_filter: &mut RxFilter, cmd: u8, data: &[u8]) -> Result<()> {
+        let on = match data.first() {
+            Some(on) => *on != 0,
+            None => return Err(Error::InvalidCtlData),
         };
-        mem.write_obj::<u8>(0, status_desc.addr)
-            .map_err(Error::GuestMemory)?;
+        match u32::from(cmd) {
+            VIRTIO_NET_CTRL_RX_PROMISC => rx_filter.promisc = on,
+            VIRTIO_NET_CTRL_RX_ALLMULTI => rx_filter.allmulti = on,
+            VIRTIO_NET_CTRL_RX_ALLUNI => rx_filter.alluni = on,
+            VIRTIO_NET_CTRL_RX_NOMULTI => rx_filter.nomulti = on,
+            VIRTIO_NET_CTRL_RX_NOUNI => rx_filter.nouni = on,
+            VIRTIO_NET_CTRL_RX_NOBCAST => rx_filter.nobcast = on,
+            _ => return Err(Error::InvalidCtlCmd(cmd)),
+        }
 
         Ok(())
     }
 
-    pub fn process_cvq(&mut self, mem: &GuestMemoryMmap) -> Result<()> {
-        let mut used_desc_heads = [(0, 0); QUEUE_SIZE];
-        let mut used_count = 0;
-        if let Some(avail_desc) = self.queue.iter(&mem).next() {
-            used_desc_heads[used_count] = (avail_desc.index, avail_desc.len);
-            used_count += 1;
-            let ctrl_hdr = mem
-                .read_obj::<u16>(avail_desc.addr)
-                .map_err(Error::GuestMemory)?;
-            let ctrl_hdr_v = ctrl_hdr.as_slice();
-            let class = ctrl_hdr_v[0];
-            let cmd = ctrl_hdr_v[1];
-            match u32::from(class) {
-                VIRTIO_NET_CTRL_MQ => {
-                    if u32::from(cmd) != VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET {
-                        return Err(Error::InvalidCtlCmd);
-                    }
-                    if let Err(_e) = self.process_mq(&mem, avail_desc) {
-                        return Err(Error::FailedProcessMQ);
-                    }
+    fn process_mac(
+        &self,
+        rx_filter: &mut RxFilter,
+        mem: &GuestMemoryMmap,
+        cmd: &CtrlCmd,
+    ) -> Result<()> {
+        let data = &cmd.data[2..];
+        match u32::from(cmd.data[1]) {
+            VIRTIO_NET_CTRL_MAC_TABLE_SET => {
+                // The tables are read from guest memory rather than from the
+                // gathered data, since they may go beyond MAX_CTRL_CMD_SIZE.
+                let (unicast, offset) = cmd.read_mac_table(mem, 2)?;
+                let (multicast, _) = cmd.read_mac_table(mem, offset)?;
+                rx_filter.set_mac_tables(unicast, multicast);
+            }
+            VIRTIO_NET_CTRL_MAC_ADDR_SET => {
+                if data.len() < MAC_ADDR_LEN {
+                    return Err(Error::InvalidCtlData);
                 }
-                _ => return Err(Error::InvalidCtlClass),
+                rx_filter.set_mac(MacAddr::from_bytes_unchecked(&data[..MAC_ADDR_LEN]));
             }
-        } else {
-            return Err(Error::InvalidDesc);
+            _ => return Err(Error::InvalidCtlCmd(cmd.data[1])),
+        }
+
+        Ok(())
+    }
+
+    fn process_vlan(&self, rx_filter: &mut RxFilter, cmd: u8, data: &[u8]) -> Result<()> {
+        if data.len() < 2 {
+            return Err(Error::InvalidCtlData);
+        }
+        let vid = u16::from_le_bytes([data[0], data[1]]);
+        let valid = match u32::from(cmd) {
+            VIRTIO_NET_CTRL_VLAN_ADD => rx_filter.add_vlan(vid),
+            VIRTIO_NET_CTRL_VLAN_DEL => rx_filter.del_vlan(vid),
+            _ => return Err(Error::InvalidCtlCmd(cmd)),
+        };
+        if !valid {
+            return Err(Error::InvalidVlanId(vid));
+        }
+
+        Ok(())
+    }
+
+    fn process_cmd(&self, mem: &GuestMemoryMmap, cmd: &CtrlCmd) -> Result<()> {
+        if cmd.data.len() < 2 {
+            return Err(Error::InvalidCtlData);
+        }
+        let (class, command) = (u32::from(cmd.data[0]), cmd.data[1]);
+        // Only the MAC filter tables can legitimately go beyond the gathered
+        // data.
+        let mac_table =
+            class == VIRTIO_NET_CTRL_MAC && u32::from(command) == VIRTIO_NET_CTRL_MAC_TABLE_SET;
+        if cmd.len > MAX_CTRL_CMD_SIZE && !mac_table {
+            return Err(Error::CtlCmdTooLarge(cmd.len));
+        }
+        let data = &cmd.data[2..];
+        if class == VIRTIO_NET_CTRL_MQ {
+            return self.process_mq(command, data);
         }
-        for &(desc_index, len) in &used_desc_heads[..used_count] {
-            self.queue.add_used(&mem, desc_index, len);
+        if class == VIRTIO_NET_CTRL_ANNOUNCE {
+            return self.process_announce(command);
+        }
+
+        let mut rx_filter = match &self.rx_filter {
+            Some(rx_filter) => rx_filter.write().unwrap(),
+            None => return Err(Error::InvalidCtlClass(class as u8)),
+        };
+        match class {
+            VIRTIO_NET_CTRL_RX => self.process_rx_mode(&mut rx_filter, command, data),
+            VIRTIO_NET_CTRL_MAC => self.process_mac(&mut rx_filter, mem, cmd),
+            VIRTIO_NET_CTRL_VLAN => self.process_vlan(&mut rx_filter, command, data),
+            _ => Err(Error::InvalidCtlClass(class as u8)),
+        }
+    }
+
+    /// Process the available commands, completing each of them with a
+    /// status so that a malformed command can't stall the driver.
+    pub fn process_cvq(&mut self, mem: &GuestMemoryMmap) -> Result<()> {
+        while let Some(avail_desc) = self.queue.iter(&mem).next() {
+            let head_index = avail_desc.index;
+            let mut cmd = match CtrlCmd::new(avail_desc) {
+                Ok(cmd) => cmd,
+                Err(e) => {
+                    // Without a status descriptor, the command can only be
+                    // returned as is.
+                    error!("Invalid control queue command: {:?}", e);
+                    self.queue.add_used(&mem, head_index, 0);
+                    self.queue.update_avail_event(&mem);
+                    continue;
+                }
+            };
+            let status = if let Err(e) = cmd.gather(mem).and_then(|_| self.process_cmd(mem, &cmd)) {
+                error!("Failed to process control queue command: {:?}", e);
+                VIRTIO_NET_ERR
+            } else {
+                VIRTIO_NET_OK
+            };
+            let len = match mem.write_obj::<u8>(status as u8, cmd.status_addr) {
+                Ok(()) => 1,
+                Err(e) => {
+                    error!("Failed to write control queue status: {:?}", e);
+                    0
+                }
+            };
+
+            self.queue.add_used(&mem, head_index, len);
             self.queue.update_avail_event(&mem);
         }
 
@@ -224,6 +567,15 @@ pub fn build_net_config_space(
     build_net_config_space_with_mq(&mut config, num_queues, &mut avail_features);
 }
 
+/// Advertise receive-side scaling and hash reporting, the frames being hashed
+/// by the device.
+pub fn build_net_config_space_with_rss(config: &mut VirtioNetConfig, avail_features: &mut u64) {
+    config.rss_max_key_size = RSS_MAX_KEY_SIZE;
+    config.rss_max_indirection_table_length = RSS_MAX_INDIRECTION_TABLE_LEN;
+    config.supported_hash_types = RSS_SUPPORTED_HASH_TYPES;
+    *avail_features |= 1 << VIRTIO_NET_F_RSS | 1 << VIRTIO_NET_F_HASH_REPORT;
+}
+
 pub fn build_net_config_space_with_mq(
     config: &mut VirtioNetConfig,
     num_queues: usize,
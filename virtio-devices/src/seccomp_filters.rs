@@ -5,18 +5,25 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use seccomp::{
-    allow_syscall, BpfProgram, Error, SeccompAction, SeccompError, SeccompFilter, SyscallRuleSet,
+    allow_syscall, allow_syscall_if, BpfProgram, Error, SeccompAction, SeccompCmpArgLen as ArgLen,
+    SeccompCmpOp::Eq, SeccompCondition as Cond, SeccompError, SeccompFilter, SeccompRule,
+    SyscallRuleSet,
 };
 use std::convert::TryInto;
 
+// See include/uapi/asm-generic/ioctls.h in the kernel code.
+const FIONBIO: u64 = 0x5421;
+
 pub enum Thread {
     VirtioBalloon,
     VirtioBlk,
+    VirtioBlkNbd,
     VirtioConsole,
     VirtioIommu,
     VirtioMem,
     VirtioNet,
     VirtioNetCtl,
+    VirtioNetUser,
     VirtioPmem,
     VirtioRng,
     VirtioVhostBlk,
@@ -69,6 +76,10 @@ fn virtio_blk_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         // Use a hard-code number instead.
         allow_syscall(46),
         allow_syscall(libc::SYS_futex),
+        allow_syscall(libc::SYS_io_destroy),
+        allow_syscall(libc::SYS_io_getevents),
+        allow_syscall(libc::SYS_io_setup),
+        allow_syscall(libc::SYS_io_submit),
         allow_syscall(libc::SYS_lseek),
         allow_syscall(libc::SYS_madvise),
         allow_syscall(libc::SYS_mmap),
@@ -85,6 +96,43 @@ fn virtio_blk_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
     ])
 }
 
+fn create_virtio_blk_nbd_socket_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
+    Ok(vec![
+        SeccompRule::new(
+            vec![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_UNIX as u64)?],
+            SeccompAction::Allow,
+        ),
+        SeccompRule::new(
+            vec![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_INET as u64)?],
+            SeccompAction::Allow,
+        ),
+        SeccompRule::new(
+            vec![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_INET6 as u64)?],
+            SeccompAction::Allow,
+        ),
+    ])
+}
+
+// The virtio_blk thread of a disk exported by an NBD server also needs to
+// reconnect to the server, waiting between attempts.
+fn virtio_blk_nbd_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
+    let mut rules = virtio_blk_thread_rules()?;
+    rules.extend(vec![
+        allow_syscall(libc::SYS_clock_nanosleep),
+        allow_syscall(libc::SYS_connect),
+        allow_syscall(libc::SYS_nanosleep),
+        allow_syscall(libc::SYS_recvfrom),
+        allow_syscall(libc::SYS_sendto),
+        allow_syscall(libc::SYS_setsockopt),
+        allow_syscall(libc::SYS_shutdown),
+        allow_syscall_if(
+            libc::SYS_socket,
+            create_virtio_blk_nbd_socket_seccomp_rule()?,
+        ),
+    ]);
+    Ok(rules)
+}
+
 fn virtio_console_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
     Ok(vec![
         allow_syscall(libc::SYS_brk),
@@ -185,6 +233,48 @@ fn virtio_net_ctl_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
     ])
 }
 
+fn create_virtio_net_user_ioctl_seccomp_rule() -> Result<Vec<SeccompRule>, Error> {
+    Ok(vec![SeccompRule::new(
+        vec![Cond::new(1, ArgLen::DWORD, Eq, FIONBIO)?],
+        SeccompAction::Allow,
+    )])
+}
+
+fn virtio_net_user_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
+    Ok(vec![
+        allow_syscall(libc::SYS_accept4),
+        allow_syscall(libc::SYS_bind),
+        allow_syscall(libc::SYS_brk),
+        allow_syscall(libc::SYS_close),
+        allow_syscall(libc::SYS_connect),
+        allow_syscall(libc::SYS_epoll_create1),
+        allow_syscall(libc::SYS_epoll_ctl),
+        allow_syscall(libc::SYS_epoll_pwait),
+        #[cfg(target_arch = "x86_64")]
+        allow_syscall(libc::SYS_epoll_wait),
+        allow_syscall(libc::SYS_exit),
+        allow_syscall(libc::SYS_futex),
+        allow_syscall(libc::SYS_getrandom),
+        allow_syscall(libc::SYS_getsockopt),
+        allow_syscall_if(
+            libc::SYS_ioctl,
+            create_virtio_net_user_ioctl_seccomp_rule()?,
+        ),
+        allow_syscall(libc::SYS_madvise),
+        allow_syscall(libc::SYS_mmap),
+        allow_syscall(libc::SYS_mremap),
+        allow_syscall(libc::SYS_munmap),
+        allow_syscall(libc::SYS_read),
+        allow_syscall(libc::SYS_recvfrom),
+        allow_syscall(libc::SYS_rt_sigprocmask),
+        allow_syscall(libc::SYS_sendto),
+        allow_syscall(libc::SYS_shutdown),
+        allow_syscall(libc::SYS_sigaltstack),
+        allow_syscall(libc::SYS_socket),
+        allow_syscall(libc::SYS_write),
+    ])
+}
+
 fn virtio_pmem_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
     Ok(vec![
         allow_syscall(libc::SYS_brk),
@@ -327,11 +417,13 @@ fn get_seccomp_filter_trap(thread_type: Thread) -> Result<SeccompFilter, Error>
     let rules = match thread_type {
         Thread::VirtioBalloon => virtio_balloon_thread_rules()?,
         Thread::VirtioBlk => virtio_blk_thread_rules()?,
+        Thread::VirtioBlkNbd => virtio_blk_nbd_thread_rules()?,
         Thread::VirtioConsole => virtio_console_thread_rules()?,
         Thread::VirtioIommu => virtio_iommu_thread_rules()?,
         Thread::VirtioMem => virtio_mem_thread_rules()?,
         Thread::VirtioNet => virtio_net_thread_rules()?,
         Thread::VirtioNetCtl => virtio_net_ctl_thread_rules()?,
+        Thread::VirtioNetUser => virtio_net_user_thread_rules()?,
         Thread::VirtioPmem => virtio_pmem_thread_rules()?,
         Thread::VirtioRng => virtio_rng_thread_rules()?,
         Thread::VirtioVhostBlk => virtio_vhost_blk_thread_rules()?,
@@ -350,11 +442,13 @@ fn get_seccomp_filter_log(thread_type: Thread) -> Result<SeccompFilter, Error> {
     let rules = match thread_type {
         Thread::VirtioBalloon => virtio_balloon_thread_rules()?,
         Thread::VirtioBlk => virtio_blk_thread_rules()?,
+        Thread::VirtioBlkNbd => virtio_blk_nbd_thread_rules()?,
         Thread::VirtioConsole => virtio_console_thread_rules()?,
         Thread::VirtioIommu => virtio_iommu_thread_rules()?,
         Thread::VirtioMem => virtio_mem_thread_rules()?,
         Thread::VirtioNet => virtio_net_thread_rules()?,
         Thread::VirtioNetCtl => virtio_net_ctl_thread_rules()?,
+        Thread::VirtioNetUser => virtio_net_user_thread_rules()?,
         Thread::VirtioPmem => virtio_pmem_thread_rules()?,
         Thread::VirtioRng => virtio_rng_thread_rules()?,
         Thread::VirtioVhostBlk => virtio_vhost_blk_thread_rules()?,
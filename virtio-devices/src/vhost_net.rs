@@ -0,0 +1,283 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Hands the queue pairs of a virtio-net device over to the vhost-net
+//! kernel driver, so that the frames are moved between the queues and the
+//! tap without going through userspace.
+
+use super::{Descriptor, Queue};
+use crate::{VirtioInterrupt, VirtioInterruptType};
+use net_util::Tap;
+use std::convert::TryInto;
+use std::sync::Arc;
+use vfio_ioctls::get_host_address_range;
+use vhost_rs::net::VhostNet;
+use vhost_rs::vhost_kern::net::Net;
+use vhost_rs::Error as VhostError;
+use vhost_rs::{VhostBackend, VhostUserMemoryRegionInfo, VringConfigData};
+use vm_memory::{
+    Address, GuestAddressSpace, GuestMemory, GuestMemoryAtomic, GuestMemoryMmap, GuestMemoryRegion,
+};
+use vmm_sys_util::eventfd::EventFd;
+
+// Index of the receive and transmit vrings of a vhost-net instance.
+const RX_VRING: usize = 0;
+const TX_VRING: usize = 1;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to open the vhost-net device.
+    Open(VhostError),
+    /// Set owner failed.
+    SetOwner(VhostError),
+    /// Get features failed.
+    GetFeatures(VhostError),
+    /// Set features failed.
+    SetFeatures(VhostError),
+    /// Set mem table failed.
+    SetMemTable(VhostError),
+    /// Set vring num failed.
+    SetVringNum(VhostError),
+    /// Set vring addr failed.
+    SetVringAddr(VhostError),
+    /// Set vring base failed.
+    SetVringBase(VhostError),
+    /// Set vring call failed.
+    SetVringCall(VhostError),
+    /// Set vring kick failed.
+    SetVringKick(VhostError),
+    /// Set backend failed.
+    SetBackend(VhostError),
+    /// Invalid descriptor table address.
+    DescriptorTableAddress,
+    /// Invalid used address.
+    UsedAddress,
+    /// Invalid available address.
+    AvailAddress,
+    /// The interrupt of the queue can't be triggered through an eventfd.
+    NoInterruptNotifier,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Queue pair whose frames are processed by the vhost-net kernel driver.
+/// The processing stops when it is dropped.
+///
+/// The frames never reach the device model, which is why `Net::set_link_up()`
+/// stops the queue pair for the link to be down.
+pub struct VhostNetQueuePair {
+    vhost: Net<GuestMemoryAtomic<GuestMemoryMmap>>,
+    tap: Tap,
+}
+
+impl VhostNetQueuePair {
+    /// Hand the receive and transmit queues, in this order, over to the
+    /// kernel, which exchanges their frames with the tap once started. The
+    /// features not handled by the kernel are filtered out of
+    /// `acked_features`, as they only matter to the guest and the tap.
+    pub fn new(
+        tap: Tap,
+        mem: &GuestMemoryAtomic<GuestMemoryMmap>,
+        queues: &[Queue],
+        queue_evts: &[EventFd],
+        virtio_interrupt: &Arc<dyn VirtioInterrupt>,
+        acked_features: u64,
+    ) -> Result<Self> {
+        let vhost = Net::new(mem.clone()).map_err(Error::Open)?;
+        let mut vhost_net = VhostNetQueuePair { vhost, tap };
+
+        vhost_net.vhost.set_owner().map_err(Error::SetOwner)?;
+        let features = vhost_net.vhost.get_features().map_err(Error::GetFeatures)?;
+        vhost_net
+            .vhost
+            .set_features(acked_features & features)
+            .map_err(Error::SetFeatures)?;
+
+        let mem = mem.memory();
+        vhost_net.update_memory(&mem)?;
+
+        for (index, (queue, queue_evt)) in queues.iter().zip(queue_evts.iter()).enumerate() {
+            vhost_net.setup_vring(index, &mem, queue, queue_evt, virtio_interrupt)?;
+        }
+
+        Ok(vhost_net)
+    }
+
+    /// Provide the kernel with the current memory table of the guest.
+    pub fn update_memory(&mut self, mem: &GuestMemoryMmap) -> Result<()> {
+        let mut regions: Vec<VhostUserMemoryRegionInfo> = Vec::new();
+        mem.with_regions_mut(|_, region| {
+            // The kernel maps the regions through the addresses of the
+            // current process, their backing file is irrelevant.
+            regions.push(VhostUserMemoryRegionInfo {
+                guest_phys_addr: region.start_addr().raw_value(),
+                memory_size: region.len() as u64,
+                userspace_addr: region.as_ptr() as u64,
+                mmap_offset: 0,
+                mmap_handle: -1,
+            });
+            Ok::<(), ()>(())
+        })
+        .unwrap();
+
+        self.vhost
+            .set_mem_table(regions.as_slice())
+            .map_err(Error::SetMemTable)
+    }
+
+    fn setup_vring(
+        &mut self,
+        index: usize,
+        mem: &GuestMemoryMmap,
+        queue: &Queue,
+        queue_evt: &EventFd,
+        virtio_interrupt: &Arc<dyn VirtioInterrupt>,
+    ) -> Result<()> {
+        let actual_size: usize = queue.actual_size().try_into().unwrap();
+
+        self.vhost
+            .set_vring_num(index, queue.actual_size())
+            .map_err(Error::SetVringNum)?;
+
+        let config_data = VringConfigData {
+            queue_max_size: queue.get_max_size(),
+            queue_size: queue.actual_size(),
+            flags: 0u32,
+            desc_table_addr: get_host_address_range(
+                mem,
+                queue.desc_table,
+                actual_size * std::mem::size_of::<Descriptor>(),
+            )
+            .ok_or(Error::DescriptorTableAddress)? as u64,
+            // The used ring is {flags: u16; idx: u16; virtq_used_elem [{id: u16, len: u16}; actual_size]},
+            // i.e. 4 + (4 + 4) * actual_size.
+            used_ring_addr: get_host_address_range(mem, queue.used_ring, 4 + actual_size * 8)
+                .ok_or(Error::UsedAddress)? as u64,
+            // The available ring is {flags: u16; idx: u16; elem [u16; actual_size]},
+            // i.e. 4 + (2) * actual_size.
+            avail_ring_addr: get_host_address_range(mem, queue.avail_ring, 4 + actual_size * 2)
+                .ok_or(Error::AvailAddress)? as u64,
+            log_addr: None,
+        };
+        self.vhost
+            .set_vring_addr(index, &config_data)
+            .map_err(Error::SetVringAddr)?;
+
+        // Start from the next descriptor the device would have processed.
+        self.vhost
+            .set_vring_base(index, queue.next_avail.0)
+            .map_err(Error::SetVringBase)?;
+
+        // The kernel signals the used buffers through the eventfd directly
+        // injecting the interrupt, there is no thread to relay it otherwise.
+        let call_evt = virtio_interrupt
+            .notifier(&VirtioInterruptType::Queue, Some(queue))
+            .ok_or(Error::NoInterruptNotifier)?;
+        self.vhost
+            .set_vring_call(index, call_evt)
+            .map_err(Error::SetVringCall)?;
+        self.vhost
+            .set_vring_kick(index, queue_evt)
+            .map_err(Error::SetVringKick)?;
+
+        Ok(())
+    }
+
+    fn set_backend(&mut self, start: bool) -> Result<()> {
+        let tap = if start {
+            Some(self.tap.get_file())
+        } else {
+            None
+        };
+        for index in &[RX_VRING, TX_VRING] {
+            self.vhost
+                .set_backend(*index, tap)
+                .map_err(Error::SetBackend)?;
+        }
+        Ok(())
+    }
+
+    /// Start exchanging the frames between the queues and the tap.
+    pub fn start(&mut self) -> Result<()> {
+        self.set_backend(true)
+    }
+
+    /// Stop processing the queues, until the queue pair is started again.
+    pub fn stop(&mut self) -> Result<()> {
+        self.set_backend(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{File, OpenOptions};
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixDatagram;
+    use vm_memory::GuestAddress;
+    use vm_virtio::queue::testing::VirtQueue as GuestQ;
+
+    struct NotifierVirtioInterrupt {
+        evt: EventFd,
+    }
+
+    impl VirtioInterrupt for NotifierVirtioInterrupt {
+        fn trigger(
+            &self,
+            _int_type: &VirtioInterruptType,
+            _queue: Option<&Queue>,
+        ) -> std::result::Result<(), std::io::Error> {
+            self.evt.write(1)
+        }
+
+        fn notifier(
+            &self,
+            _int_type: &VirtioInterruptType,
+            _queue: Option<&Queue>,
+        ) -> Option<&EventFd> {
+            Some(&self.evt)
+        }
+    }
+
+    #[test]
+    fn test_vhost_net_queue_pair() {
+        // vhost-net isn't available on every host running the tests.
+        if OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/vhost-net")
+            .is_err()
+        {
+            return;
+        }
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let rxq = GuestQ::new(GuestAddress(0), &mem, 16);
+        let txq = GuestQ::new(rxq.end(), &mem, 16);
+        let queues = vec![rxq.create_queue(), txq.create_queue()];
+        let queue_evts = vec![EventFd::new(0).unwrap(), EventFd::new(0).unwrap()];
+        let interrupt: Arc<dyn VirtioInterrupt> = Arc::new(NotifierVirtioInterrupt {
+            evt: EventFd::new(0).unwrap(),
+        });
+
+        // The queues are only handed over to the kernel, the tap is only
+        // used once the queue pair is started.
+        let (socket, _peer) = UnixDatagram::pair().unwrap();
+        let tap = Tap::from_socket(unsafe { File::from_raw_fd(socket.into_raw_fd()) });
+
+        let mut queue_pair = VhostNetQueuePair::new(
+            tap,
+            &GuestMemoryAtomic::new(mem.clone()),
+            &queues,
+            &queue_evts,
+            &interrupt,
+            0,
+        )
+        .unwrap();
+        queue_pair.update_memory(&mem).unwrap();
+        // A unix socket can't back a vhost-net instance.
+        assert!(queue_pair.start().is_err());
+        queue_pair.stop().unwrap();
+    }
+}
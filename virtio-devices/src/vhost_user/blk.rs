@@ -7,10 +7,13 @@ use super::vu_common_ctrl::*;
 use super::{Error, Result};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::VirtioInterrupt;
+use block_util::stats::SharedBlockStats;
 use block_util::VirtioBlockConfig;
 use libc::EFD_NONBLOCK;
 use seccomp::{SeccompAction, SeccompFilter};
+use std::collections::HashMap;
 use std::mem;
+use std::num::Wrapping;
 use std::os::unix::io::AsRawFd;
 use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -46,6 +49,7 @@ pub struct Blk {
     paused: Arc<AtomicBool>,
     paused_sync: Arc<Barrier>,
     seccomp_action: SeccompAction,
+    stats: Option<SharedBlockStats>,
 }
 
 impl Blk {
@@ -152,8 +156,15 @@ impl Blk {
             paused: Arc::new(AtomicBool::new(false)),
             paused_sync: Arc::new(Barrier::new(vu_cfg.num_queues + 1)),
             seccomp_action,
+            stats: None,
         })
     }
+
+    /// Report the statistics the backend records in a shared file through
+    /// the device counters.
+    pub fn set_stats(&mut self, stats: SharedBlockStats) {
+        self.stats = Some(stats);
+    }
 }
 
 impl Drop for Blk {
@@ -331,6 +342,14 @@ impl VirtioDevice for Blk {
     fn update_memory(&mut self, mem: &GuestMemoryMmap) -> std::result::Result<(), crate::Error> {
         update_mem_table(&mut self.vhost_user_blk, mem).map_err(crate::Error::VhostUserUpdateMemory)
     }
+
+    fn counters(&self) -> Option<HashMap<&'static str, Wrapping<u64>>> {
+        let stats = self.stats.as_ref()?;
+        let mut counters = HashMap::new();
+        stats.add_counters(&mut counters);
+
+        Some(counters)
+    }
 }
 
 virtio_pausable!(Blk);
@@ -11,7 +11,7 @@ use super::{Error, Result};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
 use crate::VirtioInterrupt;
 use libc::EFD_NONBLOCK;
-use net_util::MacAddr;
+use net_util::{MacAddr, NetOffloads};
 use seccomp::{SeccompAction, SeccompFilter};
 use std::os::unix::io::AsRawFd;
 use std::result;
@@ -53,28 +53,20 @@ pub struct Net {
 }
 
 impl Net {
-    /// Create a new vhost-user-net device
     /// Create a new vhost-user-net device
     pub fn new(
         id: String,
         mac_addr: MacAddr,
         vu_cfg: VhostUserConfig,
+        mtu: Option<u16>,
+        offloads: NetOffloads,
         seccomp_action: SeccompAction,
     ) -> Result<Net> {
         let mut vhost_user_net = Master::connect(&vu_cfg.socket, vu_cfg.num_queues as u64)
             .map_err(Error::VhostUserCreateMaster)?;
 
         // Filling device and vring features VMM supports.
-        let mut avail_features = 1 << virtio_net::VIRTIO_NET_F_GUEST_CSUM
-            | 1 << virtio_net::VIRTIO_NET_F_CSUM
-            | 1 << virtio_net::VIRTIO_NET_F_GUEST_TSO4
-            | 1 << virtio_net::VIRTIO_NET_F_GUEST_TSO6
-            | 1 << virtio_net::VIRTIO_NET_F_GUEST_ECN
-            | 1 << virtio_net::VIRTIO_NET_F_GUEST_UFO
-            | 1 << virtio_net::VIRTIO_NET_F_HOST_TSO4
-            | 1 << virtio_net::VIRTIO_NET_F_HOST_TSO6
-            | 1 << virtio_net::VIRTIO_NET_F_HOST_ECN
-            | 1 << virtio_net::VIRTIO_NET_F_HOST_UFO
+        let mut avail_features = offloads.virtio_features()
             | 1 << virtio_net::VIRTIO_NET_F_MRG_RXBUF
             | 1 << virtio_net::VIRTIO_F_NOTIFY_ON_EMPTY
             | 1 << virtio_net::VIRTIO_F_VERSION_1
@@ -136,6 +128,12 @@ impl Net {
             vu_cfg.num_queues,
             &mut avail_features,
         );
+        // The MTU is only reported to the driver, the backend being in
+        // charge of applying it to its own interface.
+        if let Some(mtu) = mtu {
+            config.mtu = mtu;
+            avail_features |= 1 << virtio_net::VIRTIO_NET_F_MTU;
+        }
 
         // Send set_vring_base here, since it could tell backends, like OVS + DPDK,
         // how many virt queues to be handled, which backend required to know at early stage.
@@ -263,7 +261,7 @@ impl VirtioDevice for Net {
                 mem: mem.clone(),
                 kill_evt: kill_evt.try_clone().unwrap(),
                 pause_evt: pause_evt.try_clone().unwrap(),
-                ctrl_q: CtrlVirtio::new(cvq_queue, cvq_queue_evt),
+                ctrl_q: CtrlVirtio::new(cvq_queue, cvq_queue_evt, None, None, None),
                 epoll_fd: 0,
             };
 
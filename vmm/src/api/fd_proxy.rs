@@ -0,0 +1,426 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Front of the HTTP server, accepting the connections on the API socket and
+//! forwarding their requests to the server. The HTTP server doesn't retrieve
+//! the ancillary data of its connections, so the file descriptors passed
+//! along a `vm.add-net` request with `SCM_RIGHTS` are received here, and
+//! given to the network device in place of the `fds` of the request.
+
+use crate::api::http::HTTP_ROOT;
+use crate::seccomp_filters::{get_seccomp_filter, Thread};
+use crate::{Error, Result};
+use seccomp::{SeccompAction, SeccompFilter};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use tempfile::TempDir;
+
+// Largest number of file descriptors the kernel passes in one message.
+const MAX_FDS: usize = 253;
+// Size of the control buffer, in u64 to keep it aligned for the headers,
+// fitting a header followed by MAX_FDS descriptors.
+const CMSG_BUF_LEN: usize = MAX_FDS * size_of::<RawFd>() / size_of::<u64>() + 4;
+
+// Largest headers and body of a forwarded message, in bytes.
+const MAX_HEAD_SIZE: usize = 64 << 10;
+const MAX_BODY_SIZE: usize = 1 << 20;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Receive bytes from the stream, appending the file descriptors passed
+// along them to `fds`.
+fn recv_with_fds(stream: &UnixStream, buf: &mut [u8], fds: &mut Vec<File>) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut cmsg_buf = [0u64; CMSG_BUF_LEN];
+    // Zeroing is a valid state for msghdr, the fields in use being set below.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = std::mem::size_of_val(&cmsg_buf) as _;
+
+    let ret = loop {
+        // The buffers outlive the call, and the return value is checked.
+        let ret = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if ret >= 0 {
+            break ret as usize;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    };
+
+    // The control messages were written by the kernel within the buffer,
+    // and each descriptor they carry is now owned by this process.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let count =
+                    ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / size_of::<RawFd>();
+                for i in 0..count {
+                    fds.push(File::from_raw_fd(std::ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(invalid_data("Too many file descriptors passed"));
+    }
+
+    Ok(ret)
+}
+
+// Splits the bytes read from a connection into HTTP messages.
+#[derive(Default)]
+struct MessageReader {
+    buf: Vec<u8>,
+}
+
+impl MessageReader {
+    // Read the start line and the headers of the next message, up to the
+    // empty line ending them. Returns None if the connection was closed
+    // between two messages.
+    fn read_head<F>(&mut self, read: &mut F) -> io::Result<Option<String>>
+    where
+        F: FnMut(&mut [u8]) -> io::Result<usize>,
+    {
+        loop {
+            if let Some(pos) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                let body = self.buf.split_off(pos + 4);
+                let head = std::mem::replace(&mut self.buf, body);
+                return String::from_utf8(head)
+                    .map(Some)
+                    .map_err(|_| invalid_data("Invalid HTTP headers"));
+            }
+            if self.buf.len() > MAX_HEAD_SIZE {
+                return Err(invalid_data("HTTP headers too large"));
+            }
+            if !self.fill(read)? {
+                if self.buf.is_empty() {
+                    return Ok(None);
+                }
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    // Read the body following the headers.
+    fn read_body<F>(&mut self, len: usize, read: &mut F) -> io::Result<Vec<u8>>
+    where
+        F: FnMut(&mut [u8]) -> io::Result<usize>,
+    {
+        if len > MAX_BODY_SIZE {
+            return Err(invalid_data("HTTP body too large"));
+        }
+        while self.buf.len() < len {
+            if !self.fill(read)? {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        let rest = self.buf.split_off(len);
+        Ok(std::mem::replace(&mut self.buf, rest))
+    }
+
+    fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    // Returns false at the end of the stream.
+    fn fill<F>(&mut self, read: &mut F) -> io::Result<bool>
+    where
+        F: FnMut(&mut [u8]) -> io::Result<usize>,
+    {
+        let mut chunk = [0u8; 4096];
+        let count = read(&mut chunk)?;
+        self.buf.extend_from_slice(&chunk[..count]);
+        Ok(count != 0)
+    }
+}
+
+// Value of a header, whose name is case insensitive.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.split("\r\n").skip(1).find_map(|line| {
+        let colon = line.find(':')?;
+        if line[..colon].trim().eq_ignore_ascii_case(name) {
+            Some(line[colon + 1..].trim())
+        } else {
+            None
+        }
+    })
+}
+
+fn content_length(head: &str) -> io::Result<usize> {
+    header(head, "Content-Length").map_or(Ok(0), |len| {
+        len.parse()
+            .map_err(|_| invalid_data("Invalid HTTP Content-Length"))
+    })
+}
+
+// Head of the request forwarded to the server, with the length of the body
+// actually forwarded. The client is told to send its body by the front, so
+// the server doesn't get any `Expect` header.
+fn forwarded_head(head: &str, body_len: usize) -> Vec<u8> {
+    let mut forwarded = head
+        .trim_end_matches("\r\n")
+        .split("\r\n")
+        .filter(|line| {
+            let name = line.split(':').next().unwrap_or("").trim();
+            !name.eq_ignore_ascii_case("Content-Length") && !name.eq_ignore_ascii_case("Expect")
+        })
+        .collect::<Vec<&str>>()
+        .join("\r\n");
+    if body_len > 0 {
+        forwarded.push_str(&format!("\r\nContent-Length: {}", body_len));
+    }
+    forwarded.push_str("\r\n\r\n");
+    forwarded.into_bytes()
+}
+
+fn is_add_net(head: &str) -> bool {
+    head.split_whitespace().nth(1) == Some(&format!("{}/vm.add-net", HTTP_ROOT))
+}
+
+fn is_success(head: &str) -> bool {
+    let code = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok());
+    matches!(code, Some(200..=299))
+}
+
+// Network device configuration of the body using the given file descriptors,
+// unless it isn't a JSON object, the server then reporting the error.
+fn body_with_fds(body: &[u8], fds: &[File]) -> Option<Vec<u8>> {
+    let mut config: Value = serde_json::from_slice(body).ok()?;
+    config.as_object_mut()?.insert(
+        "fds".to_owned(),
+        fds.iter().map(|fd| Value::from(fd.as_raw_fd())).collect(),
+    );
+    serde_json::to_vec(&config).ok()
+}
+
+// Forward the requests of a client to the server, and the responses back,
+// until the client closes the connection.
+fn handle_connection(client: UnixStream, server_path: &Path) -> io::Result<()> {
+    let mut server = UnixStream::connect(server_path)?;
+    let mut client_reader = MessageReader::default();
+    let mut server_reader = MessageReader::default();
+    let mut fds = Vec::new();
+
+    loop {
+        let mut recv = |buf: &mut [u8]| recv_with_fds(&client, buf, &mut fds);
+        let head = match client_reader.read_head(&mut recv)? {
+            Some(head) => head,
+            None => return Ok(()),
+        };
+        let len = content_length(&head)?;
+        let expect_continue =
+            matches!(header(&head, "Expect"), Some(v) if v.eq_ignore_ascii_case("100-continue"));
+        if expect_continue && client_reader.buffered() < len {
+            (&client).write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        }
+        let mut body = client_reader.read_body(len, &mut recv)?;
+
+        // The descriptors passed along any other request are closed.
+        let passed_fds = std::mem::take(&mut fds);
+        let mut fds_in_use = false;
+        if !passed_fds.is_empty() && is_add_net(&head) {
+            if let Some(new_body) = body_with_fds(&body, &passed_fds) {
+                body = new_body;
+                fds_in_use = true;
+            }
+        }
+
+        server.write_all(&forwarded_head(&head, body.len()))?;
+        server.write_all(&body)?;
+
+        let mut read = |buf: &mut [u8]| server.read(buf);
+        let response_head = server_reader
+            .read_head(&mut read)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let response_len = content_length(&response_head)?;
+        let response_body = server_reader.read_body(response_len, &mut read)?;
+        (&client).write_all(response_head.as_bytes())?;
+        (&client).write_all(&response_body)?;
+
+        // The network device keeps the descriptors it was created with, so
+        // that it can be created again on reboot.
+        if fds_in_use && is_success(&response_head) {
+            for fd in passed_fds {
+                let _ = fd.into_raw_fd();
+            }
+        }
+    }
+}
+
+/// Accept the connections on the API socket, forwarding them to the HTTP
+/// server listening on `server_path`. The directory holding the socket of
+/// the HTTP server is kept as long as the thread runs.
+pub fn start_fd_proxy_thread(
+    path: &str,
+    server_dir: TempDir,
+    server_path: PathBuf,
+    seccomp_action: &SeccompAction,
+) -> Result<thread::JoinHandle<Result<()>>> {
+    std::fs::remove_file(path).unwrap_or_default();
+    let listener = UnixListener::bind(path).map_err(Error::Bind)?;
+
+    // The connections are handled by API threads as well.
+    let api_seccomp_filter =
+        get_seccomp_filter(seccomp_action, Thread::Api).map_err(Error::CreateSeccompFilter)?;
+
+    thread::Builder::new()
+        .name("http-proxy".to_string())
+        .spawn(move || {
+            let _server_dir = server_dir;
+
+            SeccompFilter::apply(api_seccomp_filter).map_err(Error::ApplySeccompFilter)?;
+
+            for client in listener.incoming() {
+                let client = match client {
+                    Ok(client) => client,
+                    Err(e) => {
+                        error!("Failed to accept API connection: {}", e);
+                        continue;
+                    }
+                };
+                let server_path = server_path.clone();
+                if let Err(e) =
+                    thread::Builder::new()
+                        .name("http-conn".to_string())
+                        .spawn(move || {
+                            if let Err(e) = handle_connection(client, &server_path) {
+                                error!("Failed to forward API connection: {}", e);
+                            }
+                        })
+                {
+                    error!("Failed to spawn API connection thread: {}", e);
+                }
+            }
+
+            Ok(())
+        })
+        .map_err(Error::HttpThreadSpawn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Send the bytes along the file descriptors, as a client would.
+    fn send_with_fds(stream: &UnixStream, buf: &[u8], fds: &[RawFd]) {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut cmsg_buf = [0u64; CMSG_BUF_LEN];
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        unsafe {
+            let fds_len = std::mem::size_of_val(fds) as u32;
+            msg.msg_controllen = libc::CMSG_SPACE(fds_len) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr(),
+                libc::CMSG_DATA(cmsg) as *mut RawFd,
+                fds.len(),
+            );
+            assert_eq!(
+                libc::sendmsg(stream.as_raw_fd(), &msg, 0),
+                buf.len() as isize
+            );
+        }
+    }
+
+    #[test]
+    fn test_forwarded_head() {
+        let head = "PUT /api/v1/vm.add-net HTTP/1.1\r\nHost: localhost\r\n\
+                    content-length: 12\r\nExpect: 100-continue\r\n\r\n";
+        assert_eq!(content_length(head).unwrap(), 12);
+        assert_eq!(header(head, "expect"), Some("100-continue"));
+        assert!(is_add_net(head));
+        assert_eq!(
+            forwarded_head(head, 20),
+            b"PUT /api/v1/vm.add-net HTTP/1.1\r\nHost: localhost\r\nContent-Length: 20\r\n\r\n"
+                .to_vec()
+        );
+        assert!(is_success("HTTP/1.1 204 No Content\r\n\r\n"));
+        assert!(!is_success("HTTP/1.1 500 Internal Server Error\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_add_net_fds() {
+        let dir = tempfile::tempdir().unwrap();
+        let server_path = dir.path().join("http.sock");
+        let listener = UnixListener::bind(&server_path).unwrap();
+
+        // The server answers with the body it got.
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = MessageReader::default();
+            let mut read = |buf: &mut [u8]| (&stream).read(buf);
+            let head = reader.read_head(&mut read).unwrap().unwrap();
+            assert_eq!(header(&head, "Expect"), None);
+            let len = content_length(&head).unwrap();
+            let body = reader.read_body(len, &mut read).unwrap();
+            (&stream)
+                .write_all(format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", len).as_bytes())
+                .unwrap();
+            (&stream).write_all(&body).unwrap();
+            assert!(reader.read_head(&mut read).unwrap().is_none());
+        });
+
+        let (client, front) = UnixStream::pair().unwrap();
+        let front = thread::spawn(move || handle_connection(front, &server_path));
+
+        let tap = File::open("/dev/null").unwrap();
+        let body = br#"{"num_queues":2,"fds":[3]}"#;
+        let request = format!(
+            "PUT /api/v1/vm.add-net HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        );
+        send_with_fds(&client, request.as_bytes(), &[tap.as_raw_fd()]);
+        (&client).write_all(body).unwrap();
+
+        let mut reader = MessageReader::default();
+        let mut read = |buf: &mut [u8]| (&client).read(buf);
+        let head = reader.read_head(&mut read).unwrap().unwrap();
+        let len = content_length(&head).unwrap();
+        let config: Value =
+            serde_json::from_slice(&reader.read_body(len, &mut read).unwrap()).unwrap();
+        let fd = config["fds"][0].as_i64().unwrap() as RawFd;
+        assert_ne!(fd, tap.as_raw_fd());
+        assert_eq!(config["num_queues"], 2);
+
+        // The descriptor received for the device is kept open.
+        assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0);
+        unsafe { libc::close(fd) };
+
+        client.shutdown(std::net::Shutdown::Both).unwrap();
+        front.join().unwrap().unwrap();
+        server.join().unwrap();
+    }
+}
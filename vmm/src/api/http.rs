@@ -3,6 +3,7 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use crate::api::fd_proxy::start_fd_proxy_thread;
 use crate::api::http_endpoint::{VmActionHandler, VmCreate, VmInfo, VmmPing, VmmShutdown};
 use crate::api::{ApiError, ApiRequest, VmAction};
 use crate::seccomp_filters::{get_seccomp_filter, Thread};
@@ -11,8 +12,7 @@ use micro_http::{Body, HttpServer, MediaType, Method, Request, Response, StatusC
 use seccomp::{SeccompAction, SeccompFilter};
 use serde_json::Error as SerdeError;
 use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{channel, Sender};
 use std::sync::Arc;
 use std::thread;
 use vmm_sys_util::eventfd::EventFd;
@@ -68,6 +68,21 @@ pub enum HttpError {
     /// Could not resize a VM
     VmResize(ApiError),
 
+    /// Could not resize a disk
+    VmResizeDisk(ApiError),
+
+    /// Could not stream a disk
+    VmStreamDisk(ApiError),
+
+    /// Could not set the link state of a network device
+    VmSetLink(ApiError),
+
+    /// Could not start capturing the frames of a network device
+    VmStartNetCapture(ApiError),
+
+    /// Could not stop capturing the frames of a network device
+    VmStopNetCapture(ApiError),
+
     /// Could not add a device to a VM
     VmAddDevice(ApiError),
 
@@ -105,7 +120,7 @@ impl From<serde_json::Error> for HttpError {
     }
 }
 
-const HTTP_ROOT: &str = "/api/v1";
+pub const HTTP_ROOT: &str = "/api/v1";
 
 pub fn error_response(error: HttpError, status: StatusCode) -> Response {
     let mut response = Response::new(Version::Http11, status);
@@ -204,10 +219,15 @@ lazy_static! {
         r.routes.insert(endpoint!("/vm.reboot"), Box::new(VmActionHandler::new(VmAction::Reboot)));
         r.routes.insert(endpoint!("/vm.remove-device"), Box::new(VmActionHandler::new(VmAction::RemoveDevice(Arc::default()))));
         r.routes.insert(endpoint!("/vm.resize"), Box::new(VmActionHandler::new(VmAction::Resize(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.resize-disk"), Box::new(VmActionHandler::new(VmAction::ResizeDisk(Arc::default()))));
         r.routes.insert(endpoint!("/vm.restore"), Box::new(VmActionHandler::new(VmAction::Restore(Arc::default()))));
         r.routes.insert(endpoint!("/vm.resume"), Box::new(VmActionHandler::new(VmAction::Resume)));
+        r.routes.insert(endpoint!("/vm.set-link"), Box::new(VmActionHandler::new(VmAction::SetLink(Arc::default()))));
         r.routes.insert(endpoint!("/vm.shutdown"), Box::new(VmActionHandler::new(VmAction::Shutdown)));
         r.routes.insert(endpoint!("/vm.snapshot"), Box::new(VmActionHandler::new(VmAction::Snapshot(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.start-net-capture"), Box::new(VmActionHandler::new(VmAction::StartNetCapture(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.stop-net-capture"), Box::new(VmActionHandler::new(VmAction::StopNetCapture(Arc::default()))));
+        r.routes.insert(endpoint!("/vm.stream-disk"), Box::new(VmActionHandler::new(VmAction::StreamDisk(Arc::default()))));
         r.routes.insert(endpoint!("/vmm.ping"), Box::new(VmmPing {}));
         r.routes.insert(endpoint!("/vmm.shutdown"), Box::new(VmmShutdown {}));
 
@@ -243,14 +263,18 @@ pub fn start_http_thread(
     api_sender: Sender<ApiRequest>,
     seccomp_action: &SeccompAction,
 ) -> Result<thread::JoinHandle<Result<()>>> {
-    std::fs::remove_file(path).unwrap_or_default();
-    let socket_path = PathBuf::from(path);
+    // The connections on the API socket are accepted by the front of the
+    // HTTP server, which listens on a socket only reachable by this process.
+    let server_dir = tempfile::tempdir().map_err(Error::Bind)?;
+    let socket_path = server_dir.path().join("http.sock");
+    let server_path = socket_path.clone();
+    let (ready_sender, ready_receiver) = channel();
 
     // Retrieve seccomp filter for API thread
     let api_seccomp_filter =
         get_seccomp_filter(seccomp_action, Thread::Api).map_err(Error::CreateSeccompFilter)?;
 
-    thread::Builder::new()
+    let thread = thread::Builder::new()
         .name("http-server".to_string())
         .spawn(move || {
             // Apply seccomp filter for API thread.
@@ -258,6 +282,7 @@ pub fn start_http_thread(
 
             let mut server = HttpServer::new(socket_path).unwrap();
             server.start_server().unwrap();
+            ready_sender.send(()).unwrap();
             loop {
                 match server.requests() {
                     Ok(request_vec) => {
@@ -281,5 +306,10 @@ pub fn start_http_thread(
                 }
             }
         })
-        .map_err(Error::HttpThreadSpawn)
+        .map_err(Error::HttpThreadSpawn)?;
+
+    ready_receiver.recv().map_err(Error::HttpServerStart)?;
+    start_fd_proxy_thread(path, server_dir, server_path, seccomp_action)?;
+
+    Ok(thread)
 }
@@ -7,8 +7,9 @@ use crate::api::http::{error_response, EndpointHandler, HttpError};
 use crate::api::{
     vm_add_device, vm_add_disk, vm_add_fs, vm_add_net, vm_add_pmem, vm_add_vsock, vm_boot,
     vm_counters, vm_create, vm_delete, vm_info, vm_pause, vm_reboot, vm_remove_device, vm_resize,
-    vm_restore, vm_resume, vm_shutdown, vm_snapshot, vmm_ping, vmm_shutdown, ApiRequest, VmAction,
-    VmConfig,
+    vm_resize_disk, vm_restore, vm_resume, vm_set_link, vm_shutdown, vm_snapshot,
+    vm_start_net_capture, vm_stop_net_capture, vm_stream_disk, vmm_ping, vmm_shutdown, ApiRequest,
+    VmAction, VmConfig,
 };
 use micro_http::{Body, Method, Request, Response, StatusCode, Version};
 use std::sync::mpsc::Sender;
@@ -132,6 +133,13 @@ impl EndpointHandler for VmActionHandler {
                 )
                 .map_err(HttpError::VmResize),
 
+                ResizeDisk(_) => vm_resize_disk(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmResizeDisk),
+
                 Restore(_) => vm_restore(
                     api_notifier,
                     api_sender,
@@ -146,6 +154,34 @@ impl EndpointHandler for VmActionHandler {
                 )
                 .map_err(HttpError::VmSnapshot),
 
+                StreamDisk(_) => vm_stream_disk(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmStreamDisk),
+
+                SetLink(_) => vm_set_link(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmSetLink),
+
+                StartNetCapture(_) => vm_start_net_capture(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmStartNetCapture),
+
+                StopNetCapture(_) => vm_stop_net_capture(
+                    api_notifier,
+                    api_sender,
+                    Arc::new(serde_json::from_slice(body.raw())?),
+                )
+                .map_err(HttpError::VmStopNetCapture),
+
                 _ => Err(HttpError::BadRequest),
             }
         } else {
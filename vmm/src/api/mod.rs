@@ -33,6 +33,7 @@ extern crate vmm_sys_util;
 
 pub use self::http::start_http_thread;
 
+pub mod fd_proxy;
 pub mod http;
 pub mod http_endpoint;
 
@@ -42,6 +43,7 @@ use crate::config::{
 use crate::vm::{Error as VmError, VmState};
 use micro_http::Body;
 use std::io;
+use std::path::PathBuf;
 use std::sync::mpsc::{channel, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
 use vmm_sys_util::eventfd::EventFd;
@@ -109,6 +111,21 @@ pub enum ApiError {
     /// The VM could not be resized
     VmResize(VmError),
 
+    /// The disk could not be resized.
+    VmResizeDisk(VmError),
+
+    /// The disk could not be streamed.
+    VmStreamDisk(VmError),
+
+    /// The link state of the network device could not be set.
+    VmSetLink(VmError),
+
+    /// The packet capture of the network device could not be started.
+    VmStartNetCapture(VmError),
+
+    /// The packet capture of the network device could not be stopped.
+    VmStopNetCapture(VmError),
+
     /// The device could not be added to the VM.
     VmAddDevice(VmError),
 
@@ -156,6 +173,35 @@ pub struct VmResizeData {
     pub desired_ram_w_balloon: Option<u64>,
 }
 
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmResizeDiskData {
+    pub id: String,
+    pub desired_size: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmStreamDiskData {
+    pub id: String,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmSetLinkData {
+    pub id: String,
+    pub up: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmStartNetCaptureData {
+    pub id: String,
+    pub path: PathBuf,
+    pub snaplen: Option<u32>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Default)]
+pub struct VmStopNetCaptureData {
+    pub id: String,
+}
+
 #[derive(Clone, Deserialize, Serialize, Default)]
 pub struct VmRemoveDeviceData {
     pub id: String,
@@ -236,6 +282,21 @@ pub enum ApiRequest {
     /// Resize the VM.
     VmResize(Arc<VmResizeData>, Sender<ApiResponse>),
 
+    /// Resize a disk of the VM.
+    VmResizeDisk(Arc<VmResizeDiskData>, Sender<ApiResponse>),
+
+    /// Stream the backing file of a disk of the VM.
+    VmStreamDisk(Arc<VmStreamDiskData>, Sender<ApiResponse>),
+
+    /// Set the link state of a network device of the VM.
+    VmSetLink(Arc<VmSetLinkData>, Sender<ApiResponse>),
+
+    /// Start capturing the frames of a network device of the VM.
+    VmStartNetCapture(Arc<VmStartNetCaptureData>, Sender<ApiResponse>),
+
+    /// Stop capturing the frames of a network device of the VM.
+    VmStopNetCapture(Arc<VmStopNetCaptureData>, Sender<ApiResponse>),
+
     /// Add a device to the VM.
     VmAddDevice(Arc<DeviceConfig>, Sender<ApiResponse>),
 
@@ -331,6 +392,21 @@ pub enum VmAction {
     /// Resize VM
     Resize(Arc<VmResizeData>),
 
+    /// Resize disk
+    ResizeDisk(Arc<VmResizeDiskData>),
+
+    /// Stream disk
+    StreamDisk(Arc<VmStreamDiskData>),
+
+    /// Set network device link state
+    SetLink(Arc<VmSetLinkData>),
+
+    /// Start network device packet capture
+    StartNetCapture(Arc<VmStartNetCaptureData>),
+
+    /// Stop network device packet capture
+    StopNetCapture(Arc<VmStopNetCaptureData>),
+
     /// Restore VM
     Restore(Arc<RestoreConfig>),
 
@@ -362,6 +438,11 @@ fn vm_action(
         AddVsock(v) => ApiRequest::VmAddVsock(v, response_sender),
         RemoveDevice(v) => ApiRequest::VmRemoveDevice(v, response_sender),
         Resize(v) => ApiRequest::VmResize(v, response_sender),
+        ResizeDisk(v) => ApiRequest::VmResizeDisk(v, response_sender),
+        StreamDisk(v) => ApiRequest::VmStreamDisk(v, response_sender),
+        SetLink(v) => ApiRequest::VmSetLink(v, response_sender),
+        StartNetCapture(v) => ApiRequest::VmStartNetCapture(v, response_sender),
+        StopNetCapture(v) => ApiRequest::VmStopNetCapture(v, response_sender),
         Restore(v) => ApiRequest::VmRestore(v, response_sender),
         Snapshot(v) => ApiRequest::VmSnapshot(v, response_sender),
     };
@@ -478,6 +559,46 @@ pub fn vm_resize(
     vm_action(api_evt, api_sender, VmAction::Resize(data))
 }
 
+pub fn vm_resize_disk(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmResizeDiskData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::ResizeDisk(data))
+}
+
+pub fn vm_stream_disk(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmStreamDiskData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::StreamDisk(data))
+}
+
+pub fn vm_set_link(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSetLinkData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::SetLink(data))
+}
+
+pub fn vm_start_net_capture(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmStartNetCaptureData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::StartNetCapture(data))
+}
+
+pub fn vm_stop_net_capture(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmStopNetCaptureData>,
+) -> ApiResult<Option<Body>> {
+    vm_action(api_evt, api_sender, VmAction::StopNetCapture(data))
+}
+
 pub fn vm_add_device(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -4,13 +4,16 @@
 //
 
 use clap::ArgMatches;
-use net_util::MacAddr;
+use net_util::{
+    DhcpServer, HostFwd, MacAddr, NetOffloads, DHCP_DEFAULT_LEASE_TIME, DHCP_MAX_DNS_SERVERS,
+    DHCP_MAX_HOSTNAME_LEN, MIN_MTU,
+};
 use option_parser::{
     ByteSized, IntegerList, OptionParser, OptionParserError, Toggle, TupleTwoIntegers,
 };
-use std::convert::From;
+use std::convert::{From, TryFrom};
 use std::fmt;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::path::PathBuf;
 use std::result;
 use std::str::FromStr;
@@ -101,6 +104,61 @@ pub enum ValidationError {
     CpuTopologyZeroPart,
     /// Virtio needs a min of 2 queues
     VnetQueueLowerThan2,
+    /// Disk path is an invalid NBD URI
+    InvalidNbdUri(String),
+    /// Both key file and key fd specified for a disk
+    DiskKeyFileAndFd,
+    /// Disk key provided for an externally handled vhost-user disk
+    DiskSocketAndKey,
+    /// Copy-on-read enabled for a read-only disk
+    DiskReadOnlyCopyOnRead,
+    /// Snapshot mode enabled for a vhost-user or NBD disk
+    DiskSnapshotUnsupported,
+    /// Disk zone size isn't a power of two multiple of the sector size
+    DiskInvalidZoneSize(u64),
+    /// Disk maximum number of open zones higher than maximum number of active zones
+    DiskMaxOpenZonesAboveActive,
+    /// Zoned mode enabled for a vhost-user or NBD disk
+    DiskZonedUnsupported,
+    /// Network MTU lower than the minimum
+    NetMtuTooLow(u16),
+    /// Both tap and macvtap specified for a network device
+    NetTapAndMacvtap,
+    /// Macvtap specified for a vhost-user network device
+    NetMacvtapVhostUser,
+    /// User-mode networking combined with a tap or macvtap
+    NetUserAndTap,
+    /// User-mode networking specified for a vhost-user network device
+    NetUserVhostUser,
+    /// User-mode networking with more than one queue pair
+    NetUserMultiQueue,
+    /// Forwarding rules specified without user-mode networking
+    NetHostFwdWithoutUser,
+    /// DHCP responder enabled with user-mode networking or vhost-user
+    NetDhcpUnsupported,
+    /// DHCP lease options specified without the guest IP address
+    NetDhcpWithoutGuestIp,
+    /// Hostname too long for the DHCP option
+    NetDhcpHostnameTooLong(usize),
+    /// Too many DNS servers for the DHCP option
+    NetDhcpTooManyDns(usize),
+    /// IPv6 prefix length higher than 128
+    NetInvalidIpv6Prefix(u8),
+    /// IPv6 address specified with user-mode networking
+    NetIpv6Unsupported,
+    /// IPv4 disabled with user-mode networking or the DHCP responder
+    NetIpv4Required,
+    /// Bridge specified with user-mode networking, macvtap or vhost-user
+    NetBridgeUnsupported,
+    /// Tap file descriptors combined with another network backend
+    NetFdsUnsupported,
+    /// Number of tap file descriptors not matching the number of queue pairs
+    NetFdsQueueMismatch(usize, usize),
+    /// Tap file descriptor that can't be a valid descriptor
+    NetInvalidFd(i32),
+    /// vhost-net enabled with user-mode networking, vhost-user, the DHCP
+    /// responder or the IOMMU
+    NetVhostNetUnsupported,
 }
 
 type ValidationResult<T> = std::result::Result<T, ValidationError>;
@@ -125,6 +183,100 @@ impl fmt::Display for ValidationError {
                 "Product of CPU topology parts does not match maximum vCPUs"
             ),
             VnetQueueLowerThan2 => write!(f, "Number of queues to virtio_net less than 2"),
+            InvalidNbdUri(s) => write!(f, "Invalid NBD URI for disk path: {}", s),
+            DiskKeyFileAndFd => write!(f, "Disk key file and key fd both provided"),
+            DiskSocketAndKey => write!(f, "Disk vhost socket and key both provided"),
+            DiskReadOnlyCopyOnRead => write!(f, "Disk copy-on-read requires a writable disk"),
+            DiskSnapshotUnsupported => write!(
+                f,
+                "Disk snapshot mode is not supported for vhost-user and NBD disks"
+            ),
+            DiskInvalidZoneSize(s) => write!(
+                f,
+                "Disk zone size {} is not a power of two multiple of 512 bytes",
+                s
+            ),
+            DiskMaxOpenZonesAboveActive => write!(
+                f,
+                "Disk maximum open zones is higher than the maximum active zones"
+            ),
+            DiskZonedUnsupported => write!(
+                f,
+                "Disk zoned mode is not supported for vhost-user and NBD disks"
+            ),
+            NetMtuTooLow(mtu) => write!(
+                f,
+                "Network MTU {} is lower than the minimum of {}",
+                mtu, MIN_MTU
+            ),
+            NetTapAndMacvtap => write!(f, "Network tap and macvtap both provided"),
+            NetMacvtapVhostUser => {
+                write!(f, "Network macvtap is not supported with vhost-user")
+            }
+            NetUserAndTap => write!(
+                f,
+                "Network user-mode networking cannot use a tap or macvtap"
+            ),
+            NetUserVhostUser => write!(
+                f,
+                "Network user-mode networking is not supported with vhost-user"
+            ),
+            NetUserMultiQueue => write!(
+                f,
+                "Network user-mode networking only supports a single queue pair"
+            ),
+            NetHostFwdWithoutUser => {
+                write!(f, "Network hostfwd requires user-mode networking")
+            }
+            NetDhcpUnsupported => write!(
+                f,
+                "Network guest_ip is not supported with user-mode networking and vhost-user"
+            ),
+            NetDhcpWithoutGuestIp => {
+                write!(f, "Network gateway, dns and hostname require guest_ip")
+            }
+            NetDhcpHostnameTooLong(len) => write!(
+                f,
+                "Network hostname length {} is higher than {}",
+                len, DHCP_MAX_HOSTNAME_LEN
+            ),
+            NetDhcpTooManyDns(count) => write!(
+                f,
+                "Network dns has {} servers, more than {}",
+                count, DHCP_MAX_DNS_SERVERS
+            ),
+            NetInvalidIpv6Prefix(prefix) => {
+                write!(
+                    f,
+                    "Network IPv6 prefix length {} is higher than 128",
+                    prefix
+                )
+            }
+            NetIpv6Unsupported => {
+                write!(f, "Network ipv6 is not supported with user-mode networking")
+            }
+            NetIpv4Required => write!(
+                f,
+                "Network user-mode networking and guest_ip require ipv4 to be enabled"
+            ),
+            NetBridgeUnsupported => write!(
+                f,
+                "Network bridge is not supported with user-mode networking, macvtap and vhost-user"
+            ),
+            NetFdsUnsupported => write!(
+                f,
+                "Network fd cannot be combined with tap, macvtap, user-mode networking and vhost-user"
+            ),
+            NetFdsQueueMismatch(fds, queue_pairs) => write!(
+                f,
+                "Network fd provides {} file descriptors for {} queue pairs",
+                fds, queue_pairs
+            ),
+            NetInvalidFd(fd) => write!(f, "Network fd {} is not a valid file descriptor", fd),
+            NetVhostNetUnsupported => write!(
+                f,
+                "Network vhost_net is not supported with user-mode networking, vhost-user, guest_ip and iommu"
+            ),
         }
     }
 }
@@ -540,6 +692,34 @@ impl CmdlineConfig {
     }
 }
 
+/// Format of a disk image, detected from its content unless configured.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum DiskImageType {
+    Raw,
+    Qcow2,
+    Vhd,
+    Vhdx,
+}
+
+#[derive(Debug)]
+pub enum ParseDiskImageTypeError {
+    InvalidValue(String),
+}
+
+impl FromStr for DiskImageType {
+    type Err = ParseDiskImageTypeError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "raw" => Ok(DiskImageType::Raw),
+            "qcow2" => Ok(DiskImageType::Qcow2),
+            "vhd" => Ok(DiskImageType::Vhd),
+            "vhdx" => Ok(DiskImageType::Vhdx),
+            _ => Err(ParseDiskImageTypeError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct DiskConfig {
     pub path: Option<PathBuf>,
@@ -560,6 +740,24 @@ pub struct DiskConfig {
     pub poll_queue: bool,
     #[serde(default)]
     pub id: Option<String>,
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+    #[serde(default)]
+    pub key_fd: Option<i32>,
+    #[serde(default)]
+    pub copy_on_read: bool,
+    #[serde(default)]
+    pub snapshot: bool,
+    #[serde(default)]
+    pub zone_size: Option<u64>,
+    #[serde(default)]
+    pub max_open_zones: u32,
+    #[serde(default)]
+    pub max_active_zones: u32,
+    #[serde(default)]
+    pub image_type: Option<DiskImageType>,
+    #[serde(default)]
+    pub backing_files: bool,
 }
 
 fn default_diskconfig_num_queues() -> usize {
@@ -587,20 +785,33 @@ impl Default for DiskConfig {
             vhost_socket: None,
             poll_queue: default_diskconfig_poll_queue(),
             id: None,
+            key_file: None,
+            key_fd: None,
+            copy_on_read: false,
+            snapshot: false,
+            zone_size: None,
+            max_open_zones: 0,
+            max_active_zones: 0,
+            image_type: None,
+            backing_files: false,
         }
     }
 }
 
 impl DiskConfig {
     pub const SYNTAX: &'static str = "Disk parameters \
-         \"path=<disk_image_path>,readonly=on|off,iommu=on|off,num_queues=<number_of_queues>,\
+         \"path=<disk_image_path|nbd_uri>,readonly=on|off,iommu=on|off,num_queues=<number_of_queues>,\
          queue_size=<size_of_each_queue>,vhost_user=<vhost_user_enable>,\
-         socket=<vhost_user_socket_path>, default true>,id=<device_id>\"";
+         socket=<vhost_user_socket_path>, default true>,id=<device_id>,\
+         key_file=<luks_passphrase_file>,key_fd=<luks_passphrase_fd>,copy_on_read=on|off,\
+         snapshot=on|off,zone_size=<zone_size>,max_open_zones=<max_open_zones>,\
+         max_active_zones=<max_active_zones>,image_type=raw|qcow2|vhd|vhdx,\
+         backing_files=on|off\"";
 
     pub fn parse(disk: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
         parser
-            .add("path")
+            .add_uri("path")
             .add("readonly")
             .add("direct")
             .add("iommu")
@@ -609,7 +820,16 @@ impl DiskConfig {
             .add("vhost_user")
             .add("socket")
             .add("poll_queue")
-            .add("id");
+            .add("id")
+            .add("key_file")
+            .add("key_fd")
+            .add("copy_on_read")
+            .add("snapshot")
+            .add("zone_size")
+            .add("max_open_zones")
+            .add("max_active_zones")
+            .add("image_type")
+            .add("backing_files");
         parser.parse(disk).map_err(Error::ParseDisk)?;
 
         let path = parser.get("path").map(PathBuf::from);
@@ -648,6 +868,36 @@ impl DiskConfig {
             .unwrap_or_else(|| Toggle(default_diskconfig_poll_queue()))
             .0;
         let id = parser.get("id");
+        let key_file = parser.get("key_file").map(PathBuf::from);
+        let key_fd = parser.convert("key_fd").map_err(Error::ParseDisk)?;
+        let copy_on_read = parser
+            .convert::<Toggle>("copy_on_read")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let snapshot = parser
+            .convert::<Toggle>("snapshot")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let zone_size = parser
+            .convert::<ByteSized>("zone_size")
+            .map_err(Error::ParseDisk)?
+            .map(|v| v.0);
+        let max_open_zones = parser
+            .convert("max_open_zones")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(0);
+        let max_active_zones = parser
+            .convert("max_active_zones")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(0);
+        let image_type = parser.convert("image_type").map_err(Error::ParseDisk)?;
+        let backing_files = parser
+            .convert::<Toggle>("backing_files")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
 
         if parser.is_set("poll_queue") && !vhost_user {
             warn!("poll_queue parameter currently only has effect when used vhost_user=true");
@@ -664,8 +914,70 @@ impl DiskConfig {
             vhost_user,
             poll_queue,
             id,
+            key_file,
+            key_fd,
+            copy_on_read,
+            snapshot,
+            zone_size,
+            max_open_zones,
+            max_active_zones,
+            image_type,
+            backing_files,
         })
     }
+
+    /// Whether the disk is LUKS encrypted, unlocked with the given key.
+    pub fn has_key(&self) -> bool {
+        self.key_file.is_some() || self.key_fd.is_some()
+    }
+
+    /// Returns the NBD URI the disk path holds, if the disk is backed by an
+    /// NBD export rather than a local file.
+    pub fn nbd_uri(&self) -> Option<&str> {
+        self.path
+            .as_ref()
+            .and_then(|p| p.to_str())
+            .filter(|p| block_util::nbd::is_nbd_uri(p))
+    }
+
+    /// Checks the settings of the disk which don't depend on the rest of
+    /// the VM configuration.
+    pub fn validate(&self) -> ValidationResult<()> {
+        if self.vhost_socket.as_ref().and(self.path.as_ref()).is_some() {
+            return Err(ValidationError::DiskSocketAndPath);
+        }
+        if self.key_file.is_some() && self.key_fd.is_some() {
+            return Err(ValidationError::DiskKeyFileAndFd);
+        }
+        if self.vhost_socket.is_some() && self.has_key() {
+            return Err(ValidationError::DiskSocketAndKey);
+        }
+        if self.readonly && self.copy_on_read {
+            return Err(ValidationError::DiskReadOnlyCopyOnRead);
+        }
+        if self.snapshot && (self.vhost_user || self.nbd_uri().is_some()) {
+            return Err(ValidationError::DiskSnapshotUnsupported);
+        }
+        if let Some(zone_size) = self.zone_size {
+            if !zone_size.is_power_of_two()
+                || zone_size < block_util::SECTOR_SIZE
+                || zone_size / block_util::SECTOR_SIZE > u64::from(u32::MAX)
+            {
+                return Err(ValidationError::DiskInvalidZoneSize(zone_size));
+            }
+            if self.max_active_zones != 0 && self.max_open_zones > self.max_active_zones {
+                return Err(ValidationError::DiskMaxOpenZonesAboveActive);
+            }
+            if self.vhost_user || self.nbd_uri().is_some() {
+                return Err(ValidationError::DiskZonedUnsupported);
+            }
+        }
+        if let Some(uri) = self.nbd_uri() {
+            block_util::nbd::NbdUri::parse(uri)
+                .map_err(|_| ValidationError::InvalidNbdUri(uri.to_owned()))?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -676,6 +988,12 @@ pub struct NetConfig {
     pub ip: Ipv4Addr,
     #[serde(default = "default_netconfig_mask")]
     pub mask: Ipv4Addr,
+    #[serde(default = "default_netconfig_ipv4")]
+    pub ipv4: bool,
+    #[serde(default)]
+    pub ipv6: Option<Ipv6Addr>,
+    #[serde(default = "default_netconfig_ipv6_prefix")]
+    pub ipv6_prefix: u8,
     #[serde(default = "default_netconfig_mac")]
     pub mac: MacAddr,
     #[serde(default)]
@@ -691,6 +1009,84 @@ pub struct NetConfig {
     pub vhost_socket: Option<String>,
     #[serde(default)]
     pub id: Option<String>,
+    #[serde(default)]
+    pub mtu: Option<u16>,
+    #[serde(default = "default_netconfig_offload")]
+    pub csum: bool,
+    #[serde(default = "default_netconfig_offload")]
+    pub tso: bool,
+    #[serde(default)]
+    pub tso6: bool,
+    #[serde(default = "default_netconfig_offload")]
+    pub ufo: bool,
+    #[serde(default)]
+    pub ecn: bool,
+    #[serde(default)]
+    pub macvtap: Option<String>,
+    #[serde(default)]
+    pub user: bool,
+    #[serde(default)]
+    pub hostfwd: Vec<HostFwd>,
+    #[serde(default)]
+    pub guest_ip: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub gateway: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub dns: Vec<Ipv4Addr>,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub bridge: Option<String>,
+    #[serde(default)]
+    pub fds: Option<Vec<i32>>,
+    #[serde(default)]
+    pub vhost_net: bool,
+}
+
+pub enum HostFwdListParseError {
+    InvalidValue(String),
+}
+
+/// Forwarding rules of a user-mode network, separated by ':'.
+struct HostFwdList(Vec<HostFwd>);
+
+impl FromStr for HostFwdList {
+    type Err = HostFwdListParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut hostfwd = Vec::new();
+        for rule in s.trim().split(':') {
+            hostfwd.push(
+                rule.parse()
+                    .map_err(|_| Self::Err::InvalidValue(rule.to_owned()))?,
+            );
+        }
+
+        Ok(HostFwdList(hostfwd))
+    }
+}
+
+pub enum Ipv4AddrListParseError {
+    InvalidValue(String),
+}
+
+/// IPv4 addresses separated by ':'.
+struct Ipv4AddrList(Vec<Ipv4Addr>);
+
+impl FromStr for Ipv4AddrList {
+    type Err = Ipv4AddrListParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut addrs = Vec::new();
+        for addr in s.trim().split(':') {
+            addrs.push(
+                addr.parse()
+                    .map_err(|_| Self::Err::InvalidValue(addr.to_owned()))?,
+            );
+        }
+
+        Ok(Ipv4AddrList(addrs))
+    }
 }
 
 fn default_netconfig_tap() -> Option<String> {
@@ -705,6 +1101,14 @@ fn default_netconfig_mask() -> Ipv4Addr {
     Ipv4Addr::new(255, 255, 255, 0)
 }
 
+fn default_netconfig_ipv4() -> bool {
+    true
+}
+
+fn default_netconfig_ipv6_prefix() -> u8 {
+    64
+}
+
 fn default_netconfig_mac() -> MacAddr {
     MacAddr::local_random()
 }
@@ -717,12 +1121,19 @@ fn default_netconfig_queue_size() -> u16 {
     DEFAULT_QUEUE_SIZE_VUNET
 }
 
+fn default_netconfig_offload() -> bool {
+    true
+}
+
 impl Default for NetConfig {
     fn default() -> Self {
         Self {
             tap: default_netconfig_tap(),
             ip: default_netconfig_ip(),
             mask: default_netconfig_mask(),
+            ipv4: default_netconfig_ipv4(),
+            ipv6: None,
+            ipv6_prefix: default_netconfig_ipv6_prefix(),
             mac: default_netconfig_mac(),
             host_mac: None,
             iommu: false,
@@ -731,15 +1142,38 @@ impl Default for NetConfig {
             vhost_user: false,
             vhost_socket: None,
             id: None,
+            mtu: None,
+            csum: default_netconfig_offload(),
+            tso: default_netconfig_offload(),
+            tso6: false,
+            ufo: default_netconfig_offload(),
+            ecn: false,
+            macvtap: None,
+            user: false,
+            hostfwd: Vec::new(),
+            guest_ip: None,
+            gateway: None,
+            dns: Vec::new(),
+            hostname: None,
+            bridge: None,
+            fds: None,
+            vhost_net: false,
         }
     }
 }
 
 impl NetConfig {
     pub const SYNTAX: &'static str = "Network parameters \
-    \"tap=<if_name>,ip=<ip_addr>,mask=<net_mask>,mac=<mac_addr>,iommu=on|off,\
+    \"tap=<if_name>,ip=<ip_addr>,mask=<net_mask>,ipv4=on|off,ipv6=<ipv6_addr>,\
+    ipv6_prefix=<ipv6_prefix_len>,mac=<mac_addr>,iommu=on|off,\
     num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,\
-    vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,id=<device_id>\"";
+    vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,id=<device_id>,\
+    mtu=<mtu>,csum=on|off,tso=on|off,tso6=on|off,ufo=on|off,ecn=on|off,\
+    macvtap=<if_name|/dev/tapN>,user=on|off,\
+    hostfwd=<tcp|udp>/[<host_addr>/]<host_port>-<guest_port>[:...],\
+    guest_ip=<guest_ip_addr>,gateway=<gateway_ip_addr>,dns=<dns_ip_addr>[:...],\
+    hostname=<guest_hostname>,bridge=<bridge_name>,fd=<tap_fd>[:...],\
+    vhost_net=on|off\"";
 
     pub fn parse(net: &str) -> Result<Self> {
         let mut parser = OptionParser::new();
@@ -748,6 +1182,9 @@ impl NetConfig {
             .add("tap")
             .add("ip")
             .add("mask")
+            .add("ipv4")
+            .add("ipv6")
+            .add("ipv6_prefix")
             .add("mac")
             .add("host_mac")
             .add("iommu")
@@ -755,7 +1192,23 @@ impl NetConfig {
             .add("num_queues")
             .add("vhost_user")
             .add("socket")
-            .add("id");
+            .add("id")
+            .add("mtu")
+            .add("csum")
+            .add("tso")
+            .add("tso6")
+            .add("ufo")
+            .add("ecn")
+            .add("macvtap")
+            .add("user")
+            .add("hostfwd")
+            .add("guest_ip")
+            .add("gateway")
+            .add("dns")
+            .add("hostname")
+            .add("bridge")
+            .add("fd")
+            .add("vhost_net");
         parser.parse(net).map_err(Error::ParseNetwork)?;
 
         let tap = parser.get("tap");
@@ -767,6 +1220,16 @@ impl NetConfig {
             .convert("mask")
             .map_err(Error::ParseNetwork)?
             .unwrap_or_else(default_netconfig_mask);
+        let ipv4 = parser
+            .convert::<Toggle>("ipv4")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_else(|| Toggle(default_netconfig_ipv4()))
+            .0;
+        let ipv6 = parser.convert("ipv6").map_err(Error::ParseNetwork)?;
+        let ipv6_prefix = parser
+            .convert("ipv6_prefix")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_else(default_netconfig_ipv6_prefix);
         let mac = parser
             .convert("mac")
             .map_err(Error::ParseNetwork)?
@@ -792,10 +1255,78 @@ impl NetConfig {
             .0;
         let vhost_socket = parser.get("socket");
         let id = parser.get("id");
+        let mtu = parser.convert("mtu").map_err(Error::ParseNetwork)?;
+        let csum = parser
+            .convert::<Toggle>("csum")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_else(|| Toggle(default_netconfig_offload()))
+            .0;
+        let tso = parser
+            .convert::<Toggle>("tso")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_else(|| Toggle(default_netconfig_offload()))
+            .0;
+        let tso6 = parser
+            .convert::<Toggle>("tso6")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let ufo = parser
+            .convert::<Toggle>("ufo")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_else(|| Toggle(default_netconfig_offload()))
+            .0;
+        let ecn = parser
+            .convert::<Toggle>("ecn")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let macvtap = parser.get("macvtap");
+        let user = parser
+            .convert::<Toggle>("user")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let hostfwd = parser
+            .convert::<HostFwdList>("hostfwd")
+            .map_err(Error::ParseNetwork)?
+            .map(|l| l.0)
+            .unwrap_or_default();
+        let guest_ip = parser.convert("guest_ip").map_err(Error::ParseNetwork)?;
+        let gateway = parser.convert("gateway").map_err(Error::ParseNetwork)?;
+        let dns = parser
+            .convert::<Ipv4AddrList>("dns")
+            .map_err(Error::ParseNetwork)?
+            .map(|l| l.0)
+            .unwrap_or_default();
+        let hostname = parser.get("hostname");
+        let bridge = parser.get("bridge");
+        let fds = parser
+            .convert::<IntegerList>("fd")
+            .map_err(Error::ParseNetwork)?
+            .map(|l| {
+                l.0.iter()
+                    .map(|fd| {
+                        i32::try_from(*fd).map_err(|_| {
+                            OptionParserError::Conversion("fd".to_owned(), fd.to_string())
+                        })
+                    })
+                    .collect::<result::Result<Vec<i32>, _>>()
+            })
+            .transpose()
+            .map_err(Error::ParseNetwork)?;
+        let vhost_net = parser
+            .convert::<Toggle>("vhost_net")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
         let config = NetConfig {
             tap,
             ip,
             mask,
+            ipv4,
+            ipv6,
+            ipv6_prefix,
             mac,
             host_mac,
             iommu,
@@ -804,6 +1335,22 @@ impl NetConfig {
             vhost_user,
             vhost_socket,
             id,
+            mtu,
+            csum,
+            tso,
+            tso6,
+            ufo,
+            ecn,
+            macvtap,
+            user,
+            hostfwd,
+            guest_ip,
+            gateway,
+            dns,
+            hostname,
+            bridge,
+            fds,
+            vhost_net,
         };
         config.validate().map_err(Error::Validation)?;
         Ok(config)
@@ -812,8 +1359,110 @@ impl NetConfig {
         if self.num_queues < 2 {
             return Err(ValidationError::VnetQueueLowerThan2);
         }
+        if let Some(mtu) = self.mtu {
+            if mtu < MIN_MTU {
+                return Err(ValidationError::NetMtuTooLow(mtu));
+            }
+        }
+        if self.macvtap.is_some() {
+            if self.tap.is_some() {
+                return Err(ValidationError::NetTapAndMacvtap);
+            }
+            if self.vhost_user {
+                return Err(ValidationError::NetMacvtapVhostUser);
+            }
+        }
+        if self.user {
+            if self.tap.is_some() || self.macvtap.is_some() {
+                return Err(ValidationError::NetUserAndTap);
+            }
+            if self.vhost_user {
+                return Err(ValidationError::NetUserVhostUser);
+            }
+            if self.num_queues != 2 {
+                return Err(ValidationError::NetUserMultiQueue);
+            }
+        } else if !self.hostfwd.is_empty() {
+            return Err(ValidationError::NetHostFwdWithoutUser);
+        }
+        if self.guest_ip.is_some() {
+            if self.user || self.vhost_user {
+                return Err(ValidationError::NetDhcpUnsupported);
+            }
+        } else if self.gateway.is_some() || !self.dns.is_empty() || self.hostname.is_some() {
+            return Err(ValidationError::NetDhcpWithoutGuestIp);
+        }
+        if let Some(hostname) = &self.hostname {
+            if hostname.len() > DHCP_MAX_HOSTNAME_LEN {
+                return Err(ValidationError::NetDhcpHostnameTooLong(hostname.len()));
+            }
+        }
+        if self.dns.len() > DHCP_MAX_DNS_SERVERS {
+            return Err(ValidationError::NetDhcpTooManyDns(self.dns.len()));
+        }
+        if self.ipv6_prefix > 128 {
+            return Err(ValidationError::NetInvalidIpv6Prefix(self.ipv6_prefix));
+        }
+        if self.user && self.ipv6.is_some() {
+            return Err(ValidationError::NetIpv6Unsupported);
+        }
+        if !self.ipv4 && (self.user || self.guest_ip.is_some()) {
+            return Err(ValidationError::NetIpv4Required);
+        }
+        if self.bridge.is_some() && (self.user || self.macvtap.is_some() || self.vhost_user) {
+            return Err(ValidationError::NetBridgeUnsupported);
+        }
+        if let Some(fds) = &self.fds {
+            if self.tap.is_some() || self.macvtap.is_some() || self.user || self.vhost_user {
+                return Err(ValidationError::NetFdsUnsupported);
+            }
+            if fds.len() != self.num_queues / 2 {
+                return Err(ValidationError::NetFdsQueueMismatch(
+                    fds.len(),
+                    self.num_queues / 2,
+                ));
+            }
+            if let Some(fd) = fds.iter().find(|fd| **fd < 0) {
+                return Err(ValidationError::NetInvalidFd(*fd));
+            }
+        }
+        if self.vhost_net && (self.user || self.vhost_user || self.guest_ip.is_some() || self.iommu)
+        {
+            return Err(ValidationError::NetVhostNetUnsupported);
+        }
         Ok(())
     }
+
+    /// IPv6 address and prefix length of the host side of the tap.
+    pub fn ipv6_addr(&self) -> Option<(Ipv6Addr, u8)> {
+        self.ipv6.map(|ip| (ip, self.ipv6_prefix))
+    }
+
+    /// DHCP server answering the guest on behalf of the gateway, which
+    /// defaults to the host side of the tap.
+    pub fn dhcp_server(&self) -> Option<DhcpServer> {
+        let gateway = self.gateway.unwrap_or(self.ip);
+        self.guest_ip.map(|client_ip| DhcpServer {
+            server_ip: gateway,
+            server_mac: self.host_mac.unwrap_or_else(MacAddr::local_random),
+            client_ip,
+            mask: self.mask,
+            router: Some(gateway),
+            dns: self.dns.clone(),
+            hostname: self.hostname.clone(),
+            lease_time: DHCP_DEFAULT_LEASE_TIME,
+        })
+    }
+
+    pub fn offloads(&self) -> NetOffloads {
+        NetOffloads {
+            csum: self.csum,
+            tso: self.tso,
+            tso6: self.tso6,
+            ufo: self.ufo,
+            ecn: self.ecn,
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -1027,6 +1676,7 @@ pub enum ConsoleOutputMode {
     Off,
     Tty,
     File,
+    Pty,
     Null,
 }
 
@@ -1059,6 +1709,7 @@ impl ConsoleConfig {
             .add_valueless("off")
             .add_valueless("tty")
             .add_valueless("null")
+            .add_valueless("pty")
             .add("file")
             .add("iommu");
         parser.parse(console).map_err(Error::ParseConsole)?;
@@ -1071,6 +1722,8 @@ impl ConsoleConfig {
             mode = ConsoleOutputMode::Tty
         } else if parser.is_set("null") {
             mode = ConsoleOutputMode::Null
+        } else if parser.is_set("pty") {
+            mode = ConsoleOutputMode::Pty
         } else if parser.is_set("file") {
             mode = ConsoleOutputMode::File;
             file =
@@ -1353,12 +2006,10 @@ impl VmConfig {
 
         if let Some(disks) = &self.disks {
             for disk in disks {
-                if disk.vhost_socket.as_ref().and(disk.path.as_ref()).is_some() {
-                    return Err(ValidationError::DiskSocketAndPath);
-                }
                 if disk.vhost_user && !self.memory.shared {
                     return Err(ValidationError::VhostUserRequiresSharedMemory);
                 }
+                disk.validate()?;
             }
         }
 
@@ -1367,6 +2018,7 @@ impl VmConfig {
                 if net.vhost_user && !self.memory.shared {
                     return Err(ValidationError::VhostUserRequiresSharedMemory);
                 }
+                net.validate()?;
             }
         }
 
@@ -1759,6 +2411,75 @@ mod tests {
                 ..Default::default()
             }
         );
+        assert_eq!(
+            DiskConfig::parse("path=nbd+unix:///disk0?socket=/run/nbd.sock")?.nbd_uri(),
+            Some("nbd+unix:///disk0?socket=/run/nbd.sock")
+        );
+        assert_eq!(DiskConfig::parse("path=/path/to_file")?.nbd_uri(), None);
+        assert!(DiskConfig::parse("path=/path/to=file").is_err());
+        assert!(DiskConfig::parse("path=/path/to_file,readonly=on=off").is_err());
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,key_file=/path/to_key")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                key_file: Some(PathBuf::from("/path/to_key")),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,copy_on_read=on")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                copy_on_read: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,snapshot=on")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                snapshot: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse(
+                "path=/path/to_file,zone_size=256M,max_open_zones=8,max_active_zones=12"
+            )?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                zone_size: Some(256 << 20),
+                max_open_zones: 8,
+                max_active_zones: 12,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,key_fd=3")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                key_fd: Some(3),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,image_type=raw")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                image_type: Some(DiskImageType::Raw),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            DiskConfig::parse("path=/path/to_file,image_type=qcow2,backing_files=on")?,
+            DiskConfig {
+                path: Some(PathBuf::from("/path/to_file")),
+                image_type: Some(DiskImageType::Qcow2),
+                backing_files: true,
+                ..Default::default()
+            }
+        );
+        assert!(DiskConfig::parse("path=/path/to_file,image_type=iso").is_err());
 
         Ok(())
     }
@@ -1824,6 +2545,163 @@ mod tests {
             }
         );
 
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,mtu=9000,tso=off,tso6=on,ecn=on"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                mtu: Some(9000),
+                tso: false,
+                tso6: true,
+                ecn: true,
+                ..Default::default()
+            }
+        );
+        assert!(NetConfig::parse("mtu=67").is_err());
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,host_mac=12:34:de:ad:be:ef,macvtap=/dev/tap12"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                host_mac: Some(MacAddr::parse_str("12:34:de:ad:be:ef").unwrap()),
+                macvtap: Some("/dev/tap12".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert!(NetConfig::parse("tap=tap0,macvtap=macvtap0").is_err());
+        assert!(NetConfig::parse("vhost_user=true,socket=/tmp/sock,macvtap=macvtap0").is_err());
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,ip=10.0.2.2,mask=255.255.255.0,user=on,\
+                 hostfwd=tcp/8022-22:udp/0.0.0.0/5353-53"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                ip: Ipv4Addr::new(10, 0, 2, 2),
+                user: true,
+                hostfwd: vec![
+                    "tcp/127.0.0.1/8022-22".parse().unwrap(),
+                    "udp/0.0.0.0/5353-53".parse().unwrap(),
+                ],
+                ..Default::default()
+            }
+        );
+        assert!(NetConfig::parse("user=on,hostfwd=tcp/8022").is_err());
+        assert!(NetConfig::parse("tap=tap0,user=on").is_err());
+        assert!(NetConfig::parse("user=on,num_queues=4").is_err());
+        assert!(NetConfig::parse("hostfwd=tcp/8022-22").is_err());
+
+        assert_eq!(
+            NetConfig::parse(
+                "mac=de:ad:be:ef:12:34,guest_ip=192.168.249.2,dns=1.1.1.1:8.8.8.8,\
+                 hostname=guest"
+            )?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                guest_ip: Some(Ipv4Addr::new(192, 168, 249, 2)),
+                dns: vec![Ipv4Addr::new(1, 1, 1, 1), Ipv4Addr::new(8, 8, 8, 8)],
+                hostname: Some("guest".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert!(NetConfig::parse("guest_ip=192.168.249.2,dns=1.1.1").is_err());
+        assert!(NetConfig::parse("dns=1.1.1.1").is_err());
+        assert!(NetConfig::parse("user=on,guest_ip=10.0.2.15").is_err());
+        assert!(NetConfig::parse(&format!(
+            "guest_ip=192.168.249.2,hostname={}",
+            "a".repeat(256)
+        ))
+        .is_err());
+        assert!(NetConfig::parse(&format!(
+            "guest_ip=192.168.249.2,dns={}",
+            vec!["1.1.1.1"; 64].join(":")
+        ))
+        .is_err());
+        assert!(NetConfig::parse(&format!(
+            "guest_ip=192.168.249.2,dns={}",
+            vec!["1.1.1.1"; 63].join(":")
+        ))
+        .is_ok());
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,ipv6=fd00::1,ipv6_prefix=48")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                ipv6: Some("fd00::1".parse().unwrap()),
+                ipv6_prefix: 48,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,ipv4=off,ipv6=fd00::1")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                ipv4: false,
+                ipv6: Some("fd00::1".parse().unwrap()),
+                ..Default::default()
+            }
+        );
+        assert!(NetConfig::parse("ipv6=fd00::1,ipv6_prefix=129").is_err());
+        assert!(NetConfig::parse("ipv6=192.168.249.1").is_err());
+        assert!(NetConfig::parse("user=on,ipv6=fd00::1").is_err());
+        assert!(NetConfig::parse("ipv4=off,guest_ip=192.168.249.2").is_err());
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,tap=tap0,bridge=br0")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                tap: Some("tap0".to_owned()),
+                bridge: Some("br0".to_owned()),
+                ..Default::default()
+            }
+        );
+        assert!(NetConfig::parse("user=on,bridge=br0").is_err());
+        assert!(NetConfig::parse("macvtap=macvtap0,bridge=br0").is_err());
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,fd=3:7,num_queues=4")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                fds: Some(vec![3, 7]),
+                num_queues: 4,
+                ..Default::default()
+            }
+        );
+        assert!(NetConfig::parse("fd=3:7").is_err());
+        assert!(NetConfig::parse("tap=tap0,fd=3").is_err());
+        assert!(NetConfig::parse("fd=tap0").is_err());
+        assert!(NetConfig::parse("fd=4294967299").is_err());
+        assert!(NetConfig {
+            fds: Some(vec![-1]),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+        assert!(NetConfig {
+            fds: Some(Vec::new()),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+
+        assert_eq!(
+            NetConfig::parse("mac=de:ad:be:ef:12:34,tap=tap0,vhost_net=on")?,
+            NetConfig {
+                mac: MacAddr::parse_str("de:ad:be:ef:12:34").unwrap(),
+                tap: Some("tap0".to_owned()),
+                vhost_net: true,
+                ..Default::default()
+            }
+        );
+        assert!(NetConfig::parse("user=on,vhost_net=on").is_err());
+        assert!(NetConfig::parse("vhost_net=on,guest_ip=192.168.249.2").is_err());
+        assert!(NetConfig::parse("vhost_net=on,iommu=on").is_err());
+
         Ok(())
     }
 
@@ -1977,6 +2855,14 @@ mod tests {
                 file: None,
             }
         );
+        assert_eq!(
+            ConsoleConfig::parse("pty")?,
+            ConsoleConfig {
+                mode: ConsoleOutputMode::Pty,
+                iommu: false,
+                file: None,
+            }
+        );
         assert_eq!(
             ConsoleConfig::parse("file=/tmp/console")?,
             ConsoleConfig {
@@ -2122,6 +3008,11 @@ mod tests {
         invalid_config.console.mode = ConsoleOutputMode::Tty;
         assert!(invalid_config.validate().is_err());
 
+        let mut still_valid_config = valid_config.clone();
+        still_valid_config.serial.mode = ConsoleOutputMode::Pty;
+        still_valid_config.console.mode = ConsoleOutputMode::Pty;
+        assert!(still_valid_config.validate().is_ok());
+
         let mut invalid_config = valid_config.clone();
         invalid_config.kernel = None;
         assert!(invalid_config.validate().is_err());
@@ -2162,6 +3053,57 @@ mod tests {
         }]);
         assert!(invalid_config.validate().is_err());
 
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("nbd+unix:///disk0")),
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            key_file: Some(PathBuf::from("/path/to/key")),
+            key_fd: Some(3),
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            readonly: true,
+            copy_on_read: true,
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("nbd://localhost/disk0")),
+            snapshot: true,
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            zone_size: Some(3 << 20),
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
+        let mut invalid_config = valid_config.clone();
+        invalid_config.disks = Some(vec![DiskConfig {
+            path: Some(PathBuf::from("/path/to/image")),
+            zone_size: Some(256 << 20),
+            max_open_zones: 16,
+            max_active_zones: 8,
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
         let mut still_valid_config = valid_config.clone();
         still_valid_config.disks = Some(vec![DiskConfig {
             vhost_user: true,
@@ -2185,6 +3127,14 @@ mod tests {
         still_valid_config.memory.shared = true;
         assert!(still_valid_config.validate().is_ok());
 
+        // The network devices from the API haven't been parsed.
+        let mut invalid_config = valid_config.clone();
+        invalid_config.net = Some(vec![NetConfig {
+            fds: Some(Vec::new()),
+            ..Default::default()
+        }]);
+        assert!(invalid_config.validate().is_err());
+
         let mut invalid_config = valid_config.clone();
         invalid_config.fs = Some(vec![FsConfig {
             ..Default::default()
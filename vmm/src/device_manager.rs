@@ -12,7 +12,9 @@
 use crate::config::ConsoleOutputMode;
 #[cfg(feature = "pci_support")]
 use crate::config::DeviceConfig;
-use crate::config::{DiskConfig, FsConfig, NetConfig, PmemConfig, VmConfig, VsockConfig};
+use crate::config::{
+    DiskConfig, DiskImageType, FsConfig, NetConfig, PmemConfig, VmConfig, VsockConfig,
+};
 use crate::device_tree::{DeviceNode, DeviceTree};
 #[cfg(feature = "kvm")]
 use crate::interrupt::kvm::KvmMsiInterruptManager as MsiInterruptManager;
@@ -32,8 +34,11 @@ use arch::layout;
 use arch::layout::{APIC_START, IOAPIC_SIZE, IOAPIC_START};
 #[cfg(target_arch = "aarch64")]
 use arch::DeviceType;
+use block_util::block_aio_is_supported;
 #[cfg(feature = "io_uring")]
 use block_util::block_io_uring_is_supported;
+use block_util::nbd::NbdDisk;
+use block_util::stats::SharedBlockStats;
 #[cfg(target_arch = "aarch64")]
 use devices::gic;
 #[cfg(target_arch = "x86_64")]
@@ -53,18 +58,21 @@ use pci::{
     DeviceRelocation, PciBarRegionType, PciBus, PciConfigIo, PciConfigMmio, PciDevice, PciRoot,
     VfioPciDevice,
 };
-use qcow::{self, ImageType, QcowFile};
+use qcow::{self, FixedVhdFile, ImageType, LuksDisk, QcowFile, VhdxFile};
 use seccomp::SeccompAction;
 #[cfg(feature = "pci_support")]
 use std::any::Any;
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
+use std::ffi::{CStr, OsStr};
+use std::fs::{self, File, OpenOptions};
 use std::io::{self, sink, stdout, Seek, SeekFrom};
 use std::num::Wrapping;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
 #[cfg(all(feature = "pci_support", feature = "kvm"))]
 use std::os::unix::io::FromRawFd;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result;
 use std::sync::{Arc, Mutex};
 use tempfile::NamedTempFile;
@@ -74,9 +82,9 @@ use vfio_ioctls::{VfioContainer, VfioDevice, VfioDmaMapping};
 use virtio_devices::transport::VirtioPciDevice;
 use virtio_devices::transport::VirtioTransport;
 use virtio_devices::vhost_user::VhostUserConfig;
+use virtio_devices::{DiskFile, VirtioSharedMemory, VirtioSharedMemoryList};
 #[cfg(feature = "pci_support")]
 use virtio_devices::{DmaRemapping, IommuMapping};
-use virtio_devices::{VirtioSharedMemory, VirtioSharedMemoryList};
 use vm_allocator::SystemAllocator;
 use vm_device::interrupt::{
     InterruptIndex, InterruptManager, LegacyIrqGroupConfig, MsiIrqGroupConfig,
@@ -143,6 +151,12 @@ pub enum DeviceManagerError {
     /// Cannot create virtio-net device
     CreateVirtioNet(virtio_devices::net::Error),
 
+    /// Cannot open macvtap interface
+    OpenMacvtap(net_util::OpenTapError),
+
+    /// Cannot use the tap file descriptors
+    OpenTapFds(net_util::OpenTapError),
+
     /// Cannot create virtio-console device
     CreateVirtioConsole(io::Error),
 
@@ -182,6 +196,30 @@ pub enum DeviceManagerError {
     /// Cannot open qcow disk path
     QcowDeviceCreate(qcow::Error),
 
+    /// Cannot open fixed VHD disk path
+    VhdDeviceCreate(qcow::vhd::Error),
+
+    /// Cannot open VHDX disk path
+    VhdxDeviceCreate(qcow::vhdx::Error),
+
+    /// Cannot connect to NBD export
+    NbdDeviceCreate(block_util::nbd::Error),
+
+    /// Cannot read LUKS disk key
+    ReadDiskKey(io::Error),
+
+    /// Cannot unlock LUKS disk
+    LuksDeviceCreate(qcow::luks::Error),
+
+    /// Cannot create temporary file for disk overlay
+    CreateDiskOverlayFile(io::Error),
+
+    /// Cannot create qcow overlay for disk in snapshot mode
+    DiskOverlayCreate(qcow::Error),
+
+    /// Cannot load or create the zone state file of a zoned disk
+    ZonesState(io::Error),
+
     /// Cannot open tap interface
     OpenTap(net_util::TapError),
 
@@ -230,6 +268,12 @@ pub enum DeviceManagerError {
     /// Error creating console output file
     ConsoleOutputFileOpen(io::Error),
 
+    /// Error creating the pseudo terminal of the serial port
+    SerialPtyOpen(io::Error),
+
+    /// Error creating the pseudo terminal of the console
+    ConsolePtyOpen(io::Error),
+
     /// Cannot create a VFIO device
     #[cfg(feature = "pci_support")]
     VfioCreate(vfio_ioctls::VfioError),
@@ -278,6 +322,9 @@ pub enum DeviceManagerError {
     /// Failed to create socket file
     CreateSocketFile(io::Error),
 
+    /// Failed to create the block backend statistics file
+    CreateBlockStatsFile(io::Error),
+
     /// Failed to spawn the network backend
     SpawnNetBackend(io::Error),
 
@@ -379,6 +426,30 @@ pub enum DeviceManagerError {
 
     /// No support for device passthrough
     NoDevicePassthroughSupport,
+
+    /// Failed to find the disk corresponding to the given identifier.
+    UnknownDiskId(String),
+
+    /// Failed resizing a disk.
+    ResizeDisk(virtio_devices::Error),
+
+    /// Failed starting to stream a disk.
+    StreamDisk(virtio_devices::Error),
+
+    /// Failed to find the network device corresponding to the given identifier.
+    UnknownNetId(String),
+
+    /// Failed setting the link state of a network device.
+    SetLink(virtio_devices::Error),
+
+    /// Failed to attach the tap of a network device to a bridge.
+    AttachBridge(virtio_devices::net::Error),
+
+    /// Failed starting to capture the frames of a network device.
+    StartNetCapture(virtio_devices::Error),
+
+    /// Failed stopping to capture the frames of a network device.
+    StopNetCapture(virtio_devices::Error),
 }
 pub type DeviceManagerResult<T> = result::Result<T, DeviceManagerError>;
 
@@ -402,6 +473,96 @@ pub fn get_win_size() -> (u16, u16) {
     (ws.cols, ws.rows)
 }
 
+/// Pseudo terminal the serial port or the virtio-console is attached to.
+pub struct PtyPair {
+    /// Side the output of the device is written to, and its input read
+    /// from.
+    pub main: File,
+    /// Side the users attach to. It is kept open so that the main side
+    /// isn't hung up while no user is attached.
+    pub sub: File,
+    /// Path of the side the users attach to.
+    pub path: PathBuf,
+}
+
+fn create_pty() -> io::Result<PtyPair> {
+    let main = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+        .open("/dev/ptmx")?;
+    let main_fd = main.as_raw_fd();
+
+    // Safe because the file descriptor is valid and the buffer is large
+    // enough for any pseudo terminal path.
+    let mut name = [0 as libc::c_char; 64];
+    unsafe {
+        if libc::grantpt(main_fd) < 0 || libc::unlockpt(main_fd) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ret = libc::ptsname_r(main_fd, name.as_mut_ptr(), name.len());
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+    }
+    // Safe because ptsname_r() wrote a nul terminated string.
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+    let path = PathBuf::from(OsStr::from_bytes(name.to_bytes()));
+
+    let sub = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(&path)?;
+
+    // Without raw mode, the output of the device would be echoed back as
+    // its input until a user attaches and sets the terminal up.
+    // Safe because the file descriptor is valid and termios is a plain C
+    // structure filled by tcgetattr().
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(sub.as_raw_fd(), &mut termios) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(sub.as_raw_fd(), libc::TCSANOW, &termios) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(PtyPair { main, sub, path })
+}
+
+// Output of a device written to a pseudo terminal. Once the terminal buffer
+// is full because no user reads from it, the output is dropped rather than
+// stalling the guest.
+struct PtyOutput(File);
+
+impl io::Write for PtyOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.write(buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(buf.len()),
+            res => res,
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// Create the pseudo terminal of a device unless it was kept from a previous
+// boot, and report its path through the device configuration.
+fn attach_pty(pty: &mut Option<Arc<PtyPair>>, file: &mut Option<PathBuf>) -> io::Result<PtyOutput> {
+    if pty.is_none() {
+        *pty = Some(Arc::new(create_pty()?));
+    }
+    let pty = pty.as_ref().unwrap();
+    *file = Some(pty.path.clone());
+
+    Ok(PtyOutput(pty.main.try_clone()?))
+}
+
 enum ConsoleInput {
     Serial,
     VirtioConsole,
@@ -417,31 +578,36 @@ pub struct Console {
 impl Console {
     pub fn queue_input_bytes(&self, out: &[u8]) -> vmm_sys_util::errno::Result<()> {
         match self.input {
-            Some(ConsoleInput::Serial) => {
-                if self.serial.is_some() {
-                    self.serial
-                        .as_ref()
-                        .unwrap()
-                        .lock()
-                        .expect("Failed to process stdin event due to poisoned lock")
-                        .queue_input_bytes(out)?;
-                }
-            }
-
-            Some(ConsoleInput::VirtioConsole) => {
-                if self.virtio_console_input.is_some() {
-                    self.virtio_console_input
-                        .as_ref()
-                        .unwrap()
-                        .queue_input_bytes(out);
-                }
-            }
+            Some(ConsoleInput::Serial) => self.queue_input_bytes_serial(out)?,
+            Some(ConsoleInput::VirtioConsole) => self.queue_input_bytes_console(out),
             None => {}
         }
 
         Ok(())
     }
 
+    pub fn queue_input_bytes_serial(&self, out: &[u8]) -> vmm_sys_util::errno::Result<()> {
+        if self.serial.is_some() {
+            self.serial
+                .as_ref()
+                .unwrap()
+                .lock()
+                .expect("Failed to process console input due to poisoned lock")
+                .queue_input_bytes(out)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn queue_input_bytes_console(&self, out: &[u8]) {
+        if self.virtio_console_input.is_some() {
+            self.virtio_console_input
+                .as_ref()
+                .unwrap()
+                .queue_input_bytes(out);
+        }
+    }
+
     pub fn update_console_size(&self, cols: u16, rows: u16) {
         if self.virtio_console_input.is_some() {
             self.virtio_console_input
@@ -675,6 +841,7 @@ impl DeviceRelocation for AddressManager {
 
 struct ActivatedBackend {
     _socket_file: tempfile::NamedTempFile,
+    _stats_file: Option<tempfile::NamedTempFile>,
     child: std::process::Child,
 }
 
@@ -805,6 +972,10 @@ pub struct DeviceManager {
 
     // seccomp action
     seccomp_action: SeccompAction,
+
+    // Pseudo terminals of the serial port and the virtio-console
+    serial_pty: Option<Arc<PtyPair>>,
+    console_pty: Option<Arc<PtyPair>>,
 }
 
 impl DeviceManager {
@@ -816,6 +987,8 @@ impl DeviceManager {
         #[cfg_attr(target_arch = "aarch64", allow(unused_variables))] reset_evt: &EventFd,
         vmm_path: PathBuf,
         seccomp_action: SeccompAction,
+        serial_pty: Option<Arc<PtyPair>>,
+        console_pty: Option<Arc<PtyPair>>,
     ) -> DeviceManagerResult<Arc<Mutex<Self>>> {
         let device_tree = Arc::new(Mutex::new(DeviceTree::new()));
 
@@ -878,6 +1051,8 @@ impl DeviceManager {
             #[cfg(target_arch = "aarch64")]
             id_to_dev_info: HashMap::new(),
             seccomp_action,
+            serial_pty,
+            console_pty,
         };
 
         #[cfg(feature = "acpi")]
@@ -1492,6 +1667,13 @@ impl DeviceManager {
                 File::create(serial_config.file.as_ref().unwrap())
                     .map_err(DeviceManagerError::SerialOutputFileOpen)?,
             )),
+            ConsoleOutputMode::Pty => Some(Box::new(
+                attach_pty(
+                    &mut self.serial_pty,
+                    &mut self.config.lock().unwrap().serial.file,
+                )
+                .map_err(DeviceManagerError::SerialPtyOpen)?,
+            )),
             ConsoleOutputMode::Tty => Some(Box::new(stdout())),
             ConsoleOutputMode::Off | ConsoleOutputMode::Null => None,
         };
@@ -1508,6 +1690,13 @@ impl DeviceManager {
                 File::create(console_config.file.as_ref().unwrap())
                     .map_err(DeviceManagerError::ConsoleOutputFileOpen)?,
             )),
+            ConsoleOutputMode::Pty => Some(Box::new(
+                attach_pty(
+                    &mut self.console_pty,
+                    &mut self.config.lock().unwrap().console.file,
+                )
+                .map_err(DeviceManagerError::ConsolePtyOpen)?,
+            )),
             ConsoleOutputMode::Tty => Some(Box::new(stdout())),
             ConsoleOutputMode::Null => Some(Box::new(sink())),
             ConsoleOutputMode::Off => None,
@@ -1585,27 +1774,50 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    /// Launch block backend
-    fn start_block_backend(&mut self, disk_cfg: &DiskConfig) -> DeviceManagerResult<String> {
+    /// Launch block backend, returning the socket to connect to and the
+    /// statistics recorded by the backend.
+    fn start_block_backend(
+        &mut self,
+        disk_cfg: &DiskConfig,
+    ) -> DeviceManagerResult<(String, SharedBlockStats)> {
         let _socket_file = NamedTempFile::new().map_err(DeviceManagerError::CreateSocketFile)?;
         let socket = _socket_file.path().to_str().unwrap().to_owned();
+        let stats_file = NamedTempFile::new().map_err(DeviceManagerError::CreateBlockStatsFile)?;
+        let stats = SharedBlockStats::new(
+            stats_file
+                .reopen()
+                .map_err(DeviceManagerError::CreateBlockStatsFile)?,
+        )
+        .map_err(DeviceManagerError::CreateBlockStatsFile)?;
+
+        let mut backend_args = format!(
+            "path={},socket={},num_queues={},queue_size={}",
+            disk_cfg
+                .path
+                .as_ref()
+                .ok_or(DeviceManagerError::NoDiskPath)?
+                .to_str()
+                .unwrap(),
+            &socket,
+            disk_cfg.num_queues,
+            disk_cfg.queue_size
+        );
+        if let Some(key_file) = &disk_cfg.key_file {
+            backend_args.push_str(&format!(",key_file={}", key_file.to_str().unwrap()));
+        }
+        if let Some(key_fd) = disk_cfg.key_fd {
+            backend_args.push_str(&format!(",key_fd={}", key_fd));
+        }
+        if disk_cfg.copy_on_read {
+            backend_args.push_str(",copy_on_read=on");
+        }
+        backend_args.push_str(&format!(
+            ",stats_file={}",
+            stats_file.path().to_str().unwrap()
+        ));
 
         let child = std::process::Command::new(&self.vmm_path)
-            .args(&[
-                "--block-backend",
-                &format!(
-                    "path={},socket={},num_queues={},queue_size={}",
-                    disk_cfg
-                        .path
-                        .as_ref()
-                        .ok_or(DeviceManagerError::NoDiskPath)?
-                        .to_str()
-                        .unwrap(),
-                    &socket,
-                    disk_cfg.num_queues,
-                    disk_cfg.queue_size
-                ),
-            ])
+            .args(&["--block-backend", &backend_args])
             .spawn()
             .map_err(DeviceManagerError::SpawnBlockBackend)?;
 
@@ -1613,9 +1825,10 @@ impl DeviceManager {
         self.vhost_user_backends.push(ActivatedBackend {
             child,
             _socket_file,
+            _stats_file: Some(stats_file),
         });
 
-        Ok(socket)
+        Ok((socket, stats))
     }
 
     fn make_virtio_block_device(
@@ -1631,24 +1844,27 @@ impl DeviceManager {
         };
 
         if disk_cfg.vhost_user {
-            let socket = if let Some(socket) = disk_cfg.vhost_socket.clone() {
-                socket
+            let (socket, stats) = if let Some(socket) = disk_cfg.vhost_socket.clone() {
+                (socket, None)
             } else {
-                self.start_block_backend(disk_cfg)?
+                let (socket, stats) = self.start_block_backend(disk_cfg)?;
+                (socket, Some(stats))
             };
             let vu_cfg = VhostUserConfig {
                 socket,
                 num_queues: disk_cfg.num_queues,
                 queue_size: disk_cfg.queue_size,
             };
-            let vhost_user_block_device = Arc::new(Mutex::new(
-                virtio_devices::vhost_user::Blk::new(
-                    id.clone(),
-                    vu_cfg,
-                    self.seccomp_action.clone(),
-                )
-                .map_err(DeviceManagerError::CreateVhostUserBlk)?,
-            ));
+            let mut vhost_user_block = virtio_devices::vhost_user::Blk::new(
+                id.clone(),
+                vu_cfg,
+                self.seccomp_action.clone(),
+            )
+            .map_err(DeviceManagerError::CreateVhostUserBlk)?;
+            if let Some(stats) = stats {
+                vhost_user_block.set_stats(stats);
+            }
+            let vhost_user_block_device = Arc::new(Mutex::new(vhost_user_block));
 
             // Fill the device tree with a new node. In case of restore, we
             // know there is nothing to do, so we can simply override the
@@ -1664,134 +1880,54 @@ impl DeviceManager {
                 id,
             ))
         } else {
-            let mut options = OpenOptions::new();
-            options.read(true);
-            options.write(!disk_cfg.readonly);
-            if disk_cfg.direct {
-                options.custom_flags(libc::O_DIRECT);
-            }
-            // Open block device path
-            let image: File = options
-                .open(
-                    disk_cfg
-                        .path
-                        .as_ref()
-                        .ok_or(DeviceManagerError::NoDiskPath)?
-                        .clone(),
-                )
-                .map_err(DeviceManagerError::Disk)?;
-
-            let mut raw_img = qcow::RawFile::new(image.try_clone().unwrap(), disk_cfg.direct);
-
-            let image_type = qcow::detect_image_type(&mut raw_img)
-                .map_err(DeviceManagerError::DetectImageType)?;
-            let (virtio_device, migratable_device) = match image_type {
-                ImageType::Raw => {
-                    #[cfg(feature = "io_uring")]
-                    {
-                        // Use asynchronous backend relying on io_uring if the
-                        // syscalls are supported.
-                        if block_io_uring_is_supported() {
-                            let dev = Arc::new(Mutex::new(
-                                virtio_devices::BlockIoUring::new(
-                                    id.clone(),
-                                    image,
-                                    disk_cfg
-                                        .path
-                                        .as_ref()
-                                        .ok_or(DeviceManagerError::NoDiskPath)?
-                                        .clone(),
-                                    disk_cfg.readonly,
-                                    disk_cfg.iommu,
-                                    disk_cfg.num_queues,
-                                    disk_cfg.queue_size,
-                                )
-                                .map_err(DeviceManagerError::CreateVirtioBlock)?,
-                            ));
-
-                            (
-                                Arc::clone(&dev) as VirtioDeviceArc,
-                                dev as Arc<Mutex<dyn Migratable>>,
-                            )
-                        } else {
-                            let dev = Arc::new(Mutex::new(
-                                virtio_devices::Block::new(
-                                    id.clone(),
-                                    raw_img,
-                                    disk_cfg
-                                        .path
-                                        .as_ref()
-                                        .ok_or(DeviceManagerError::NoDiskPath)?
-                                        .clone(),
-                                    disk_cfg.readonly,
-                                    disk_cfg.iommu,
-                                    disk_cfg.num_queues,
-                                    disk_cfg.queue_size,
-                                    self.seccomp_action.clone(),
-                                )
-                                .map_err(DeviceManagerError::CreateVirtioBlock)?,
-                            ));
-
-                            (
-                                Arc::clone(&dev) as VirtioDeviceArc,
-                                dev as Arc<Mutex<dyn Migratable>>,
-                            )
-                        }
-                    }
-
-                    #[cfg(not(feature = "io_uring"))]
-                    {
-                        let dev = Arc::new(Mutex::new(
-                            virtio_devices::Block::new(
-                                id.clone(),
-                                raw_img,
-                                disk_cfg
-                                    .path
-                                    .as_ref()
-                                    .ok_or(DeviceManagerError::NoDiskPath)?
-                                    .clone(),
-                                disk_cfg.readonly,
-                                disk_cfg.iommu,
-                                disk_cfg.num_queues,
-                                disk_cfg.queue_size,
-                                self.seccomp_action.clone(),
-                            )
-                            .map_err(DeviceManagerError::CreateVirtioBlock)?,
-                        ));
+            let (virtio_device, migratable_device) = if let Some(uri) = disk_cfg.nbd_uri() {
+                let disk = NbdDisk::new(uri).map_err(DeviceManagerError::NbdDeviceCreate)?;
+                let readonly = disk_cfg.readonly || disk.is_read_only();
+                self.make_virtio_block_sync_device(&id, disk, readonly, disk_cfg)?
+            } else if disk_cfg.has_key() {
+                self.make_virtio_block_luks_device(&id, disk_cfg)?
+            } else if disk_cfg.snapshot {
+                let overlay_img = Self::open_disk_overlay(disk_cfg)?;
+                self.make_virtio_block_sync_device(&id, overlay_img, disk_cfg.readonly, disk_cfg)?
+            } else if disk_cfg.zone_size.is_some() {
+                self.make_virtio_block_zoned_device(&id, disk_cfg)?
+            } else {
+                let image = Self::open_disk_image(disk_cfg)?;
+                let mut raw_img = qcow::RawFile::new(image.try_clone().unwrap(), disk_cfg.direct);
 
-                        (
-                            Arc::clone(&dev) as VirtioDeviceArc,
-                            dev as Arc<Mutex<dyn Migratable>>,
-                        )
+                match Self::disk_image_type(&mut raw_img, disk_cfg)? {
+                    ImageType::Raw => {
+                        self.make_virtio_block_raw_device(&id, image, raw_img, disk_cfg)?
                     }
-                }
-                ImageType::Qcow2 => {
-                    let qcow_img =
-                        QcowFile::from(raw_img).map_err(DeviceManagerError::QcowDeviceCreate)?;
-                    let dev = Arc::new(Mutex::new(
-                        virtio_devices::Block::new(
-                            id.clone(),
-                            qcow_img,
-                            disk_cfg
-                                .path
-                                .as_ref()
-                                .ok_or(DeviceManagerError::NoDiskPath)?
-                                .clone(),
+                    ImageType::Qcow2 => {
+                        let mut qcow_img = QcowFile::from(raw_img, disk_cfg.backing_files)
+                            .map_err(DeviceManagerError::QcowDeviceCreate)?;
+                        qcow_img.set_copy_on_read(disk_cfg.copy_on_read);
+                        self.make_virtio_block_qcow_device(&id, qcow_img, disk_cfg)?
+                    }
+                    ImageType::FixedVhd => {
+                        let vhd_img = FixedVhdFile::new(raw_img)
+                            .map_err(DeviceManagerError::VhdDeviceCreate)?;
+                        self.make_virtio_block_sync_device(
+                            &id,
+                            vhd_img,
                             disk_cfg.readonly,
-                            disk_cfg.iommu,
-                            disk_cfg.num_queues,
-                            disk_cfg.queue_size,
-                            self.seccomp_action.clone(),
-                        )
-                        .map_err(DeviceManagerError::CreateVirtioBlock)?,
-                    ));
-
-                    (
-                        Arc::clone(&dev) as VirtioDeviceArc,
-                        dev as Arc<Mutex<dyn Migratable>>,
-                    )
+                            disk_cfg,
+                        )?
+                    }
+                    ImageType::Vhdx => {
+                        let vhdx_img = VhdxFile::from(raw_img)
+                            .map_err(DeviceManagerError::VhdxDeviceCreate)?;
+                        self.make_virtio_block_sync_device(
+                            &id,
+                            vhdx_img,
+                            disk_cfg.readonly,
+                            disk_cfg,
+                        )?
+                    }
                 }
             };
+
             // Fill the device tree with a new node. In case of restore, we
             // know there is nothing to do, so we can simply override the
             // existing entry.
@@ -1804,6 +1940,324 @@ impl DeviceManager {
         }
     }
 
+    fn open_disk_image(disk_cfg: &DiskConfig) -> DeviceManagerResult<File> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        options.write(!disk_cfg.readonly);
+        if disk_cfg.direct {
+            options.custom_flags(libc::O_DIRECT);
+        }
+        // Open block device path
+        options
+            .open(
+                disk_cfg
+                    .path
+                    .as_ref()
+                    .ok_or(DeviceManagerError::NoDiskPath)?
+                    .clone(),
+            )
+            .map_err(DeviceManagerError::Disk)
+    }
+
+    fn qcow_image_type(image_type: DiskImageType) -> ImageType {
+        match image_type {
+            DiskImageType::Raw => ImageType::Raw,
+            DiskImageType::Qcow2 => ImageType::Qcow2,
+            DiskImageType::Vhd => ImageType::FixedVhd,
+            DiskImageType::Vhdx => ImageType::Vhdx,
+        }
+    }
+
+    // Returns the format of the disk image. A configured format is trusted
+    // as is, since the guest could write a header of another format on a raw
+    // image.
+    fn disk_image_type(
+        raw_img: &mut qcow::RawFile,
+        disk_cfg: &DiskConfig,
+    ) -> DeviceManagerResult<ImageType> {
+        match disk_cfg.image_type {
+            Some(image_type) => Ok(Self::qcow_image_type(image_type)),
+            None => qcow::detect_image_type(raw_img).map_err(DeviceManagerError::DetectImageType),
+        }
+    }
+
+    // Create a qcow2 overlay on top of the disk image, backed by an unlinked
+    // temporary file. All writes land in the overlay, which is discarded as
+    // soon as the file is closed, leaving the disk image untouched.
+    fn open_disk_overlay(disk_cfg: &DiskConfig) -> DeviceManagerResult<QcowFile> {
+        // The overlay doesn't live next to the image, so relative names
+        // can't be resolved against its location.
+        let backing_path = disk_cfg
+            .path
+            .as_ref()
+            .ok_or(DeviceManagerError::NoDiskPath)?
+            .canonicalize()
+            .map_err(DeviceManagerError::Disk)?;
+        let overlay = tempfile::tempfile().map_err(DeviceManagerError::CreateDiskOverlayFile)?;
+        let mut qcow_img = QcowFile::new_from_backing(
+            qcow::RawFile::new(overlay, false),
+            3,
+            &backing_path,
+            disk_cfg.image_type.map(Self::qcow_image_type),
+            disk_cfg.backing_files,
+        )
+        .map_err(DeviceManagerError::DiskOverlayCreate)?;
+        qcow_img.set_copy_on_read(disk_cfg.copy_on_read);
+
+        Ok(qcow_img)
+    }
+
+    // Create a virtio-block device for a LUKS encrypted image. Decryption
+    // happens synchronously.
+    fn make_virtio_block_luks_device(
+        &self,
+        id: &str,
+        disk_cfg: &DiskConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, Arc<Mutex<dyn Migratable>>)> {
+        let key = if let Some(key_file) = &disk_cfg.key_file {
+            fs::read(key_file).map_err(DeviceManagerError::ReadDiskKey)?
+        } else {
+            qcow::luks::read_passphrase_fd(disk_cfg.key_fd.unwrap())
+                .map_err(DeviceManagerError::ReadDiskKey)?
+        };
+
+        if let Some(uri) = disk_cfg.nbd_uri() {
+            let disk = NbdDisk::new(uri).map_err(DeviceManagerError::NbdDeviceCreate)?;
+            let readonly = disk_cfg.readonly || disk.is_read_only();
+            let luks_img =
+                LuksDisk::new(disk, &key).map_err(DeviceManagerError::LuksDeviceCreate)?;
+            return self.make_virtio_block_sync_device(id, luks_img, readonly, disk_cfg);
+        }
+
+        if disk_cfg.snapshot {
+            // The overlay stores the encrypted sectors as they are written,
+            // hence the LUKS layer sits on top of it.
+            let overlay_img = Self::open_disk_overlay(disk_cfg)?;
+            let luks_img =
+                LuksDisk::new(overlay_img, &key).map_err(DeviceManagerError::LuksDeviceCreate)?;
+            return self.make_virtio_block_sync_device(id, luks_img, disk_cfg.readonly, disk_cfg);
+        }
+
+        let image = Self::open_disk_image(disk_cfg)?;
+        let mut raw_img = qcow::RawFile::new(image, disk_cfg.direct);
+        match Self::disk_image_type(&mut raw_img, disk_cfg)? {
+            ImageType::Raw => {
+                let luks_img =
+                    LuksDisk::new(raw_img, &key).map_err(DeviceManagerError::LuksDeviceCreate)?;
+                self.make_virtio_block_sync_device(id, luks_img, disk_cfg.readonly, disk_cfg)
+            }
+            ImageType::Qcow2 => {
+                let mut qcow_img = QcowFile::from(raw_img, disk_cfg.backing_files)
+                    .map_err(DeviceManagerError::QcowDeviceCreate)?;
+                qcow_img.set_copy_on_read(disk_cfg.copy_on_read);
+                let luks_img =
+                    LuksDisk::new(qcow_img, &key).map_err(DeviceManagerError::LuksDeviceCreate)?;
+                self.make_virtio_block_sync_device(id, luks_img, disk_cfg.readonly, disk_cfg)
+            }
+            ImageType::FixedVhd => {
+                let vhd_img =
+                    FixedVhdFile::new(raw_img).map_err(DeviceManagerError::VhdDeviceCreate)?;
+                let luks_img =
+                    LuksDisk::new(vhd_img, &key).map_err(DeviceManagerError::LuksDeviceCreate)?;
+                self.make_virtio_block_sync_device(id, luks_img, disk_cfg.readonly, disk_cfg)
+            }
+            ImageType::Vhdx => {
+                let vhdx_img =
+                    VhdxFile::from(raw_img).map_err(DeviceManagerError::VhdxDeviceCreate)?;
+                let luks_img =
+                    LuksDisk::new(vhdx_img, &key).map_err(DeviceManagerError::LuksDeviceCreate)?;
+                self.make_virtio_block_sync_device(id, luks_img, disk_cfg.readonly, disk_cfg)
+            }
+        }
+    }
+
+    // Create a virtio-block device for a zoned disk. The zone conditions are
+    // checked and updated along with each request.
+    fn make_virtio_block_zoned_device(
+        &self,
+        id: &str,
+        disk_cfg: &DiskConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, Arc<Mutex<dyn Migratable>>)> {
+        let image = Self::open_disk_image(disk_cfg)?;
+        let mut raw_img = qcow::RawFile::new(image, disk_cfg.direct);
+        match Self::disk_image_type(&mut raw_img, disk_cfg)? {
+            ImageType::Raw => {
+                self.make_virtio_block_sync_device(id, raw_img, disk_cfg.readonly, disk_cfg)
+            }
+            ImageType::Qcow2 => {
+                let mut qcow_img = QcowFile::from(raw_img, disk_cfg.backing_files)
+                    .map_err(DeviceManagerError::QcowDeviceCreate)?;
+                qcow_img.set_copy_on_read(disk_cfg.copy_on_read);
+                self.make_virtio_block_sync_device(id, qcow_img, disk_cfg.readonly, disk_cfg)
+            }
+            ImageType::FixedVhd => {
+                let vhd_img =
+                    FixedVhdFile::new(raw_img).map_err(DeviceManagerError::VhdDeviceCreate)?;
+                self.make_virtio_block_sync_device(id, vhd_img, disk_cfg.readonly, disk_cfg)
+            }
+            ImageType::Vhdx => {
+                let vhdx_img =
+                    VhdxFile::from(raw_img).map_err(DeviceManagerError::VhdxDeviceCreate)?;
+                self.make_virtio_block_sync_device(id, vhdx_img, disk_cfg.readonly, disk_cfg)
+            }
+        }
+    }
+
+    // Create a virtio-block device processing the requests synchronously.
+    // Disks which need every request to go through their own code, such as
+    // NBD, LUKS, overlays or zoned disks, rely on it, since the asynchronous
+    // backends relying on io_uring or Linux native AIO bypass that code.
+    fn make_virtio_block_sync_device<T: 'static + DiskFile + Send>(
+        &self,
+        id: &str,
+        disk: T,
+        readonly: bool,
+        disk_cfg: &DiskConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, Arc<Mutex<dyn Migratable>>)> {
+        let mut block = virtio_devices::Block::new(
+            id.to_string(),
+            disk,
+            disk_cfg
+                .path
+                .as_ref()
+                .ok_or(DeviceManagerError::NoDiskPath)?
+                .clone(),
+            readonly,
+            disk_cfg.iommu,
+            disk_cfg.num_queues,
+            disk_cfg.queue_size,
+            self.seccomp_action.clone(),
+        )
+        .map_err(DeviceManagerError::CreateVirtioBlock)?;
+        if let Some(zone_size) = disk_cfg.zone_size {
+            // The write pointers are only saved when the image itself can
+            // be modified.
+            block
+                .set_zoned(
+                    zone_size / block_util::SECTOR_SIZE,
+                    disk_cfg.max_open_zones,
+                    disk_cfg.max_active_zones,
+                    &block_util::zoned::zones_state_path(
+                        disk_cfg
+                            .path
+                            .as_ref()
+                            .ok_or(DeviceManagerError::NoDiskPath)?,
+                    ),
+                    !disk_cfg.readonly && !disk_cfg.snapshot,
+                )
+                .map_err(DeviceManagerError::ZonesState)?;
+        }
+        let dev = Arc::new(Mutex::new(block));
+
+        Ok((
+            Arc::clone(&dev) as VirtioDeviceArc,
+            dev as Arc<Mutex<dyn Migratable>>,
+        ))
+    }
+
+    // Create a virtio-block device for a raw image. The asynchronous backend
+    // relying on io_uring is used if the syscalls are supported. Otherwise,
+    // Linux native AIO is used for images opened with O_DIRECT, as it only
+    // behaves asynchronously when bypassing the page cache.
+    fn make_virtio_block_raw_device(
+        &self,
+        id: &str,
+        image: File,
+        raw_img: qcow::RawFile,
+        disk_cfg: &DiskConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, Arc<Mutex<dyn Migratable>>)> {
+        let disk_path = disk_cfg
+            .path
+            .as_ref()
+            .ok_or(DeviceManagerError::NoDiskPath)?
+            .clone();
+
+        #[cfg(feature = "io_uring")]
+        {
+            if block_io_uring_is_supported() {
+                let dev = Arc::new(Mutex::new(
+                    virtio_devices::BlockIoUring::new(
+                        id.to_string(),
+                        virtio_devices::DiskImage::Raw(image),
+                        disk_path,
+                        disk_cfg.readonly,
+                        disk_cfg.iommu,
+                        disk_cfg.num_queues,
+                        disk_cfg.queue_size,
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioBlock)?,
+                ));
+
+                return Ok((
+                    Arc::clone(&dev) as VirtioDeviceArc,
+                    dev as Arc<Mutex<dyn Migratable>>,
+                ));
+            }
+        }
+
+        if disk_cfg.direct && block_aio_is_supported() {
+            let dev = Arc::new(Mutex::new(
+                virtio_devices::BlockAio::new(
+                    id.to_string(),
+                    image,
+                    disk_path,
+                    disk_cfg.readonly,
+                    disk_cfg.iommu,
+                    disk_cfg.num_queues,
+                    disk_cfg.queue_size,
+                )
+                .map_err(DeviceManagerError::CreateVirtioBlock)?,
+            ));
+
+            Ok((
+                Arc::clone(&dev) as VirtioDeviceArc,
+                dev as Arc<Mutex<dyn Migratable>>,
+            ))
+        } else {
+            self.make_virtio_block_sync_device(id, raw_img, disk_cfg.readonly, disk_cfg)
+        }
+    }
+
+    // Create a virtio-block device for a qcow2 image. The asynchronous
+    // backend relying on io_uring is used for the data clusters if the
+    // syscalls are supported. Images reading clusters from a backing file
+    // can't use it, since these clusters aren't in the image.
+    fn make_virtio_block_qcow_device(
+        &self,
+        id: &str,
+        qcow_img: QcowFile,
+        disk_cfg: &DiskConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, Arc<Mutex<dyn Migratable>>)> {
+        #[cfg(feature = "io_uring")]
+        {
+            if block_io_uring_is_supported() && !qcow_img.has_backing_file() {
+                let dev = Arc::new(Mutex::new(
+                    virtio_devices::BlockIoUring::new(
+                        id.to_string(),
+                        virtio_devices::DiskImage::Qcow(Arc::new(Mutex::new(qcow_img))),
+                        disk_cfg
+                            .path
+                            .as_ref()
+                            .ok_or(DeviceManagerError::NoDiskPath)?
+                            .clone(),
+                        disk_cfg.readonly,
+                        disk_cfg.iommu,
+                        disk_cfg.num_queues,
+                        disk_cfg.queue_size,
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioBlock)?,
+                ));
+
+                return Ok((
+                    Arc::clone(&dev) as VirtioDeviceArc,
+                    dev as Arc<Mutex<dyn Migratable>>,
+                ));
+            }
+        }
+
+        self.make_virtio_block_sync_device(id, qcow_img, disk_cfg.readonly, disk_cfg)
+    }
+
     fn make_virtio_block_devices(
         &mut self,
     ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, String)>> {
@@ -1829,16 +2283,32 @@ impl DeviceManager {
             .args(&[
                 "--net-backend",
                 &format!(
-                    "ip={},mask={},socket={},num_queues={},queue_size={}{}",
+                    "ip={},mask={},ipv4={},socket={},num_queues={},queue_size={},csum={},tso={},tso6={},ufo={},ecn={}{}{}{}",
                     net_cfg.ip,
                     net_cfg.mask,
+                    net_cfg.ipv4,
                     &socket,
                     net_cfg.num_queues,
                     net_cfg.queue_size,
+                    net_cfg.csum,
+                    net_cfg.tso,
+                    net_cfg.tso6,
+                    net_cfg.ufo,
+                    net_cfg.ecn,
                     if let Some(mac) = net_cfg.host_mac {
                         format!(",host_mac={:}", mac)
                     } else {
                         "".to_owned()
+                    },
+                    if let Some(mtu) = net_cfg.mtu {
+                        format!(",mtu={}", mtu)
+                    } else {
+                        "".to_owned()
+                    },
+                    if let Some((ip, prefix_len)) = net_cfg.ipv6_addr() {
+                        format!(",ipv6={},ipv6_prefix={}", ip, prefix_len)
+                    } else {
+                        "".to_owned()
                     }
                 ),
             ])
@@ -1849,6 +2319,7 @@ impl DeviceManager {
         self.vhost_user_backends.push(ActivatedBackend {
             child,
             _socket_file,
+            _stats_file: None,
         });
 
         Ok(socket)
@@ -1882,6 +2353,8 @@ impl DeviceManager {
                     id.clone(),
                     net_cfg.mac,
                     vu_cfg,
+                    net_cfg.mtu,
+                    net_cfg.offloads(),
                     self.seccomp_action.clone(),
                 )
                 .map_err(DeviceManagerError::CreateVhostUserNet)?,
@@ -1901,40 +2374,125 @@ impl DeviceManager {
                 id,
             ))
         } else {
-            let virtio_net_device = if let Some(ref tap_if_name) = net_cfg.tap {
+            let virtio_net_device = if net_cfg.user {
+                Arc::new(Mutex::new(
+                    virtio_devices::Net::new_user(
+                        id.clone(),
+                        net_cfg.mac,
+                        net_cfg.iommu,
+                        net_cfg.queue_size,
+                        net_cfg.mtu,
+                        net_cfg.ip,
+                        net_cfg.mask,
+                        net_cfg.hostfwd.clone(),
+                        self.seccomp_action.clone(),
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioNet)?,
+                ))
+            } else if let Some(macvtap) = net_cfg.macvtap.clone() {
+                let (taps, mac) = net_util::open_macvtap(
+                    &macvtap,
+                    net_cfg.num_queues / 2,
+                    net_cfg.mtu,
+                    net_cfg.offloads(),
+                )
+                .map_err(DeviceManagerError::OpenMacvtap)?;
+                // Frames are only delivered to the macvtap if they are sent
+                // to the MAC address of its link, the guest must inherit it.
+                net_cfg.mac = mac;
+                Arc::new(Mutex::new(
+                    virtio_devices::Net::new_with_tap(
+                        id.clone(),
+                        taps,
+                        Some(mac),
+                        net_cfg.iommu,
+                        net_cfg.num_queues,
+                        net_cfg.queue_size,
+                        net_cfg.mtu,
+                        net_cfg.offloads(),
+                        net_cfg.dhcp_server(),
+                        self.seccomp_action.clone(),
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioNet)?,
+                ))
+            } else if let Some(fds) = net_cfg.fds.clone() {
+                let (taps, host_mac) = net_util::open_tap_fds(&fds, net_cfg.offloads())
+                    .map_err(DeviceManagerError::OpenTapFds)?;
+                net_cfg.host_mac = Some(host_mac);
+                Arc::new(Mutex::new(
+                    virtio_devices::Net::new_with_tap(
+                        id.clone(),
+                        taps,
+                        Some(net_cfg.mac),
+                        net_cfg.iommu,
+                        net_cfg.num_queues,
+                        net_cfg.queue_size,
+                        net_cfg.mtu,
+                        net_cfg.offloads(),
+                        net_cfg.dhcp_server(),
+                        self.seccomp_action.clone(),
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioNet)?,
+                ))
+            } else if let Some(ref tap_if_name) = net_cfg.tap {
                 Arc::new(Mutex::new(
                     virtio_devices::Net::new(
                         id.clone(),
                         Some(tap_if_name),
                         None,
                         None,
+                        None,
                         Some(net_cfg.mac),
                         &mut net_cfg.host_mac,
                         net_cfg.iommu,
                         net_cfg.num_queues,
                         net_cfg.queue_size,
+                        net_cfg.mtu,
+                        net_cfg.offloads(),
+                        net_cfg.dhcp_server(),
                         self.seccomp_action.clone(),
                     )
                     .map_err(DeviceManagerError::CreateVirtioNet)?,
                 ))
             } else {
+                let (ip, mask) = if net_cfg.ipv4 {
+                    (Some(net_cfg.ip), Some(net_cfg.mask))
+                } else {
+                    (None, None)
+                };
                 Arc::new(Mutex::new(
                     virtio_devices::Net::new(
                         id.clone(),
                         None,
-                        Some(net_cfg.ip),
-                        Some(net_cfg.mask),
+                        ip,
+                        mask,
+                        net_cfg.ipv6_addr(),
                         Some(net_cfg.mac),
                         &mut net_cfg.host_mac,
                         net_cfg.iommu,
                         net_cfg.num_queues,
                         net_cfg.queue_size,
+                        net_cfg.mtu,
+                        net_cfg.offloads(),
+                        net_cfg.dhcp_server(),
                         self.seccomp_action.clone(),
                     )
                     .map_err(DeviceManagerError::CreateVirtioNet)?,
                 ))
             };
 
+            if let Some(bridge) = &net_cfg.bridge {
+                virtio_net_device
+                    .lock()
+                    .unwrap()
+                    .attach_to_bridge(bridge)
+                    .map_err(DeviceManagerError::AttachBridge)?;
+            }
+            virtio_net_device
+                .lock()
+                .unwrap()
+                .set_vhost_net(net_cfg.vhost_net);
+
             // Fill the device tree with a new node. In case of restore, we
             // know there is nothing to do, so we can simply override the
             // existing entry.
@@ -3020,6 +3578,14 @@ impl DeviceManager {
         &self.console
     }
 
+    pub fn serial_pty(&self) -> Option<Arc<PtyPair>> {
+        self.serial_pty.clone()
+    }
+
+    pub fn console_pty(&self) -> Option<Arc<PtyPair>> {
+        self.console_pty.clone()
+    }
+
     pub fn cmdline_additions(&self) -> &[String] {
         self.cmdline_additions.as_slice()
     }
@@ -3123,8 +3689,22 @@ impl DeviceManager {
                             .device_type(),
                     );
                     match device_type {
-                        VirtioDeviceType::TYPE_NET
-                        | VirtioDeviceType::TYPE_BLOCK
+                        VirtioDeviceType::TYPE_NET => {
+                            // The device is removed even if its tap can't be
+                            // detached from the bridge, which can be done by
+                            // hand afterwards.
+                            if let Err(e) = virtio_pci_device
+                                .lock()
+                                .unwrap()
+                                .virtio_device()
+                                .lock()
+                                .unwrap()
+                                .detach_from_bridge()
+                            {
+                                error!("Failed to detach tap from bridge: {:?}", e);
+                            }
+                        }
+                        VirtioDeviceType::TYPE_BLOCK
                         | VirtioDeviceType::TYPE_PMEM
                         | VirtioDeviceType::TYPE_FS
                         | VirtioDeviceType::TYPE_VSOCK => {}
@@ -3345,6 +3925,94 @@ impl DeviceManager {
 
         counters
     }
+
+    pub fn resize_disk(&mut self, id: &str, desired_size: u64) -> DeviceManagerResult<()> {
+        for (virtio_device, _, device_id) in &self.virtio_devices {
+            if device_id == id {
+                return virtio_device
+                    .lock()
+                    .unwrap()
+                    .resize_disk(desired_size)
+                    .map_err(DeviceManagerError::ResizeDisk);
+            }
+        }
+
+        Err(DeviceManagerError::UnknownDiskId(id.to_owned()))
+    }
+
+    pub fn stream_disk(&mut self, id: &str) -> DeviceManagerResult<()> {
+        for (virtio_device, _, device_id) in &self.virtio_devices {
+            if device_id == id {
+                return virtio_device
+                    .lock()
+                    .unwrap()
+                    .stream_disk()
+                    .map_err(DeviceManagerError::StreamDisk);
+            }
+        }
+
+        Err(DeviceManagerError::UnknownDiskId(id.to_owned()))
+    }
+
+    pub fn set_link(&mut self, id: &str, up: bool) -> DeviceManagerResult<()> {
+        for (virtio_device, _, device_id) in &self.virtio_devices {
+            if device_id == id {
+                return virtio_device
+                    .lock()
+                    .unwrap()
+                    .set_link_up(up)
+                    .map_err(DeviceManagerError::SetLink);
+            }
+        }
+
+        Err(DeviceManagerError::UnknownNetId(id.to_owned()))
+    }
+
+    pub fn start_net_capture(
+        &mut self,
+        id: &str,
+        path: &Path,
+        snaplen: Option<u32>,
+    ) -> DeviceManagerResult<()> {
+        // The frames of a device using vhost-net don't go through the VMM,
+        // even before its queue pairs are handed over to the kernel.
+        if let Some(net_list_cfg) = &self.config.lock().unwrap().net {
+            if net_list_cfg
+                .iter()
+                .any(|net_cfg| net_cfg.vhost_net && net_cfg.id.as_deref() == Some(id))
+            {
+                return Err(DeviceManagerError::StartNetCapture(
+                    virtio_devices::Error::CaptureNotSupported,
+                ));
+            }
+        }
+
+        for (virtio_device, _, device_id) in &self.virtio_devices {
+            if device_id == id {
+                return virtio_device
+                    .lock()
+                    .unwrap()
+                    .start_capture(path, snaplen)
+                    .map_err(DeviceManagerError::StartNetCapture);
+            }
+        }
+
+        Err(DeviceManagerError::UnknownNetId(id.to_owned()))
+    }
+
+    pub fn stop_net_capture(&mut self, id: &str) -> DeviceManagerResult<()> {
+        for (virtio_device, _, device_id) in &self.virtio_devices {
+            if device_id == id {
+                return virtio_device
+                    .lock()
+                    .unwrap()
+                    .stop_capture()
+                    .map_err(DeviceManagerError::StopNetCapture);
+            }
+        }
+
+        Err(DeviceManagerError::UnknownNetId(id.to_owned()))
+    }
 }
 
 #[cfg(feature = "acpi")]
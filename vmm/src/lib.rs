@@ -84,9 +84,15 @@ pub enum Error {
     /// Cannot create HTTP thread
     HttpThreadSpawn(io::Error),
 
+    /// The HTTP server failed to start
+    HttpServerStart(RecvError),
+
     /// Cannot handle the VM STDIN stream
     Stdin(VmError),
 
+    /// Cannot handle the VM pseudo terminal input
+    Pty(VmError),
+
     /// Cannot reboot the VM
     VmReboot(VmError),
 
@@ -116,6 +122,7 @@ pub enum EpollDispatch {
     Reset,
     Stdin,
     Api,
+    Pty,
 }
 
 pub struct EpollContext {
@@ -323,7 +330,10 @@ impl Vmm {
                     self.vmm_path.clone(),
                     &self.seccomp_action,
                     self.hypervisor.clone(),
+                    None,
+                    None,
                 )?;
+                self.add_pty_events(&vm)?;
                 self.vm = Some(vm);
             }
         }
@@ -336,6 +346,22 @@ impl Vmm {
         }
     }
 
+    fn add_pty_events(&mut self, vm: &Vm) -> result::Result<(), VmError> {
+        if let Some(pty) = vm.serial_pty() {
+            self.epoll
+                .add_event(&pty.main, EpollDispatch::Pty)
+                .map_err(VmError::PtyEpoll)?;
+        }
+
+        if let Some(pty) = vm.console_pty() {
+            self.epoll
+                .add_event(&pty.main, EpollDispatch::Pty)
+                .map_err(VmError::PtyEpoll)?;
+        }
+
+        Ok(())
+    }
+
     fn vm_pause(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
             vm.pause().map_err(VmError::Pause)
@@ -395,6 +421,7 @@ impl Vmm {
             &self.seccomp_action,
             self.hypervisor.clone(),
         )?;
+        self.add_pty_events(&vm)?;
         self.vm = Some(vm);
 
         // Now we can restore the rest of the VM.
@@ -426,6 +453,10 @@ impl Vmm {
         // First we stop the current VM and create a new one.
         if let Some(ref mut vm) = self.vm {
             let config = vm.get_config();
+            // The pseudo terminals are kept, so that the users attached to
+            // them stay attached, and remain registered with the event loop.
+            let serial_pty = vm.serial_pty();
+            let console_pty = vm.console_pty();
             self.vm_shutdown()?;
 
             let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
@@ -444,6 +475,8 @@ impl Vmm {
                 self.vmm_path.clone(),
                 &self.seccomp_action,
                 self.hypervisor.clone(),
+                serial_pty,
+                console_pty,
             )?);
         }
 
@@ -517,6 +550,76 @@ impl Vmm {
         }
     }
 
+    fn vm_resize_disk(&mut self, id: String, desired_size: u64) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.resize_disk(id, desired_size) {
+                error!("Error when resizing disk: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_stream_disk(&mut self, id: String) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.stream_disk(id) {
+                error!("Error when streaming disk: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_set_link(&mut self, id: String, up: bool) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.set_link(id, up) {
+                error!("Error when setting link state: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_start_net_capture(
+        &mut self,
+        id: String,
+        path: PathBuf,
+        snaplen: Option<u32>,
+    ) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.start_net_capture(id, path, snaplen) {
+                error!("Error when starting packet capture: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_stop_net_capture(&mut self, id: String) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.stop_net_capture(id) {
+                error!("Error when stopping packet capture: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_add_device(&mut self, device_cfg: DeviceConfig) -> result::Result<Vec<u8>, VmError> {
         if let Some(ref mut vm) = self.vm {
             let info = vm.add_device(device_cfg).map_err(|e| {
@@ -660,6 +763,11 @@ impl Vmm {
                                 vm.handle_stdin().map_err(Error::Stdin)?;
                             }
                         }
+                        EpollDispatch::Pty => {
+                            if let Some(ref vm) = self.vm {
+                                vm.handle_pty().map_err(Error::Pty)?;
+                            }
+                        }
                         EpollDispatch::Api => {
                             // Consume the event.
                             self.api_evt.read().map_err(Error::EventFdRead)?;
@@ -786,6 +894,48 @@ impl Vmm {
                                         .map(|_| ApiResponsePayload::Empty);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmResizeDisk(resize_disk_data, sender) => {
+                                    let response = self
+                                        .vm_resize_disk(
+                                            resize_disk_data.id.clone(),
+                                            resize_disk_data.desired_size,
+                                        )
+                                        .map_err(ApiError::VmResizeDisk)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmStreamDisk(stream_disk_data, sender) => {
+                                    let response = self
+                                        .vm_stream_disk(stream_disk_data.id.clone())
+                                        .map_err(ApiError::VmStreamDisk)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSetLink(set_link_data, sender) => {
+                                    let response = self
+                                        .vm_set_link(set_link_data.id.clone(), set_link_data.up)
+                                        .map_err(ApiError::VmSetLink)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmStartNetCapture(capture_data, sender) => {
+                                    let response = self
+                                        .vm_start_net_capture(
+                                            capture_data.id.clone(),
+                                            capture_data.path.clone(),
+                                            capture_data.snaplen,
+                                        )
+                                        .map_err(ApiError::VmStartNetCapture)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmStopNetCapture(capture_data, sender) => {
+                                    let response = self
+                                        .vm_stop_net_capture(capture_data.id.clone())
+                                        .map_err(ApiError::VmStopNetCapture)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmAddDevice(add_device_data, sender) => {
                                     let response = self
                                         .vm_add_device(add_device_data.as_ref().clone())
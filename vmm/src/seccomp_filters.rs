@@ -45,6 +45,8 @@ const SYS_IO_URING_REGISTER: i64 = 427;
 const TCGETS: u64 = 0x5401;
 const TCSETS: u64 = 0x5402;
 const TIOCGWINSZ: u64 = 0x5413;
+const TIOCSPTLCK: u64 = 0x4004_5431;
+const TIOCGPTN: u64 = 0x8004_5430;
 const FIOCLEX: u64 = 0x5451;
 const FIONBIO: u64 = 0x5421;
 
@@ -53,15 +55,32 @@ const TUNSETIFF: u64 = 0x4004_54ca;
 const TUNSETOFFLOAD: u64 = 0x4004_54d0;
 const TUNSETVNETHDRSZ: u64 = 0x4004_54d8;
 const TUNGETFEATURES: u64 = 0x8004_54cf;
+const TUNGETIFF: u64 = 0x8004_54d2;
 
 // See include/uapi/linux/sockios.h in the kernel code.
+const SIOCBRADDIF: u64 = 0x89a2;
+const SIOCBRDELIF: u64 = 0x89a3;
 const SIOCGIFFLAGS: u64 = 0x8913;
 const SIOCGIFHWADDR: u64 = 0x8927;
+const SIOCGIFINDEX: u64 = 0x8933;
 const SIOCSIFFLAGS: u64 = 0x8914;
 const SIOCSIFADDR: u64 = 0x8916;
 const SIOCSIFHWADDR: u64 = 0x8924;
+const SIOCSIFMTU: u64 = 0x8922;
 const SIOCSIFNETMASK: u64 = 0x891c;
 
+// See include/uapi/linux/vhost.h in the kernel code.
+const VHOST_GET_FEATURES: u64 = 0x8008_af00;
+const VHOST_SET_FEATURES: u64 = 0x4008_af00;
+const VHOST_SET_OWNER: u64 = 0xaf01;
+const VHOST_SET_MEM_TABLE: u64 = 0x4008_af03;
+const VHOST_SET_VRING_NUM: u64 = 0x4008_af10;
+const VHOST_SET_VRING_ADDR: u64 = 0x4028_af11;
+const VHOST_SET_VRING_BASE: u64 = 0x4008_af12;
+const VHOST_SET_VRING_KICK: u64 = 0x4008_af20;
+const VHOST_SET_VRING_CALL: u64 = 0x4008_af21;
+const VHOST_NET_SET_BACKEND: u64 = 0x4008_af30;
+
 // See include/uapi/linux/vfio.h in the kernel code.
 const VFIO_GET_API_VERSION: u64 = 0x3b64;
 const VFIO_CHECK_EXTENSION: u64 = 0x3b65;
@@ -133,19 +152,36 @@ fn create_vmm_ioctl_seccomp_rule_common() -> Result<Vec<SeccompRule>, Error> {
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_REGS)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_USER_MEMORY_REGION,)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, KVM_SET_VCPU_EVENTS,)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, SIOCBRADDIF)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, SIOCBRDELIF)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, SIOCGIFFLAGS)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, SIOCGIFHWADDR)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, SIOCGIFINDEX)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, SIOCSIFADDR)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, SIOCSIFFLAGS)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, SIOCSIFHWADDR)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, SIOCSIFMTU)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, SIOCSIFNETMASK)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TCSETS)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TCGETS)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TIOCGWINSZ)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, TIOCGPTN)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, TIOCSPTLCK)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNGETFEATURES)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, TUNGETIFF)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETIFF)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETOFFLOAD)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, TUNSETVNETHDRSZ)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_GET_FEATURES)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_FEATURES)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_OWNER)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_MEM_TABLE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_NUM)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_ADDR)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_BASE)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_KICK)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_SET_VRING_CALL)?],
+        and![Cond::new(1, ArgLen::DWORD, Eq, VHOST_NET_SET_BACKEND)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_GET_API_VERSION)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_CHECK_EXTENSION)?],
         and![Cond::new(1, ArgLen::DWORD, Eq, VFIO_SET_IOMMU)?],
@@ -281,8 +317,10 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         #[cfg(target_arch = "aarch64")]
         allow_syscall(libc::SYS_newfstatat),
         allow_syscall(libc::SYS_futex),
+        allow_syscall(libc::SYS_getdents64),
         allow_syscall(libc::SYS_getpid),
         allow_syscall(libc::SYS_getrandom),
+        allow_syscall(libc::SYS_getsockopt),
         allow_syscall(libc::SYS_gettid),
         allow_syscall(libc::SYS_gettimeofday),
         allow_syscall(libc::SYS_getuid),
@@ -290,6 +328,10 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         allow_syscall(SYS_IO_URING_ENTER),
         allow_syscall(SYS_IO_URING_SETUP),
         allow_syscall(SYS_IO_URING_REGISTER),
+        allow_syscall(libc::SYS_io_destroy),
+        allow_syscall(libc::SYS_io_getevents),
+        allow_syscall(libc::SYS_io_setup),
+        allow_syscall(libc::SYS_io_submit),
         allow_syscall(libc::SYS_listen),
         allow_syscall(libc::SYS_lseek),
         allow_syscall(libc::SYS_madvise),
@@ -311,6 +353,7 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         allow_syscall(libc::SYS_read),
         #[cfg(target_arch = "x86_64")]
         allow_syscall(libc::SYS_readlink),
+        allow_syscall(libc::SYS_readlinkat),
         allow_syscall(libc::SYS_recvfrom),
         allow_syscall(libc::SYS_recvmsg),
         allow_syscall(libc::SYS_restart_syscall),
@@ -322,12 +365,15 @@ fn vmm_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         allow_syscall(libc::SYS_sendto),
         allow_syscall(libc::SYS_set_robust_list),
         allow_syscall(libc::SYS_set_tid_address),
+        allow_syscall(libc::SYS_setsockopt),
+        allow_syscall(libc::SYS_shutdown),
         allow_syscall(libc::SYS_sigaltstack),
         allow_syscall_if(
             libc::SYS_socket,
             or![
                 and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_UNIX as u64)?],
                 and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_INET as u64)?],
+                and![Cond::new(0, ArgLen::DWORD, Eq, libc::AF_INET6 as u64)?],
             ],
         ),
         allow_syscall(libc::SYS_socketpair),
@@ -356,7 +402,9 @@ fn api_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         allow_syscall(libc::SYS_accept4),
         allow_syscall(libc::SYS_bind),
         allow_syscall(libc::SYS_brk),
+        allow_syscall(libc::SYS_clone),
         allow_syscall(libc::SYS_close),
+        allow_syscall(libc::SYS_connect),
         allow_syscall(libc::SYS_dup),
         allow_syscall(libc::SYS_epoll_create1),
         allow_syscall(libc::SYS_epoll_ctl),
@@ -369,9 +417,15 @@ fn api_thread_rules() -> Result<Vec<SyscallRuleSet>, Error> {
         allow_syscall_if(libc::SYS_ioctl, create_api_ioctl_seccomp_rule()?),
         allow_syscall(libc::SYS_listen),
         allow_syscall(libc::SYS_madvise),
+        allow_syscall(libc::SYS_mmap),
         allow_syscall(libc::SYS_mprotect),
         allow_syscall(libc::SYS_munmap),
+        allow_syscall(libc::SYS_prctl),
+        allow_syscall(libc::SYS_read),
         allow_syscall(libc::SYS_recvfrom),
+        allow_syscall(libc::SYS_recvmsg),
+        allow_syscall(libc::SYS_rt_sigprocmask),
+        allow_syscall(libc::SYS_set_robust_list),
         allow_syscall(libc::SYS_sigaltstack),
         allow_syscall(libc::SYS_socket),
         allow_syscall(libc::SYS_write),
@@ -28,7 +28,9 @@ use crate::config::{
     ValidationError, VmConfig, VsockConfig,
 };
 use crate::cpu;
-use crate::device_manager::{self, get_win_size, Console, DeviceManager, DeviceManagerError};
+use crate::device_manager::{
+    self, get_win_size, Console, DeviceManager, DeviceManagerError, PtyPair,
+};
 use crate::memory_manager::{Error as MemoryManagerError, MemoryManager};
 use crate::migration::{get_vm_snapshot, url_to_path, VM_SNAPSHOT_FILE};
 use crate::{
@@ -51,7 +53,7 @@ use std::collections::HashMap;
 use std::convert::TryInto;
 use std::ffi::CString;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
 use std::io::{Seek, SeekFrom};
 use std::num::Wrapping;
 use std::ops::Deref;
@@ -198,6 +200,12 @@ pub enum Error {
 
     /// Invalid configuration for NUMA.
     InvalidNumaConfig,
+
+    /// Cannot read from a pseudo terminal.
+    PtyRead(io::Error),
+
+    /// Cannot register a pseudo terminal with the VMM event loop.
+    PtyEpoll(io::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -273,6 +281,8 @@ impl Vm {
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
         _saved_clock: Option<hypervisor::ClockData>,
+        serial_pty: Option<Arc<PtyPair>>,
+        console_pty: Option<Arc<PtyPair>>,
     ) -> Result<Self> {
         config
             .lock()
@@ -288,6 +298,8 @@ impl Vm {
             &reset_evt,
             vmm_path,
             seccomp_action.clone(),
+            serial_pty,
+            console_pty,
         )
         .map_err(Error::DeviceManager)?;
 
@@ -372,6 +384,7 @@ impl Vm {
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         config: Arc<Mutex<VmConfig>>,
         exit_evt: EventFd,
@@ -379,6 +392,8 @@ impl Vm {
         vmm_path: PathBuf,
         seccomp_action: &SeccompAction,
         hypervisor: Arc<dyn hypervisor::Hypervisor>,
+        serial_pty: Option<Arc<PtyPair>>,
+        console_pty: Option<Arc<PtyPair>>,
     ) -> Result<Self> {
         #[cfg(target_arch = "x86_64")]
         hypervisor.check_required_extensions().unwrap();
@@ -414,6 +429,8 @@ impl Vm {
             seccomp_action,
             hypervisor,
             None,
+            serial_pty,
+            console_pty,
         )?;
 
         // The device manager must create the devices from here as it is part
@@ -481,6 +498,8 @@ impl Vm {
             vm_snapshot.clock,
             #[cfg(target_arch = "aarch64")]
             None,
+            None,
+            None,
         )
     }
 
@@ -947,6 +966,15 @@ impl Vm {
 
     #[cfg(feature = "pci_support")]
     pub fn add_disk(&mut self, mut _disk_cfg: DiskConfig) -> Result<PciDeviceInfo> {
+        // The disks added through the API don't go through the validation
+        // of the VM configuration.
+        _disk_cfg.validate().map_err(Error::ConfigValidation)?;
+        if _disk_cfg.vhost_user && !self.config.lock().unwrap().memory.shared {
+            return Err(Error::ConfigValidation(
+                ValidationError::VhostUserRequiresSharedMemory,
+            ));
+        }
+
         let pci_device_info = self
             .device_manager
             .lock()
@@ -1049,6 +1077,15 @@ impl Vm {
 
     #[cfg(feature = "pci_support")]
     pub fn add_net(&mut self, mut _net_cfg: NetConfig) -> Result<PciDeviceInfo> {
+        // The network devices added through the API don't go through the
+        // validation of the VM configuration.
+        _net_cfg.validate().map_err(Error::ConfigValidation)?;
+        if _net_cfg.vhost_user && !self.config.lock().unwrap().memory.shared {
+            return Err(Error::ConfigValidation(
+                ValidationError::VhostUserRequiresSharedMemory,
+            ));
+        }
+
         let pci_device_info = self
             .device_manager
             .lock()
@@ -1114,6 +1151,51 @@ impl Vm {
         Ok(self.device_manager.lock().unwrap().counters())
     }
 
+    pub fn resize_disk(&mut self, id: String, desired_size: u64) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .resize_disk(&id, desired_size)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn stream_disk(&mut self, id: String) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .stream_disk(&id)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn set_link(&mut self, id: String, up: bool) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .set_link(&id, up)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn start_net_capture(
+        &mut self,
+        id: String,
+        path: PathBuf,
+        snaplen: Option<u32>,
+    ) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .start_net_capture(&id, &path, snaplen)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn stop_net_capture(&mut self, id: String) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .stop_net_capture(&id)
+            .map_err(Error::DeviceManager)
+    }
+
     fn os_signal_handler(signals: Signals, console_input_clone: Arc<Console>, on_tty: bool) {
         for signal in signals.forever() {
             match signal {
@@ -1224,6 +1306,52 @@ impl Vm {
         Ok(())
     }
 
+    pub fn serial_pty(&self) -> Option<Arc<PtyPair>> {
+        self.device_manager.lock().unwrap().serial_pty()
+    }
+
+    pub fn console_pty(&self) -> Option<Arc<PtyPair>> {
+        self.device_manager.lock().unwrap().console_pty()
+    }
+
+    // Read what the user typed on a pseudo terminal, if anything.
+    fn read_pty(pty: &PtyPair, out: &mut [u8]) -> Result<usize> {
+        match (&pty.main).read(out) {
+            Ok(count) => Ok(count),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            Err(e) => Err(Error::PtyRead(e)),
+        }
+    }
+
+    pub fn handle_pty(&self) -> Result<()> {
+        let mut out = [0u8; 64];
+
+        if let Some(pty) = self.serial_pty() {
+            let count = Vm::read_pty(&pty, &mut out)?;
+            if count > 0 {
+                self.device_manager
+                    .lock()
+                    .unwrap()
+                    .console()
+                    .queue_input_bytes_serial(&out[..count])
+                    .map_err(Error::Console)?;
+            }
+        }
+
+        if let Some(pty) = self.console_pty() {
+            let count = Vm::read_pty(&pty, &mut out)?;
+            if count > 0 {
+                self.device_manager
+                    .lock()
+                    .unwrap()
+                    .console()
+                    .queue_input_bytes_console(&out[..count]);
+            }
+        }
+
+        Ok(())
+    }
+
     /// Gets a thread-safe reference counted pointer to the VM configuration.
     pub fn get_config(&self) -> Arc<Mutex<VmConfig>> {
         Arc::clone(&self.config)